@@ -0,0 +1,198 @@
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::io::{IoSlice, IoSliceMut};
+
+use tokio::runtime::Runtime;
+
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::rw::AccessMode;
+use crate::vfs::{OpenOptions, VirtualFile, VirtualFileDescription, VirtualFileSystem};
+
+/// VirtualFileSystem 的异步版本，只包含文件和目录的基本操作
+///
+/// 返回的 future 都是 Send 的，可以交给多线程的运行时
+pub trait AsyncVirtualFileSystem: Send {
+    type File: VirtualFile + Send;
+    type Error: Error + Send;
+    type FileDescription: VirtualFileDescription + Send;
+
+    fn init(&mut self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn create_file(&mut self, path: &Path) -> impl Future<Output = Result<Self::FileDescription, Self::Error>> + Send;
+    fn delete_file(&mut self, path: &Path) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> impl Future<Output = Result<Self::File, Self::Error>> + Send;
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> impl Future<Output = Result<Self::File, Self::Error>> + Send;
+    fn description(&mut self, file: &Self::File) -> impl Future<Output = Result<Self::FileDescription, Self::Error>> + Send;
+    fn close(&mut self, file: Self::File) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+
+    fn list(&mut self, path: &Path) -> impl Future<Output = Result<Vec<Self::FileDescription>, Self::Error>> + Send;
+    fn mkdir(&mut self, path: &Path) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn rmdir(&mut self, path: &Path) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// 移动文件或目录，to 不能已经存在，目录不能移动到自己下面
+    fn rename(&mut self, from: &Path, to: &Path) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    fn exists(&mut self, path: &Path) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    fn user(&self) -> u32;
+    fn set_user(&mut self, uid: u32);
+
+    fn end_session(&mut self, session: usize) -> impl Future<Output = Result<usize, Self::Error>> + Send;
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> impl Future<Output = Result<bool, Self::Error>> + Send;
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> impl Future<Output = Result<LockStatus, Self::Error>> + Send;
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    fn locks(&mut self) -> impl Future<Output = Result<Vec<LockInfo>, Self::Error>> + Send;
+
+    /// 把打开的文件还没有写回的修改写到存储上
+    fn fsync(&mut self, file: &Self::File) -> impl Future<Output = Result<(), Self::Error>> + Send;
+    /// 把还没有写回的修改写到存储上
+    fn sync(&mut self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
+
+
+/// 在自己的运行时上阻塞执行异步文件系统，当作 VirtualFileSystem 使用，比如交给 shell
+///
+/// 异步接口中没有的操作返回 Unsupported
+pub struct BlockingFileSystem<F: AsyncVirtualFileSystem> {
+    fs: F,
+    runtime: Runtime,
+}
+
+impl<F: AsyncVirtualFileSystem> BlockingFileSystem<F> {
+    /// 新建一个运行时，在上面等待 create 创建出文件系统
+    pub fn new(create: impl Future<Output = io::Result<F>>) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+        let fs = runtime.block_on(create)?;
+        Ok(BlockingFileSystem { fs, runtime })
+    }
+
+    /// 阻塞执行 f 返回的 future
+    fn block_on<'a, T, Fut>(&'a mut self, f: impl FnOnce(&'a mut F) -> Fut) -> io::Result<T>
+    where Fut: Future<Output = Result<T, F::Error>>, F::Error: Into<io::Error> {
+        self.runtime.block_on(f(&mut self.fs)).map_err(Into::into)
+    }
+}
+
+impl<F: AsyncVirtualFileSystem> VirtualFileSystem for BlockingFileSystem<F>
+where F::Error: Into<io::Error> {
+    type File = F::File;
+    type Error = io::Error;
+    type FileDescription = F::FileDescription;
+
+    fn init(&mut self) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.init())
+    }
+
+    fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, Self::Error> {
+        self.block_on(|fs| fs.create_file(path))
+    }
+
+    fn delete_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.delete_file(path))
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error> {
+        self.block_on(|fs| fs.open(path, mode))
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> Result<Self::File, Self::Error> {
+        self.block_on(|fs| fs.open_with(path, options))
+    }
+
+    fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, Self::Error> {
+        self.block_on(|fs| fs.description(file))
+    }
+
+    fn close(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.close(file))
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.block_on(|fs| fs.read(file, buf))
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.block_on(|fs| fs.write(file, buf))
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        self.block_on(|fs| fs.read_at(file, buf, offset))
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, Self::Error> {
+        self.block_on(|fs| fs.write_at(file, buf, offset))
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, Self::Error> {
+        self.block_on(|fs| fs.readv(file, bufs))
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, Self::Error> {
+        self.block_on(|fs| fs.writev(file, bufs))
+    }
+
+    fn fsync(&mut self, file: &Self::File) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.fsync(file))
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.sync())
+    }
+
+    fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        self.block_on(|fs| fs.list(path))
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.mkdir(path))
+    }
+
+    fn rmdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.rmdir(path))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.rename(from, to))
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, Self::Error> {
+        self.block_on(|fs| fs.exists(path))
+    }
+
+    fn user(&self) -> u32 {
+        self.fs.user()
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.fs.set_user(uid)
+    }
+
+    fn end_session(&mut self, session: usize) -> Result<usize, Self::Error> {
+        self.block_on(|fs| fs.end_session(session))
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, Self::Error> {
+        self.block_on(|fs| fs.is_open(session, path, mode))
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, Self::Error> {
+        self.block_on(|fs| fs.lock(file, kind, range, wait))
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), Self::Error> {
+        self.block_on(|fs| fs.unlock(file, range))
+    }
+
+    fn locks(&mut self) -> Result<Vec<LockInfo>, Self::Error> {
+        self.block_on(|fs| fs.locks())
+    }
+}
@@ -0,0 +1,572 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::{ErrorKind, IoSlice, IoSliceMut, SeekFrom};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::async_vfs::AsyncVirtualFileSystem;
+use crate::logic;
+use crate::io::Savable;
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::repr::{Disk, SuperBlock, INLINE_DATA_SIZE};
+use crate::rw::AccessMode;
+use crate::vfs::{OpenOptions as VfsOpenOptions, VirtualFile, VirtualFileSystem};
+use crate::vsfs;
+use crate::vsfs_vfs::{VerySimpleError, VerySimpleFile, VerySimpleFileDescription, VerySimpleFileSystem};
+
+#[derive(Debug)]
+pub enum AsyncError {
+    Fs(VerySimpleError),
+    Io(io::Error),
+}
+
+impl Display for AsyncError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsyncError::Fs(err) => Display::fmt(err, f),
+            AsyncError::Io(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl Error for AsyncError {}
+
+impl From<VerySimpleError> for AsyncError {
+    fn from(err: VerySimpleError) -> Self {
+        AsyncError::Fs(err)
+    }
+}
+
+impl From<io::Error> for AsyncError {
+    fn from(err: io::Error) -> Self {
+        AsyncError::Io(err)
+    }
+}
+
+impl From<AsyncError> for io::Error {
+    fn from(err: AsyncError) -> Self {
+        match err {
+            AsyncError::Fs(err) => err.into(),
+            AsyncError::Io(err) => err,
+        }
+    }
+}
+
+
+/// 把镜像文件当作块设备
+struct ImageDevice {
+    file: File,
+    data_offset: u64,           // 数据块区域在镜像中的位置
+    loaded: Vec<bool>,          // 已经读进内存的数据块
+    dirty: BTreeSet<usize>,     // 修改过还没有写回的数据块
+}
+
+impl ImageDevice {
+    /// 在 offset 处写入 data
+    async fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.write_all(data).await?;
+        // 写入完成之后才能再次 seek
+        self.file.flush().await
+    }
+}
+
+
+/// 建立在镜像文件上的异步 vsfs
+///
+/// 打开时只读取元数据，数据块在用到时才从镜像中异步读取；
+/// 修改都先保存在内存中，sync 时把元数据和修改过的数据块写回镜像。
+/// 加密的镜像每次保存都要重新加密整个镜像，不能这样使用
+pub struct AsyncVerySimpleFileSystem {
+    fs: VerySimpleFileSystem<'static>,
+    device: ImageDevice,
+}
+
+/// 旧版本的镜像各个区域的位置不同，整个加载之后按现在的格式写回，之后才能按区域读写
+fn upgrade_image(path: &std::path::Path) -> io::Result<()> {
+    if Disk::read_super_block(path)?.is_legacy() {
+        let disk = Disk::load_with_passphrase(path, None)?;
+        disk.save(path).map_err(|err| io::Error::other(err.to_string()))?;
+    }
+    Ok(())
+}
+
+impl AsyncVerySimpleFileSystem {
+    /// 打开镜像文件，旧版本的镜像先按现在的格式重新保存
+    pub async fn open_image<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        let image = path.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || upgrade_image(&image)).await
+            .map_err(io::Error::other)??;
+
+        let mut file = OpenOptions::new().read(true).write(true).open(path).await?;
+
+        let mut sb = vec![0u8; size_of::<SuperBlock>()];
+        file.read_exact(&mut sb).await?;
+        let mut disk = Disk::from_super_block_bytes(&sb)?;
+        if disk.sb.is_encrypted() {
+            return Err(io::Error::new(ErrorKind::Unsupported, "encrypted image cannot be used as a block device"));
+        }
+
+        for (offset, region) in disk.metadata_mut() {
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.read_exact(region).await?;
+        }
+
+        let device = ImageDevice {
+            file,
+            data_offset: disk.data_block_offset(0),
+            loaded: vec![false; disk.d_blocks.len()],
+            dirty: BTreeSet::new(),
+        };
+        // 写到设备上的数据块按读写的范围记录，不能先留在页缓存中
+        let mut fs = VerySimpleFileSystem::with_disk(disk);
+        fs.set_page_cache_capacity(0);
+        Ok(AsyncVerySimpleFileSystem {
+            fs,
+            device,
+        })
+    }
+
+    /// 确保这些数据块已经读进内存
+    async fn load(&mut self, dnums: &[usize]) -> io::Result<()> {
+        let disk = self.fs.disk_mut();
+        for &dnum in dnums {
+            if self.device.loaded[dnum] {
+                continue;
+            }
+            let offset = self.device.data_offset + dnum as u64 * 4096;
+            self.device.file.seek(SeekFrom::Start(offset)).await?;
+            self.device.file.read_exact(&mut disk.d_blocks[dnum].data).await?;
+            self.device.loaded[dnum] = true;
+        }
+        Ok(())
+    }
+
+    /// 这些数据块在内存中被修改了，新分配的块也以内存中的为准
+    fn mark(&mut self, dnums: &[usize]) {
+        for &dnum in dnums {
+            self.device.loaded[dnum] = true;
+            self.device.dirty.insert(dnum);
+        }
+    }
+
+    /// inum 中覆盖 [start, end) 的数据块，内联的文件没有数据块
+    fn dnums(&self, inum: usize, start: usize, end: usize) -> Vec<usize> {
+        let i_blocks = &self.fs.disk().i_blocks;
+        let inode = unsafe { logic::get_inode(i_blocks, inum) };
+        (start / 4096..end.div_ceil(4096).min(inode.block_count as usize))
+            .map(|index| *logic::get_dnum(i_blocks, inum, index) as usize)
+            .collect()
+    }
+
+    /// inum 的所有数据块
+    fn all_dnums(&self, inum: usize) -> Vec<usize> {
+        logic::get_dnums(&self.fs.disk().i_blocks, inum)
+    }
+
+    /// path 上的目录，path 本身是目录时也包括它，以及配额文件
+    ///
+    /// 这些是路径操作会读写的数据，不存在的部分跳过
+    fn path_inums(&self, path: &Path) -> Vec<usize> {
+        let disk = self.fs.disk();
+        let mut inums = Vec::new();
+        if disk.sb.quota_inum != 0 {
+            inums.push(disk.sb.quota_inum as usize);
+        }
+
+        let mut current = Path::root();
+        let mut segs = path.iter();
+        while let Some(inum) = vsfs::get_inum_by_path(disk, &current) {
+            if !unsafe { logic::get_inode(&disk.i_blocks, inum) }.is_dir {
+                break;
+            }
+            inums.push(inum);
+            // 没有读入的目录还不能查找下一级
+            if self.all_dnums(inum).iter().any(|dnum| !self.device.loaded[*dnum]) {
+                break;
+            }
+            match segs.next() {
+                Some(seg) => current.push(seg.clone()),
+                None => break,
+            }
+        }
+        inums
+    }
+
+    /// 读入 path 上目录的数据，要一级一级读入才能找到下一级
+    async fn load_path(&mut self, path: &Path) -> io::Result<()> {
+        let mut count = 0;
+        loop {
+            let inums = self.path_inums(path);
+            let dnums = inums.iter()
+                .flat_map(|inum| self.all_dnums(*inum))
+                .collect::<Vec<_>>();
+            self.load(&dnums).await?;
+            if inums.len() == count {
+                return Ok(());
+            }
+            count = inums.len();
+        }
+    }
+
+    /// 读入 path 下面整个子树中目录的数据，移动目录时要统计它的用量
+    async fn load_tree(&mut self, path: &Path) -> io::Result<()> {
+        self.load_path(path).await?;
+        let mut stack = vec![path.clone()];
+        while let Some(dir) = stack.pop() {
+            let Ok(entries) = vsfs::get_dir(self.fs.disk(), &dir) else {
+                continue;
+            };
+            for entry in entries.iter() {
+                let inum = entry.inum as usize;
+                if unsafe { logic::get_inode(&self.fs.disk().i_blocks, inum) }.is_dir {
+                    self.load(&self.all_dnums(inum)).await?;
+                    stack.push(dir.clone().move_push(entry.name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 读入从 start 开始读 len 字节要用到的数据块，压缩文件要从簇表开始读
+    ///
+    /// 读写都通过 inum，不需要读入路径上的目录
+    async fn load_read(&mut self, inum: usize, start: usize, len: usize) -> io::Result<()> {
+        let inode = *unsafe { logic::get_inode(&self.fs.disk().i_blocks, inum) };
+        let dnums = if inode.is_compressed() {
+            self.all_dnums(inum)
+        } else {
+            self.dnums(inum, start, start + len)
+        };
+        self.load(&dnums).await
+    }
+
+    /// 下一次 write 的起始位置，追加写入时是文件末尾
+    fn write_start(&self, file: &VerySimpleFile) -> usize {
+        if file.append() {
+            unsafe { logic::get_inode(&self.fs.disk().i_blocks, file.inum()) }.file_size()
+        } else {
+            file.position()
+        }
+    }
+
+    /// 读入从 start 开始写 len 字节之前要用到的数据
+    ///
+    /// 只有部分被覆盖的数据块需要原来的内容，变成内联时要读出开头的数据
+    async fn load_write(&mut self, file: &VerySimpleFile, start: usize, len: usize) -> io::Result<()> {
+        self.load_path(file.path()).await?;
+        let inum = file.inum();
+        // 文件被移动过，开了配额时要在整个目录树中找它的父目录
+        let disk = self.fs.disk();
+        if disk.sb.quota_inum != 0 && vsfs::get_inum_by_path(disk, file.path()) != Some(inum) {
+            self.load_tree(&Path::root()).await?;
+        }
+
+        let inode = *unsafe { logic::get_inode(&self.fs.disk().i_blocks, inum) };
+        let end = start + len;
+        let dnums = if inode.is_compressed() {
+            self.all_dnums(inum)
+        } else {
+            let mut dnums = self.dnums(inum, start, start + 1);
+            dnums.extend(self.dnums(inum, end.saturating_sub(1), end));
+            if end <= INLINE_DATA_SIZE {
+                dnums.extend(self.dnums(inum, 0, end));
+            }
+            dnums
+        };
+        self.load(&dnums).await
+    }
+
+    /// 从 start 开始写了 len 字节之后标记改动的数据块和路径
+    fn mark_write(&mut self, file: &VerySimpleFile, start: usize, len: usize) {
+        let inum = file.inum();
+        let inode = *unsafe { logic::get_inode(&self.fs.disk().i_blocks, inum) };
+        let dnums = if inode.is_compressed() {
+            self.all_dnums(inum)
+        } else {
+            self.dnums(inum, start, start + len)
+        };
+        self.mark(&dnums);
+        self.mark_path(file.path());
+    }
+
+    /// 修改路径之后，路径上的目录和配额文件都可能变了
+    fn mark_path(&mut self, path: &Path) {
+        let dnums = self.path_inums(path).iter()
+            .flat_map(|inum| self.all_dnums(*inum))
+            .collect::<Vec<_>>();
+        self.mark(&dnums);
+    }
+
+}
+
+
+impl AsyncVirtualFileSystem for AsyncVerySimpleFileSystem {
+    type File = VerySimpleFile;
+    type Error = AsyncError;
+    type FileDescription = VerySimpleFileDescription;
+
+    async fn init(&mut self) -> Result<(), Self::Error> {
+        self.fs.init()?;
+        // 内存中全部清零了，镜像中原来的数据块都不再有用
+        self.device.loaded.fill(true);
+        self.mark_path(&Path::root());
+        Ok(())
+    }
+
+    async fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, Self::Error> {
+        self.load_path(path).await?;
+        let description = self.fs.create_file(path)?;
+        self.mark_path(path);
+        Ok(description)
+    }
+
+    async fn delete_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.load_path(path).await?;
+        self.fs.delete_file(path)?;
+        self.mark_path(path);
+        Ok(())
+    }
+
+    async fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error> {
+        self.load_path(path).await?;
+        Ok(self.fs.open(path, mode)?)
+    }
+
+    async fn open_with(&mut self, path: &Path, options: &VfsOpenOptions) -> Result<Self::File, Self::Error> {
+        self.load_path(path).await?;
+        let file = self.fs.open_with(path, options)?;
+        if options.creates() || options.truncate {
+            self.mark_path(path);
+        }
+        Ok(file)
+    }
+
+    async fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, Self::Error> {
+        self.load_path(file.path()).await?;
+        Ok(self.fs.description(file)?)
+    }
+
+    async fn close(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        Ok(self.fs.close(file)?)
+    }
+
+    async fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.load_read(file.inum(), file.position(), buf.len()).await?;
+        Ok(self.fs.read(file, buf)?)
+    }
+
+    async fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error> {
+        let start = self.write_start(file);
+        self.load_write(file, start, buf.len()).await?;
+        let len = self.fs.write(file, buf)?;
+        self.mark_write(file, start, len);
+        Ok(len)
+    }
+
+    async fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        self.load_read(file.inum(), offset, buf.len()).await?;
+        Ok(self.fs.read_at(file, buf, offset)?)
+    }
+
+    async fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, Self::Error> {
+        self.load_write(file, offset, buf.len()).await?;
+        let len = self.fs.write_at(file, buf, offset)?;
+        self.mark_write(file, offset, len);
+        Ok(len)
+    }
+
+    async fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, Self::Error> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        self.load_read(file.inum(), file.position(), len).await?;
+        Ok(self.fs.readv(file, bufs)?)
+    }
+
+    async fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, Self::Error> {
+        let start = self.write_start(file);
+        self.load_write(file, start, bufs.iter().map(|buf| buf.len()).sum()).await?;
+        let len = self.fs.writev(file, bufs)?;
+        self.mark_write(file, start, len);
+        Ok(len)
+    }
+
+    async fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        self.load_path(path).await?;
+        Ok(self.fs.list(path)?)
+    }
+
+    async fn mkdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.load_path(path).await?;
+        self.fs.mkdir(path)?;
+        self.mark_path(path);
+        Ok(())
+    }
+
+    async fn rmdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.load_path(path).await?;
+        self.fs.rmdir(path)?;
+        self.mark_path(path);
+        Ok(())
+    }
+
+    async fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        self.load_tree(from).await?;
+        self.load_path(to).await?;
+        self.fs.rename(from, to)?;
+        self.mark_path(from);
+        self.mark_path(to);
+        Ok(())
+    }
+
+    async fn exists(&mut self, path: &Path) -> Result<bool, Self::Error> {
+        self.load_path(path).await?;
+        Ok(self.fs.exists(path)?)
+    }
+
+    fn user(&self) -> u32 {
+        self.fs.user()
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.fs.set_user(uid)
+    }
+
+    // 打开文件表和锁只在内存中，不需要读写镜像
+    async fn end_session(&mut self, session: usize) -> Result<usize, Self::Error> {
+        Ok(self.fs.end_session(session)?)
+    }
+
+    async fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, Self::Error> {
+        Ok(self.fs.is_open(session, path, mode)?)
+    }
+
+    async fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, Self::Error> {
+        Ok(self.fs.lock(file, kind, range, wait)?)
+    }
+
+    async fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), Self::Error> {
+        Ok(self.fs.unlock(file, range)?)
+    }
+
+    async fn locks(&mut self) -> Result<Vec<LockInfo>, Self::Error> {
+        Ok(self.fs.locks()?)
+    }
+
+    /// 元数据和数据块一起写回，和 sync 一样
+    async fn fsync(&mut self, file: &Self::File) -> Result<(), Self::Error> {
+        self.fs.fsync(file)?;
+        self.sync().await
+    }
+
+    async fn sync(&mut self) -> Result<(), Self::Error> {
+        let disk = self.fs.disk();
+        for (offset, region) in disk.metadata() {
+            self.device.write_at(offset, region).await?;
+        }
+        for dnum in std::mem::take(&mut self.device.dirty) {
+            let offset = self.device.data_offset + dnum as u64 * 4096;
+            self.device.write_at(offset, &disk.d_blocks[dnum].data).await?;
+        }
+        self.device.file.sync_data().await?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::async_vfs::BlockingFileSystem;
+    use crate::io::{Loadable, Savable};
+    use crate::vfs::{conformance, VirtualFileDescription};
+
+    /// 在临时目录中新建一个空的镜像
+    fn new_image(dir: &tempfile::TempDir) -> std::path::PathBuf {
+        let image = dir.path().join("disk");
+        let mut disk = Disk::with_size(1024).unwrap();
+        vsfs::init(&mut disk);
+        disk.save(&image).unwrap();
+        image
+    }
+
+    #[test]
+    fn test_conformance() {
+        // 镜像所在的临时目录要保留到所有场景结束
+        let mut dirs = Vec::new();
+        conformance::run_all(|| {
+            let dir = tempfile::tempdir().unwrap();
+            let image = new_image(&dir);
+            dirs.push(dir);
+            BlockingFileSystem::new(AsyncVerySimpleFileSystem::open_image(&image)).unwrap()
+        });
+    }
+
+    #[tokio::test]
+    async fn test_image() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = new_image(&dir);
+        let a = Path::from_str("/dir/a").unwrap();
+        let data = (0..4096 * 3 + 100).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut fs = AsyncVerySimpleFileSystem::open_image(&image).await.unwrap();
+        fs.mkdir(&Path::from_str("/dir").unwrap()).await.unwrap();
+        fs.create_file(&a).await.unwrap();
+        let mut file = fs.open(&a, AccessMode::Write).await.unwrap();
+        assert_eq!(fs.write(&mut file, &data).await.unwrap(), data.len());
+        fs.close(file).await.unwrap();
+        fs.sync().await.unwrap();
+        drop(fs);
+
+        // 写回的镜像可以正常加载
+        let mut disk = Disk::load(&image).unwrap();
+        let mut sync_fs = VerySimpleFileSystem::new(&mut disk);
+        let mut file = sync_fs.open(&a, AccessMode::Read).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(sync_fs.read(&mut file, &mut buf).unwrap(), data.len());
+        assert_eq!(buf, data);
+        drop(sync_fs);
+
+        // 重新打开时只读入用到的数据块
+        let mut fs = AsyncVerySimpleFileSystem::open_image(&image).await.unwrap();
+        let mut file = fs.open(&a, AccessMode::ReadWrite).await.unwrap();
+        file.set_position(4096 * 2);
+        let mut buf = vec![0u8; 10];
+        assert_eq!(fs.read(&mut file, &mut buf).await.unwrap(), 10);
+        assert_eq!(buf, data[4096 * 2..4096 * 2 + 10]);
+        let dir_blocks = fs.path_inums(&a).iter().map(|inum| fs.all_dnums(*inum).len()).sum::<usize>();
+        assert_eq!(fs.device.loaded.iter().filter(|loaded| **loaded).count(), dir_blocks + 1);
+
+        // 覆盖一个块的中间，前后的数据保留
+        file.set_position(4096 + 10);
+        fs.write(&mut file, &[0xff; 10]).await.unwrap();
+        fs.close(file).await.unwrap();
+        fs.sync().await.unwrap();
+
+        let mut disk = Disk::load(&image).unwrap();
+        let mut sync_fs = VerySimpleFileSystem::new(&mut disk);
+        let mut file = sync_fs.open(&a, AccessMode::Read).unwrap();
+        assert_eq!(sync_fs.description(&file).unwrap().size(), data.len());
+        let mut buf = vec![0u8; data.len()];
+        sync_fs.read(&mut file, &mut buf).unwrap();
+        assert_eq!(buf[..4096 + 10], data[..4096 + 10]);
+        assert_eq!(buf[4096 + 10..4096 + 20], [0xff; 10]);
+        assert_eq!(buf[4096 + 20..], data[4096 + 20..]);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk");
+        let mut disk = Disk::with_size(1024).unwrap();
+        vsfs::init(&mut disk);
+        disk.set_passphrase(Some("secret"), crate::crypto::KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 }).unwrap();
+        disk.save(&image).unwrap();
+
+        let err = AsyncVerySimpleFileSystem::open_image(&image).await.err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+}
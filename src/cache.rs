@@ -0,0 +1,368 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::logic::DirectoryData;
+
+/// 目录项缓存的默认大小
+pub const DENTRY_CACHE_SIZE: usize = 4096;
+/// 解码后的目录缓存的默认大小
+pub const DIR_CACHE_SIZE: usize = 64;
+/// 页缓存的默认页数，每页 4096 字节
+pub const PAGE_CACHE_SIZE: usize = 256;
+/// 页缓存中块号表的默认个数
+pub const BLOCK_MAP_CACHE_SIZE: usize = 64;
+
+/// 容量有限的缓存，满了之后淘汰最久没有使用的项
+pub struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    items: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,    // 最后一次使用的时刻到键，最小的最先淘汰
+}
+
+impl<K: Eq + Hash + Clone, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru {
+            capacity,
+            tick: 0,
+            items: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// 取出并标记为最近使用
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let (_, used) = self.items.get_mut(key)?;
+        self.order.remove(used);
+        self.tick += 1;
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.items.get_mut(key).map(|(value, _)| value)
+    }
+
+    /// 放入缓存，返回因为放不下被淘汰的项；容量为 0 时直接返回放入的项
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if self.capacity == 0 {
+            return Some((key, value));
+        }
+
+        self.remove(&key);
+        let evicted = if self.items.len() >= self.capacity {
+            self.pop_oldest()
+        } else {
+            None
+        };
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.items.insert(key, (value, self.tick));
+        evicted
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, used) = self.items.remove(key)?;
+        self.order.remove(&used);
+        Some(value)
+    }
+
+    /// 只保留 f 返回 true 的项
+    pub fn retain(&mut self, mut f: impl FnMut(&K, &mut V) -> bool) {
+        let order = &mut self.order;
+        self.items.retain(|key, (value, used)| {
+            let keep = f(key, value);
+            if !keep {
+                order.remove(used);
+            }
+            keep
+        });
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.items.iter_mut().map(|(key, (value, _))| (key, value))
+    }
+
+    /// 取出所有的项，缓存变空
+    pub fn drain(&mut self) -> Vec<(K, V)> {
+        self.order.clear();
+        self.items.drain().map(|(key, (value, _))| (key, value)).collect()
+    }
+
+    /// 改变容量，放不下的项按最久没有使用的顺序淘汰并返回
+    pub fn set_capacity(&mut self, capacity: usize) -> Vec<(K, V)> {
+        self.capacity = capacity;
+        let mut evicted = Vec::new();
+        while self.items.len() > capacity {
+            evicted.extend(self.pop_oldest());
+        }
+        evicted
+    }
+
+    fn pop_oldest(&mut self) -> Option<(K, V)> {
+        let (_, key) = self.order.pop_first()?;
+        self.items.remove(&key).map(|(value, _)| (key, value))
+    }
+}
+
+
+/// 目录缓存：(父目录 inum, 名字) 到 inum 的目录项，以及解码后的目录内容
+///
+/// 目录的内容被改写时要调用 invalidate，换到快照等整个 inode 表被替换时要调用 clear
+pub struct DirCache {
+    dentries: Lru<(usize, String), usize>,
+    dirs: Lru<usize, DirectoryData>,
+}
+
+impl DirCache {
+    pub fn new() -> Self {
+        DirCache {
+            dentries: Lru::new(DENTRY_CACHE_SIZE),
+            dirs: Lru::new(DIR_CACHE_SIZE),
+        }
+    }
+
+    /// 在目录 parent 中查找 name，没有缓存时返回 None
+    pub fn lookup(&mut self, parent: usize, name: &str) -> Option<usize> {
+        self.dentries.get_mut(&(parent, name.to_string())).copied()
+    }
+
+    pub fn insert_dentry(&mut self, parent: usize, name: &str, inum: usize) {
+        self.dentries.insert((parent, name.to_string()), inum);
+    }
+
+    /// 缓存的目录内容
+    pub fn dir(&mut self, inum: usize) -> Option<DirectoryData> {
+        self.dirs.get_mut(&inum).cloned()
+    }
+
+    pub fn insert_dir(&mut self, inum: usize, dir: DirectoryData) {
+        self.dirs.insert(inum, dir);
+    }
+
+    /// 目录 inum 的内容变了，丢掉它的内容和它下面的目录项
+    pub fn invalidate(&mut self, inum: usize) {
+        self.dirs.remove(&inum);
+        self.dentries.retain(|(parent, _), _| *parent != inum);
+    }
+
+    pub fn clear(&mut self) {
+        self.dentries.drain();
+        self.dirs.drain();
+    }
+}
+
+impl Default for DirCache {
+    fn default() -> Self {
+        DirCache::new()
+    }
+}
+
+/// 放在 Disk 中的目录缓存，只读的查找也要更新它，所以用锁包起来
+///
+/// 缓存不属于磁盘的内容，比较磁盘时忽略
+#[derive(Default)]
+pub struct DirCacheCell(Mutex<DirCache>);
+
+impl DirCacheCell {
+    pub fn lock(&self) -> MutexGuard<'_, DirCache> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl PartialEq for DirCacheCell {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+
+/// 缓存的一页，对应文件的一个数据块
+pub struct Page {
+    pub data: Box<[u8; 4096]>,
+    pub dirty: bool,        // 修改之后还没有写回数据块
+}
+
+/// 页的位置：(inum, 块序号)
+pub type PageKey = (usize, usize);
+
+/// 文件的页缓存，按 (inum, 块序号) 缓存数据块的内容，按 inum 缓存文件的块号表
+///
+/// 写入先改缓存中的页，sync、fsync 或者页被淘汰时才写回数据块。
+/// 块号表是从 inode 和一级间接块中解出来的所有数据块的块号，读写页时不用再查 inode 表和间接块；
+/// 它只是磁盘内容的副本，文件的数据块变化时随页一起丢掉（remove_inode、clear）。
+/// 缓存中的写入不会分配数据块，不改位图；目录的数据块解码之后放在 DirCache 中
+pub struct PageCache {
+    pages: Lru<PageKey, Page>,
+    maps: Lru<usize, Vec<usize>>,
+}
+
+impl PageCache {
+    pub fn new(capacity: usize) -> Self {
+        PageCache {
+            pages: Lru::new(capacity),
+            maps: Lru::new(BLOCK_MAP_CACHE_SIZE),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.pages.capacity()
+    }
+
+    /// 改变容量，返回被淘汰的脏页
+    pub fn set_capacity(&mut self, capacity: usize) -> Vec<(PageKey, Page)> {
+        dirty_only(self.pages.set_capacity(capacity))
+    }
+
+    pub fn get_mut(&mut self, inum: usize, index: usize) -> Option<&mut Page> {
+        self.pages.get_mut(&(inum, index))
+    }
+
+    /// inum 的块号表，没有缓存时用 load 从磁盘读出
+    pub fn block_map(&mut self, inum: usize, load: impl FnOnce() -> Vec<usize>) -> &[usize] {
+        if self.maps.get_mut(&inum).is_none() {
+            self.maps.insert(inum, load());
+        }
+        self.maps.get_mut(&inum).unwrap()
+    }
+
+    /// 已经缓存的 inum 的块号表，不从磁盘读取
+    pub fn cached_map(&mut self, inum: usize) -> Option<&[usize]> {
+        self.maps.get_mut(&inum).map(|map| map.as_slice())
+    }
+
+    /// 丢掉所有的块号表，比如磁盘被直接修改之后
+    pub fn clear_maps(&mut self) {
+        self.maps.drain();
+    }
+
+    /// 放入一页，返回因为放不下被淘汰的脏页
+    pub fn insert(&mut self, inum: usize, index: usize, page: Page) -> Option<(PageKey, Page)> {
+        self.pages.insert((inum, index), page)
+            .filter(|(_, page)| page.dirty)
+    }
+
+    /// inum 的所有脏页，inum 为 None 时是所有文件的脏页，返回之后这些页都变成干净的
+    pub fn take_dirty(&mut self, inum: Option<usize>) -> Vec<(PageKey, Box<[u8; 4096]>)> {
+        self.pages.iter_mut()
+            .filter(|((page_inum, _), page)| page.dirty && inum.is_none_or(|inum| inum == *page_inum))
+            .map(|(key, page)| {
+                page.dirty = false;
+                (*key, page.data.clone())
+            })
+            .collect()
+    }
+
+    /// 丢掉 inum 的所有页和块号表，返回其中的脏页
+    ///
+    /// 返回的脏页要在文件的数据块变化之前写回
+    pub fn remove_inode(&mut self, inum: usize) -> Vec<(PageKey, Page)> {
+        self.maps.remove(&inum);
+        let mut removed = Vec::new();
+        for index in self.indexes(inum) {
+            if let Some(page) = self.pages.remove(&(inum, index)) {
+                removed.push(((inum, index), page));
+            }
+        }
+        dirty_only(removed)
+    }
+
+    /// 丢掉所有的页和块号表，返回其中的脏页
+    pub fn clear(&mut self) -> Vec<(PageKey, Page)> {
+        self.maps.drain();
+        dirty_only(self.pages.drain())
+    }
+
+    fn indexes(&self, inum: usize) -> Vec<usize> {
+        self.pages.items.keys()
+            .filter(|(page_inum, _)| *page_inum == inum)
+            .map(|(_, index)| *index)
+            .collect()
+    }
+}
+
+fn dirty_only(pages: Vec<(PageKey, Page)>) -> Vec<(PageKey, Page)> {
+    pages.into_iter().filter(|(_, page)| page.dirty).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logic::DirectoryEntry;
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::new(2);
+        assert!(lru.insert(1, "a").is_none());
+        assert!(lru.insert(2, "b").is_none());
+
+        // 用过的项不会被先淘汰
+        assert_eq!(lru.get_mut(&1), Some(&mut "a"));
+        assert_eq!(lru.insert(3, "c"), Some((2, "b")));
+        assert!(lru.get_mut(&2).is_none());
+
+        // 重新放入同一个键只更新，不淘汰
+        assert!(lru.insert(3, "d").is_none());
+        assert_eq!(lru.get_mut(&1), Some(&mut "a"));
+
+        lru.retain(|key, _| *key != 1);
+        assert_eq!(lru.set_capacity(0), vec![(3, "d")]);
+        assert_eq!(lru.insert(4, "e"), Some((4, "e")));
+        assert!(lru.drain().is_empty());
+    }
+
+    #[test]
+    fn test_dir_cache() {
+        let mut cache = DirCache::new();
+        cache.insert_dentry(0, "a", 1);
+        cache.insert_dentry(0, "b", 2);
+        cache.insert_dentry(1, "c", 3);
+        cache.insert_dir(0, DirectoryData {
+            entries: vec![DirectoryEntry { name: "a".to_string(), inum: 1 }],
+        });
+        assert_eq!(cache.lookup(0, "a"), Some(1));
+        assert!(cache.dir(0).is_some_and(|dir| dir.exists("a")));
+
+        // 只丢掉这个目录下面的项
+        cache.invalidate(0);
+        assert_eq!(cache.lookup(0, "a"), None);
+        assert_eq!(cache.lookup(0, "b"), None);
+        assert!(cache.dir(0).is_none());
+        assert_eq!(cache.lookup(1, "c"), Some(3));
+
+        cache.clear();
+        assert_eq!(cache.lookup(1, "c"), None);
+    }
+
+    #[test]
+    fn test_page_cache() {
+        let page = |byte: u8, dirty: bool| Page { data: Box::new([byte; 4096]), dirty };
+        let mut cache = PageCache::new(2);
+        assert!(cache.insert(1, 0, page(1, true)).is_none());
+        assert!(cache.insert(1, 1, page(2, false)).is_none());
+
+        // 淘汰的干净页直接丢掉
+        cache.get_mut(1, 0).unwrap();
+        assert!(cache.insert(2, 0, page(3, true)).is_none());
+        assert!(cache.get_mut(1, 1).is_none());
+
+        // 淘汰的脏页要写回
+        cache.get_mut(2, 0).unwrap();
+        let (key, evicted) = cache.insert(2, 1, page(4, false)).unwrap();
+        assert_eq!(key, (1, 0));
+        assert_eq!(evicted.data[0], 1);
+
+        let dirty = cache.take_dirty(None);
+        assert_eq!(dirty.len(), 1);
+        assert_eq!(dirty[0].0, (2, 0));
+        assert!(cache.take_dirty(Some(2)).is_empty());
+
+        cache.get_mut(2, 1).unwrap().dirty = true;
+        assert_eq!(cache.remove_inode(2).len(), 1);
+        assert!(cache.clear().is_empty());
+    }
+}
@@ -1,12 +1,22 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
 use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use prettytable::{format, row, Table};
 use structopt::StructOpt;
 
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
 use crate::path::Path;
+use crate::quota::{Limit, QuotaReport, QuotaTarget};
 use crate::rw::AccessMode;
 use crate::utils;
-use crate::vfs::{VirtualFile, VirtualFileDescription, VirtualFileSystem};
+use crate::vfs::{DefragSkip, FileHandle, FragmentReport, MountInfo, OpenOptions, SnapshotDiff, SnapshotInfo, VirtualFile, VirtualFileDescription, VirtualFileSystem};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "file system", about = "A simple file system", bin_name = "fs")]
@@ -49,6 +59,54 @@ enum Command {
         name: String,
     },
 
+    /// 复制文件
+    Cp {
+        /// 和源文件共享数据块，修改时再复制
+        #[structopt(long = "reflink")]
+        reflink: bool,
+
+        /// 源文件名
+        #[structopt(name = "src")]
+        src: String,
+
+        /// 目标文件名
+        #[structopt(name = "dst")]
+        dst: String,
+    },
+
+    /// 移动或者重命名文件和目录，不能跨挂载点
+    Mv {
+        /// 源文件名，以 / 开头时是绝对路径
+        #[structopt(name = "src")]
+        src: String,
+
+        /// 目标文件名，以 / 开头时是绝对路径
+        #[structopt(name = "dst")]
+        dst: String,
+    },
+
+    /// 把宿主机上的文件导入到文件系统中
+    Import {
+        /// 宿主机上的文件路径
+        #[structopt(name = "host")]
+        host: String,
+
+        /// 文件名
+        #[structopt(name = "name")]
+        name: String,
+    },
+
+    /// 把文件导出到宿主机上
+    Export {
+        /// 文件名
+        #[structopt(name = "name")]
+        name: String,
+
+        /// 宿主机上的文件路径
+        #[structopt(name = "host")]
+        host: String,
+    },
+
     /// 退出
     Exit {
         /// 文件名
@@ -64,6 +122,22 @@ enum Command {
 
         #[structopt(name = "mode")]
         mode: String,
+
+        /// 追加写入，每次都写到文件末尾
+        #[structopt(long = "append")]
+        append: bool,
+
+        /// 文件不存在时新建
+        #[structopt(long = "create")]
+        create: bool,
+
+        /// 新建文件，已经存在时失败
+        #[structopt(long = "excl")]
+        excl: bool,
+
+        /// 打开时清空文件
+        #[structopt(long = "trunc")]
+        trunc: bool,
     },
 
     /// 关闭文件
@@ -102,14 +176,241 @@ enum Command {
         #[structopt(name = "hex")]
         data: String,
     },
+
+    /// 切换当前用户
+    Su {
+        /// 用户 ID
+        #[structopt(name = "uid")]
+        uid: u32,
+    },
+
+    /// 查看配额
+    Quota,
+
+    /// 设置配额，限制为 0 表示不限制，全部为 0 表示删除配额
+    Setquota {
+        /// 用户 ID，不指定用户和目录时为当前用户
+        #[structopt(long = "user")]
+        user: Option<u32>,
+
+        /// 目录名
+        #[structopt(long = "dir")]
+        dir: Option<String>,
+
+        /// 块软限制
+        #[structopt(name = "block-soft")]
+        block_soft: u32,
+
+        /// 块硬限制
+        #[structopt(name = "block-hard")]
+        block_hard: u32,
+
+        /// inode 软限制
+        #[structopt(name = "inode-soft")]
+        inode_soft: u32,
+
+        /// inode 硬限制
+        #[structopt(name = "inode-hard")]
+        inode_hard: u32,
+    },
+
+    /// 设置配额宽限期
+    Grace {
+        /// 宽限期，单位秒
+        #[structopt(name = "seconds")]
+        seconds: u32,
+    },
+
+    /// 查看碎片情况
+    Frag {
+        /// 文件或目录名，默认为当前目录
+        #[structopt(name = "name")]
+        name: Option<String>,
+    },
+
+    /// 整理碎片
+    Defrag {
+        /// 文件或目录名，默认为当前目录
+        #[structopt(name = "name")]
+        name: Option<String>,
+    },
+
+    /// 打开或关闭压缩，目录的设置会被之后新建的文件继承
+    Compress {
+        /// 文件或目录名，默认为当前目录
+        #[structopt(name = "name")]
+        name: Option<String>,
+
+        /// 关闭压缩
+        #[structopt(long = "off")]
+        off: bool,
+    },
+
+    /// 离线去重：扫描整个镜像，合并内容相同的数据块，写入时不会自动去重
+    Dedup,
+
+    /// 管理快照
+    Snapshot {
+        #[structopt(subcommand)]
+        command: SnapshotCommand,
+    },
+
+    /// 把文件系统挂载到一个目录上
+    Mount {
+        /// 来源：mem 为内存文件系统，host:<目录> 为宿主机目录，其他为镜像文件
+        #[structopt(name = "source")]
+        source: String,
+
+        /// 挂载点，以 / 开头时是绝对路径
+        #[structopt(name = "point")]
+        point: String,
+    },
+
+    /// 卸载挂载点上的文件系统
+    Umount {
+        /// 挂载点，以 / 开头时是绝对路径
+        #[structopt(name = "point")]
+        point: String,
+    },
+
+    /// 列出所有挂载点
+    Mounts,
+
+    /// 新建一个模拟的进程并切换过去，新进程从当前目录开始
+    Spawn,
+
+    /// 切换到另一个进程
+    Switch {
+        /// 进程 ID
+        #[structopt(name = "pid")]
+        pid: usize,
+    },
+
+    /// 列出所有进程
+    Ps,
+
+    /// 结束一个进程，关闭它打开的所有文件
+    Kill {
+        /// 进程 ID
+        #[structopt(name = "pid")]
+        pid: usize,
+    },
+
+    /// 给打开的文件加锁，不指定范围时锁住整个文件（flock），否则锁住一段字节（POSIX）
+    Lock {
+        /// 文件名
+        #[structopt(name = "name")]
+        name: String,
+
+        /// 锁的类型：sh 为共享锁，ex 为独占锁
+        #[structopt(name = "kind")]
+        kind: String,
+
+        /// 开始位置
+        #[structopt(name = "start")]
+        start: Option<usize>,
+
+        /// 长度，0 或者不指定表示到文件末尾
+        #[structopt(name = "len")]
+        len: Option<usize>,
+
+        /// 有冲突时排队等待，而不是立即失败
+        #[structopt(long = "wait")]
+        wait: bool,
+
+        /// 排队等待，但是会造成死锁时失败
+        #[structopt(long = "detect")]
+        detect: bool,
+    },
+
+    /// 解锁，不指定范围时解开整个文件的锁（flock）
+    Unlock {
+        /// 文件名
+        #[structopt(name = "name")]
+        name: String,
+
+        /// 开始位置
+        #[structopt(name = "start")]
+        start: Option<usize>,
+
+        /// 长度，0 或者不指定表示到文件末尾
+        #[structopt(name = "len")]
+        len: Option<usize>,
+    },
+
+    /// 列出所有持有的锁和等待中的请求
+    Locks,
+
+    /// 把所有的修改写回存储
+    Sync,
+
+    /// 把一个打开的文件的修改写回存储
+    Fsync {
+        /// 文件名
+        #[structopt(name = "name")]
+        name: String,
+    },
 }
 
+#[derive(StructOpt, Debug)]
+enum SnapshotCommand {
+    /// 创建快照
+    Create {
+        /// 快照名称
+        #[structopt(name = "name")]
+        name: String,
+    },
+
+    /// 列出所有快照
+    List,
 
-fn format_print_descriptions<D: VirtualFileDescription>(descriptions: &[D]) {
-    let mut table = Table::new();
+    /// 删除快照
+    Delete {
+        /// 快照名称
+        #[structopt(name = "name")]
+        name: String,
+    },
 
-    table.set_titles(row!["名称", "类型", "大小（字节）", "创建时间", "修改时间"]);
+    /// 回滚到快照
+    Rollback {
+        /// 快照名称
+        #[structopt(name = "name")]
+        name: String,
+    },
 
+    /// 比较快照和当前文件系统
+    Diff {
+        /// 快照名称
+        #[structopt(name = "name")]
+        name: String,
+    },
+
+    /// 列出快照中的目录，路径从根目录开始
+    Ls {
+        /// 快照名称
+        #[structopt(name = "name")]
+        name: String,
+
+        /// 目录路径，默认为根目录
+        #[structopt(name = "path")]
+        path: Option<String>,
+    },
+
+    /// 读取快照中的文件，路径从根目录开始
+    Cat {
+        /// 快照名称
+        #[structopt(name = "name")]
+        name: String,
+
+        /// 文件路径
+        #[structopt(name = "path")]
+        path: String,
+    },
+}
+
+
+/// 打印表格的格式
+fn table_format() -> format::TableFormat {
     let mut format = *format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR;
 
     format.column_separator(' ');
@@ -125,8 +426,14 @@ fn format_print_descriptions<D: VirtualFileDescription>(descriptions: &[D]) {
         format::LineSeparator::new('-', '-', '-', '-'),
     );
 
+    format
+}
 
-    table.set_format(format);
+fn format_print_descriptions<D: VirtualFileDescription>(descriptions: &[D]) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["名称", "类型", "大小（字节）", "所有者", "创建时间", "修改时间"]);
+    table.set_format(table_format());
 
     for desc in descriptions {
         let ty_str = if desc.is_dir() {
@@ -144,13 +451,301 @@ fn format_print_descriptions<D: VirtualFileDescription>(descriptions: &[D]) {
         let create_time_str = utils::format_time(desc.ctime() as u32);
         let modify_time_str = utils::format_time(desc.mtime() as u32);
 
-        table.add_row(row![desc.name(), ty_str, size_str, create_time_str, modify_time_str]);
+        table.add_row(row![desc.name(), ty_str, size_str, desc.owner(), create_time_str, modify_time_str]);
+    }
+
+
+    table.printstd();
+}
+
+/// 格式化限制，0 表示不限制
+fn format_limit(limit: u32) -> String {
+    if limit == 0 {
+        "-".to_string()
+    } else {
+        limit.to_string()
+    }
+}
+
+/// 格式化宽限期开始时间
+fn format_grace(grace_start: u32) -> String {
+    if grace_start == 0 {
+        "-".to_string()
+    } else {
+        utils::format_time(grace_start)
     }
+}
 
+fn format_print_quotas(reports: &[QuotaReport]) {
+    let mut table = Table::new();
+
+    table.set_titles(row![
+        "对象", "块用量", "块软限制", "块硬限制", "块超限时间",
+        "inode 用量", "inode 软限制", "inode 硬限制", "inode 超限时间"
+    ]);
+    table.set_format(table_format());
+
+    for report in reports {
+        let target_str = match &report.target {
+            QuotaTarget::User(uid) => format!("用户 {}", uid),
+            QuotaTarget::Dir(path) => format!("目录 {}", path.to_str()),
+        };
+        let entry = &report.entry;
+
+        table.add_row(row![
+            target_str,
+            entry.blocks_used,
+            format_limit(entry.blocks.soft),
+            format_limit(entry.blocks.hard),
+            format_grace(entry.block_grace_start),
+            entry.inodes_used,
+            format_limit(entry.inodes.soft),
+            format_limit(entry.inodes.hard),
+            format_grace(entry.inode_grace_start)
+        ]);
+    }
 
     table.printstd();
 }
 
+fn format_print_fragments(reports: &[FragmentReport]) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["路径", "数据块", "整理前区间数", "整理后区间数", "说明"]);
+    table.set_format(table_format());
+
+    for report in reports {
+        let note = match report.skipped {
+            Some(DefragSkip::Shared) => "共享数据块，没有整理",
+            Some(DefragSkip::NoSpace) => "连续空间不足，没有整理",
+            None => "",
+        };
+        table.add_row(row![report.path.to_str(), report.blocks, report.extents_before, report.extents_after, note]);
+    }
+
+    table.printstd();
+}
+
+fn format_print_snapshots(snapshots: &[SnapshotInfo]) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["名称", "创建时间"]);
+    table.set_format(table_format());
+
+    for snapshot in snapshots {
+        table.add_row(row![snapshot.name, utils::format_time(snapshot.ctime)]);
+    }
+
+    table.printstd();
+}
+
+fn format_print_snapshot_diff(diff: &SnapshotDiff) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["变化", "路径"]);
+    table.set_format(table_format());
+
+    let changes = [("新增", &diff.added), ("删除", &diff.removed), ("修改", &diff.modified)];
+    for (kind, paths) in changes {
+        for path in paths {
+            table.add_row(row![kind, path.to_str()]);
+        }
+    }
+
+    table.printstd();
+}
+
+fn format_print_mounts(mounts: &[MountInfo]) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["挂载点", "来源"]);
+    table.set_format(table_format());
+
+    for mount in mounts {
+        table.add_row(row![mount.point.to_str(), mount.source]);
+    }
+
+    table.printstd();
+}
+
+fn format_print_processes<F>(current: usize, path: &Path, files: &[F], others: &BTreeMap<usize, (Path, Vec<F>)>) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["进程", "当前目录", "打开的文件"]);
+    table.set_format(table_format());
+
+    let mut processes = others.iter()
+        .map(|(pid, (path, files))| (*pid, path, files.len()))
+        .collect::<Vec<_>>();
+    processes.push((current, path, files.len()));
+    processes.sort_by_key(|(pid, _, _)| *pid);
+
+    for (pid, path, count) in processes {
+        let pid_str = if pid == current {
+            format!("* {}", pid)
+        } else {
+            pid.to_string()
+        };
+        table.add_row(row![pid_str, path.to_str(), count]);
+    }
+
+    table.printstd();
+}
+
+fn format_print_locks(locks: &[LockInfo]) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["路径", "进程", "类型", "范围", "状态"]);
+    table.set_format(table_format());
+
+    for lock in locks {
+        let kind_str = match lock.kind {
+            LockKind::Shared => "共享",
+            LockKind::Exclusive => "独占",
+        };
+        let range_str = match lock.range {
+            LockRange::File => "整个文件".to_string(),
+            LockRange::Bytes { start, len: 0 } => format!("{}-", start),
+            LockRange::Bytes { start, len } => format!("{}-{}", start, start + len),
+        };
+        let state_str = if lock.waiting { "等待" } else { "持有" };
+
+        table.add_row(row![lock.path.to_str(), lock.session, kind_str, range_str, state_str]);
+    }
+
+    table.printstd();
+}
+
+/// 命令中的开始位置和长度对应的锁范围，都不指定时是整个文件
+fn lock_range(start: Option<usize>, len: Option<usize>) -> LockRange {
+    match start {
+        Some(start) => LockRange::Bytes { start, len: len.unwrap_or(0) },
+        None => LockRange::File,
+    }
+}
+
+/// 会话 pid 是否以任意模式打开了 path
+fn opened<FS: VirtualFileSystem>(fs: &mut FS, pid: usize, path: &Path) -> Result<bool, FS::Error> {
+    for mode in [AccessMode::Read, AccessMode::Write, AccessMode::ReadWrite] {
+        if fs.is_open(pid, path, mode)? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// 命令中的名字对应的路径，以 / 开头时是绝对路径，否则相对于当前目录
+fn resolve(path: &Path, name: &str) -> Option<Path> {
+    if name.starts_with('/') {
+        Path::from_str(name)
+    } else {
+        Path::from_str(&format!("/{}", name)).map(|rel| path.join(&rel))
+    }
+}
+
+/// 把宿主机上的文件 host 复制到 path，path 不存在时新建，返回复制的字节数
+fn import_file<FS: VirtualFileSystem>(fs: &mut FS, host: &str, path: &Path) -> io::Result<u64>
+where FS::Error: Into<io::Error> {
+    let mut src = File::open(host)?;
+    if !fs.exists(path).map_err(Into::into)? {
+        fs.create_file(path).map_err(Into::into)?;
+    }
+
+    // 已经存在的文件先清空，写入不会截断后面的数据
+    let options = OpenOptions::new().write(true).truncate(true).clone();
+    let mut file = fs.open_with(path, &options).map_err(Into::into)?;
+    let res = io::copy(&mut src, &mut FileHandle::new(fs, &mut file));
+    fs.close(file).map_err(Into::into)?;
+    res
+}
+
+/// 把 path 复制到宿主机上的文件 host，返回复制的字节数
+fn export_file<FS: VirtualFileSystem>(fs: &mut FS, path: &Path, host: &str) -> io::Result<u64>
+where FS::Error: Into<io::Error> {
+    let mut file = fs.open(path, AccessMode::Read).map_err(Into::into)?;
+    let res = File::create(host)
+        .and_then(|mut dst| io::copy(&mut FileHandle::new(fs, &mut file), &mut dst));
+    fs.close(file).map_err(Into::into)?;
+    res
+}
+
+/// 读出 src 的全部内容写入新文件 dst
+fn copy_file<FS: VirtualFileSystem>(fs: &mut FS, src: &Path, dst: &Path) -> Result<(), FS::Error> {
+    let mut src_file = fs.open(src, AccessMode::Read)?;
+    let size = fs.description(&src_file)?.size();
+    let mut buf = vec![0u8; size];
+    let res = fs.read(&mut src_file, &mut buf);
+    fs.close(src_file)?;
+    res?;
+
+    fs.create_file(dst)?;
+    let mut dst_file = fs.open(dst, AccessMode::Write)?;
+    let res = fs.write(&mut dst_file, &buf);
+    fs.close(dst_file)?;
+    res.map(|_| ())
+}
+
+/// 执行快照命令
+fn run_snapshot<FS: VirtualFileSystem>(fs: &mut FS, command: SnapshotCommand) {
+    match command {
+        SnapshotCommand::Create { name } => {
+            match fs.snapshot_create(&name) {
+                Ok(()) => println!("快照 {} 创建成功", name),
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+        SnapshotCommand::List => {
+            match fs.snapshots() {
+                Ok(snapshots) => format_print_snapshots(&snapshots),
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+        SnapshotCommand::Delete { name } => {
+            if let Err(err) = fs.snapshot_delete(&name) {
+                println!("Error: {:?}", err);
+            }
+        }
+        SnapshotCommand::Rollback { name } => {
+            match fs.snapshot_rollback(&name) {
+                Ok(()) => println!("已经回滚到快照 {}", name),
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+        SnapshotCommand::Diff { name } => {
+            match fs.snapshot_diff(&name) {
+                Ok(diff) => format_print_snapshot_diff(&diff),
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+        SnapshotCommand::Ls { name, path } => {
+            let path = match path {
+                Some(path) => Path::from_str(&path),
+                None => Some(Path::root()),
+            };
+            let Some(path) = path else {
+                println!("路径不合法");
+                return;
+            };
+
+            match fs.snapshot_list(&name, &path) {
+                Ok(descriptions) => format_print_descriptions(&descriptions),
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+        SnapshotCommand::Cat { name, path } => {
+            let Some(path) = Path::from_str(&path) else {
+                println!("路径不合法");
+                return;
+            };
+
+            match fs.snapshot_read(&name, &path) {
+                Ok(buf) => println!("读取了{}字节，读取结果：{:?}", buf.len(), buf),
+                Err(err) => println!("Error: {:?}", err),
+            }
+        }
+    }
+}
+
 /// 准备命令参数
 fn prepare_args(mut input: String) -> Option<Vec<String>> {
     input = input.replace("\n", "")
@@ -167,19 +762,172 @@ fn prepare_args(mut input: String) -> Option<Vec<String>> {
     Some(args)
 }
 
-/// 开始执行
-pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
+/// 什么时候把修改写回存储
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteBack {
+    /// 每条命令之后
+    EveryOp,
+    /// 每隔一段时间，没有修改时不写回
+    Interval(Duration),
+    /// 只在退出时
+    OnExit,
+}
+
+impl Default for WriteBack {
+    fn default() -> Self {
+        WriteBack::Interval(Duration::from_secs(30))
+    }
+}
+
+/// always 为每条命令之后，exit 为只在退出时，数字为每隔多少秒
+impl FromStr for WriteBack {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(WriteBack::EveryOp),
+            "exit" => Ok(WriteBack::OnExit),
+            _ => s.parse::<u64>().ok()
+                .filter(|secs| *secs > 0)
+                .map(|secs| WriteBack::Interval(Duration::from_secs(secs)))
+                .ok_or(format!("无效的写回策略：{}，可以是 always、exit 或者秒数", s)),
+        }
+    }
+}
+
+/// 等待输入时多久检查一次定时写回和 Ctrl-C
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 按照写回策略调用 sync
+struct Flusher {
+    policy: WriteBack,
+    last: Instant,      // 上一次写回的时刻
+    pending: bool,      // 上一次写回之后执行过命令
+}
+
+impl Flusher {
+    fn new(policy: WriteBack) -> Self {
+        Flusher { policy, last: Instant::now(), pending: false }
+    }
+
+    /// 执行了一条命令
+    fn touch(&mut self) {
+        self.pending = true;
+    }
+
+    /// 有需要写回的修改并且到了写回的时候就写回，出错时只打印
+    fn check<FS: VirtualFileSystem>(&mut self, fs: &mut FS)
+    where FS::Error: Into<io::Error> {
+        let due = match self.policy {
+            WriteBack::EveryOp => true,
+            WriteBack::Interval(interval) => self.last.elapsed() >= interval,
+            WriteBack::OnExit => false,
+        };
+        if self.pending && due {
+            if let Err(err) = self.sync(fs) {
+                println!("写回失败：{}", err);
+            }
+        }
+    }
+
+    fn sync<FS: VirtualFileSystem>(&mut self, fs: &mut FS) -> io::Result<()>
+    where FS::Error: Into<io::Error> {
+        self.last = Instant::now();
+        self.pending = false;
+        fs.sync().map_err(Into::into)
+    }
+}
+
+/// 收到 Ctrl-C 之后置位，shell 在等待输入时检查
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// 收到 Ctrl-C 时不直接退出，让 shell 写回之后再退出
+fn catch_interrupt() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+/// 在另一个线程中读取输入，读到末尾或者出错时关闭通道
+fn read_lines() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        loop {
+            let mut input = String::new();
+            match std::io::stdin().read_line(&mut input) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if sender.send(input).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// 等待下一行输入，等待时按照写回策略定时写回；读到末尾或者收到 Ctrl-C 时返回 None
+fn next_line<FS: VirtualFileSystem>(lines: &Receiver<String>, flusher: &mut Flusher, fs: &mut FS) -> Option<String>
+where FS::Error: Into<io::Error> {
+    loop {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            return None;
+        }
+        match lines.recv_timeout(POLL_INTERVAL) {
+            Ok(line) => return Some(line),
+            Err(RecvTimeoutError::Timeout) => flusher.check(fs),
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+}
+
+/// 开始执行，按照 write_back 把修改写回存储
+///
+/// 执行 exit 命令时返回指定的文件名；读到输入末尾或者收到 Ctrl-C 时写回所有修改之后返回 None
+pub fn run<FS: VirtualFileSystem>(fs: &mut FS, write_back: WriteBack) -> Option<String>
+where FS::Error: Into<io::Error> {
     let mut path = Path::from_str("/").unwrap();
     let mut files = Vec::<FS::File>::new();
 
+    // 当前进程的状态放在 path 和 files 中，其他进程的放在 others 中；打开文件时指定进程号作为会话
+    let mut pid = 0;
+    let mut others = BTreeMap::<usize, (Path, Vec<FS::File>)>::new();
+
+    let mut flusher = Flusher::new(write_back);
+    let lines = read_lines();
+    catch_interrupt();
+
     loop {
-        // 打印提示符
-        print!("FS {}> ", path.to_str());
+        // 上一条命令之后按照策略写回
+        flusher.check(fs);
+
+        // 打印提示符，不是初始进程时带上进程 ID
+        if pid == 0 {
+            print!("FS {}> ", path.to_str());
+        } else {
+            print!("FS[{}] {}> ", pid, path.to_str());
+        }
         std::io::stdout().flush().unwrap();
 
-        // 读取输入
-        let mut input = String::new();
-        std::io::stdin().read_line(&mut input).unwrap();
+        // 读取输入，没有输入了就写回之后退出
+        let Some(input) = next_line(&lines, &mut flusher, fs) else {
+            println!();
+            for target in others.keys().copied().chain([pid]) {
+                if let Err(err) = fs.end_session(target) {
+                    println!("Error: {:?}", err);
+                }
+            }
+            match flusher.sync(fs) {
+                Ok(()) => println!("修改已经写回"),
+                Err(err) => println!("写回失败：{}", err),
+            }
+            return None;
+        };
 
         // 准备参数
         let args = prepare_args(input);
@@ -190,11 +938,12 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
         let args = args.unwrap();
 
         if let Ok(command) = Command::from_iter_safe(args) {
+            flusher.touch();
             match command {
                 Command::Ls => {
                     let res = fs.list(&path);
-                    if res.is_err() {
-                        println!("Error: {:?}", res.unwrap_err());
+                    if let Err(err) = &res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
                     let res = res.unwrap();
@@ -215,8 +964,9 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     path.push(name);
 
                     let exist_res = fs.exists(&path);
-                    if exist_res.is_err() {
-                        println!("Error: {:?}", exist_res.unwrap_err());
+                    if let Err(err) = &exist_res {
+                        println!("Error: {:?}", err);
+                        path = path.parent().unwrap();
                         continue;
                     }
                     let exist = exist_res.unwrap();
@@ -231,8 +981,8 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     new_path.push(name);
 
                     let mkdir_res = fs.mkdir(&new_path);
-                    if mkdir_res.is_err() {
-                        println!("Error: {:?}", mkdir_res.unwrap_err());
+                    if let Err(err) = &mkdir_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
                     mkdir_res.unwrap()
@@ -242,8 +992,8 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     new_path.push(name);
 
                     let rmdir_res = fs.rmdir(&new_path);
-                    if rmdir_res.is_err() {
-                        println!("Error: {:?}", rmdir_res.unwrap_err());
+                    if let Err(err) = &rmdir_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
                     rmdir_res.unwrap()
@@ -253,8 +1003,8 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     new_path.push(name);
 
                     let create_res = fs.create_file(&new_path);
-                    if create_res.is_err() {
-                        println!("Error: {:?}", create_res.unwrap_err());
+                    if let Err(err) = &create_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
                     create_res.unwrap();
@@ -264,41 +1014,93 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     new_path.push(name);
 
                     let delete_res = fs.delete_file(&new_path);
-                    if delete_res.is_err() {
-                        println!("Error: {:?}", delete_res.unwrap_err());
+                    if let Err(err) = &delete_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
                     delete_res.unwrap();
                 }
+                Command::Cp { reflink, src, dst } => {
+                    let src = path.clone().move_push(src);
+                    let dst = path.clone().move_push(dst);
+
+                    let res = if reflink {
+                        fs.clone_file(&src, &dst).map(|_| ())
+                    } else {
+                        copy_file(fs, &src, &dst)
+                    };
+                    if let Err(err) = res {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Mv { src, dst } => {
+                    let (Some(src), Some(dst)) = (resolve(&path, &src), resolve(&path, &dst)) else {
+                        println!("路径不合法");
+                        continue;
+                    };
+
+                    if let Err(err) = fs.rename(&src, &dst) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Import { host, name } => {
+                    match import_file(fs, &host, &path.clone().move_push(name)) {
+                        Ok(len) => println!("导入了 {} 字节", len),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Export { name, host } => {
+                    match export_file(fs, &path.clone().move_push(name), &host) {
+                        Ok(len) => println!("导出了 {} 字节", len),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
                 Command::Exit { name } => {
-                    return name;
+                    // 关闭所有进程打开的文件，删除之后还打开着的文件这时才释放
+                    for target in others.keys().copied().chain([pid]) {
+                        if let Err(err) = fs.end_session(target) {
+                            println!("Error: {:?}", err);
+                        }
+                    }
+                    return Some(name);
                 }
-                Command::Open { name, mode } => {
+                Command::Open { name, mode, append, create, excl, trunc } => {
                     let mut new_path = path.clone();
                     new_path.push(name);
 
-                    if files.iter()
-                        .any(|file| {
-                            *file.path() == new_path
-                        }) {
-                        println!("文件已经被打开过！");
-                        continue;
+                    // 同一个进程中文件按路径查找，只能打开一次；不同进程可以各自打开
+                    match opened(fs, pid, &new_path) {
+                        Ok(true) => {
+                            println!("文件已经被打开过！");
+                            continue;
+                        }
+                        Ok(false) => {}
+                        Err(err) => {
+                            println!("Error: {:?}", err);
+                            continue;
+                        }
                     }
 
-                    let access_mode = if mode == "r" {
-                        AccessMode::Read
-                    } else if mode == "w" {
-                        AccessMode::Write
-                    } else if mode == "rw" || mode == "wr" {
-                        AccessMode::ReadWrite
-                    } else {
-                        println!("需要指定文件访问模式：r、w、rw");
-                        continue;
+                    let (read, write) = match mode.as_str() {
+                        "r" => (true, false),
+                        "w" => (false, true),
+                        "rw" | "wr" => (true, true),
+                        _ => {
+                            println!("需要指定文件访问模式：r、w、rw");
+                            continue;
+                        }
                     };
 
-                    let open_res = fs.open(&new_path, access_mode);
-                    if open_res.is_err() {
-                        println!("Error: {:?}", open_res.unwrap_err());
+                    let open_res = fs.open_with(&new_path, OpenOptions::new()
+                        .read(read)
+                        .write(write)
+                        .append(append)
+                        .create(create)
+                        .create_new(excl)
+                        .truncate(trunc)
+                        .session(pid));
+                    if let Err(err) = &open_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
                     files.push(open_res.unwrap());
@@ -322,8 +1124,8 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     }
 
                     let close_res = fs.close(file.unwrap());
-                    if close_res.is_err() {
-                        println!("Error: {:?}", close_res.unwrap_err());
+                    if let Err(err) = &close_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
 
@@ -347,13 +1149,11 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     }
 
                     let file = file.unwrap();
-                    file.set_position(start);
-
                     let mut buf = vec![0u8; len];
 
-                    let read_res = fs.read(file, &mut buf);
-                    if read_res.is_err() {
-                        println!("Error: {:?}", read_res.unwrap_err());
+                    let read_res = fs.read_at(file, &mut buf, start);
+                    if let Err(err) = &read_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
                     let read_res = read_res.unwrap();
@@ -377,6 +1177,7 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                         continue;
                     }
 
+                    // 追加方式打开的文件要写到末尾，不能用 write_at
                     let file = file.unwrap();
                     file.set_position(start);
 
@@ -388,17 +1189,257 @@ pub fn run<FS: VirtualFileSystem>(fs: &mut FS) -> String {
                     let data = data_res.unwrap();
 
                     let write_res = fs.write(file, &data);
-                    if write_res.is_err() {
-                        println!("Error: {:?}", write_res.unwrap_err());
+                    if let Err(err) = &write_res {
+                        println!("Error: {:?}", err);
                         continue;
                     }
 
                     let write_res = write_res.unwrap();
                     println!("写入了{}字节", write_res);
                 }
+                Command::Su { uid } => {
+                    fs.set_user(uid);
+                }
+                Command::Quota => {
+                    match fs.quotas() {
+                        Ok(reports) => format_print_quotas(&reports),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Setquota { user, dir, block_soft, block_hard, inode_soft, inode_hard } => {
+                    let target = match (user, dir) {
+                        (Some(_), Some(_)) => {
+                            println!("不能同时指定用户和目录！");
+                            continue;
+                        }
+                        (Some(uid), None) => QuotaTarget::User(uid),
+                        (None, Some(name)) => QuotaTarget::Dir(path.clone().move_push(name)),
+                        (None, None) => QuotaTarget::User(fs.user()),
+                    };
+
+                    let blocks = Limit::new(block_soft, block_hard);
+                    let inodes = Limit::new(inode_soft, inode_hard);
+                    if let Err(err) = fs.set_quota(&target, blocks, inodes) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Grace { seconds } => {
+                    if let Err(err) = fs.set_grace_period(seconds) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Frag { name } => {
+                    let target = match name {
+                        Some(name) => path.clone().move_push(name),
+                        None => path.clone(),
+                    };
+
+                    match fs.fragmentation(&target) {
+                        Ok(reports) => format_print_fragments(&reports),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Defrag { name } => {
+                    let target = match name {
+                        Some(name) => path.clone().move_push(name),
+                        None => path.clone(),
+                    };
+
+                    match fs.defrag(&target) {
+                        Ok(reports) => format_print_fragments(&reports),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Compress { name, off } => {
+                    let target = match name {
+                        Some(name) => path.clone().move_push(name),
+                        None => path.clone(),
+                    };
+
+                    if let Err(err) = fs.set_compression(&target, !off) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Dedup => {
+                    match fs.dedup() {
+                        Ok(report) => println!(
+                            "检查了 {} 个数据块，合并了 {} 个，节省 {} 字节",
+                            report.scanned, report.merged, report.saved
+                        ),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Snapshot { command } => run_snapshot(fs, command),
+                Command::Mount { source, point } => {
+                    let Some(point) = resolve(&path, &point) else {
+                        println!("路径不合法");
+                        continue;
+                    };
+
+                    if let Err(err) = fs.mount(&source, &point) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Umount { point } => {
+                    let Some(point) = resolve(&path, &point) else {
+                        println!("路径不合法");
+                        continue;
+                    };
+
+                    if let Err(err) = fs.umount(&point) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Mounts => {
+                    match fs.mounts() {
+                        Ok(mounts) => format_print_mounts(&mounts),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Spawn => {
+                    let new_pid = others.keys().copied().chain([pid]).max().unwrap() + 1;
+                    others.insert(pid, (path.clone(), std::mem::take(&mut files)));
+                    pid = new_pid;
+                    println!("新建进程 {}", pid);
+                }
+                Command::Switch { pid: target } => {
+                    if target == pid {
+                        continue;
+                    }
+                    let Some((target_path, target_files)) = others.remove(&target) else {
+                        println!("不存在这个进程！");
+                        continue;
+                    };
+                    others.insert(pid, (std::mem::replace(&mut path, target_path), std::mem::replace(&mut files, target_files)));
+                    pid = target;
+                }
+                Command::Ps => format_print_processes(pid, &path, &files, &others),
+                Command::Kill { pid: target } => {
+                    if target == pid {
+                        println!("不能结束当前进程，请先切换到别的进程！");
+                        continue;
+                    }
+                    if !others.contains_key(&target) {
+                        println!("不存在这个进程！");
+                        continue;
+                    }
+
+                    match fs.end_session(target) {
+                        Ok(closed) => {
+                            others.remove(&target);
+                            println!("进程 {} 已结束，关闭了 {} 个文件", target, closed);
+                        }
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Lock { name, kind, start, len, wait, detect } => {
+                    let new_path = path.clone().move_push(name);
+                    let Some(file) = files.iter().find(|file| *file.path() == new_path) else {
+                        println!("文件没有被打开！");
+                        continue;
+                    };
+
+                    let kind = match kind.as_str() {
+                        "sh" => LockKind::Shared,
+                        "ex" => LockKind::Exclusive,
+                        _ => {
+                            println!("需要指定锁的类型：sh、ex");
+                            continue;
+                        }
+                    };
+                    let wait = if detect {
+                        LockWait::DetectDeadlock
+                    } else if wait {
+                        LockWait::Blocking
+                    } else {
+                        LockWait::NonBlocking
+                    };
+
+                    match fs.lock(file, kind, lock_range(start, len), wait) {
+                        Ok(LockStatus::Acquired) => println!("加锁成功"),
+                        Ok(LockStatus::Waiting) => println!("锁被占用，正在等待"),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Unlock { name, start, len } => {
+                    let new_path = path.clone().move_push(name);
+                    let Some(file) = files.iter().find(|file| *file.path() == new_path) else {
+                        println!("文件没有被打开！");
+                        continue;
+                    };
+
+                    if let Err(err) = fs.unlock(file, lock_range(start, len)) {
+                        println!("Error: {:?}", err);
+                    }
+                }
+                Command::Locks => {
+                    match fs.locks() {
+                        Ok(locks) => format_print_locks(&locks),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Sync => {
+                    match flusher.sync(fs) {
+                        Ok(()) => println!("写回成功"),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
+                Command::Fsync { name } => {
+                    let new_path = path.clone().move_push(name);
+                    let Some(file) = files.iter().find(|file| *file.path() == new_path) else {
+                        println!("文件没有被打开！");
+                        continue;
+                    };
+
+                    match fs.fsync(file) {
+                        Ok(()) => println!("写回成功"),
+                        Err(err) => println!("Error: {:?}", err),
+                    }
+                }
             }
         } else {
             println!("无效命令");
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::Loadable;
+    use crate::repr::Disk;
+    use crate::shared::SharedFileSystem;
+
+    #[test]
+    fn test_write_back() {
+        assert_eq!("always".parse(), Ok(WriteBack::EveryOp));
+        assert_eq!("exit".parse(), Ok(WriteBack::OnExit));
+        assert_eq!("5".parse(), Ok(WriteBack::Interval(Duration::from_secs(5))));
+        assert!("0".parse::<WriteBack>().is_err());
+        assert!("soon".parse::<WriteBack>().is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("fs.img");
+        let mut disk = Disk::with_size(1024).unwrap();
+        crate::vsfs::init(&mut disk);
+        let mut fs = SharedFileSystem::new(disk);
+        fs.set_image(&image);
+
+        // 没有执行过命令或者还没到时间时不写回
+        let mut flusher = Flusher::new(WriteBack::Interval(Duration::from_secs(3600)));
+        flusher.check(&mut fs);
+        flusher.touch();
+        flusher.check(&mut fs);
+        let mut flusher = Flusher::new(WriteBack::OnExit);
+        flusher.touch();
+        flusher.check(&mut fs);
+        assert!(!image.exists());
+
+        let mut flusher = Flusher::new(WriteBack::EveryOp);
+        fs.mkdir(&Path::from_str("/d").unwrap()).unwrap();
+        flusher.touch();
+        flusher.check(&mut fs);
+        let saved = Disk::load(&image).unwrap();
+        assert!(crate::vsfs::get_inode_by_path(&saved, &Path::from_str("/d").unwrap()).is_some());
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt::{Debug, Display, Formatter};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{AeadCore, ChaCha20Poly1305, KeyInit, Nonce};
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::aead::rand_core::RngCore;
+
+/// 不加密
+pub const CIPHER_NONE: u32 = 0;
+/// ChaCha20-Poly1305，密钥由 Argon2id 从口令派生
+pub const CIPHER_CHACHA20_POLY1305: u32 = 1;
+
+/// 随机数长度
+pub const NONCE_LEN: usize = 12;
+/// 认证标签长度
+pub const TAG_LEN: usize = 16;
+/// 盐的长度
+pub const SALT_LEN: usize = 16;
+/// 镜像标识的长度
+pub const IMAGE_ID_LEN: usize = 16;
+/// 口令校验值的长度：随机数、加密后的 16 字节 0 和认证标签
+pub const KEY_CHECK_LEN: usize = NONCE_LEN + 16 + TAG_LEN;
+/// 一个块加密之后的大小
+pub const SEALED_BLOCK_SIZE: usize = NONCE_LEN + 4096 + TAG_LEN;
+
+
+pub enum Error {
+    /// 口令错误，或者超级块被改动过
+    WrongPassphrase,
+
+    /// 第几个块解密失败
+    Corrupted(usize),
+
+    /// 密钥派生参数不合法
+    InvalidParams,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::WrongPassphrase => write!(f, "wrong passphrase"),
+            Error::Corrupted(index) => write!(f, "block {} is corrupted", index),
+            Error::InvalidParams => write!(f, "invalid key derivation parameters"),
+        }
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {}
+
+
+/// Argon2id 的参数，保存在超级块中
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct KdfParams {
+    pub m_cost: u32,    // 内存，单位 KiB
+    pub t_cost: u32,    // 迭代次数
+    pub p_cost: u32,    // 并行度
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+
+/// 加密密钥，只存在内存中
+#[derive(PartialEq, Eq, Clone)]
+pub struct Key([u8; 32]);
+
+impl Debug for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
+
+impl Key {
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new((&self.0).into())
+    }
+}
+
+
+/// 生成随机的盐
+pub fn random_salt() -> [u8; SALT_LEN] {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// 生成随机的镜像标识
+pub fn random_image_id() -> [u8; IMAGE_ID_LEN] {
+    let mut id = [0u8; IMAGE_ID_LEN];
+    OsRng.fill_bytes(&mut id);
+    id
+}
+
+/// 从口令派生密钥
+pub fn derive_key(passphrase: &str, salt: &[u8; SALT_LEN], params: &KdfParams) -> Result<Key, Error> {
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|_| Error::InvalidParams)?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|_| Error::InvalidParams)?;
+    Ok(Key(key))
+}
+
+/// 加密一段数据，结果是随机数、密文和认证标签
+///
+/// aad 不加密，但是会被认证，用来把密文和它的位置绑定在一起
+fn seal(key: &Key, aad: &[u8], plain: &[u8]) -> Vec<u8> {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = key.cipher()
+        .encrypt(&nonce, Payload { msg: plain, aad })
+        .unwrap();
+
+    let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    sealed
+}
+
+/// 解密 seal 的结果，认证失败时返回 None
+fn open(key: &Key, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    key.cipher()
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .ok()
+}
+
+/// 块的附加数据：块的位置、镜像标识和保存次数
+///
+/// 这样块不能被挪到别的位置、用同一个口令加密的别的镜像，或者同一个镜像之前保存的版本中
+fn block_aad(image_id: &[u8; IMAGE_ID_LEN], generation: u32, index: usize) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(8 + IMAGE_ID_LEN + 4);
+    aad.extend_from_slice(&(index as u64).to_le_bytes());
+    aad.extend_from_slice(image_id);
+    aad.extend_from_slice(&generation.to_le_bytes());
+    aad
+}
+
+/// 加密镜像中的第 index 个块，image_id 和 generation 来自超级块
+pub fn seal_block(key: &Key, image_id: &[u8; IMAGE_ID_LEN], generation: u32, index: usize, block: &[u8]) -> Vec<u8> {
+    seal(key, &block_aad(image_id, generation, index), block)
+}
+
+/// 解密镜像中的第 index 个块
+pub fn open_block(key: &Key, image_id: &[u8; IMAGE_ID_LEN], generation: u32, index: usize, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    open(key, &block_aad(image_id, generation, index), sealed)
+        .ok_or(Error::Corrupted(index))
+}
+
+/// 生成口令校验值，同时认证 header（超级块中除校验值以外的内容）
+pub fn make_key_check(key: &Key, header: &[u8]) -> [u8; KEY_CHECK_LEN] {
+    seal(key, header, &[0u8; 16]).try_into().unwrap()
+}
+
+/// 检查口令校验值
+pub fn verify_key_check(key: &Key, header: &[u8], check: &[u8; KEY_CHECK_LEN]) -> Result<(), Error> {
+    open(key, header, check)
+        .map(|_| ())
+        .ok_or(Error::WrongPassphrase)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// 测试用的参数，减少派生密钥的时间
+    const FAST: KdfParams = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+
+    #[test]
+    fn test_seal_open() {
+        let salt = random_salt();
+        let key = derive_key("secret", &salt, &FAST).unwrap();
+        let block = [7u8; 4096];
+
+        let id = random_image_id();
+        let sealed = seal_block(&key, &id, 5, 3, &block);
+        assert_eq!(sealed.len(), SEALED_BLOCK_SIZE);
+        assert_eq!(open_block(&key, &id, 5, 3, &sealed).unwrap(), block);
+
+        // 位置、镜像或者保存次数不对，或者被改动过都会认证失败
+        assert!(matches!(open_block(&key, &id, 5, 4, &sealed), Err(Error::Corrupted(4))));
+        assert!(open_block(&key, &random_image_id(), 5, 3, &sealed).is_err());
+        assert!(open_block(&key, &id, 4, 3, &sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered[100] ^= 1;
+        assert!(open_block(&key, &id, 5, 3, &tampered).is_err());
+
+        // 口令错误
+        let check = make_key_check(&key, b"header");
+        let wrong = derive_key("wrong", &salt, &FAST).unwrap();
+        assert!(verify_key_check(&key, b"header", &check).is_ok());
+        assert!(matches!(verify_key_check(&wrong, b"header", &check), Err(Error::WrongPassphrase)));
+        assert!(verify_key_check(&key, b"other", &check).is_err());
+    }
+}
@@ -0,0 +1,694 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::quota::{Limit, QuotaReport, QuotaTarget};
+use crate::rw::AccessMode;
+use crate::vfs::{DedupReport, FragmentReport, MountInfo, OpenOptions, SnapshotDiff, SnapshotInfo, VirtualFile, VirtualFileDescription, VirtualFileSystem};
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+pub type DynResult<T> = Result<T, BoxError>;
+pub type BoxDescription = Box<dyn VirtualFileDescription>;
+
+/// 打开的文件的编号，只在打开它的文件系统中有意义
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId(usize);
+
+/// VirtualFileSystem 的对象安全版本，可以放进 `Box<dyn DynFileSystem>`，在运行时选择实现
+///
+/// 错误和文件描述都装箱，打开的文件只用编号表示，读写位置保存在文件系统中
+pub trait DynFileSystem {
+    fn init(&mut self) -> DynResult<()>;
+
+    fn create_file(&mut self, path: &Path) -> DynResult<BoxDescription>;
+    fn delete_file(&mut self, path: &Path) -> DynResult<()>;
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> DynResult<BoxDescription>;
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> DynResult<FileId>;
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> DynResult<FileId>;
+    fn description(&mut self, file: FileId) -> DynResult<BoxDescription>;
+    fn close(&mut self, file: FileId) -> DynResult<()>;
+    fn read(&mut self, file: FileId, buf: &mut [u8]) -> DynResult<usize>;
+    fn write(&mut self, file: FileId, buf: &[u8]) -> DynResult<usize>;
+    fn read_at(&mut self, file: FileId, buf: &mut [u8], offset: usize) -> DynResult<usize>;
+    fn write_at(&mut self, file: FileId, buf: &[u8], offset: usize) -> DynResult<usize>;
+    fn readv(&mut self, file: FileId, bufs: &mut [IoSliceMut<'_>]) -> DynResult<usize>;
+    fn writev(&mut self, file: FileId, bufs: &[IoSlice<'_>]) -> DynResult<usize>;
+    fn fsync(&mut self, file: FileId) -> DynResult<()>;
+    fn sync(&mut self) -> DynResult<()>;
+    fn position(&self, file: FileId) -> DynResult<usize>;
+    fn set_position(&mut self, file: FileId, pos: usize) -> DynResult<()>;
+
+    fn list(&mut self, path: &Path) -> DynResult<Vec<BoxDescription>>;
+    fn mkdir(&mut self, path: &Path) -> DynResult<()>;
+    fn rmdir(&mut self, path: &Path) -> DynResult<()>;
+    fn rename(&mut self, from: &Path, to: &Path) -> DynResult<()>;
+
+    fn exists(&mut self, path: &Path) -> DynResult<bool>;
+
+    fn user(&self) -> u32;
+    fn set_user(&mut self, uid: u32);
+
+    fn end_session(&mut self, session: usize) -> DynResult<usize>;
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> DynResult<bool>;
+
+    fn lock(&mut self, file: FileId, kind: LockKind, range: LockRange, wait: LockWait) -> DynResult<LockStatus>;
+    fn unlock(&mut self, file: FileId, range: LockRange) -> DynResult<()>;
+    fn locks(&mut self) -> DynResult<Vec<LockInfo>>;
+
+    fn quotas(&mut self) -> DynResult<Vec<QuotaReport>>;
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> DynResult<()>;
+    fn set_grace_period(&mut self, grace_period: u32) -> DynResult<()>;
+
+    fn fragmentation(&mut self, path: &Path) -> DynResult<Vec<FragmentReport>>;
+    fn defrag(&mut self, path: &Path) -> DynResult<Vec<FragmentReport>>;
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> DynResult<()>;
+    fn dedup(&mut self) -> DynResult<DedupReport>;
+
+    fn snapshot_create(&mut self, name: &str) -> DynResult<()>;
+    fn snapshots(&mut self) -> DynResult<Vec<SnapshotInfo>>;
+    fn snapshot_delete(&mut self, name: &str) -> DynResult<()>;
+    fn snapshot_rollback(&mut self, name: &str) -> DynResult<()>;
+    fn snapshot_diff(&mut self, name: &str) -> DynResult<SnapshotDiff>;
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> DynResult<Vec<BoxDescription>>;
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> DynResult<Vec<u8>>;
+
+    fn mount(&mut self, source: &str, point: &Path) -> DynResult<()>;
+    fn umount(&mut self, point: &Path) -> DynResult<()>;
+    fn mounts(&mut self) -> DynResult<Vec<MountInfo>>;
+}
+
+
+/// 把任意 VirtualFileSystem 包装成 DynFileSystem
+///
+/// 打开的文件留在适配器里，按编号查找；错误先转换成 io::Error 再装箱，这样错误种类不会丢
+pub struct DynAdapter<F: VirtualFileSystem> {
+    fs: F,
+    files: HashMap<usize, (F::File, usize)>,    // 打开文件表，值是文件和打开它的会话
+    next_id: usize,
+}
+
+impl<F: VirtualFileSystem> DynAdapter<F> {
+    pub fn new(fs: F) -> Self {
+        DynAdapter {
+            fs,
+            files: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    /// 记下 session 打开的文件，返回它的编号
+    fn insert(&mut self, file: F::File, session: usize) -> FileId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.files.insert(id, (file, session));
+        FileId(id)
+    }
+
+    fn file(&self, file: FileId) -> DynResult<&F::File> {
+        self.files.get(&file.0)
+            .map(|(file, _)| file)
+            .ok_or(not_open())
+    }
+
+    fn file_mut(&mut self, file: FileId) -> DynResult<&mut F::File> {
+        self.files.get_mut(&file.0)
+            .map(|(file, _)| file)
+            .ok_or(not_open())
+    }
+}
+
+/// 把 fs 装箱成 DynFileSystem
+pub fn boxed<'a, F>(fs: F) -> Box<dyn DynFileSystem + 'a>
+where F: VirtualFileSystem + 'a, F::Error: Into<io::Error>, F::FileDescription: 'static {
+    Box::new(DynAdapter::new(fs))
+}
+
+fn not_open() -> BoxError {
+    Box::new(io::Error::new(ErrorKind::InvalidInput, "file not open"))
+}
+
+fn boxed_error<E: Into<io::Error>>(err: E) -> BoxError {
+    Box::new(err.into())
+}
+
+fn boxed_description<D: VirtualFileDescription + 'static>(fd: D) -> BoxDescription {
+    Box::new(fd)
+}
+
+fn boxed_descriptions<D: VirtualFileDescription + 'static>(fds: Vec<D>) -> Vec<BoxDescription> {
+    fds.into_iter().map(boxed_description).collect()
+}
+
+impl<F> DynFileSystem for DynAdapter<F>
+where F: VirtualFileSystem, F::Error: Into<io::Error>, F::FileDescription: 'static {
+    fn init(&mut self) -> DynResult<()> {
+        self.fs.init().map_err(boxed_error)
+    }
+
+    fn create_file(&mut self, path: &Path) -> DynResult<BoxDescription> {
+        self.fs.create_file(path).map(boxed_description).map_err(boxed_error)
+    }
+
+    fn delete_file(&mut self, path: &Path) -> DynResult<()> {
+        self.fs.delete_file(path).map_err(boxed_error)
+    }
+
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> DynResult<BoxDescription> {
+        self.fs.clone_file(src, dst).map(boxed_description).map_err(boxed_error)
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> DynResult<FileId> {
+        let file = self.fs.open(path, mode).map_err(boxed_error)?;
+        Ok(self.insert(file, 0))
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> DynResult<FileId> {
+        let file = self.fs.open_with(path, options).map_err(boxed_error)?;
+        Ok(self.insert(file, options.session))
+    }
+
+    fn description(&mut self, file: FileId) -> DynResult<BoxDescription> {
+        let file = self.files.get(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.description(file).map(boxed_description).map_err(boxed_error)
+    }
+
+    fn close(&mut self, file: FileId) -> DynResult<()> {
+        let (file, _) = self.files.remove(&file.0).ok_or(not_open())?;
+        self.fs.close(file).map_err(boxed_error)
+    }
+
+    fn read(&mut self, file: FileId, buf: &mut [u8]) -> DynResult<usize> {
+        let file = self.files.get_mut(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.read(file, buf).map_err(boxed_error)
+    }
+
+    fn write(&mut self, file: FileId, buf: &[u8]) -> DynResult<usize> {
+        let file = self.files.get_mut(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.write(file, buf).map_err(boxed_error)
+    }
+
+    fn read_at(&mut self, file: FileId, buf: &mut [u8], offset: usize) -> DynResult<usize> {
+        let file = self.files.get(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.read_at(file, buf, offset).map_err(boxed_error)
+    }
+
+    fn write_at(&mut self, file: FileId, buf: &[u8], offset: usize) -> DynResult<usize> {
+        let file = self.files.get(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.write_at(file, buf, offset).map_err(boxed_error)
+    }
+
+    fn readv(&mut self, file: FileId, bufs: &mut [IoSliceMut<'_>]) -> DynResult<usize> {
+        let file = self.files.get_mut(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.readv(file, bufs).map_err(boxed_error)
+    }
+
+    fn writev(&mut self, file: FileId, bufs: &[IoSlice<'_>]) -> DynResult<usize> {
+        let file = self.files.get_mut(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.writev(file, bufs).map_err(boxed_error)
+    }
+
+    fn fsync(&mut self, file: FileId) -> DynResult<()> {
+        let file = self.files.get(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.fsync(file).map_err(boxed_error)
+    }
+
+    fn sync(&mut self) -> DynResult<()> {
+        self.fs.sync().map_err(boxed_error)
+    }
+
+    fn position(&self, file: FileId) -> DynResult<usize> {
+        Ok(self.file(file)?.position())
+    }
+
+    fn set_position(&mut self, file: FileId, pos: usize) -> DynResult<()> {
+        self.file_mut(file)?.set_position(pos);
+        Ok(())
+    }
+
+    fn list(&mut self, path: &Path) -> DynResult<Vec<BoxDescription>> {
+        self.fs.list(path).map(boxed_descriptions).map_err(boxed_error)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> DynResult<()> {
+        self.fs.mkdir(path).map_err(boxed_error)
+    }
+
+    fn rmdir(&mut self, path: &Path) -> DynResult<()> {
+        self.fs.rmdir(path).map_err(boxed_error)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> DynResult<()> {
+        self.fs.rename(from, to).map_err(boxed_error)
+    }
+
+    fn exists(&mut self, path: &Path) -> DynResult<bool> {
+        self.fs.exists(path).map_err(boxed_error)
+    }
+
+    fn user(&self) -> u32 {
+        self.fs.user()
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.fs.set_user(uid)
+    }
+
+    fn end_session(&mut self, session: usize) -> DynResult<usize> {
+        let closed = self.fs.end_session(session).map_err(boxed_error)?;
+        self.files.retain(|_, (_, owner)| *owner != session);
+        Ok(closed)
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> DynResult<bool> {
+        self.fs.is_open(session, path, mode).map_err(boxed_error)
+    }
+
+    fn lock(&mut self, file: FileId, kind: LockKind, range: LockRange, wait: LockWait) -> DynResult<LockStatus> {
+        let file = self.files.get(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.lock(file, kind, range, wait).map_err(boxed_error)
+    }
+
+    fn unlock(&mut self, file: FileId, range: LockRange) -> DynResult<()> {
+        let file = self.files.get(&file.0).map(|(file, _)| file).ok_or(not_open())?;
+        self.fs.unlock(file, range).map_err(boxed_error)
+    }
+
+    fn locks(&mut self) -> DynResult<Vec<LockInfo>> {
+        self.fs.locks().map_err(boxed_error)
+    }
+
+    fn quotas(&mut self) -> DynResult<Vec<QuotaReport>> {
+        self.fs.quotas().map_err(boxed_error)
+    }
+
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> DynResult<()> {
+        self.fs.set_quota(target, blocks, inodes).map_err(boxed_error)
+    }
+
+    fn set_grace_period(&mut self, grace_period: u32) -> DynResult<()> {
+        self.fs.set_grace_period(grace_period).map_err(boxed_error)
+    }
+
+    fn fragmentation(&mut self, path: &Path) -> DynResult<Vec<FragmentReport>> {
+        self.fs.fragmentation(path).map_err(boxed_error)
+    }
+
+    fn defrag(&mut self, path: &Path) -> DynResult<Vec<FragmentReport>> {
+        self.fs.defrag(path).map_err(boxed_error)
+    }
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> DynResult<()> {
+        self.fs.set_compression(path, enabled).map_err(boxed_error)
+    }
+
+    fn dedup(&mut self) -> DynResult<DedupReport> {
+        self.fs.dedup().map_err(boxed_error)
+    }
+
+    fn snapshot_create(&mut self, name: &str) -> DynResult<()> {
+        self.fs.snapshot_create(name).map_err(boxed_error)
+    }
+
+    fn snapshots(&mut self) -> DynResult<Vec<SnapshotInfo>> {
+        self.fs.snapshots().map_err(boxed_error)
+    }
+
+    fn snapshot_delete(&mut self, name: &str) -> DynResult<()> {
+        self.fs.snapshot_delete(name).map_err(boxed_error)
+    }
+
+    fn snapshot_rollback(&mut self, name: &str) -> DynResult<()> {
+        self.fs.snapshot_rollback(name).map_err(boxed_error)
+    }
+
+    fn snapshot_diff(&mut self, name: &str) -> DynResult<SnapshotDiff> {
+        self.fs.snapshot_diff(name).map_err(boxed_error)
+    }
+
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> DynResult<Vec<BoxDescription>> {
+        self.fs.snapshot_list(name, path).map(boxed_descriptions).map_err(boxed_error)
+    }
+
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> DynResult<Vec<u8>> {
+        self.fs.snapshot_read(name, path).map_err(boxed_error)
+    }
+
+    fn mount(&mut self, source: &str, point: &Path) -> DynResult<()> {
+        self.fs.mount(source, point).map_err(boxed_error)
+    }
+
+    fn umount(&mut self, point: &Path) -> DynResult<()> {
+        self.fs.umount(point).map_err(boxed_error)
+    }
+
+    fn mounts(&mut self) -> DynResult<Vec<MountInfo>> {
+        self.fs.mounts().map_err(boxed_error)
+    }
+}
+
+
+impl VirtualFileDescription for BoxDescription {
+    fn is_dir(&self) -> bool {
+        (**self).is_dir()
+    }
+
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn ctime(&self) -> u64 {
+        (**self).ctime()
+    }
+
+    fn mtime(&self) -> u64 {
+        (**self).mtime()
+    }
+
+    fn size(&self) -> usize {
+        (**self).size()
+    }
+
+    fn owner(&self) -> u32 {
+        (**self).owner()
+    }
+}
+
+/// `Box<dyn DynFileSystem>` 中打开的文件
+///
+/// 读写位置在这里修改，读写之前同步给文件系统，读写之后再取回来
+#[derive(Debug)]
+pub struct DynFile {
+    id: FileId,
+    path: Path,
+    mode: AccessMode,
+    position: usize,
+}
+
+impl VirtualFile for DynFile {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        self.position = pos;
+    }
+}
+
+/// 装箱的错误转换回 io::Error，本来就是 io::Error 时保留错误种类
+fn into_io(err: BoxError) -> io::Error {
+    match err.downcast::<io::Error>() {
+        Ok(err) => *err,
+        Err(err) => io::Error::other(err),
+    }
+}
+
+/// 让装箱的文件系统也能交给 commands::run 等使用 VirtualFileSystem 的代码
+impl VirtualFileSystem for Box<dyn DynFileSystem + '_> {
+    type File = DynFile;
+    type Error = io::Error;
+    type FileDescription = BoxDescription;
+
+    fn init(&mut self) -> io::Result<()> {
+        (**self).init().map_err(into_io)
+    }
+
+    fn create_file(&mut self, path: &Path) -> io::Result<Self::FileDescription> {
+        (**self).create_file(path).map_err(into_io)
+    }
+
+    fn delete_file(&mut self, path: &Path) -> io::Result<()> {
+        (**self).delete_file(path).map_err(into_io)
+    }
+
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> io::Result<Self::FileDescription> {
+        (**self).clone_file(src, dst).map_err(into_io)
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> io::Result<Self::File> {
+        let id = (**self).open(path, mode).map_err(into_io)?;
+        Ok(DynFile {
+            id,
+            path: path.clone(),
+            mode,
+            position: 0,
+        })
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> io::Result<Self::File> {
+        let id = (**self).open_with(path, options).map_err(into_io)?;
+        Ok(DynFile {
+            id,
+            path: path.clone(),
+            // 打开成功时选项一定是合法的
+            mode: options.access_mode().unwrap_or(AccessMode::Read),
+            position: 0,
+        })
+    }
+
+    fn description(&mut self, file: &Self::File) -> io::Result<Self::FileDescription> {
+        (**self).description(file.id).map_err(into_io)
+    }
+
+    fn close(&mut self, file: Self::File) -> io::Result<()> {
+        (**self).close(file.id).map_err(into_io)
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> io::Result<usize> {
+        let fs = &mut **self;
+        fs.set_position(file.id, file.position).map_err(into_io)?;
+        let len = fs.read(file.id, buf).map_err(into_io)?;
+        file.position = fs.position(file.id).map_err(into_io)?;
+        Ok(len)
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> io::Result<usize> {
+        if file.mode == AccessMode::Read {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "file is opened read-only"));
+        }
+        let fs = &mut **self;
+        fs.set_position(file.id, file.position).map_err(into_io)?;
+        let len = fs.write(file.id, buf).map_err(into_io)?;
+        file.position = fs.position(file.id).map_err(into_io)?;
+        Ok(len)
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> io::Result<usize> {
+        (**self).read_at(file.id, buf, offset).map_err(into_io)
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> io::Result<usize> {
+        if file.mode == AccessMode::Read {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "file is opened read-only"));
+        }
+        (**self).write_at(file.id, buf, offset).map_err(into_io)
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        let fs = &mut **self;
+        fs.set_position(file.id, file.position).map_err(into_io)?;
+        let len = fs.readv(file.id, bufs).map_err(into_io)?;
+        file.position = fs.position(file.id).map_err(into_io)?;
+        Ok(len)
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        if file.mode == AccessMode::Read {
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "file is opened read-only"));
+        }
+        let fs = &mut **self;
+        fs.set_position(file.id, file.position).map_err(into_io)?;
+        let len = fs.writev(file.id, bufs).map_err(into_io)?;
+        file.position = fs.position(file.id).map_err(into_io)?;
+        Ok(len)
+    }
+
+    fn fsync(&mut self, file: &Self::File) -> io::Result<()> {
+        (**self).fsync(file.id).map_err(into_io)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync().map_err(into_io)
+    }
+
+    fn list(&mut self, path: &Path) -> io::Result<Vec<Self::FileDescription>> {
+        (**self).list(path).map_err(into_io)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> io::Result<()> {
+        (**self).mkdir(path).map_err(into_io)
+    }
+
+    fn rmdir(&mut self, path: &Path) -> io::Result<()> {
+        (**self).rmdir(path).map_err(into_io)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        (**self).rename(from, to).map_err(into_io)
+    }
+
+    fn exists(&mut self, path: &Path) -> io::Result<bool> {
+        (**self).exists(path).map_err(into_io)
+    }
+
+    fn user(&self) -> u32 {
+        (**self).user()
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        (**self).set_user(uid)
+    }
+
+    fn end_session(&mut self, session: usize) -> io::Result<usize> {
+        (**self).end_session(session).map_err(into_io)
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> io::Result<bool> {
+        (**self).is_open(session, path, mode).map_err(into_io)
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> io::Result<LockStatus> {
+        (**self).lock(file.id, kind, range, wait).map_err(into_io)
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> io::Result<()> {
+        (**self).unlock(file.id, range).map_err(into_io)
+    }
+
+    fn locks(&mut self) -> io::Result<Vec<LockInfo>> {
+        (**self).locks().map_err(into_io)
+    }
+
+    fn quotas(&mut self) -> io::Result<Vec<QuotaReport>> {
+        (**self).quotas().map_err(into_io)
+    }
+
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> io::Result<()> {
+        (**self).set_quota(target, blocks, inodes).map_err(into_io)
+    }
+
+    fn set_grace_period(&mut self, grace_period: u32) -> io::Result<()> {
+        (**self).set_grace_period(grace_period).map_err(into_io)
+    }
+
+    fn fragmentation(&mut self, path: &Path) -> io::Result<Vec<FragmentReport>> {
+        (**self).fragmentation(path).map_err(into_io)
+    }
+
+    fn defrag(&mut self, path: &Path) -> io::Result<Vec<FragmentReport>> {
+        (**self).defrag(path).map_err(into_io)
+    }
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> io::Result<()> {
+        (**self).set_compression(path, enabled).map_err(into_io)
+    }
+
+    fn dedup(&mut self) -> io::Result<DedupReport> {
+        (**self).dedup().map_err(into_io)
+    }
+
+    fn snapshot_create(&mut self, name: &str) -> io::Result<()> {
+        (**self).snapshot_create(name).map_err(into_io)
+    }
+
+    fn snapshots(&mut self) -> io::Result<Vec<SnapshotInfo>> {
+        (**self).snapshots().map_err(into_io)
+    }
+
+    fn snapshot_delete(&mut self, name: &str) -> io::Result<()> {
+        (**self).snapshot_delete(name).map_err(into_io)
+    }
+
+    fn snapshot_rollback(&mut self, name: &str) -> io::Result<()> {
+        (**self).snapshot_rollback(name).map_err(into_io)
+    }
+
+    fn snapshot_diff(&mut self, name: &str) -> io::Result<SnapshotDiff> {
+        (**self).snapshot_diff(name).map_err(into_io)
+    }
+
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> io::Result<Vec<Self::FileDescription>> {
+        (**self).snapshot_list(name, path).map_err(into_io)
+    }
+
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> io::Result<Vec<u8>> {
+        (**self).snapshot_read(name, path).map_err(into_io)
+    }
+
+    fn mount(&mut self, source: &str, point: &Path) -> io::Result<()> {
+        (**self).mount(source, point).map_err(into_io)
+    }
+
+    fn umount(&mut self, point: &Path) -> io::Result<()> {
+        (**self).umount(point).map_err(into_io)
+    }
+
+    fn mounts(&mut self) -> io::Result<Vec<MountInfo>> {
+        (**self).mounts().map_err(into_io)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memfs::MemoryFileSystem;
+    use crate::overlay::OverlayFileSystem;
+    use crate::repr::Disk;
+    use crate::vfs::conformance;
+    use crate::vsfs_vfs::VerySimpleFileSystem;
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run_all(|| boxed(MemoryFileSystem::new()));
+    }
+
+    #[test]
+    fn test_heterogeneous() {
+        let mut disk = Disk::new();
+        let mut filesystems: Vec<Box<dyn DynFileSystem>> = vec![
+            boxed(MemoryFileSystem::new()),
+            boxed(VerySimpleFileSystem::new(&mut disk)),
+            boxed(OverlayFileSystem::new(MemoryFileSystem::new(), MemoryFileSystem::new())),
+        ];
+
+        for fs in filesystems.iter_mut() {
+            let fs = &mut **fs;
+            fs.init().unwrap();
+            fs.create_file(&path("/a")).unwrap();
+            let file = fs.open(&path("/a"), AccessMode::ReadWrite).unwrap();
+            fs.write(file, b"hello").unwrap();
+            fs.set_position(file, 1).unwrap();
+            let mut buf = [0u8; 4];
+            assert_eq!(fs.read(file, &mut buf).unwrap(), 4);
+            assert_eq!(&buf, b"ello");
+            assert_eq!(fs.position(file).unwrap(), 5);
+            assert_eq!(fs.description(file).unwrap().size(), 5);
+            fs.close(file).unwrap();
+
+            // 关闭之后编号失效
+            assert!(fs.close(file).is_err());
+            assert!(fs.read(file, &mut buf).is_err());
+
+            // 错误种类保留下来
+            let err = fs.open(&path("/missing"), AccessMode::Read).unwrap_err();
+            assert_eq!(into_io(err).kind(), ErrorKind::NotFound);
+        }
+    }
+}
@@ -0,0 +1,562 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, IoSlice, IoSliceMut, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::lock::{LockError, LockInfo, LockKind, LockManager, LockOwner, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::rw::AccessMode;
+use crate::vfs;
+use crate::vfs::{Unsupported, VirtualFile, VirtualFileDescription, VirtualFileSystem};
+
+#[derive(Debug)]
+pub struct HostFile {
+    path: Path,
+    mode: AccessMode,
+    position: usize,
+    append: bool,       // 每次写入之前先移到文件末尾
+    id: usize,
+}
+
+impl VirtualFile for HostFile {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        self.position = pos;
+    }
+}
+
+#[derive(Debug)]
+pub struct HostFileDescription {
+    name: String,
+    metadata: Metadata,
+}
+
+/// 把 SystemTime 转换为时间戳，取不到时为 0
+fn timestamp(time: io::Result<SystemTime>) -> u64 {
+    time.ok()
+        .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+impl VirtualFileDescription for HostFileDescription {
+    fn is_dir(&self) -> bool {
+        self.metadata.is_dir()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ctime(&self) -> u64 {
+        timestamp(self.metadata.created().or_else(|_| self.metadata.modified()))
+    }
+
+    fn mtime(&self) -> u64 {
+        timestamp(self.metadata.modified())
+    }
+
+    fn size(&self) -> usize {
+        if self.metadata.is_dir() {
+            0
+        } else {
+            self.metadata.len() as usize
+        }
+    }
+
+    #[cfg(unix)]
+    fn owner(&self) -> u32 {
+        std::os::unix::fs::MetadataExt::uid(&self.metadata)
+    }
+
+    #[cfg(not(unix))]
+    fn owner(&self) -> u32 {
+        0
+    }
+}
+
+
+#[derive(Debug)]
+pub enum HostError {
+    Io(io::Error),
+    InvalidPath,
+    Escape(Path),
+    IsADirectory(Path),
+    FileExist(Path),
+    FileCannotWrite,
+    FileNotOpen,
+    AccessError,
+    Unsupported,
+    Lock(LockError),
+}
+
+impl Display for HostError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HostError::Io(err) => Display::fmt(err, f),
+            HostError::InvalidPath => write!(f, "invalid path"),
+            HostError::Escape(path) => write!(f, "path {} leaves the host root", path.to_str()),
+            HostError::IsADirectory(path) => write!(f, "{} is a directory", path.to_str()),
+            HostError::FileExist(path) => write!(f, "file {} is already exist", path.to_str()),
+            HostError::FileCannotWrite => write!(f, "File cannot write"),
+            HostError::FileNotOpen => write!(f, "File not open"),
+            HostError::AccessError => write!(f, "access error. r, w, or rw"),
+            HostError::Unsupported => write!(f, "not supported by the host file system"),
+            HostError::Lock(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl Error for HostError {}
+
+impl From<Unsupported> for HostError {
+    fn from(_: Unsupported) -> Self {
+        HostError::Unsupported
+    }
+}
+
+impl From<io::Error> for HostError {
+    fn from(err: io::Error) -> Self {
+        HostError::Io(err)
+    }
+}
+
+impl From<HostError> for io::Error {
+    fn from(err: HostError) -> Self {
+        let kind = match &err {
+            HostError::Io(err) => err.kind(),
+            HostError::InvalidPath | HostError::FileNotOpen => ErrorKind::InvalidInput,
+            HostError::Escape(_) | HostError::AccessError => ErrorKind::PermissionDenied,
+            HostError::IsADirectory(_) => ErrorKind::IsADirectory,
+            HostError::FileExist(_) => ErrorKind::AlreadyExists,
+            HostError::FileCannotWrite => ErrorKind::ResourceBusy,
+            HostError::Unsupported => ErrorKind::Unsupported,
+            HostError::Lock(LockError::WouldBlock) => ErrorKind::WouldBlock,
+            HostError::Lock(LockError::Deadlock) => ErrorKind::Deadlock,
+        };
+        match err {
+            HostError::Io(err) => err,
+            err => io::Error::new(kind, err),
+        }
+    }
+}
+
+
+/// 把宿主机上的一个目录当作文件系统
+///
+/// 路径中不能有 `.` 和 `..`，经过符号链接之后也必须还在根目录下面；配额、快照等功能不支持
+pub struct HostFileSystem {
+    root: PathBuf,
+    open_files: HashMap<usize, (String, AccessMode, usize)>,  // 打开文件表，值是路径、模式和打开它的会话
+    next_id: usize,
+    locks: LockManager,
+    uid: u32,
+}
+
+impl HostFileSystem {
+    pub fn new(root: &std::path::Path) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(ErrorKind::NotADirectory, "host root is not a directory"));
+        }
+
+        Ok(HostFileSystem {
+            root,
+            open_files: HashMap::new(),
+            next_id: 0,
+            locks: LockManager::new(),
+            uid: 0,
+        })
+    }
+
+    /// 把 path 映射到宿主机上的路径
+    fn resolve(&self, path: &Path) -> Result<PathBuf, HostError> {
+        let mut full = self.root.clone();
+        for seg in path.iter() {
+            if seg == "." || seg == ".." {
+                return Err(HostError::InvalidPath);
+            }
+            full.push(seg);
+        }
+
+        // 解析已经存在的那部分路径中的符号链接，结果不能离开根目录
+        let mut existing = full.as_path();
+        while fs::symlink_metadata(existing).is_err() {
+            existing = existing.parent().ok_or(HostError::InvalidPath)?;
+        }
+        let real = fs::canonicalize(existing)
+            .map_err(|_| HostError::Escape(path.clone()))?;
+        if !real.starts_with(&self.root) {
+            return Err(HostError::Escape(path.clone()));
+        }
+
+        Ok(full)
+    }
+
+    fn describe(&self, path: &Path) -> Result<HostFileDescription, HostError> {
+        let metadata = fs::metadata(self.resolve(path)?)?;
+        Ok(HostFileDescription {
+            name: path.current().cloned().unwrap_or_default(),
+            metadata,
+        })
+    }
+
+    /// 文件是否还打开着，返回锁的持有者：打开它的会话和文件描述符
+    fn check_open(&self, file: &HostFile) -> Result<LockOwner, HostError> {
+        self.open_files.get(&file.id)
+            .map(|(_, _, session)| LockOwner { session: *session, file: file.id })
+            .ok_or(HostError::FileNotOpen)
+    }
+
+    /// 在 session 中打开已经存在的文件
+    fn open_in(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<HostFile, HostError> {
+        fs::metadata(self.resolve(path)?)?;
+
+        // 同一个文件同时只能有一个写者
+        let key = path.to_str();
+        if mode != AccessMode::Read && self.open_files.values()
+            .any(|(other, other_mode, _)| *other == key && *other_mode != AccessMode::Read) {
+            return Err(HostError::FileCannotWrite);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open_files.insert(id, (key, mode, session));
+
+        Ok(HostFile {
+            path: path.clone(),
+            mode,
+            position: 0,
+            append: false,
+            id,
+        })
+    }
+}
+
+
+impl VirtualFileSystem for HostFileSystem {
+    type File = HostFile;
+    type Error = HostError;
+    type FileDescription = HostFileDescription;
+
+    /// 宿主机目录已经存在，不需要初始化
+    fn init(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, Self::Error> {
+        let full = self.resolve(path)?;
+        if path.is_root() {
+            return Err(HostError::InvalidPath);
+        }
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(full)
+            .map_err(|err| match err.kind() {
+                ErrorKind::AlreadyExists => HostError::FileExist(path.clone()),
+                _ => HostError::Io(err),
+            })?;
+        self.describe(path)
+    }
+
+    fn delete_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        let full = self.resolve(path)?;
+        if full.is_dir() {
+            return Err(HostError::IsADirectory(path.clone()));
+        }
+        fs::remove_file(full)?;
+        Ok(())
+    }
+
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> Result<Self::FileDescription, Self::Error> {
+        let src_full = self.resolve(src)?;
+        if src_full.is_dir() {
+            return Err(HostError::IsADirectory(src.clone()));
+        }
+        let data = fs::read(src_full)?;
+
+        self.create_file(dst)?;
+        fs::write(self.resolve(dst)?, data)?;
+        self.describe(dst)
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error> {
+        self.open_in(0, path, mode)
+    }
+
+    fn open_with(&mut self, path: &Path, options: &vfs::OpenOptions) -> Result<Self::File, Self::Error> {
+        let mode = options.access_mode()
+            .ok_or(HostError::AccessError)?;
+
+        let exists = self.resolve(path)?.exists();
+        if options.create_new && exists {
+            return Err(HostError::FileExist(path.clone()));
+        }
+        if options.creates() && !exists {
+            self.create_file(path)?;
+        }
+
+        let file = self.open_in(options.session, path, mode)?;
+        if options.truncate {
+            let full = self.resolve(path)?;
+            if full.is_dir() {
+                self.open_files.remove(&file.id);
+                return Err(HostError::IsADirectory(path.clone()));
+            }
+            OpenOptions::new().write(true).truncate(true).open(full)?;
+        }
+        Ok(HostFile { append: options.append, ..file })
+    }
+
+    fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, Self::Error> {
+        self.describe(&file.path)
+    }
+
+    fn close(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        let owner = self.check_open(&file)?;
+        self.open_files.remove(&file.id);
+        self.locks.release_file(&file.path, owner);
+        Ok(())
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.read_at(file, buf, file.position)?;
+        file.position += len;
+        Ok(len)
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.check_open(file)?;
+        if file.append {
+            file.position = fs::metadata(self.resolve(&file.path)?)?.len() as usize;
+        }
+
+        let len = self.write_at(file, buf, file.position)?;
+        file.position += len;
+        Ok(len)
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        self.check_open(file)?;
+
+        let mut host_file = File::open(self.resolve(&file.path)?)?;
+        let size = host_file.metadata()?.len() as usize;
+        let len = min(buf.len(), size.saturating_sub(offset));
+
+        host_file.seek(SeekFrom::Start(offset as u64))?;
+        host_file.read_exact(&mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, Self::Error> {
+        self.check_open(file)?;
+        if file.mode == AccessMode::Read {
+            return Err(HostError::AccessError);
+        }
+
+        let mut host_file = OpenOptions::new().write(true).open(self.resolve(&file.path)?)?;
+
+        // 写过末尾时文件变大，中间空出来的部分补 0，不会截断后面的数据
+        host_file.seek(SeekFrom::Start(offset as u64))?;
+        host_file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, Self::Error> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let len = self.read(file, buf)?;
+            total += len;
+            if len < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, Self::Error> {
+        self.write(file, &bufs.iter().flat_map(|buf| buf.iter().copied()).collect::<Vec<_>>())
+    }
+
+    fn fsync(&mut self, file: &Self::File) -> Result<(), Self::Error> {
+        self.check_open(file)?;
+        File::open(self.resolve(&file.path)?)?.sync_all()?;
+        Ok(())
+    }
+
+    /// 每次读写都直接打开宿主机上的文件，没有自己的缓存
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        let mut fds = Vec::new();
+        for entry in fs::read_dir(self.resolve(path)?)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !Path::check_seg_valid(&name) {
+                continue;
+            }
+            let child = path.clone().move_push(name);
+
+            // 指向根目录外面的符号链接不显示
+            if let Ok(fd) = self.describe(&child) {
+                fds.push(fd);
+            }
+        }
+        fds.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(fds)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        let full = self.resolve(path)?;
+        fs::create_dir(full).map_err(|err| match err.kind() {
+            ErrorKind::AlreadyExists => HostError::FileExist(path.clone()),
+            _ => HostError::Io(err),
+        })
+    }
+
+    fn rmdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        if path.is_root() {
+            return Err(HostError::InvalidPath);
+        }
+        fs::remove_dir(self.resolve(path)?)?;
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        if from.is_root() || to.is_root() {
+            return Err(HostError::InvalidPath);
+        }
+        let from_full = self.resolve(from)?;
+        let to_full = self.resolve(to)?;
+        fs::symlink_metadata(&from_full)?;
+        if to == from {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(HostError::InvalidPath);
+        }
+
+        // 宿主机的 rename 会覆盖已经存在的文件
+        if fs::symlink_metadata(&to_full).is_ok() {
+            return Err(HostError::FileExist(to.clone()));
+        }
+
+        // 打开的文件按路径记录，移动之后就找不到了
+        if self.open_files.values()
+            .any(|(key, _, _)| Path::from_str(key).is_some_and(|open| open.starts_with(from))) {
+            return Err(HostError::FileCannotWrite);
+        }
+
+        fs::rename(from_full, to_full)?;
+        Ok(())
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, Self::Error> {
+        Ok(self.resolve(path)?.exists())
+    }
+
+    fn user(&self) -> u32 {
+        self.uid
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.uid = uid;
+    }
+
+    fn end_session(&mut self, session: usize) -> Result<usize, Self::Error> {
+        let before = self.open_files.len();
+        self.open_files.retain(|_, (_, _, owner)| *owner != session);
+        self.locks.release_session(session);
+        Ok(before - self.open_files.len())
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, Self::Error> {
+        let key = path.to_str();
+        Ok(self.open_files.values()
+            .any(|(other, other_mode, owner)| *other == key && *other_mode == mode && *owner == session))
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, Self::Error> {
+        let owner = self.check_open(file)?;
+        self.locks.lock(&file.path, &file.path, owner, kind, range, wait)
+            .map_err(HostError::Lock)
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), Self::Error> {
+        let owner = self.check_open(file)?;
+        self.locks.unlock(&file.path, owner, range);
+        Ok(())
+    }
+
+    fn locks(&mut self) -> Result<Vec<LockInfo>, Self::Error> {
+        Ok(self.locks.locks())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vfs::conformance;
+
+    #[test]
+    fn test_conformance() {
+        // 临时目录要保留到所有场景结束
+        let mut dirs = Vec::new();
+        conformance::run_all(|| {
+            let dir = tempfile::tempdir().unwrap();
+            let fs = HostFileSystem::new(dir.path()).unwrap();
+            dirs.push(dir);
+            fs
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sandbox() {
+        let outside = tempfile::tempdir().unwrap();
+        fs::write(outside.path().join("secret"), b"secret").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("inner")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("out")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("inner"), dir.path().join("in")).unwrap();
+        let mut fs = HostFileSystem::new(dir.path()).unwrap();
+
+        // .. 和指向外面的符号链接都不能用
+        let up = Path::from_str("/inner/../../secret").unwrap();
+        assert!(matches!(fs.open(&up, AccessMode::Read), Err(HostError::InvalidPath)));
+        let secret = Path::from_str("/out/secret").unwrap();
+        assert!(matches!(fs.open(&secret, AccessMode::Read), Err(HostError::Escape(_))));
+        assert!(matches!(fs.create_file(&Path::from_str("/out/new").unwrap()), Err(HostError::Escape(_))));
+        assert!(!outside.path().join("new").exists());
+
+        // 指向根目录里面的符号链接可以用
+        fs.create_file(&Path::from_str("/in/a").unwrap()).unwrap();
+        assert!(dir.path().join("inner/a").exists());
+        let names = fs.list(&Path::root()).unwrap().iter()
+            .map(|fd| fd.name().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["in", "inner"]);
+    }
+}
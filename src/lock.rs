@@ -0,0 +1,339 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::ErrorKind;
+
+use crate::path::Path;
+
+/// 共享锁（读锁）或独占锁（写锁）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+/// 锁住的范围
+///
+/// flock 风格的锁锁住整个文件，属于打开的文件；POSIX 风格的锁锁住一段字节，属于会话。
+/// 两种锁互不影响，和 Linux 上一样
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockRange {
+    File,
+    Bytes {
+        start: usize,
+        len: usize,     // 0 表示一直到文件末尾，包括以后写入的部分
+    },
+}
+
+impl LockRange {
+    /// 字节范围的开始和结束，不含结束
+    fn bounds(&self) -> (usize, usize) {
+        match *self {
+            LockRange::File => (0, usize::MAX),
+            LockRange::Bytes { start, len: 0 } => (start, usize::MAX),
+            LockRange::Bytes { start, len } => (start, start.saturating_add(len)),
+        }
+    }
+
+    fn from_bounds(start: usize, end: usize) -> Self {
+        let len = if end == usize::MAX { 0 } else { end - start };
+        LockRange::Bytes { start, len }
+    }
+}
+
+/// 有冲突时怎么办
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockWait {
+    NonBlocking,        // 立即返回 WouldBlock
+    Blocking,           // 排队等待，冲突的锁释放之后自动获得
+    DetectDeadlock,     // 排队等待，但是会造成死锁时返回 Deadlock
+}
+
+/// 加锁的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockStatus {
+    Acquired,
+    Waiting,
+}
+
+/// 锁的持有者：会话和会话中打开的文件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockOwner {
+    pub session: usize,
+    pub file: usize,
+}
+
+/// 一把持有或者正在等待的锁
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub path: Path,
+    pub session: usize,
+    pub kind: LockKind,
+    pub range: LockRange,
+    pub waiting: bool,
+}
+
+
+#[derive(Debug)]
+pub enum LockError {
+    WouldBlock,
+    Deadlock,
+}
+
+impl Display for LockError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockError::WouldBlock => write!(f, "lock is held by another owner"),
+            LockError::Deadlock => write!(f, "waiting for the lock would deadlock"),
+        }
+    }
+}
+
+impl Error for LockError {}
+
+impl From<LockError> for io::Error {
+    fn from(err: LockError) -> Self {
+        let kind = match &err {
+            LockError::WouldBlock => ErrorKind::WouldBlock,
+            LockError::Deadlock => ErrorKind::Deadlock,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct Lock<K> {
+    key: K,
+    path: Path,
+    owner: LockOwner,
+    kind: LockKind,
+    range: LockRange,
+}
+
+impl<K: Clone + Eq> Lock<K> {
+    fn is_file(&self) -> bool {
+        self.range == LockRange::File
+    }
+
+    /// 是不是同一个持有者，字节范围锁只看会话
+    fn same_owner(&self, other: &Lock<K>) -> bool {
+        if self.is_file() {
+            self.owner == other.owner
+        } else {
+            self.owner.session == other.owner.session
+        }
+    }
+
+    fn overlaps(&self, other: &Lock<K>) -> bool {
+        let (start, end) = self.range.bounds();
+        let (other_start, other_end) = other.range.bounds();
+        start < other_end && other_start < end
+    }
+
+    fn conflicts(&self, other: &Lock<K>) -> bool {
+        self.key == other.key
+            && self.is_file() == other.is_file()
+            && !self.same_owner(other)
+            && self.overlaps(other)
+            && (self.kind == LockKind::Exclusive || other.kind == LockKind::Exclusive)
+    }
+
+    fn info(&self, waiting: bool) -> LockInfo {
+        LockInfo {
+            path: self.path.clone(),
+            session: self.owner.session,
+            kind: self.kind,
+            range: self.range,
+            waiting,
+        }
+    }
+}
+
+
+/// 锁表，记录持有的锁和排队等待的请求
+///
+/// 等待不会真的阻塞调用者：请求留在队列里，冲突的锁释放之后自动变成持有的锁。
+/// 锁按 key 区分文件，默认是路径；文件改名之后仍然有效的实现可以用 inum 之类的标识，
+/// path 只用来显示
+pub struct LockManager<K = Path> {
+    held: Vec<Lock<K>>,
+    waiting: Vec<Lock<K>>,  // 按请求的先后排列
+}
+
+impl<K: Clone + Eq> LockManager<K> {
+    pub fn new() -> Self {
+        LockManager {
+            held: Vec::new(),
+            waiting: Vec::new(),
+        }
+    }
+
+    /// 给 key 对应的文件加锁，同一个持有者已经持有的锁会被转换或者拆开
+    pub fn lock(&mut self, key: &K, path: &Path, owner: LockOwner, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, LockError> {
+        let lock = Lock { key: key.clone(), path: path.clone(), owner, kind, range };
+        if !self.held.iter().any(|held| held.conflicts(&lock)) {
+            self.grant(lock);
+            return Ok(LockStatus::Acquired);
+        }
+
+        match wait {
+            LockWait::NonBlocking => Err(LockError::WouldBlock),
+            LockWait::DetectDeadlock if self.would_deadlock(&lock) => Err(LockError::Deadlock),
+            LockWait::Blocking | LockWait::DetectDeadlock => {
+                self.waiting.push(lock);
+                Ok(LockStatus::Waiting)
+            }
+        }
+    }
+
+    /// 解锁，同时取消这个持有者在这个范围上还在等待的请求
+    pub fn unlock(&mut self, key: &K, owner: LockOwner, range: LockRange) {
+        let lock = Lock { key: key.clone(), path: Path::root(), owner, kind: LockKind::Shared, range };
+        self.waiting.retain(|waiting| !(waiting.key == lock.key
+            && waiting.is_file() == lock.is_file()
+            && waiting.same_owner(&lock)
+            && waiting.overlaps(&lock)));
+        self.remove(&lock);
+        self.wake();
+    }
+
+    /// 关闭文件时释放它的 flock 锁，以及会话在这个文件上的所有字节范围锁
+    pub fn release_file(&mut self, key: &K, owner: LockOwner) {
+        let released = |lock: &Lock<K>| lock.key == *key && if lock.is_file() {
+            lock.owner == owner
+        } else {
+            lock.owner.session == owner.session
+        };
+        self.held.retain(|lock| !released(lock));
+        self.waiting.retain(|lock| !released(lock));
+        self.wake();
+    }
+
+    /// 结束会话时释放它的所有锁
+    pub fn release_session(&mut self, session: usize) {
+        self.held.retain(|lock| lock.owner.session != session);
+        self.waiting.retain(|lock| lock.owner.session != session);
+        self.wake();
+    }
+
+    /// 所有持有的锁，后面是所有等待中的请求
+    pub fn locks(&self) -> Vec<LockInfo> {
+        self.held.iter().map(|lock| lock.info(false))
+            .chain(self.waiting.iter().map(|lock| lock.info(true)))
+            .collect()
+    }
+
+    /// 去掉同一个持有者在 lock 范围内已有的锁，字节范围锁只去掉重叠的部分
+    fn remove(&mut self, lock: &Lock<K>) {
+        let mut kept = Vec::new();
+        for held in self.held.drain(..) {
+            if held.key != lock.key || held.is_file() != lock.is_file() || !held.same_owner(lock) || !held.overlaps(lock) {
+                kept.push(held);
+                continue;
+            }
+            if held.is_file() {
+                continue;
+            }
+
+            let (start, end) = held.range.bounds();
+            let (lock_start, lock_end) = lock.range.bounds();
+            if start < lock_start {
+                kept.push(Lock { range: LockRange::from_bounds(start, lock_start), ..held.clone() });
+            }
+            if lock_end < end {
+                kept.push(Lock { range: LockRange::from_bounds(lock_end, end), ..held });
+            }
+        }
+        self.held = kept;
+    }
+
+    fn grant(&mut self, lock: Lock<K>) {
+        self.remove(&lock);
+        self.held.push(lock);
+    }
+
+    /// 把已经没有冲突的等待请求变成持有的锁
+    fn wake(&mut self) {
+        while let Some(index) = self.waiting.iter()
+            .position(|waiting| !self.held.iter().any(|held| held.conflicts(waiting))) {
+            let lock = self.waiting.remove(index);
+            self.grant(lock);
+        }
+    }
+
+    /// lock 的会话等待下去会不会形成环：沿着“等待的会话 -> 持有冲突锁的会话”走，看能不能回到自己
+    fn would_deadlock(&self, lock: &Lock<K>) -> bool {
+        let blockers = |lock: &Lock<K>| self.held.iter()
+            .filter(|held| held.conflicts(lock))
+            .map(|held| held.owner.session)
+            .collect::<Vec<_>>();
+
+        let mut visited = HashSet::new();
+        let mut stack = blockers(lock);
+        while let Some(session) = stack.pop() {
+            if session == lock.owner.session {
+                return true;
+            }
+            if !visited.insert(session) {
+                continue;
+            }
+            for waiting in self.waiting.iter().filter(|waiting| waiting.owner.session == session) {
+                stack.extend(blockers(waiting));
+            }
+        }
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn owner(session: usize) -> LockOwner {
+        LockOwner { session, file: 0 }
+    }
+
+    fn bytes(start: usize, len: usize) -> LockRange {
+        LockRange::Bytes { start, len }
+    }
+
+    #[test]
+    fn test_lock_manager() {
+        let path = Path::from_str("/a").unwrap();
+        let mut locks = LockManager::new();
+
+        // 同一个会话的锁被拆开：[0, 100) 解开 [40, 60) 之后剩下两段
+        locks.lock(&path, &path, owner(1), LockKind::Exclusive, bytes(0, 100), LockWait::NonBlocking).unwrap();
+        locks.unlock(&path, owner(1), bytes(40, 20));
+        let ranges = locks.locks().iter().map(|info| info.range).collect::<Vec<_>>();
+        assert_eq!(ranges, vec![bytes(0, 40), bytes(60, 40)]);
+
+        // 解开的部分别的会话可以锁，重叠的部分不行
+        locks.lock(&path, &path, owner(2), LockKind::Exclusive, bytes(40, 20), LockWait::NonBlocking).unwrap();
+        assert!(matches!(
+            locks.lock(&path, &path, owner(2), LockKind::Shared, bytes(30, 20), LockWait::NonBlocking),
+            Err(LockError::WouldBlock)
+        ));
+
+        // flock 锁和字节范围锁互不影响
+        locks.lock(&path, &path, owner(2), LockKind::Exclusive, LockRange::File, LockWait::NonBlocking).unwrap();
+
+        // 1 等 2，2 再等 1 就会死锁
+        assert_eq!(locks.lock(&path, &path, owner(1), LockKind::Shared, bytes(40, 1), LockWait::DetectDeadlock).unwrap(), LockStatus::Waiting);
+        assert!(matches!(
+            locks.lock(&path, &path, owner(2), LockKind::Shared, bytes(0, 1), LockWait::DetectDeadlock),
+            Err(LockError::Deadlock)
+        ));
+
+        // 2 结束之后 1 的等待请求自动获得
+        locks.release_session(2);
+        assert!(locks.locks().iter().all(|info| info.session == 1 && !info.waiting));
+        assert_eq!(locks.locks().len(), 3);
+
+        locks.release_file(&path, owner(1));
+        assert!(locks.locks().is_empty());
+    }
+}
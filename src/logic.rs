@@ -1,3 +1,4 @@
+use std::cmp::{max, min};
 use std::ops::{Deref, DerefMut, Range};
 
 use serde::{Deserialize, Serialize};
@@ -41,8 +42,8 @@ impl DerefMut for DirectoryData {
 
 /// 获取 bitmap 状态，true 表示已经被占用，false 表示空闲
 pub fn get_state(bitmap_blocks: &[BitmapBlock], index: usize) -> bool {
-    let block_index = index / (ALIGN_SIZE * 32);
-    let u32_index = index % (ALIGN_SIZE * 32) / 32;
+    let block_index = index / BITMAP_BLOCK_BITS;
+    let u32_index = index % BITMAP_BLOCK_BITS / 32;
     let bit_index = index % 32;
 
     let block = &bitmap_blocks[block_index];
@@ -52,8 +53,8 @@ pub fn get_state(bitmap_blocks: &[BitmapBlock], index: usize) -> bool {
 
 /// 获取一个块的状态，在一级间接索引块使用
 pub fn get_block_state(bitmap_blocks: &[BitmapBlock], index: usize) -> u32 {
-    let block_index = index / ALIGN_SIZE;
-    let u32_index = index % ALIGN_SIZE;
+    let block_index = index / (BITMAP_BLOCK_BITS / 32);
+    let u32_index = index % (BITMAP_BLOCK_BITS / 32);
 
     let block = &bitmap_blocks[block_index];
     let u32 = &block.bitmaps[u32_index];
@@ -63,8 +64,8 @@ pub fn get_block_state(bitmap_blocks: &[BitmapBlock], index: usize) -> u32 {
 
 /// 设置 bitmap 状态，true 表示已经被占用，false 表示空闲
 pub fn set_state(bitmap_blocks: &mut [BitmapBlock], index: usize, state: bool) {
-    let block_index = index / (ALIGN_SIZE * 32);
-    let u32_index = index % (ALIGN_SIZE * 32) / 32;
+    let block_index = index / BITMAP_BLOCK_BITS;
+    let u32_index = index % BITMAP_BLOCK_BITS / 32;
     let bit_index = index % 32;
 
     let block = &mut bitmap_blocks[block_index];
@@ -78,8 +79,8 @@ pub fn set_state(bitmap_blocks: &mut [BitmapBlock], index: usize, state: bool) {
 
 /// 设置一个块的状态，在一级间接索引块使用
 pub unsafe fn set_block_state(bitmap_blocks: &mut [BitmapBlock], index: usize, state: bool) {
-    let block_index = index / ALIGN_SIZE;
-    let u32_index = index % ALIGN_SIZE;
+    let block_index = index / (BITMAP_BLOCK_BITS / 32);
+    let u32_index = index % (BITMAP_BLOCK_BITS / 32);
 
     let block = &mut bitmap_blocks[block_index];
     let u32 = &mut block.bitmaps[u32_index];
@@ -92,33 +93,82 @@ pub unsafe fn set_block_state(bitmap_blocks: &mut [BitmapBlock], index: usize, s
 }
 
 /// 获得 bitmap 中的空闲项
-pub fn get_free_item(bitmap_blocks: &[BitmapBlock], range: Range<usize>) -> Option<usize> {
+pub fn get_free_item(bitmap_blocks: &[BitmapBlock], mut range: Range<usize>) -> Option<usize> {
+    range.find(|&i| !get_state(bitmap_blocks, i))
+}
+
+/// 获得连续 count 个空闲项的起始位置
+pub fn get_free_run(bitmap_blocks: &[BitmapBlock], range: Range<usize>, count: usize) -> Option<usize> {
+    let mut start = range.start;
+    let mut len = 0;
     for i in range {
-        if !get_state(bitmap_blocks, i) {
-            return Some(i);
+        if get_state(bitmap_blocks, i) {
+            start = i + 1;
+            len = 0;
+        } else {
+            len += 1;
+        }
+        if len >= count {
+            return Some(start);
         }
     }
     None
 }
 
 /// 获得一个空闲块，32 个项
-pub fn get_free_block(bitmap_blocks: &[BitmapBlock], range: Range<usize>) -> Option<usize> {
-    for i in range {
-        if get_block_state(bitmap_blocks, i) == 0 {
-            return Some(i);
-        }
-    }
-    None
+pub fn get_free_block(bitmap_blocks: &[BitmapBlock], mut range: Range<usize>) -> Option<usize> {
+    range.find(|&i| get_block_state(bitmap_blocks, i) == 0)
+}
+
+/// 所有 inode 的范围，超出磁盘范围的项在位图中已经被标记为占用
+pub fn all_inode_range(index_bitmap_blocks: &[BitmapBlock]) -> Range<usize> {
+    0..index_bitmap_blocks.len() * BITMAP_BLOCK_BITS
+}
+
+/// 所有的索引块范围，超出磁盘范围的块在位图中已经被标记为占用
+pub fn all_index_block_range(index_bitmap_blocks: &[BitmapBlock]) -> Range<usize> {
+    0..index_bitmap_blocks.len() * BITMAP_BLOCK_BITS / 32
+}
+
+/// 所有数据块的范围，超出磁盘范围的项在位图中已经被标记为占用
+pub fn all_data_block_range(data_bitmap_blocks: &[BitmapBlock]) -> Range<usize> {
+    0..data_bitmap_blocks.len() * BITMAP_BLOCK_BITS
+}
+
+/// 统计 range 中空闲的项数
+pub fn count_free_items(bitmap_blocks: &[BitmapBlock], range: Range<usize>) -> usize {
+    range.filter(|index| !get_state(bitmap_blocks, *index)).count()
+}
+
+/// 获取数据块的额外引用数，0 表示只有一个引用
+pub fn get_ref(ref_blocks: &[RefBlock], dnum: usize) -> u32 {
+    ref_blocks[dnum / REF_BLOCK_ITEMS].refs[dnum % REF_BLOCK_ITEMS]
+}
+
+/// 设置数据块的额外引用数
+pub fn set_ref(ref_blocks: &mut [RefBlock], dnum: usize, refs: u32) {
+    ref_blocks[dnum / REF_BLOCK_ITEMS].refs[dnum % REF_BLOCK_ITEMS] = refs;
 }
 
-/// 所有索引块的范围
-pub const ALL_INODE_RANGE: Range<usize> = 0..INDEX_BLOCK_COUNT * 32;
+/// 数据块是否被多处引用
+pub fn is_shared(ref_blocks: &[RefBlock], dnum: usize) -> bool {
+    get_ref(ref_blocks, dnum) != 0
+}
 
-/// 所有的索引块范围
-pub const ALL_INDEX_BLOCK_RANGE: Range<usize> = 0..INDEX_BLOCK_COUNT;
+/// 增加一个数据块的引用
+pub fn share_block(ref_blocks: &mut [RefBlock], dnum: usize) {
+    ref_blocks[dnum / REF_BLOCK_ITEMS].refs[dnum % REF_BLOCK_ITEMS] += 1;
+}
 
-/// 所有数据块的范围
-pub const ALL_DATA_BLOCK_RANGE: Range<usize> = 0..DATA_BLOCK_COUNT;
+/// 减少一个数据块的引用，最后一个引用释放时把数据块标记为空闲
+pub fn release_block(data_bitmap_blocks: &mut [BitmapBlock], ref_blocks: &mut [RefBlock], dnum: usize) {
+    let refs = &mut ref_blocks[dnum / REF_BLOCK_ITEMS].refs[dnum % REF_BLOCK_ITEMS];
+    if *refs > 0 {
+        *refs -= 1;
+    } else {
+        set_state(data_bitmap_blocks, dnum, false);
+    }
+}
 
 
 /// 获取 inode
@@ -221,14 +271,14 @@ pub fn extend_data_block_of_inode(
         let inode = unsafe { get_inode_mut(index_blocks, inum) };
         // 直接
         for i in inode.block_count as usize..count {
-            let dnum = get_free_item(data_bitmap_blocks, ALL_DATA_BLOCK_RANGE).unwrap();
+            let dnum = get_free_item(data_bitmap_blocks, all_data_block_range(data_bitmap_blocks)).unwrap();
             set_state(data_bitmap_blocks, dnum, true);
             inode.block_direct[i] = dnum as u32;
         }
         inode.block_count = count as u32;
     } else {
 
-        let inode = unsafe { get_inode(index_blocks, inum) }.clone();
+        let inode = *unsafe { get_inode(index_blocks, inum) };
         if inode.block_count < DIRECT_BLOCK_COUNT as u32 {
             // 直接的扩充到 DIRECT_BLOCK_COUNT
             extend_data_block_of_inode(
@@ -239,17 +289,17 @@ pub fn extend_data_block_of_inode(
             );
 
             // 申请一个间接块
-            let block_id = get_free_block(index_bitmap_blocks, ALL_INDEX_BLOCK_RANGE).unwrap();
+            let block_id = get_free_block(index_bitmap_blocks, all_index_block_range(index_bitmap_blocks)).unwrap();
             unsafe { set_block_state(index_bitmap_blocks, block_id, true); }
 
             // 设置间接块
             let inode = unsafe { get_inode_mut(index_blocks, inum) };
-            inode.block_indirect = block_id as u32 * 32;
+            inode.block_indirect = block_id as u32;
         }
 
-        let inode = unsafe { get_inode(index_blocks, inum) }.clone();
+        let inode = *unsafe { get_inode(index_blocks, inum) };
         for i in inode.block_count as usize..count {
-            let dnum = get_free_item(data_bitmap_blocks, ALL_DATA_BLOCK_RANGE).unwrap();
+            let dnum = get_free_item(data_bitmap_blocks, all_data_block_range(data_bitmap_blocks)).unwrap();
             let target_dnum = unsafe {
                 get_indirect_dnum_mut(index_blocks, inode.block_indirect as usize, i - DIRECT_BLOCK_COUNT)
             };
@@ -258,7 +308,6 @@ pub fn extend_data_block_of_inode(
         }
         let inode = unsafe { get_inode_mut(index_blocks, inum) };
         inode.block_count = count as u32;
-        return;
     }
 }
 
@@ -266,19 +315,20 @@ pub fn extend_data_block_of_inode(
 pub fn shrink_data_block_of_inode(
     index_bitmap_blocks: &mut [BitmapBlock],
     data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &mut [RefBlock],
     index_blocks: &mut [IBlock],
     inum: usize,
     count: usize
 ) {
-    let inode = unsafe { get_inode(index_blocks, inum) }.clone();
+    let inode = *unsafe { get_inode(index_blocks, inum) };
     if inode.block_count as usize <= count {
         return;
     }
 
-    // 先释放掉数据块
+    // 先释放掉数据块，共享的块只减少引用
     for i in count..inode.block_count as usize {
         let dnum = get_dnum_mut(index_blocks, inum, i);
-        set_state(data_bitmap_blocks, *dnum as usize, false);
+        release_block(data_bitmap_blocks, ref_blocks, *dnum as usize);
         *dnum = 0;
     }
 
@@ -311,31 +361,19 @@ pub fn shrink_data_block_of_inode(
 pub fn resize_data_block_of_inode(
     index_bitmap_blocks: &mut [BitmapBlock],
     data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &mut [RefBlock],
     index_blocks: &mut [IBlock],
     inum: usize,
     count: usize
 ) {
-    let inode = unsafe { get_inode(index_blocks, inum) }.clone();
+    let inode = *unsafe { get_inode(index_blocks, inum) };
     if inode.block_count < count as u32 {
         extend_data_block_of_inode(index_bitmap_blocks, data_bitmap_blocks, index_blocks, inum, count);
     } else if inode.block_count > count as u32 {
-        shrink_data_block_of_inode(index_bitmap_blocks, data_bitmap_blocks, index_blocks, inum, count);
+        shrink_data_block_of_inode(index_bitmap_blocks, data_bitmap_blocks, ref_blocks, index_blocks, inum, count);
     }
 }
 
-/// 获取数据块索引，如果不够则扩充
-pub fn get_or_extend_dnum<'a>(
-    index_bitmap_blocks: &mut [BitmapBlock],
-    data_bitmap_blocks: &mut [BitmapBlock],
-    index_blocks: &'a mut [IBlock],
-    inum: usize,
-    index: usize
-) -> &'a mut u32 {
-    // 扩充
-    extend_data_block_of_inode(index_bitmap_blocks, data_bitmap_blocks, index_blocks, inum, index + 1);
-    get_dnum_mut(index_blocks, inum, index)
-}
-
 
 /// 将 pos 转化为 (dnum, offset)
 pub fn transform_pos(index_blocks: &[IBlock], inum: usize, pos: usize) -> (usize, usize) {
@@ -368,18 +406,19 @@ pub fn get_size_of_data_block(inode: &INode, index: usize) -> usize {
 pub fn free_inode(
     index_bitmap_blocks: &mut[BitmapBlock],
     block_bitmap_blocks: &mut[BitmapBlock],
+    ref_blocks: &mut [RefBlock],
     index_blocks: &mut [IBlock],
     inum: usize
 ) {
-    let inode = unsafe { get_inode(index_blocks, inum) }.clone();
+    let inode = *unsafe { get_inode(index_blocks, inum) };
 
     for i in 0..inode.block_count {
         let dnum = get_dnum(index_blocks, inum, i as usize);
-        set_state(block_bitmap_blocks, *dnum as usize, false);
+        release_block(block_bitmap_blocks, ref_blocks, *dnum as usize);
     }
 
     if inode.block_indirect != 0 {
-        set_state(index_bitmap_blocks, inode.block_indirect as usize, false);
+        unsafe { set_block_state(index_bitmap_blocks, inode.block_indirect as usize, false); }
     }
 
     let inode = unsafe { get_inode_mut(index_blocks, inum) };
@@ -388,6 +427,84 @@ pub fn free_inode(
     set_state(index_bitmap_blocks, inum, false);
 }
 
+/// 获取 inode 的所有数据块编号
+pub fn get_dnums(index_blocks: &[IBlock], inum: usize) -> Vec<usize> {
+    let inode = unsafe { get_inode(index_blocks, inum) };
+    (0..inode.block_count as usize)
+        .map(|i| *get_dnum(index_blocks, inum, i) as usize)
+        .collect()
+}
+
+/// 统计数据块分成了几段连续的区间
+pub fn count_extents(dnums: &[usize]) -> usize {
+    if dnums.is_empty() {
+        return 0;
+    }
+    1 + dnums.windows(2)
+        .filter(|pair| pair[1] != pair[0] + 1)
+        .count()
+}
+
+/// 把 inode 的第 index 个数据块搬到 new_dnum，new_dnum 必须是空闲的，旧的块不能是共享的
+pub fn move_data_block(
+    data_bitmap_blocks: &mut [BitmapBlock],
+    index_blocks: &mut [IBlock],
+    data_blocks: &mut [DataBlock],
+    inum: usize,
+    index: usize,
+    new_dnum: usize
+) {
+    let dnum = get_dnum_mut(index_blocks, inum, index);
+    let old_dnum = *dnum as usize;
+    if old_dnum == new_dnum {
+        return;
+    }
+
+    // 先复制数据，再修改索引，最后释放旧的块
+    data_blocks[new_dnum].data = data_blocks[old_dnum].data;
+    set_state(data_bitmap_blocks, new_dnum, true);
+    *dnum = new_dnum as u32;
+    set_state(data_bitmap_blocks, old_dnum, false);
+}
+
+/// 整理一个 inode 的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Defrag {
+    Moved,          // 数据块搬到了连续的区域
+    Contiguous,     // 本来就是连续的，不需要搬动
+    Shared,         // 和快照或者克隆的文件共享数据块，没有搬动
+    NoSpace,        // 没有足够长的连续空闲区域，没有搬动
+}
+
+/// 把 inode 的数据块搬到一段连续的空闲区域
+///
+/// 和其他 inode 共享数据块的文件不会被搬动，搬动共享的块要改写所有引用它的 inode 和快照
+pub fn defrag_inode(
+    data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &[RefBlock],
+    index_blocks: &mut [IBlock],
+    data_blocks: &mut [DataBlock],
+    inum: usize
+) -> Defrag {
+    let dnums = get_dnums(index_blocks, inum);
+    if count_extents(&dnums) <= 1 {
+        return Defrag::Contiguous;
+    }
+    if dnums.iter().any(|dnum| is_shared(ref_blocks, *dnum)) {
+        return Defrag::Shared;
+    }
+
+    let start = match get_free_run(data_bitmap_blocks, all_data_block_range(data_bitmap_blocks), dnums.len()) {
+        Some(start) => start,
+        None => return Defrag::NoSpace,
+    };
+
+    for index in 0..dnums.len() {
+        move_data_block(data_bitmap_blocks, index_blocks, data_blocks, inum, index, start + index);
+    }
+    Defrag::Moved
+}
+
 
 /// 读取数据
 pub fn read_data(
@@ -398,6 +515,14 @@ pub fn read_data(
     buf: &mut [u8]
 ) {
     let inode = unsafe { get_inode(index_blocks, inum) };
+    if inode.is_inline() {
+        if start_pos + buf.len() > inode.size as usize {
+            panic!("pos out of range");
+        }
+        buf.copy_from_slice(&inode.inline_data()[start_pos..start_pos + buf.len()]);
+        return;
+    }
+
     let mut readed = 0;
     while readed < buf.len() {
         let (dnum, offset) = transform_pos(index_blocks, inum, start_pos + readed);
@@ -423,11 +548,17 @@ pub fn write_data(
     buf: &[u8]
 ) {
     let inode = unsafe { get_inode(index_blocks, inum) };
+    if inode.is_inline() {
+        if start_pos + buf.len() > inode.size as usize {
+            panic!("pos out of range");
+        }
+        let inode = unsafe { get_inode_mut(index_blocks, inum) };
+        inode.inline_data_mut()[start_pos..start_pos + buf.len()].copy_from_slice(buf);
+        return;
+    }
 
     let mut written = 0;
-    let mut cnt = 0;
     while written < buf.len() {
-        cnt = cnt + 1;
         let (dnum, offset) = transform_pos(index_blocks, inum, start_pos + written);
 
         let data = get_data_block_mut(data_blocks, dnum);
@@ -442,41 +573,144 @@ pub fn write_data(
     }
 }
 
-/// 写入数据，自动调整大小
-pub fn write_data_auto_resize(
+/// 统计 [start_pos, end_pos) 这段数据中有几个共享的数据块，写入前需要为它们各申请一个新块
+pub fn count_shared_blocks(
+    ref_blocks: &[RefBlock],
+    index_blocks: &[IBlock],
+    inum: usize,
+    start_pos: usize,
+    end_pos: usize
+) -> usize {
+    let inode = unsafe { get_inode(index_blocks, inum) };
+    let end = end_pos.div_ceil(4096).min(inode.block_count as usize);
+    (start_pos / 4096..end)
+        .filter(|index| is_shared(ref_blocks, *get_dnum(index_blocks, inum, *index) as usize))
+        .count()
+}
+
+/// 写时复制：把 [start_pos, end_pos) 涉及到的共享数据块复制一份，让这个 inode 独占
+pub fn unshare_data(
+    data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &mut [RefBlock],
+    index_blocks: &mut [IBlock],
+    data_blocks: &mut [DataBlock],
+    inum: usize,
+    start_pos: usize,
+    end_pos: usize
+) {
+    let inode = unsafe { get_inode(index_blocks, inum) };
+    let end = end_pos.div_ceil(4096).min(inode.block_count as usize);
+    for index in start_pos / 4096..end {
+        let old_dnum = *get_dnum(index_blocks, inum, index) as usize;
+        if !is_shared(ref_blocks, old_dnum) {
+            continue;
+        }
+
+        let new_dnum = get_free_item(data_bitmap_blocks, all_data_block_range(data_bitmap_blocks)).unwrap();
+        set_state(data_bitmap_blocks, new_dnum, true);
+        data_blocks[new_dnum].data = data_blocks[old_dnum].data;
+        *get_dnum_mut(index_blocks, inum, index) = new_dnum as u32;
+        release_block(data_bitmap_blocks, ref_blocks, old_dnum);
+    }
+}
+
+/// 把 bufs 依次写到 start_pos 处，写完之后大小为 new_size，new_size 不能小于写入的末尾
+///
+/// 多个缓冲区一起写时，大小和共享的块都只处理一次
+#[allow(clippy::too_many_arguments)]
+pub fn write_vectored_with_size(
     index_bitmap_blocks: &mut [BitmapBlock],
     data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &mut [RefBlock],
     index_blocks: &mut [IBlock],
     data_blocks: &mut [DataBlock],
     inum: usize,
     start_pos: usize,
-    buf: &[u8]
+    bufs: &[&[u8]],
+    new_size: usize
 ) {
-    let new_size = start_pos + buf.len();
+    let end_pos = start_pos + bufs.iter().map(|buf| buf.len()).sum::<usize>();
+    assert!(end_pos <= new_size);
     let inode = unsafe { get_inode(index_blocks, inum) };
     if new_size != inode.size as usize {
-        resize(index_bitmap_blocks, data_bitmap_blocks, index_blocks, inum, new_size);
+        resize_with_inline(index_bitmap_blocks, data_bitmap_blocks, ref_blocks, index_blocks, data_blocks, inum, new_size);
     }
 
-    write_data(data_blocks, index_blocks, inum, start_pos, buf);
+    unshare_data(data_bitmap_blocks, ref_blocks, index_blocks, data_blocks, inum, start_pos, end_pos);
+    let mut pos = start_pos;
+    for buf in bufs {
+        write_data(data_blocks, index_blocks, inum, pos, buf);
+        pos += buf.len();
+    }
 }
 
 /// 设置大小
 pub fn resize(
     index_bitmap_blocks: &mut [BitmapBlock],
     data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &mut [RefBlock],
     index_blocks: &mut [IBlock],
     inum: usize,
     new_size: usize
 ) {
-    let new_block_count = (new_size + 4095) / 4096;
+    let new_block_count = new_size.div_ceil(4096);
 
-    resize_data_block_of_inode(index_bitmap_blocks, data_bitmap_blocks, index_blocks, inum, new_block_count);
+    resize_data_block_of_inode(index_bitmap_blocks, data_bitmap_blocks, ref_blocks, index_blocks, inum, new_block_count);
 
     let inode = unsafe { get_inode_mut(index_blocks, inum) };
     inode.size = new_size as u32;
 }
 
+/// 大小为 size 的数据需要的数据块个数，放得下时内联存放，不需要数据块
+pub fn block_count_for_size(size: usize) -> usize {
+    if size <= INLINE_DATA_SIZE {
+        0
+    } else {
+        size.div_ceil(4096)
+    }
+}
+
+/// 设置大小，放得下的数据内联存放在 inode 中，变大或变小时在内联和数据块之间搬移已有数据
+#[allow(clippy::too_many_arguments)]
+pub fn resize_with_inline(
+    index_bitmap_blocks: &mut [BitmapBlock],
+    data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &mut [RefBlock],
+    index_blocks: &mut [IBlock],
+    data_blocks: &mut [DataBlock],
+    inum: usize,
+    new_size: usize
+) {
+    let inode = *unsafe { get_inode(index_blocks, inum) };
+    let to_inline = new_size <= INLINE_DATA_SIZE;
+    if !inode.is_inline() && !to_inline {
+        resize(index_bitmap_blocks, data_bitmap_blocks, ref_blocks, index_blocks, inum, new_size);
+        return;
+    }
+
+    // 保留两种大小中较小的那部分数据
+    let mut kept = vec![0u8; min(inode.size as usize, new_size)];
+    read_data(data_blocks, index_blocks, inum, 0, &mut kept);
+
+    if inode.is_inline() {
+        let inode = unsafe { get_inode_mut(index_blocks, inum) };
+        inode.block_direct = [0; DIRECT_BLOCK_COUNT];
+        inode.flags &= !INODE_INLINE;
+        inode.size = 0;
+    } else {
+        resize(index_bitmap_blocks, data_bitmap_blocks, ref_blocks, index_blocks, inum, 0);
+    }
+
+    if to_inline {
+        let inode = unsafe { get_inode_mut(index_blocks, inum) };
+        inode.flags |= INODE_INLINE;
+        inode.size = new_size as u32;
+    } else {
+        resize(index_bitmap_blocks, data_bitmap_blocks, ref_blocks, index_blocks, inum, new_size);
+    }
+    write_data(data_blocks, index_blocks, inum, 0, &kept);
+}
+
 /// 读取数据结构
 pub fn read_data_struct<T: for<'a> Deserialize<'a>>(
     data_blocks: &[DataBlock],
@@ -493,26 +727,12 @@ pub fn read_data_struct<T: for<'a> Deserialize<'a>>(
     serde_json::from_slice(&buf).unwrap()
 }
 
-/// 写入数据结构
-pub fn write_data_struct<T: Serialize>(
-    data_blocks: &mut [DataBlock],
-    index_blocks: &mut [IBlock],
-    inum: usize,
-    start_pos: usize,
-    data: &T
-) -> usize {
-    let buf = serde_json::to_vec(data).unwrap();
-    let len = buf.len() as u32;
-    write_data(data_blocks, index_blocks, inum, start_pos, &len.to_le_bytes());
-    write_data(data_blocks, index_blocks, inum, start_pos + 4, &buf);
-
-    4 + buf.len()
-}
-
 /// 读取数据结构，自动调整大小
+#[allow(clippy::too_many_arguments)]
 pub fn write_data_struct_auto_resize<T: Serialize>(
     index_bitmap_blocks: &mut [BitmapBlock],
     data_bitmap_blocks: &mut [BitmapBlock],
+    ref_blocks: &mut [RefBlock],
     index_blocks: &mut [IBlock],
     data_blocks: &mut [DataBlock],
     inum: usize,
@@ -525,15 +745,359 @@ pub fn write_data_struct_auto_resize<T: Serialize>(
     let new_size = start_pos + 4 + buf.len();
     let inode = unsafe { get_inode(index_blocks, inum) };
     if new_size != inode.size as usize {
-        resize(index_bitmap_blocks, data_bitmap_blocks, index_blocks, inum, new_size);
+        resize_with_inline(index_bitmap_blocks, data_bitmap_blocks, ref_blocks, index_blocks, data_blocks, inum, new_size);
     }
 
+    unshare_data(data_bitmap_blocks, ref_blocks, index_blocks, data_blocks, inum, start_pos, new_size);
     write_data(data_blocks, index_blocks, inum, start_pos, &len.to_le_bytes());
     write_data(data_blocks, index_blocks, inum, start_pos + 4, &buf);
 
     4 + buf.len()
 }
 
+/// 压缩的单位，随机读取时只需要解压涉及到的簇
+pub const COMPRESSION_CLUSTER_SIZE: usize = 4096 * 4;
+
+/// 簇表至少预留的空间，簇表变长时可以原地改写
+const CLUSTER_TABLE_MIN_SPACE: usize = 256;
+
+/// 压缩文件开头的簇表
+///
+/// 文件数据依次是：簇表占用的空间、簇表（后面用空格补齐）、簇数据。
+/// 改写一个簇时新数据放得下就写回原处，否则放到簇数据的末尾，原来的位置留空
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ClusterTable {
+    pub size: u32,              // 解压之后的大小
+    pub clusters: Vec<u32>,     // 每个簇存储的字节数，等于簇大小时表示没有压缩
+    #[serde(default)]
+    pub offsets: Vec<u32>,      // 每个簇在簇数据中的位置，为空时簇依次紧挨着存放
+}
+
+impl ClusterTable {
+    /// 第 index 个簇解压之后的大小
+    fn raw_len(&self, index: usize) -> usize {
+        min(COMPRESSION_CLUSTER_SIZE, self.size as usize - index * COMPRESSION_CLUSTER_SIZE)
+    }
+
+    /// 第 index 个簇在簇数据中的位置
+    fn offset(&self, index: usize) -> usize {
+        match self.offsets.get(index) {
+            Some(offset) => *offset as usize,
+            None => self.clusters[..index].iter().map(|len| *len as usize).sum(),
+        }
+    }
+
+    /// 每个簇原地改写时最多能放下的字节数，也就是到下一个簇或者簇数据末尾的距离
+    fn capacities(&self, end: usize) -> Vec<usize> {
+        let mut order = (0..self.clusters.len()).collect::<Vec<_>>();
+        order.sort_by_key(|index| self.offset(*index));
+
+        let mut capacities = vec![0; self.clusters.len()];
+        for (i, index) in order.iter().enumerate() {
+            let next = order.get(i + 1).map_or(end, |next| self.offset(*next));
+            capacities[*index] = next - self.offset(*index);
+        }
+        capacities
+    }
+}
+
+/// 压缩文件的簇表或者簇数据已经损坏，无法解压
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct CorruptData;
+
+/// 读取压缩文件的簇表，返回簇表和簇数据的开始位置
+///
+/// 同时检查簇表和存储的大小是否对得上，之后按簇表读取簇数据时不会越界
+pub fn read_cluster_table(data_blocks: &[DataBlock], index_blocks: &[IBlock], inum: usize) -> Result<(ClusterTable, usize), CorruptData> {
+    let stored_size = unsafe { get_inode(index_blocks, inum) }.size as usize;
+    if stored_size == 0 {
+        return Ok((ClusterTable::default(), 0));
+    }
+    if stored_size < 4 {
+        return Err(CorruptData);
+    }
+
+    let mut len = [0u8; 4];
+    read_data(data_blocks, index_blocks, inum, 0, &mut len);
+    let data_start = 4 + u32::from_le_bytes(len) as usize;
+    if data_start > stored_size {
+        return Err(CorruptData);
+    }
+
+    // 簇表后面补齐的空格在解析时被忽略
+    let mut header = vec![0u8; data_start - 4];
+    read_data(data_blocks, index_blocks, inum, 4, &mut header);
+    let table: ClusterTable = serde_json::from_slice(&header).map_err(|_| CorruptData)?;
+
+    let count = (table.size as usize).div_ceil(COMPRESSION_CLUSTER_SIZE);
+    if table.clusters.len() != count || !(table.offsets.is_empty() || table.offsets.len() == count) {
+        return Err(CorruptData);
+    }
+    for (index, len) in table.clusters.iter().enumerate() {
+        let len = *len as usize;
+        if len > table.raw_len(index) || data_start + table.offset(index) + len > stored_size {
+            return Err(CorruptData);
+        }
+    }
+
+    Ok((table, data_start))
+}
+
+/// 读出第 index 个簇并解压
+fn read_cluster(
+    data_blocks: &[DataBlock],
+    index_blocks: &[IBlock],
+    inum: usize,
+    table: &ClusterTable,
+    data_start: usize,
+    index: usize
+) -> Result<Vec<u8>, CorruptData> {
+    let mut stored = vec![0u8; table.clusters[index] as usize];
+    read_data(data_blocks, index_blocks, inum, data_start + table.offset(index), &mut stored);
+
+    let raw_len = table.raw_len(index);
+    if stored.len() == raw_len {
+        return Ok(stored);
+    }
+    match lz4_flex::block::decompress(&stored, raw_len) {
+        Ok(raw) if raw.len() == raw_len => Ok(raw),
+        _ => Err(CorruptData),
+    }
+}
+
+/// 读取压缩文件，只解压 buf 涉及到的簇
+pub fn read_compressed_data(
+    data_blocks: &[DataBlock],
+    index_blocks: &[IBlock],
+    inum: usize,
+    start_pos: usize,
+    buf: &mut [u8]
+) -> Result<(), CorruptData> {
+    if buf.is_empty() {
+        return Ok(());
+    }
+
+    let (table, data_start) = read_cluster_table(data_blocks, index_blocks, inum)?;
+    if start_pos + buf.len() > table.size as usize {
+        panic!("pos out of range");
+    }
+
+    let first = start_pos / COMPRESSION_CLUSTER_SIZE;
+    let last = (start_pos + buf.len() - 1) / COMPRESSION_CLUSTER_SIZE;
+    for index in first..=last {
+        let raw = read_cluster(data_blocks, index_blocks, inum, &table, data_start, index)?;
+        let cluster_start = index * COMPRESSION_CLUSTER_SIZE;
+
+        let from = max(start_pos, cluster_start);
+        let to = min(start_pos + buf.len(), cluster_start + raw.len());
+        buf[from - start_pos..to - start_pos].copy_from_slice(&raw[from - cluster_start..to - cluster_start]);
+    }
+    Ok(())
+}
+
+/// 压缩一个簇，压缩之后没有变小时直接存原始数据
+fn compress_cluster(raw: Vec<u8>) -> Vec<u8> {
+    let compressed = lz4_flex::block::compress(&raw);
+    if compressed.len() < raw.len() { compressed } else { raw }
+}
+
+/// 把 buf 按簇压缩，得到压缩文件的数据
+pub fn compress_data(buf: &[u8]) -> Vec<u8> {
+    let mut table = ClusterTable {
+        size: buf.len() as u32,
+        ..Default::default()
+    };
+
+    let mut stored = Vec::new();
+    for raw in buf.chunks(COMPRESSION_CLUSTER_SIZE) {
+        let data = compress_cluster(raw.to_vec());
+        table.clusters.push(data.len() as u32);
+        stored.extend_from_slice(&data);
+    }
+
+    encode_compressed(&mut table, &stored)
+}
+
+/// 簇表的编码，用空格补齐到 space 字节，放不下时返回 None
+fn encode_cluster_table(table: &ClusterTable, space: usize) -> Option<Vec<u8>> {
+    let mut header = serde_json::to_vec(table).unwrap();
+    if header.len() > space {
+        return None;
+    }
+    header.resize(space, b' ');
+
+    let mut result = Vec::with_capacity(4 + space);
+    result.extend_from_slice(&(space as u32).to_le_bytes());
+    result.extend_from_slice(&header);
+    Some(result)
+}
+
+/// 拼接簇表和紧挨着存放的簇数据，簇表预留出变长的空间
+fn encode_compressed(table: &mut ClusterTable, stored: &[u8]) -> Vec<u8> {
+    table.offsets = table.clusters.iter()
+        .scan(0, |offset, len| {
+            let start = *offset;
+            *offset += len;
+            Some(start)
+        })
+        .collect();
+
+    let len = serde_json::to_vec(table).unwrap().len();
+    let mut result = encode_cluster_table(table, max(len * 2, CLUSTER_TABLE_MIN_SPACE)).unwrap();
+    result.extend_from_slice(stored);
+    result
+}
+
+/// 把 buf 写到压缩文件的 start_pos 处，写过末尾时文件变大，不会截断后面的数据
+///
+/// 返回新的文件数据，没有被写到的簇直接复用原来压缩好的数据
+fn build_compressed_data(
+    data_blocks: &[DataBlock],
+    index_blocks: &[IBlock],
+    inum: usize,
+    start_pos: usize,
+    buf: &[u8]
+) -> Result<Vec<u8>, CorruptData> {
+    let (old, data_start) = read_cluster_table(data_blocks, index_blocks, inum)?;
+    let end_pos = start_pos + buf.len();
+    let mut table = ClusterTable {
+        size: max(old.size as usize, end_pos) as u32,
+        ..Default::default()
+    };
+
+    let mut stored = Vec::new();
+    for index in 0..(table.size as usize).div_ceil(COMPRESSION_CLUSTER_SIZE) {
+        let data = if cluster_changed(&old, &table, index, start_pos, end_pos) {
+            let raw = patch_cluster(data_blocks, index_blocks, inum, &old, data_start, &table, index, start_pos, buf)?;
+            compress_cluster(raw)
+        } else {
+            let mut data = vec![0u8; old.clusters[index] as usize];
+            read_data(data_blocks, index_blocks, inum, data_start + old.offset(index), &mut data);
+            data
+        };
+
+        table.clusters.push(data.len() as u32);
+        stored.extend_from_slice(&data);
+    }
+
+    Ok(encode_compressed(&mut table, &stored))
+}
+
+/// 第 index 个簇是否需要重新压缩：被写到了，或者因为文件变大而变长、新出现
+fn cluster_changed(old: &ClusterTable, new: &ClusterTable, index: usize, start_pos: usize, end_pos: usize) -> bool {
+    let cluster_start = index * COMPRESSION_CLUSTER_SIZE;
+    let raw_len = new.raw_len(index);
+    index >= old.clusters.len()
+        || old.raw_len(index) != raw_len
+        || (cluster_start < end_pos && start_pos < cluster_start + raw_len)
+}
+
+/// 第 index 个簇写入之后的原始数据：先解压原来的内容，再覆盖写入的部分，超出原来大小的部分补 0
+#[allow(clippy::too_many_arguments)]
+fn patch_cluster(
+    data_blocks: &[DataBlock],
+    index_blocks: &[IBlock],
+    inum: usize,
+    old: &ClusterTable,
+    data_start: usize,
+    new: &ClusterTable,
+    index: usize,
+    start_pos: usize,
+    buf: &[u8]
+) -> Result<Vec<u8>, CorruptData> {
+    let cluster_start = index * COMPRESSION_CLUSTER_SIZE;
+    let raw_len = new.raw_len(index);
+    let mut raw = if index < old.clusters.len() {
+        read_cluster(data_blocks, index_blocks, inum, old, data_start, index)?
+    } else {
+        Vec::new()
+    };
+    raw.resize(raw_len, 0);
+
+    let from = max(start_pos, cluster_start);
+    let to = min(start_pos + buf.len(), cluster_start + raw_len);
+    if from < to {
+        raw[from - cluster_start..to - cluster_start].copy_from_slice(&buf[from - start_pos..to - start_pos]);
+    }
+    Ok(raw)
+}
+
+/// 压缩文件的一次写入：要写到存储的数据中的几段，和写完之后存储的大小
+#[derive(PartialEq, Eq, Debug)]
+pub struct CompressedWrite {
+    pub patches: Vec<(usize, Vec<u8>)>,     // 每一段的位置和数据
+    pub stored_size: usize,
+}
+
+/// 把 buf 写到压缩文件的 start_pos 处，写过末尾时文件变大，不会截断后面的数据
+///
+/// 只重新压缩被写到的簇和因为文件变大而变化的簇，再改写簇表。
+/// 旧格式的文件、簇表放不下或者留空的位置超过簇数据的一半时整个重新写入
+pub fn update_compressed_data(
+    data_blocks: &[DataBlock],
+    index_blocks: &[IBlock],
+    inum: usize,
+    start_pos: usize,
+    buf: &[u8]
+) -> Result<CompressedWrite, CorruptData> {
+    let rebuild = || {
+        let data = build_compressed_data(data_blocks, index_blocks, inum, start_pos, buf)?;
+        Ok(CompressedWrite { stored_size: data.len(), patches: vec![(0, data)] })
+    };
+
+    let (old, data_start) = read_cluster_table(data_blocks, index_blocks, inum)?;
+    if data_start == 0 || old.offsets.len() != old.clusters.len() {
+        return rebuild();
+    }
+
+    let stored_size = unsafe { get_inode(index_blocks, inum) }.size as usize;
+    let mut end = stored_size - data_start;
+    let capacities = old.capacities(end);
+
+    let end_pos = start_pos + buf.len();
+    let mut table = ClusterTable {
+        size: max(old.size as usize, end_pos) as u32,
+        ..old.clone()
+    };
+
+    let mut patches = vec![(0, Vec::new())];
+    for index in 0..(table.size as usize).div_ceil(COMPRESSION_CLUSTER_SIZE) {
+        if !cluster_changed(&old, &table, index, start_pos, end_pos) {
+            continue;
+        }
+        let raw = patch_cluster(data_blocks, index_blocks, inum, &old, data_start, &table, index, start_pos, buf)?;
+        let data = compress_cluster(raw);
+
+        let offset = match capacities.get(index) {
+            Some(capacity) if data.len() <= *capacity => old.offset(index),
+            _ => {
+                end += data.len();
+                end - data.len()
+            }
+        };
+        if index < table.clusters.len() {
+            table.clusters[index] = data.len() as u32;
+            table.offsets[index] = offset as u32;
+        } else {
+            table.clusters.push(data.len() as u32);
+            table.offsets.push(offset as u32);
+        }
+        patches.push((data_start + offset, data));
+    }
+
+    let garbage = end - table.clusters.iter().map(|len| *len as usize).sum::<usize>();
+    if garbage > COMPRESSION_CLUSTER_SIZE && garbage * 2 > end {
+        return rebuild();
+    }
+    match encode_cluster_table(&table, data_start - 4) {
+        Some(header) => patches[0].1 = header,
+        None => return rebuild(),
+    }
+
+    Ok(CompressedWrite { patches, stored_size: data_start + end })
+}
+
 #[cfg(test)]
 mod test {
     use std::mem::ManuallyDrop;
@@ -545,12 +1109,15 @@ mod test {
         let inode = INode {
             size: 3134333,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 15,
             block_direct: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
             block_indirect: 1,
+            flags: 0,
+            logical_size: 0,
         };
 
         let mut idx = unsafe { std::mem::zeroed::<[u32; 1024]>() };
@@ -574,8 +1141,8 @@ mod test {
         assert_eq!(transform_pos(&blocks, 0, 4096 * 13 + 1), (13, 1));
         assert_eq!(transform_pos(&blocks, 0, 4096 * 13 + 4095), (13, 4095));
 
-        let old_idx = idx.clone();
-        let old_blocks = vec![IBlock {
+        let old_idx = idx;
+        let old_blocks = [IBlock {
             idx: ManuallyDrop::new(old_idx),
         }];
 
@@ -601,12 +1168,15 @@ mod test {
         *inode = INode {
             size: 23423,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 14,
             block_direct: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
             block_indirect: 1,
+            flags: 0,
+            logical_size: 0,
         };
 
         let indirect_1 = unsafe { get_indirect_dnum_mut(&mut disk.i_blocks, 1, 0) };
@@ -616,8 +1186,8 @@ mod test {
         *indirect_2 = 13;
 
         let mut buf = vec![0; 4096 * 3 + 234];
-        for i in 0..buf.len() {
-            buf[i] = i as u8;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = i as u8;
         }
 
         let data_blocks = &mut disk.d_blocks;
@@ -632,8 +1202,8 @@ mod test {
 
 
         let mut buf = vec![0; 32];
-        for i in 0..buf.len() {
-            buf[i] = i as u8;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = i as u8;
         }
 
         write_data(data_blocks, blocks, 0, 4096 * 5 + 23, &buf);
@@ -649,12 +1219,15 @@ mod test {
         let inode = INode {
             size: 4096 * 3 + 234,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 4,
             block_direct: [0, 1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0],
             block_indirect: 0,
+            flags: 0,
+            logical_size: 0,
         };
 
         assert_eq!(get_size_of_data_block(&inode, 0), 4096);
@@ -669,12 +1242,15 @@ mod test {
         let inode = INode {
             size: 4096 * 3 + 234,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 4,
             block_direct: [0, 1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0],
             block_indirect: 0,
+            flags: 0,
+            logical_size: 0,
         };
 
         get_size_of_data_block(&inode, 5);
@@ -690,12 +1266,15 @@ mod test {
             *inode = INode {
                 size: 4096 * 3 + 234,
                 is_dir: false,
+                uid: 0,
                 atime: 0,
                 ctime: 0,
                 mtime: 0,
                 block_count: 4,
                 block_direct: [0, 1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0],
                 block_indirect: 0,
+                flags: 0,
+                logical_size: 0,
             };
             disk.d_bitmaps[0].bitmaps[0] = 15;  // ...001111
             disk.i_bitmaps[0].bitmaps[0] = 1;   // ...000001
@@ -704,46 +1283,47 @@ mod test {
         let i_blocks = &mut disk.i_blocks;
         let i_bitmaps = &mut disk.i_bitmaps;
         let d_bitmaps = &mut disk.d_bitmaps;
+        let d_refs = &mut disk.d_refs;
 
-        resize(i_bitmaps, d_bitmaps, i_blocks, 0, 4096 * 3 + 234 + 4096);
+        resize(i_bitmaps, d_bitmaps, d_refs, i_blocks, 0, 4096 * 3 + 234 + 4096);
 
         let inode = unsafe { get_inode(i_blocks, 0) };
         assert_eq!(inode.size, 4096 * 3 + 234 + 4096);
         assert_eq!(inode.block_count, 5);
 
-        assert_eq!(get_state(d_bitmaps, 0), true);
-        assert_eq!(get_state(d_bitmaps, 1), true);
-        assert_eq!(get_state(d_bitmaps, 2), true);
-        assert_eq!(get_state(d_bitmaps, 3), true);
-        assert_eq!(get_state(d_bitmaps, 4), true);
+        assert!(get_state(d_bitmaps, 0));
+        assert!(get_state(d_bitmaps, 1));
+        assert!(get_state(d_bitmaps, 2));
+        assert!(get_state(d_bitmaps, 3));
+        assert!(get_state(d_bitmaps, 4));
 
-        assert_eq!(get_state(d_bitmaps, 5), false);
-        assert_eq!(get_state(d_bitmaps, 6), false);
+        assert!(!get_state(d_bitmaps, 5));
+        assert!(!get_state(d_bitmaps, 6));
 
-        resize(i_bitmaps, d_bitmaps, i_blocks, 0, 4096 * 3 + 234);
+        resize(i_bitmaps, d_bitmaps, d_refs, i_blocks, 0, 4096 * 3 + 234);
 
         let inode = unsafe { get_inode_mut(i_blocks, 0) };
         assert_eq!(inode.size, 4096 * 3 + 234);
         assert_eq!(inode.block_count, 4);
 
-        assert_eq!(get_state(d_bitmaps, 0), true);
-        assert_eq!(get_state(d_bitmaps, 1), true);
-        assert_eq!(get_state(d_bitmaps, 2), true);
-        assert_eq!(get_state(d_bitmaps, 3), true);
+        assert!(get_state(d_bitmaps, 0));
+        assert!(get_state(d_bitmaps, 1));
+        assert!(get_state(d_bitmaps, 2));
+        assert!(get_state(d_bitmaps, 3));
 
-        assert_eq!(get_state(d_bitmaps, 4), false);
-        assert_eq!(get_state(d_bitmaps, 5), false);
-        assert_eq!(get_state(d_bitmaps, 6), false);
+        assert!(!get_state(d_bitmaps, 4));
+        assert!(!get_state(d_bitmaps, 5));
+        assert!(!get_state(d_bitmaps, 6));
 
-        resize(i_bitmaps, d_bitmaps, i_blocks, 0, 0);
+        resize(i_bitmaps, d_bitmaps, d_refs, i_blocks, 0, 0);
         let inode = unsafe { get_inode_mut(i_blocks, 0) };
         assert_eq!(inode.size, 0);
         assert_eq!(inode.block_count, 0);
 
-        assert_eq!(get_state(d_bitmaps, 0), false);
-        assert_eq!(get_state(d_bitmaps, 1), false);
-        assert_eq!(get_state(d_bitmaps, 2), false);
-        assert_eq!(get_state(d_bitmaps, 3), false);
+        assert!(!get_state(d_bitmaps, 0));
+        assert!(!get_state(d_bitmaps, 1));
+        assert!(!get_state(d_bitmaps, 2));
+        assert!(!get_state(d_bitmaps, 3));
     }
 
     #[test]
@@ -775,12 +1355,15 @@ mod test {
         *inode = INode {
             size: 4096 * 3 + 234,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 4,
             block_direct: [0, 1, 2, 3, 0, 0, 0, 0, 0, 0, 0, 0],
             block_indirect: 0,
+            flags: 0,
+            logical_size: 0,
         };
         disk.d_bitmaps[0].bitmaps[0] = 15;  // ...001111
         disk.i_bitmaps[0].bitmaps[0] = 1;   // ...000001
@@ -789,7 +1372,7 @@ mod test {
 
         let i_blocks = &mut disk.i_blocks;
 
-        let i_blocks_ptr = i_blocks as *mut [IBlock; INDEX_BLOCK_COUNT];
+        let i_blocks_ptr = i_blocks.as_mut_slice() as *mut [IBlock];
 
         let inode = unsafe { get_inode_mut(i_blocks, 0) };
 
@@ -806,19 +1389,13 @@ mod test {
             ],
         };
 
-        unsafe {
-            write_data_struct(disk.d_blocks.as_mut(), i_blocks_ptr.as_mut().unwrap(), 0, 0, &data);
-        }
-
-        let read_data = read_data_struct(disk.d_blocks.as_ref(), unsafe { i_blocks_ptr.as_mut() }.unwrap(), 0, 0);
-        assert_eq!(data, read_data);
-
         unsafe {
             let disk = &mut *disk_ptr;
             println!("before size: {:?}", inode);
             write_data_struct_auto_resize(
                 disk.i_bitmaps.as_mut(),
                 disk.d_bitmaps.as_mut(),
+                disk.d_refs.as_mut(),
                 disk.i_blocks.as_mut(),
                 disk.d_blocks.as_mut(),
                 0, 0, &data
@@ -838,32 +1415,37 @@ mod test {
         *inode = INode {
             size: 0,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 0,
             block_direct: [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
             block_indirect: 0,
+            flags: 0,
+            logical_size: 0,
         };
         disk.d_bitmaps[0].bitmaps[0] = 0;
         disk.i_bitmaps[0].bitmaps[0] = 1;
 
         let i_bitmaps = &mut disk.i_bitmaps;
         let d_bitmaps = &mut disk.d_bitmaps;
+        let d_refs = &mut disk.d_refs;
         let i_blocks = &mut disk.i_blocks;
         let d_blocks = &mut disk.d_blocks;
 
         let mut buf = vec![0; 4096 * 3 + 234];
-        for i in 0..buf.len() {
-            buf[i] = i as u8;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = i as u8;
         }
 
-        write_data_auto_resize(
+        write_vectored_with_size(
             i_bitmaps,
             d_bitmaps,
+            d_refs,
             i_blocks,
             d_blocks,
-            0, 0, &buf
+            0, 0, &[&buf], buf.len()
         );
 
         assert_eq!(unsafe { get_inode(i_blocks, 0) }.block_count, 4);
@@ -882,20 +1464,23 @@ mod test {
         *inode = INode {
             size: 0,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 0,
             block_direct: [0; 12],
             block_indirect: 0,
+            flags: 0,
+            logical_size: 0,
         };
 
         disk.i_bitmaps[0].bitmaps[0] = 1;
 
         let i_bitmaps = &mut disk.i_bitmaps;
         let d_bitmaps = &mut disk.d_bitmaps;
+        let d_refs = &mut disk.d_refs;
         let i_blocks = &mut disk.i_blocks;
-        let d_blocks = &mut disk.d_blocks;
 
         extend_data_block_of_inode(
             i_bitmaps,
@@ -914,13 +1499,13 @@ mod test {
         assert_eq!(inode.block_direct[3], 0);
         assert_eq!(inode.block_direct[4], 0);
 
-        assert_eq!(get_state(d_bitmaps, 0), true);
-        assert_eq!(get_state(d_bitmaps, 1), true);
-        assert_eq!(get_state(d_bitmaps, 2), true);
+        assert!(get_state(d_bitmaps, 0));
+        assert!(get_state(d_bitmaps, 1));
+        assert!(get_state(d_bitmaps, 2));
 
-        assert_eq!(get_state(d_bitmaps, 3), false);
-        assert_eq!(get_state(d_bitmaps, 4), false);
-        assert_eq!(get_state(d_bitmaps, 5), false);
+        assert!(!get_state(d_bitmaps, 3));
+        assert!(!get_state(d_bitmaps, 4));
+        assert!(!get_state(d_bitmaps, 5));
 
         println!("{:?}", inode);
 
@@ -958,6 +1543,7 @@ mod test {
         shrink_data_block_of_inode(
             i_bitmaps,
             d_bitmaps,
+            d_refs,
             i_blocks,
             0, 10,
         );
@@ -979,6 +1565,7 @@ mod test {
         shrink_data_block_of_inode(
             i_bitmaps,
             d_bitmaps,
+            d_refs,
             i_blocks,
             0, 0,
         );
@@ -1001,20 +1588,23 @@ mod test {
         *inode = INode {
             size: 0,
             is_dir: false,
+            uid: 0,
             atime: 0,
             ctime: 0,
             mtime: 0,
             block_count: 0,
             block_direct: [0; 12],
             block_indirect: 0,
+            flags: 0,
+            logical_size: 0,
         };
 
         disk.i_bitmaps[0].bitmaps[0] = 1;
 
         let i_bitmaps = &mut disk.i_bitmaps;
         let d_bitmaps = &mut disk.d_bitmaps;
+        let d_refs = &mut disk.d_refs;
         let i_blocks = &mut disk.i_blocks;
-        let d_blocks = &mut disk.d_blocks;
 
         extend_data_block_of_inode(
             i_bitmaps,
@@ -1026,13 +1616,245 @@ mod test {
         let inode = unsafe { get_inode(i_blocks, 0) };
         assert_eq!(inode.block_count, 20);
 
-        free_inode(i_bitmaps, d_bitmaps, i_blocks, 0);
+        free_inode(i_bitmaps, d_bitmaps, d_refs, i_blocks, 0);
 
         for i in 0..20 {
-            assert_eq!(get_state(d_bitmaps, i as usize), false);
+            assert!(!get_state(d_bitmaps, i as usize));
         }
 
         assert_eq!(i_bitmaps[0].bitmaps[0], 0);
         assert_eq!(d_bitmaps[0].bitmaps[0], 0);
+
+        // 间接块也被释放
+        assert!(i_bitmaps[0].bitmaps.iter().all(|state| *state == 0));
+    }
+
+    #[test]
+    fn test_get_free_run() {
+        let mut disk = Disk::new();
+        disk.d_bitmaps[0].bitmaps[0] = 0b1011_0101;
+
+        assert_eq!(get_free_run(&disk.d_bitmaps, all_data_block_range(&disk.d_bitmaps), 1), Some(1));
+        assert_eq!(get_free_run(&disk.d_bitmaps, all_data_block_range(&disk.d_bitmaps), 2), Some(8));
+        assert_eq!(get_free_run(&disk.d_bitmaps, 0..9, 2), None);
+        assert_eq!(count_extents(&[1, 2, 3, 7, 8, 10]), 3);
+        assert_eq!(count_extents(&[]), 0);
+    }
+
+    #[test]
+    fn test_defrag_inode() {
+        let mut disk = Disk::new();
+        let inode = unsafe { get_inode_mut(&mut disk.i_blocks, 0) };
+        *inode = INode {
+            size: 4096 * 3,
+            is_dir: false,
+            uid: 0,
+            atime: 0,
+            ctime: 0,
+            mtime: 0,
+            block_count: 3,
+            block_direct: [0, 2, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            block_indirect: 0,
+            flags: 0,
+            logical_size: 0,
+        };
+        disk.i_bitmaps[0].bitmaps[0] = 1;
+        disk.d_bitmaps[0].bitmaps[0] = 0b1_0111;   // 0 1 2 4 被占用，1 属于别的文件
+
+        for (i, dnum) in [0, 2, 4].iter().enumerate() {
+            disk.d_blocks[*dnum].data = [i as u8 + 1; 4096];
+        }
+
+        assert_eq!(defrag_inode(&mut disk.d_bitmaps, &disk.d_refs, &mut disk.i_blocks, &mut disk.d_blocks, 0), Defrag::Moved);
+        assert_eq!(get_dnums(&disk.i_blocks, 0), vec![5, 6, 7]);
+        assert_eq!(defrag_inode(&mut disk.d_bitmaps, &disk.d_refs, &mut disk.i_blocks, &mut disk.d_blocks, 0), Defrag::Contiguous);
+
+        assert_eq!(disk.d_bitmaps[0].bitmaps[0], 0b1110_0010);
+        let mut buf = vec![0u8; 4096 * 3];
+        read_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &mut buf);
+        assert_eq!(buf[0], 1);
+        assert_eq!(buf[4096], 2);
+        assert_eq!(buf[4096 * 2], 3);
+    }
+
+    #[test]
+    fn test_compressed_data() {
+        let mut disk = Disk::new();
+        let inode = unsafe { get_inode_mut(&mut disk.i_blocks, 0) };
+        inode.flags = INODE_COMPRESSED;
+        disk.i_bitmaps[0].bitmaps[0] = 1;
+
+        let mut expected = (0..COMPRESSION_CLUSTER_SIZE * 3 + 100)
+            .map(|i| (i / 1000) as u8)
+            .collect::<Vec<_>>();
+        let data = compress_data(&expected);
+        assert!(data.len() < expected.len() / 10);
+        write_vectored_with_size(
+            &mut disk.i_bitmaps, &mut disk.d_bitmaps, &mut disk.d_refs,
+            &mut disk.i_blocks, &mut disk.d_blocks,
+            0, 0, &[&data], data.len()
+        );
+
+        // 跨簇随机读取
+        let mut buf = vec![0u8; 5000];
+        read_compressed_data(&disk.d_blocks, &disk.i_blocks, 0, COMPRESSION_CLUSTER_SIZE - 2000, &mut buf).unwrap();
+        assert_eq!(buf, expected[COMPRESSION_CLUSTER_SIZE - 2000..COMPRESSION_CLUSTER_SIZE + 3000]);
+
+        let write = |disk: &mut Disk, pos: usize, buf: &[u8]| {
+            let update = update_compressed_data(&disk.d_blocks, &disk.i_blocks, 0, pos, buf).unwrap();
+            for (pos, data) in update.patches.iter() {
+                write_vectored_with_size(
+                    &mut disk.i_bitmaps, &mut disk.d_bitmaps, &mut disk.d_refs,
+                    &mut disk.i_blocks, &mut disk.d_blocks,
+                    0, *pos, &[data], update.stored_size
+                );
+            }
+            update
+        };
+        let check = |disk: &Disk, expected: &[u8]| {
+            let (table, _) = read_cluster_table(&disk.d_blocks, &disk.i_blocks, 0).unwrap();
+            assert_eq!(table.size as usize, expected.len());
+            let mut buf = vec![0u8; expected.len()];
+            read_compressed_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &mut buf).unwrap();
+            assert_eq!(buf, expected);
+        };
+
+        // 只改写簇表和被写到的簇，压缩之后没有变长时写回原处
+        let size = unsafe { get_inode(&disk.i_blocks, 0) }.size as usize;
+        let update = write(&mut disk, COMPRESSION_CLUSTER_SIZE, &[1u8; COMPRESSION_CLUSTER_SIZE]);
+        assert_eq!(update.patches.len(), 2);
+        assert_eq!(update.stored_size, size);
+        expected[COMPRESSION_CLUSTER_SIZE..COMPRESSION_CLUSTER_SIZE * 2].fill(1);
+        check(&disk, &expected);
+
+        // 变长的簇放到簇数据末尾
+        let noise = (0..3000).map(|i| (i * 7919 % 251) as u8).collect::<Vec<_>>();
+        let update = write(&mut disk, 100, &noise);
+        assert_eq!(update.patches.len(), 2);
+        assert!(update.patches[1].0 >= size);
+        expected[100..3100].copy_from_slice(&noise);
+        check(&disk, &expected);
+
+        // 覆盖中间的一段，并且写过文件末尾
+        let start = COMPRESSION_CLUSTER_SIZE * 3;
+        let patch = vec![0xaau8; 5000];
+        write(&mut disk, start + 1000, &patch);
+        expected.resize(start + 1000, 0);
+        expected.extend_from_slice(&patch);
+        check(&disk, &expected);
+
+        // 没有记录簇位置的旧格式整个重新写入
+        let mut table = ClusterTable {
+            size: expected.len() as u32,
+            ..Default::default()
+        };
+        let mut stored = Vec::new();
+        for raw in expected.chunks(COMPRESSION_CLUSTER_SIZE) {
+            let data = compress_cluster(raw.to_vec());
+            table.clusters.push(data.len() as u32);
+            stored.extend_from_slice(&data);
+        }
+        let header = serde_json::to_vec(&table).unwrap();
+        let data = [&(header.len() as u32).to_le_bytes()[..], &header, &stored].concat();
+        write_vectored_with_size(
+            &mut disk.i_bitmaps, &mut disk.d_bitmaps, &mut disk.d_refs,
+            &mut disk.i_blocks, &mut disk.d_blocks,
+            0, 0, &[&data], data.len()
+        );
+        check(&disk, &expected);
+
+        let update = write(&mut disk, 0, &[2u8; 10]);
+        assert_eq!(update.patches.len(), 1);
+        expected[..10].fill(2);
+        check(&disk, &expected);
+        assert_eq!(read_cluster_table(&disk.d_blocks, &disk.i_blocks, 0).unwrap().0.offsets.len(), 4);
+    }
+
+    #[test]
+    fn test_corrupt_compressed_data() {
+        let mut disk = Disk::new();
+        let inode = unsafe { get_inode_mut(&mut disk.i_blocks, 0) };
+        inode.flags = INODE_COMPRESSED;
+        disk.i_bitmaps[0].bitmaps[0] = 1;
+
+        let raw = vec![7u8; COMPRESSION_CLUSTER_SIZE * 2];
+        let data = compress_data(&raw);
+        let write = |disk: &mut Disk, data: &[u8]| {
+            write_vectored_with_size(
+                &mut disk.i_bitmaps, &mut disk.d_bitmaps, &mut disk.d_refs,
+                &mut disk.i_blocks, &mut disk.d_blocks,
+                0, 0, &[data], data.len()
+            );
+        };
+
+        // 簇数据被改坏，解压失败时返回错误而不是 panic
+        write(&mut disk, &data);
+        let (table, data_start) = read_cluster_table(&disk.d_blocks, &disk.i_blocks, 0).unwrap();
+        let mut broken = data.clone();
+        broken[data_start..data_start + table.clusters[0] as usize].fill(0xff);
+        write(&mut disk, &broken);
+        let mut buf = vec![0u8; 10];
+        assert_eq!(read_compressed_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &mut buf), Err(CorruptData));
+        assert_eq!(update_compressed_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &[1u8; 10]), Err(CorruptData));
+
+        // 存储被截断，簇表指向末尾之外
+        write(&mut disk, &data[..data.len() - 1]);
+        assert_eq!(read_cluster_table(&disk.d_blocks, &disk.i_blocks, 0), Err(CorruptData));
+
+        // 簇表无法解析
+        let mut broken = data.clone();
+        broken[4] = b'x';
+        write(&mut disk, &broken);
+        assert_eq!(read_compressed_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &mut buf), Err(CorruptData));
+    }
+
+    #[test]
+    fn test_inline_data() {
+        let mut disk = Disk::new();
+        disk.i_bitmaps[0].bitmaps[0] = 1;
+        let free = count_free_items(&disk.d_bitmaps, all_data_block_range(&disk.d_bitmaps));
+
+        // 小数据内联存放，不占用数据块
+        let small = b"hello, inline".to_vec();
+        write_vectored_with_size(
+            &mut disk.i_bitmaps, &mut disk.d_bitmaps, &mut disk.d_refs,
+            &mut disk.i_blocks, &mut disk.d_blocks,
+            0, 0, &[&small], small.len()
+        );
+        let inode = unsafe { get_inode(&disk.i_blocks, 0) };
+        assert!(inode.is_inline());
+        assert_eq!(inode.block_count, 0);
+        assert_eq!(count_free_items(&disk.d_bitmaps, all_data_block_range(&disk.d_bitmaps)), free);
+        let mut buf = vec![0u8; small.len()];
+        read_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &mut buf);
+        assert_eq!(buf, small);
+
+        // 变大之后搬到数据块上，原来的数据保留
+        write_vectored_with_size(
+            &mut disk.i_bitmaps, &mut disk.d_bitmaps, &mut disk.d_refs,
+            &mut disk.i_blocks, &mut disk.d_blocks,
+            0, small.len(), &[&[7u8; 5000]], small.len() + 5000
+        );
+        let inode = unsafe { get_inode(&disk.i_blocks, 0) };
+        assert!(!inode.is_inline());
+        assert_eq!(inode.block_count, 2);
+        let mut buf = vec![0u8; small.len() + 5000];
+        read_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &mut buf);
+        assert_eq!(buf[..small.len()], small);
+        assert!(buf[small.len()..].iter().all(|c| *c == 7));
+
+        // 变小之后重新内联，数据块被释放
+        write_vectored_with_size(
+            &mut disk.i_bitmaps, &mut disk.d_bitmaps, &mut disk.d_refs,
+            &mut disk.i_blocks, &mut disk.d_blocks,
+            0, 5, &[b"!"], 6
+        );
+        let inode = unsafe { get_inode(&disk.i_blocks, 0) };
+        assert!(inode.is_inline());
+        assert_eq!(inode.size, 6);
+        assert_eq!(count_free_items(&disk.d_bitmaps, all_data_block_range(&disk.d_bitmaps)), free);
+        let mut buf = vec![0u8; 6];
+        read_data(&disk.d_blocks, &disk.i_blocks, 0, 0, &mut buf);
+        assert_eq!(&buf, b"hello!");
     }
 }
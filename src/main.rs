@@ -1,25 +1,60 @@
 use structopt::StructOpt;
 
 use crate::io::{Loadable, Savable};
+use crate::mount::Mountable;
 use crate::vfs::VirtualFileSystem;
 
 mod io;
 mod repr;
 mod logic;
 mod rw;
+mod lock;
 mod vsfs;
+mod cache;
 mod path;
 mod utils;
 mod vfs;
 mod vsfs_vfs;
 mod commands;
+mod quota;
+mod crypto;
+mod memfs;
+mod hostfs;
+mod overlay;
+mod mount;
+mod dynfs;
+mod shared;
+mod async_vfs;
+mod async_vsfs;
 
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "file system", about = "A simple file system")]
+struct Options {
+    /// 修改写回存储的时机：always 为每条命令之后，exit 为只在退出时，数字为每隔多少秒
+    #[structopt(long = "write-back", default_value = "30")]
+    write_back: commands::WriteBack,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
 enum Command {
-    /// 创建一个新的文件系统并加载
-    New {},
+    /// 创建一个新的文件系统并加载，修改写回到镜像文件中
+    New {
+        /// 新的镜像文件路径，不能已经存在；不指定时退出时保存到 exit 给出的文件名
+        #[structopt(name = "path")]
+        path: Option<std::path::PathBuf>,
+
+        /// 磁盘大小，可以带 K、M、G 后缀，默认 256M
+        #[structopt(long = "size")]
+        size: Option<String>,
+
+        /// 使用口令加密镜像
+        #[structopt(long = "encrypt")]
+        encrypt: bool,
+    },
 
     /// 加载已有的文件系统
     Sfs {
@@ -28,18 +63,180 @@ enum Command {
         path: std::path::PathBuf
     },
 
+    /// 通过线程安全的共享文件系统加载已有的文件系统，多个线程可以同时读写不同的文件
+    Shared {
+        /// 文件系统文件路径
+        #[structopt(name = "path")]
+        path: std::path::PathBuf
+    },
+
+    /// 调整已有文件系统的大小
+    Resize {
+        /// 文件系统文件路径
+        #[structopt(name = "path")]
+        path: std::path::PathBuf,
+
+        /// 新的磁盘大小，可以带 K、M、G 后缀
+        #[structopt(name = "size")]
+        size: String,
+    },
+
+    /// 加密已有的文件系统，已经加密时更换口令
+    Encrypt {
+        /// 文件系统文件路径
+        #[structopt(name = "path")]
+        path: std::path::PathBuf,
+    },
+
+    /// 解密已有的文件系统
+    Decrypt {
+        /// 文件系统文件路径
+        #[structopt(name = "path")]
+        path: std::path::PathBuf,
+    },
+
+    /// 在内存中创建一个临时文件系统，退出后不保存
+    Mem,
+
+    /// 把一个文件系统作为只读的下层，修改都保存到上层的文件系统中
+    Overlay {
+        /// 下层文件系统文件路径，不会被修改
+        #[structopt(name = "lower")]
+        lower: std::path::PathBuf,
+
+        /// 上层文件系统文件路径，不存在时新建
+        #[structopt(name = "upper")]
+        upper: std::path::PathBuf,
+    },
+
+    /// 把宿主机上的一个目录当作文件系统
+    Host {
+        /// 宿主机上的目录
+        #[structopt(name = "dir")]
+        dir: std::path::PathBuf,
+    },
+
+    /// 挂载表，根目录挂载一个文件系统，之后可以用 mount 命令挂载更多
+    Mount {
+        /// 根目录的来源：mem 为内存文件系统，host:<目录> 为宿主机目录，其他为镜像文件
+        #[structopt(name = "root")]
+        root: String,
+    },
+
+    /// 通过异步接口使用镜像，数据块用到时才从镜像中读取，退出时把修改写回原来的镜像
+    Async {
+        /// 文件系统文件路径，不能是加密的镜像
+        #[structopt(name = "path")]
+        path: std::path::PathBuf,
+    },
+
     /// 显示帮助信息
     Help,
 }
 
 
+/// 解析磁盘大小，返回块数
+fn parse_size(size: &str) -> Option<usize> {
+    let size = size.trim().to_uppercase();
+    let (num, unit) = match size.chars().last()? {
+        'K' => (&size[..size.len() - 1], 1024),
+        'M' => (&size[..size.len() - 1], 1024 * 1024),
+        'G' => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+        _ => (&size[..], 1),
+    };
+    let bytes = num.parse::<usize>().ok()? * unit;
+    Some(bytes / 4096)
+}
+
+
+/// 读取口令，设置了环境变量 VSFS_PASSPHRASE 时直接使用它
+fn read_passphrase(prompt: &str) -> Option<String> {
+    if let Ok(passphrase) = std::env::var("VSFS_PASSPHRASE") {
+        return Some(passphrase);
+    }
+    rpassword::prompt_password(prompt).ok()
+}
+
+/// 读取新口令，需要输入两次
+fn read_new_passphrase() -> Option<String> {
+    if let Ok(passphrase) = std::env::var("VSFS_PASSPHRASE") {
+        return Some(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("请输入新口令：").ok()?;
+    let confirm = rpassword::prompt_password("请再次输入新口令：").ok()?;
+    if passphrase != confirm {
+        println!("两次输入的口令不一致！");
+        return None;
+    }
+    Some(passphrase)
+}
+
+/// 给磁盘设置新口令
+fn encrypt_disk(disk: &mut repr::Disk) -> bool {
+    let passphrase = match read_new_passphrase() {
+        Some(passphrase) => passphrase,
+        None => return false,
+    };
+    if let Err(err) = disk.set_passphrase(Some(&passphrase), crypto::KdfParams::default()) {
+        println!("设置口令失败：{}", err);
+        return false;
+    }
+    true
+}
+
+/// 加载文件系统，加密的镜像需要输入口令
+fn load_disk(path: &std::path::Path) -> Option<Box<repr::Disk>> {
+    println!("准备加载文件系统: {:?}", path);
+
+    let res = match repr::Disk::read_super_block(path) {
+        Ok(sb) if sb.is_encrypted() => {
+            let passphrase = read_passphrase("请输入口令：").unwrap_or_default();
+            repr::Disk::load_with_passphrase(path, Some(&passphrase))
+                .map_err(|err| err.to_string())
+        }
+        Ok(_) => repr::Disk::load(path).map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    };
+
+    match res {
+        Ok(disk) => {
+            println!("文件系统加载成功！");
+            Some(disk)
+        }
+        Err(err) => {
+            println!("文件系统加载失败：{}", err);
+            None
+        }
+    }
+}
+
 fn main() {
-    let command = Command::from_args();
+    let Options { write_back, command } = Options::from_args();
     match command {
-        Command::New {} => {
+        Command::New { path, size, encrypt } => {
+            if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+                println!("镜像文件已经存在：{:?}，加载已有的文件系统请用 sfs", path);
+                return;
+            }
             println!("准备创建文件系统...");
 
-            let mut disk = repr::Disk::new();
+            let mut disk = match size {
+                Some(size) => {
+                    let disk = parse_size(&size).and_then(repr::Disk::with_size);
+                    if disk.is_none() {
+                        println!("磁盘大小不合法！");
+                        return;
+                    }
+                    disk.unwrap()
+                }
+                None => repr::Disk::new(),
+            };
+            if encrypt && !encrypt_disk(&mut disk) {
+                println!("文件系统创建失败！");
+                return;
+            }
+
             let mut fs = vsfs_vfs::VerySimpleFileSystem::new(&mut disk);
             let res = fs.init();
 
@@ -49,28 +246,173 @@ fn main() {
             }
 
             println!("文件系统创建成功！");
+            if let Some(path) = &path {
+                fs.set_image(path);
+            }
 
-            let name = commands::run(&mut fs);
+            // 没有用 exit 指定文件名时保存到新建的镜像，两个都没有时无处保存
+            let name = commands::run(&mut fs, write_back).map(std::path::PathBuf::from).or(path);
+            drop(fs);
+            let Some(name) = name else {
+                println!("文件系统退出，没有指定镜像文件，修改没有保存");
+                return;
+            };
 
             println!("文件系统退出，准备将文件系统保存到: {:?}", name);
             disk.save(name).unwrap();
             println!("文件系统保存成功！");
         }
         Command::Sfs { path } => {
-            println!("准备加载文件系统: {:?}", path);
-            let mut disk = repr::Disk::load(path).unwrap();
+            let Some(mut disk) = load_disk(&path) else {
+                return;
+            };
             let mut fs = vsfs_vfs::VerySimpleFileSystem::new(&mut disk);
-            println!("文件系统加载成功！");
+            fs.set_image(&path);
 
-            let name = commands::run(&mut fs);
+            // 没有用 exit 指定文件名时保存回原来的镜像
+            let name = commands::run(&mut fs, write_back).map(std::path::PathBuf::from).unwrap_or(path);
+            drop(fs);
 
             println!("文件系统退出，准备将文件系统保存到: {:?}", name);
             disk.save(name).unwrap();
             println!("文件系统保存成功！");
         },
+        Command::Shared { path } => {
+            let Some(disk) = load_disk(&path) else {
+                return;
+            };
+            let mut fs = shared::SharedFileSystem::new(disk);
+            fs.set_image(&path);
+
+            // 没有用 exit 指定文件名时保存回原来的镜像
+            let name = commands::run(&mut fs, write_back).map(std::path::PathBuf::from).unwrap_or(path);
+
+            println!("文件系统退出，准备将文件系统保存到: {:?}", name);
+            fs.save(name).unwrap();
+            println!("文件系统保存成功！");
+        },
+        Command::Resize { path, size } => {
+            let size = parse_size(&size);
+            if size.is_none() {
+                println!("磁盘大小不合法！");
+                return;
+            }
+
+            let Some(mut disk) = load_disk(&path) else {
+                return;
+            };
+
+            if let Err(err) = vsfs::resize(&mut disk, size.unwrap()) {
+                println!("调整大小失败：{}", err);
+                return;
+            }
+
+            println!("调整大小成功，准备将文件系统保存到: {:?}", path);
+            disk.save(&path).unwrap();
+            println!("文件系统保存成功！");
+        }
+        Command::Encrypt { path } => {
+            let Some(mut disk) = load_disk(&path) else {
+                return;
+            };
+            if !encrypt_disk(&mut disk) {
+                return;
+            }
+
+            disk.save(&path).unwrap();
+            println!("文件系统已加密！");
+        }
+        Command::Decrypt { path } => {
+            let Some(mut disk) = load_disk(&path) else {
+                return;
+            };
+            disk.set_passphrase(None, crypto::KdfParams::default()).unwrap();
+
+            disk.save(&path).unwrap();
+            println!("文件系统已解密！");
+        }
+        Command::Mem => {
+            let mut fs = dynfs::boxed(memfs::MemoryFileSystem::new());
+            commands::run(&mut fs, write_back);
+            println!("内存文件系统退出，数据没有保存");
+        }
+        Command::Overlay { lower, upper } => {
+            let Some(mut lower_disk) = load_disk(&lower) else {
+                return;
+            };
+            let mut upper_disk = if upper.exists() {
+                let Some(disk) = load_disk(&upper) else {
+                    return;
+                };
+                disk
+            } else {
+                let mut disk = repr::Disk::new();
+                vsfs::init(&mut disk);
+                disk
+            };
+
+            let mut upper_fs = vsfs_vfs::VerySimpleFileSystem::new(&mut upper_disk);
+            upper_fs.set_image(&upper);
+            let mut fs = overlay::OverlayFileSystem::new(
+                vsfs_vfs::VerySimpleFileSystem::new(&mut lower_disk),
+                upper_fs,
+            );
+
+            let name = commands::run(&mut fs, write_back).map(std::path::PathBuf::from).unwrap_or(upper);
+            drop(fs);
+
+            println!("文件系统退出，准备将上层文件系统保存到: {:?}", name);
+            upper_disk.save(name).unwrap();
+            println!("文件系统保存成功！");
+        }
+        Command::Host { dir } => {
+            let mut fs = match hostfs::HostFileSystem::new(&dir) {
+                Ok(fs) => dynfs::boxed(fs),
+                Err(err) => {
+                    println!("打开目录失败：{}", err);
+                    return;
+                }
+            };
+            commands::run(&mut fs, write_back);
+            println!("文件系统退出");
+        }
+        Command::Mount { root } => {
+            let fs = match mount::Backend::open_source(&root) {
+                Ok(fs) => fs,
+                Err(err) => {
+                    println!("挂载失败：{}", err);
+                    return;
+                }
+            };
+            let mut fs = mount::MountTable::new(&root, fs);
+            commands::run(&mut fs, write_back);
+
+            println!("文件系统退出，准备卸载所有文件系统，镜像保存回原来的文件");
+            match fs.unmount_all() {
+                Ok(()) => println!("卸载成功！"),
+                Err(err) => println!("卸载失败：{}", err),
+            }
+        }
+        Command::Async { path } => {
+            let open = async_vsfs::AsyncVerySimpleFileSystem::open_image(&path);
+            let mut fs = match async_vfs::BlockingFileSystem::new(open) {
+                Ok(fs) => fs,
+                Err(err) => {
+                    println!("打开镜像失败：{}", err);
+                    return;
+                }
+            };
+            commands::run(&mut fs, write_back);
+
+            println!("文件系统退出，准备把修改写回镜像: {:?}", path);
+            match fs.sync() {
+                Ok(()) => println!("写回成功！"),
+                Err(err) => println!("写回失败：{}", err),
+            }
+        }
         Command::Help => {
-            print!("\n");
-            Command::clap().print_help().unwrap();
+            println!();
+            Options::clap().print_help().unwrap();
             print!("\n\n");
         }
     }
@@ -0,0 +1,544 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+
+use crate::lock::{LockError, LockInfo, LockKind, LockManager, LockOwner, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::rw::AccessMode;
+use crate::utils;
+use crate::vfs::{OpenOptions, Unsupported, VirtualFile, VirtualFileDescription, VirtualFileSystem};
+
+#[derive(Debug)]
+pub struct MemoryFile {
+    path: Path,
+    mode: AccessMode,
+    position: usize,
+    append: bool,       // 每次写入之前先移到文件末尾
+    id: usize,
+}
+
+impl VirtualFile for MemoryFile {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn mode(&self) -> AccessMode {
+        self.mode
+    }
+
+    fn position(&self) -> usize {
+        self.position
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        self.position = pos;
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryFileDescription {
+    name: String,
+    is_dir: bool,
+    ctime: u32,
+    mtime: u32,
+    size: usize,
+    owner: u32,
+}
+
+impl VirtualFileDescription for MemoryFileDescription {
+    fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn ctime(&self) -> u64 {
+        self.ctime as u64
+    }
+
+    fn mtime(&self) -> u64 {
+        self.mtime as u64
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn owner(&self) -> u32 {
+        self.owner
+    }
+}
+
+
+#[derive(Debug)]
+pub enum MemoryError {
+    NotFound(Path),
+    AlreadyExists(Path),
+    NotADirectory(Path),
+    IsADirectory(Path),
+    DirIsNotEmpty(Path),
+    InvalidPath,
+    FileCannotWrite,
+    FileNotOpen,
+    AccessError,
+    Unsupported,
+    Lock(LockError),
+}
+
+impl Display for MemoryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemoryError::NotFound(path) => write!(f, "path {} not found", path.to_str()),
+            MemoryError::AlreadyExists(path) => write!(f, "file {} is already exist", path.to_str()),
+            MemoryError::NotADirectory(path) => write!(f, "{} is not a directory", path.to_str()),
+            MemoryError::IsADirectory(path) => write!(f, "{} is a directory", path.to_str()),
+            MemoryError::DirIsNotEmpty(path) => write!(f, "dir {} is not empty", path.to_str()),
+            MemoryError::InvalidPath => write!(f, "invalid path"),
+            MemoryError::FileCannotWrite => write!(f, "File cannot write"),
+            MemoryError::FileNotOpen => write!(f, "File not open"),
+            MemoryError::AccessError => write!(f, "access error. r, w, or rw"),
+            MemoryError::Unsupported => write!(f, "not supported by the in-memory file system"),
+            MemoryError::Lock(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl Error for MemoryError {}
+
+impl From<Unsupported> for MemoryError {
+    fn from(_: Unsupported) -> Self {
+        MemoryError::Unsupported
+    }
+}
+
+impl From<MemoryError> for io::Error {
+    fn from(err: MemoryError) -> Self {
+        let kind = match &err {
+            MemoryError::NotFound(_) => ErrorKind::NotFound,
+            MemoryError::AlreadyExists(_) => ErrorKind::AlreadyExists,
+            MemoryError::NotADirectory(_) => ErrorKind::NotADirectory,
+            MemoryError::IsADirectory(_) => ErrorKind::IsADirectory,
+            MemoryError::DirIsNotEmpty(_) => ErrorKind::DirectoryNotEmpty,
+            MemoryError::InvalidPath | MemoryError::FileNotOpen => ErrorKind::InvalidInput,
+            MemoryError::FileCannotWrite => ErrorKind::ResourceBusy,
+            MemoryError::AccessError => ErrorKind::PermissionDenied,
+            MemoryError::Unsupported => ErrorKind::Unsupported,
+            MemoryError::Lock(LockError::WouldBlock) => ErrorKind::WouldBlock,
+            MemoryError::Lock(LockError::Deadlock) => ErrorKind::Deadlock,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+
+/// 文件或目录，目录没有数据
+struct Node {
+    path: Path,
+    is_dir: bool,
+    data: Vec<u8>,
+    ctime: u32,
+    mtime: u32,
+    owner: u32,
+}
+
+/// 数据全部放在内存中的文件系统，用来和 VerySimpleFileSystem 对照
+///
+/// 以路径为键存放所有文件和目录，退出后数据不保存；配额、快照等磁盘相关的功能不支持
+pub struct MemoryFileSystem {
+    nodes: HashMap<String, Node>,
+    open_files: HashMap<usize, (String, AccessMode, usize)>,  // 打开文件表，值是路径、模式和打开它的会话
+    next_id: usize,
+    locks: LockManager,
+    uid: u32,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        let mut fs = MemoryFileSystem {
+            nodes: HashMap::new(),
+            open_files: HashMap::new(),
+            next_id: 0,
+            locks: LockManager::new(),
+            uid: 0,
+        };
+        fs.insert(&Path::root(), true);
+        fs
+    }
+
+    /// 文件是否还打开着，返回锁的持有者：打开它的会话和文件描述符
+    fn check_open(&self, file: &MemoryFile) -> Result<LockOwner, MemoryError> {
+        self.open_files.get(&file.id)
+            .map(|(_, _, session)| LockOwner { session: *session, file: file.id })
+            .ok_or(MemoryError::FileNotOpen)
+    }
+
+    /// 在 session 中打开已经存在的文件
+    fn open_in(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<MemoryFile, MemoryError> {
+        self.node(path)?;
+
+        // 同一个文件同时只能有一个写者
+        let key = path.to_str();
+        if mode != AccessMode::Read && self.open_files.values()
+            .any(|(other, other_mode, _)| *other == key && *other_mode != AccessMode::Read) {
+            return Err(MemoryError::FileCannotWrite);
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.open_files.insert(id, (key, mode, session));
+
+        Ok(MemoryFile {
+            path: path.clone(),
+            mode,
+            position: 0,
+            append: false,
+            id,
+        })
+    }
+
+
+    fn insert(&mut self, path: &Path, is_dir: bool) {
+        let now = utils::time();
+        self.nodes.insert(path.to_str(), Node {
+            path: path.clone(),
+            is_dir,
+            data: Vec::new(),
+            ctime: now,
+            mtime: now,
+            owner: self.uid,
+        });
+    }
+
+    fn node(&self, path: &Path) -> Result<&Node, MemoryError> {
+        self.nodes.get(&path.to_str())
+            .ok_or(MemoryError::NotFound(path.clone()))
+    }
+
+    fn node_mut(&mut self, path: &Path) -> Result<&mut Node, MemoryError> {
+        self.nodes.get_mut(&path.to_str())
+            .ok_or(MemoryError::NotFound(path.clone()))
+    }
+
+    fn children<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a Node> {
+        self.nodes.values()
+            .filter(move |node| node.path.clone().parent().as_ref() == Some(path))
+    }
+
+    /// 检查 path 可以新建：父目录存在并且 path 不存在
+    fn check_new(&self, path: &Path) -> Result<(), MemoryError> {
+        let parent = path.clone().parent()
+            .ok_or(MemoryError::InvalidPath)?;
+        if !self.node(&parent)?.is_dir {
+            return Err(MemoryError::NotADirectory(parent));
+        }
+        if self.nodes.contains_key(&path.to_str()) {
+            return Err(MemoryError::AlreadyExists(path.clone()));
+        }
+        Ok(())
+    }
+
+    fn describe(node: &Node) -> MemoryFileDescription {
+        MemoryFileDescription {
+            name: node.path.current().cloned().unwrap_or_default(),
+            is_dir: node.is_dir,
+            ctime: node.ctime,
+            mtime: node.mtime,
+            size: node.data.len(),
+            owner: node.owner,
+        }
+    }
+}
+
+impl Default for MemoryFileSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl VirtualFileSystem for MemoryFileSystem {
+    type File = MemoryFile;
+    type Error = MemoryError;
+    type FileDescription = MemoryFileDescription;
+
+    fn init(&mut self) -> Result<(), Self::Error> {
+        *self = MemoryFileSystem::new();
+        Ok(())
+    }
+
+    fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, Self::Error> {
+        self.check_new(path)?;
+        self.insert(path, false);
+        self.node(path).map(Self::describe)
+    }
+
+    fn delete_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        if self.node(path)?.is_dir {
+            return Err(MemoryError::IsADirectory(path.clone()));
+        }
+        self.nodes.remove(&path.to_str());
+        Ok(())
+    }
+
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> Result<Self::FileDescription, Self::Error> {
+        let node = self.node(src)?;
+        if node.is_dir {
+            return Err(MemoryError::IsADirectory(src.clone()));
+        }
+        let data = node.data.clone();
+
+        self.check_new(dst)?;
+        self.insert(dst, false);
+        let node = self.node_mut(dst)?;
+        node.data = data;
+        Ok(Self::describe(node))
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error> {
+        self.open_in(0, path, mode)
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> Result<Self::File, Self::Error> {
+        let mode = options.access_mode()
+            .ok_or(MemoryError::AccessError)?;
+
+        let exists = self.nodes.contains_key(&path.to_str());
+        if options.create_new && exists {
+            return Err(MemoryError::AlreadyExists(path.clone()));
+        }
+        if options.creates() && !exists {
+            self.create_file(path)?;
+        }
+
+        let mut file = self.open_in(options.session, path, mode)?;
+        file.append = options.append;
+        if options.truncate {
+            let node = self.node_mut(path)?;
+            if node.is_dir {
+                self.open_files.remove(&file.id);
+                return Err(MemoryError::IsADirectory(path.clone()));
+            }
+            node.data.clear();
+            node.mtime = utils::time();
+        }
+        Ok(file)
+    }
+
+    fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, Self::Error> {
+        self.node(&file.path).map(Self::describe)
+    }
+
+    fn close(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        let owner = self.check_open(&file)?;
+        self.open_files.remove(&file.id);
+        self.locks.release_file(&file.path, owner);
+        Ok(())
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.read_at(file, buf, file.position)?;
+        file.position += len;
+        Ok(len)
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.check_open(file)?;
+        if file.append {
+            file.position = self.node(&file.path)?.data.len();
+        }
+
+        let len = self.write_at(file, buf, file.position)?;
+        file.position += len;
+        Ok(len)
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        self.check_open(file)?;
+
+        let node = self.node(&file.path)?;
+        if node.is_dir {
+            return Err(MemoryError::IsADirectory(file.path.clone()));
+        }
+
+        // offset 可能在文件末尾之后，这时读不到数据
+        let len = min(buf.len(), node.data.len().saturating_sub(offset));
+        if len > 0 {
+            buf[..len].copy_from_slice(&node.data[offset..offset + len]);
+        }
+        Ok(len)
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, Self::Error> {
+        self.check_open(file)?;
+        if file.mode == AccessMode::Read {
+            return Err(MemoryError::AccessError);
+        }
+
+        let node = self.node_mut(&file.path)?;
+        if node.is_dir {
+            return Err(MemoryError::IsADirectory(file.path.clone()));
+        }
+
+        // 写过末尾时文件变大，中间空出来的部分补 0，不会截断后面的数据
+        let end = offset + buf.len();
+        if node.data.len() < end {
+            node.data.resize(end, 0);
+        }
+        node.data[offset..end].copy_from_slice(buf);
+        node.mtime = utils::time();
+        Ok(buf.len())
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, Self::Error> {
+        let mut total = 0;
+        for buf in bufs.iter_mut() {
+            let len = self.read(file, buf)?;
+            total += len;
+            if len < buf.len() {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, Self::Error> {
+        self.write(file, &bufs.iter().flat_map(|buf| buf.iter().copied()).collect::<Vec<_>>())
+    }
+
+    /// 数据只在内存中，没有要写回的
+    fn fsync(&mut self, file: &Self::File) -> Result<(), Self::Error> {
+        self.check_open(file).map(|_| ())
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        if !self.node(path)?.is_dir {
+            return Err(MemoryError::NotADirectory(path.clone()));
+        }
+
+        let mut fds = self.children(path)
+            .map(Self::describe)
+            .collect::<Vec<_>>();
+        fds.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(fds)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.check_new(path)?;
+        self.insert(path, true);
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        if path.is_root() {
+            return Err(MemoryError::InvalidPath);
+        }
+        if !self.node(path)?.is_dir {
+            return Err(MemoryError::NotADirectory(path.clone()));
+        }
+        if self.children(path).next().is_some() {
+            return Err(MemoryError::DirIsNotEmpty(path.clone()));
+        }
+        self.nodes.remove(&path.to_str());
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        if from.is_root() {
+            return Err(MemoryError::InvalidPath);
+        }
+        self.node(from)?;
+        if to == from {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(MemoryError::InvalidPath);
+        }
+        self.check_new(to)?;
+
+        // 打开的文件按路径记录，移动之后就找不到了
+        if self.open_files.values()
+            .any(|(key, _, _)| Path::from_str(key).is_some_and(|open| open.starts_with(from))) {
+            return Err(MemoryError::FileCannotWrite);
+        }
+
+        let moved = self.nodes.values()
+            .filter(|node| node.path.starts_with(from))
+            .map(|node| node.path.to_str())
+            .collect::<Vec<_>>();
+        for key in moved {
+            let mut node = self.nodes.remove(&key).unwrap();
+            node.path = to.join(&node.path.strip_prefix(from).unwrap());
+            self.nodes.insert(node.path.to_str(), node);
+        }
+        Ok(())
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, Self::Error> {
+        Ok(self.nodes.contains_key(&path.to_str()))
+    }
+
+    fn user(&self) -> u32 {
+        self.uid
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.uid = uid;
+    }
+
+    fn end_session(&mut self, session: usize) -> Result<usize, Self::Error> {
+        let before = self.open_files.len();
+        self.open_files.retain(|_, (_, _, owner)| *owner != session);
+        self.locks.release_session(session);
+        Ok(before - self.open_files.len())
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, Self::Error> {
+        let key = path.to_str();
+        Ok(self.open_files.values()
+            .any(|(other, other_mode, owner)| *other == key && *other_mode == mode && *owner == session))
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, Self::Error> {
+        let owner = self.check_open(file)?;
+        self.locks.lock(&file.path, &file.path, owner, kind, range, wait)
+            .map_err(MemoryError::Lock)
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), Self::Error> {
+        let owner = self.check_open(file)?;
+        self.locks.unlock(&file.path, owner, range);
+        Ok(())
+    }
+
+    fn locks(&mut self) -> Result<Vec<LockInfo>, Self::Error> {
+        Ok(self.locks.locks())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::vfs::conformance;
+
+    #[test]
+    fn test_conformance() {
+        conformance::run_all(MemoryFileSystem::new);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let mut fs = MemoryFileSystem::new();
+        assert!(matches!(fs.snapshot_create("s1"), Err(MemoryError::Unsupported)));
+        assert!(matches!(fs.quotas(), Err(MemoryError::Unsupported)));
+    }
+}
@@ -0,0 +1,988 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+
+use crate::hostfs::{HostFile, HostFileDescription, HostFileSystem};
+use crate::memfs::{MemoryFile, MemoryFileDescription, MemoryFileSystem};
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::quota::{Limit, QuotaReport, QuotaTarget};
+use crate::repr::Disk;
+use crate::rw::AccessMode;
+use crate::vfs::{DedupReport, FragmentReport, MountInfo, OpenOptions, SnapshotDiff, SnapshotInfo, Unsupported, VirtualFile, VirtualFileDescription, VirtualFileSystem};
+use crate::vsfs_vfs::{VerySimpleFile, VerySimpleFileDescription, VerySimpleFileSystem};
+
+/// 宿主机目录的挂载来源前缀
+const HOST_PREFIX: &str = "host:";
+/// 内存文件系统的挂载来源
+const MEMORY_SOURCE: &str = "mem";
+
+/// 可以按来源字符串打开并挂载到挂载表中的文件系统
+pub trait Mountable: VirtualFileSystem + Sized {
+    /// 按来源打开文件系统
+    fn open_source(source: &str) -> io::Result<Self>;
+
+    /// 卸载，需要保存的数据在这里保存
+    fn unmount(self) -> io::Result<()>;
+}
+
+
+/// 挂载表中打开的文件，记住它属于哪个挂载点
+#[derive(Debug)]
+pub struct MountFile<T> {
+    path: Path,         // 挂载表中的完整路径
+    point: Path,        // 所在的挂载点
+    inner: T,
+}
+
+impl<T: VirtualFile> VirtualFile for MountFile<T> {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn mode(&self) -> AccessMode {
+        self.inner.mode()
+    }
+
+    fn position(&self) -> usize {
+        self.inner.position()
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        self.inner.set_position(pos)
+    }
+}
+
+
+#[derive(Debug)]
+pub enum MountError<E> {
+    Fs(E),
+    Io(io::Error),
+    NotMounted(Path),
+    AlreadyMounted(Path),
+    NotADirectory(Path),
+    Busy(Path),
+    CrossMount(Path, Path),
+}
+
+impl<E: Display> Display for MountError<E> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MountError::Fs(err) => Display::fmt(err, f),
+            MountError::Io(err) => Display::fmt(err, f),
+            MountError::NotMounted(path) => write!(f, "nothing is mounted at {}", path.to_str()),
+            MountError::AlreadyMounted(path) => write!(f, "{} is already a mount point", path.to_str()),
+            MountError::NotADirectory(path) => write!(f, "{} is not a directory", path.to_str()),
+            MountError::Busy(path) => write!(f, "{} is busy", path.to_str()),
+            MountError::CrossMount(from, to) => write!(f, "{} and {} are on different mounts", from.to_str(), to.to_str()),
+        }
+    }
+}
+
+impl<E: Error> Error for MountError<E> {}
+
+impl<E> From<Unsupported> for MountError<E> {
+    fn from(_: Unsupported) -> Self {
+        MountError::Io(Unsupported.into())
+    }
+}
+
+impl<E> From<MountError<E>> for io::Error
+where E: Error + Into<io::Error> + Send + Sync + 'static {
+    fn from(err: MountError<E>) -> Self {
+        let kind = match &err {
+            MountError::Fs(_) | MountError::Io(_) => ErrorKind::Other,
+            MountError::NotMounted(_) => ErrorKind::InvalidInput,
+            MountError::AlreadyMounted(_) | MountError::Busy(_) => ErrorKind::ResourceBusy,
+            MountError::NotADirectory(_) => ErrorKind::NotADirectory,
+            MountError::CrossMount(_, _) => ErrorKind::CrossesDevices,
+        };
+        match err {
+            MountError::Fs(err) => err.into(),
+            MountError::Io(err) => err,
+            err => io::Error::new(kind, err),
+        }
+    }
+}
+
+
+struct Mount<F> {
+    point: Path,
+    source: String,
+    fs: F,
+    open: usize,        // 打开的文件个数，不为 0 时不能卸载
+}
+
+/// 挂载表，把多个文件系统挂到同一棵目录树上
+///
+/// 每个路径交给挂载点是它最长前缀的文件系统处理，路径去掉挂载点之后传下去。
+/// 根目录总是挂载着一个文件系统，不能卸载；配额、快照等不针对路径的操作作用在根目录的文件系统上。
+pub struct MountTable<F: Mountable> {
+    mounts: Vec<Mount<F>>,      // 第一个是根目录
+    uid: u32,
+}
+
+type Result<T, F> = std::result::Result<T, MountError<<F as VirtualFileSystem>::Error>>;
+
+impl<F: Mountable> MountTable<F> {
+    pub fn new(source: &str, root: F) -> Self {
+        MountTable {
+            mounts: vec![Mount {
+                point: Path::root(),
+                source: source.to_string(),
+                fs: root,
+                open: 0,
+            }],
+            uid: 0,
+        }
+    }
+
+    /// 负责 path 的挂载，返回它的下标和 path 在其中的路径
+    fn find(&self, path: &Path) -> (usize, Path) {
+        let index = (0..self.mounts.len())
+            .filter(|index| path.starts_with(&self.mounts[*index].point))
+            .max_by_key(|index| self.mounts[*index].point.segs().len())
+            .unwrap();
+        (index, path.strip_prefix(&self.mounts[index].point).unwrap())
+    }
+
+    fn find_mut(&mut self, path: &Path) -> (&mut F, Path) {
+        let (index, rel) = self.find(path);
+        (&mut self.mounts[index].fs, rel)
+    }
+
+    /// 正好挂载在 point 的挂载
+    fn mount_at(&self, point: &Path) -> Option<usize> {
+        self.mounts.iter().position(|mount| mount.point == *point)
+    }
+
+    /// 打开的文件所在的挂载
+    fn mount_of<T>(&mut self, file: &MountFile<T>) -> Result<&mut Mount<F>, F> {
+        self.mounts.iter_mut()
+            .find(|mount| mount.point == file.point)
+            .ok_or(MountError::NotMounted(file.point.clone()))
+    }
+
+    /// path 本身或者它下面有没有别的挂载点
+    fn has_mount_below(&self, path: &Path) -> bool {
+        self.mounts.iter().skip(1).any(|mount| mount.point.starts_with(path))
+    }
+
+    /// 两个路径要在同一个挂载中，返回它们在其中的路径
+    fn same_mount(&self, from: &Path, to: &Path) -> Result<(usize, Path, Path), F> {
+        let (from_index, from_rel) = self.find(from);
+        let (to_index, to_rel) = self.find(to);
+        if from_index != to_index {
+            return Err(MountError::CrossMount(from.clone(), to.clone()));
+        }
+        Ok((from_index, from_rel, to_rel))
+    }
+
+    /// 把挂载中的路径换回挂载表中的路径
+    fn with_point(&self, index: usize, mut reports: Vec<FragmentReport>) -> Vec<FragmentReport> {
+        for report in reports.iter_mut() {
+            report.path = self.mounts[index].point.join(&report.path);
+        }
+        reports
+    }
+
+    /// 卸载所有文件系统，从最深的挂载点开始
+    pub fn unmount_all(mut self) -> io::Result<()> {
+        self.mounts.sort_by_key(|mount| mount.point.segs().len());
+        while let Some(mount) = self.mounts.pop() {
+            mount.fs.unmount()?;
+        }
+        Ok(())
+    }
+}
+
+
+impl<F: Mountable> VirtualFileSystem for MountTable<F> {
+    type File = MountFile<F::File>;
+    type Error = MountError<F::Error>;
+    type FileDescription = F::FileDescription;
+
+    fn init(&mut self) -> Result<(), F> {
+        for mount in self.mounts.iter_mut() {
+            mount.fs.init().map_err(MountError::Fs)?;
+        }
+        Ok(())
+    }
+
+    fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, F> {
+        let (fs, rel) = self.find_mut(path);
+        fs.create_file(&rel).map_err(MountError::Fs)
+    }
+
+    fn delete_file(&mut self, path: &Path) -> Result<(), F> {
+        let (fs, rel) = self.find_mut(path);
+        fs.delete_file(&rel).map_err(MountError::Fs)
+    }
+
+    /// 共享数据块只能在同一个文件系统中进行
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> Result<Self::FileDescription, F> {
+        let (index, src_rel, dst_rel) = self.same_mount(src, dst)?;
+        self.mounts[index].fs.clone_file(&src_rel, &dst_rel).map_err(MountError::Fs)
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, F> {
+        let (index, rel) = self.find(path);
+        let mount = &mut self.mounts[index];
+        let inner = mount.fs.open(&rel, mode).map_err(MountError::Fs)?;
+        mount.open += 1;
+
+        Ok(MountFile {
+            path: path.clone(),
+            point: mount.point.clone(),
+            inner,
+        })
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> Result<Self::File, F> {
+        let (index, rel) = self.find(path);
+        let mount = &mut self.mounts[index];
+        let inner = mount.fs.open_with(&rel, options).map_err(MountError::Fs)?;
+        mount.open += 1;
+
+        Ok(MountFile {
+            path: path.clone(),
+            point: mount.point.clone(),
+            inner,
+        })
+    }
+
+    fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, F> {
+        self.mount_of(file)?.fs.description(&file.inner).map_err(MountError::Fs)
+    }
+
+    fn close(&mut self, file: Self::File) -> Result<(), F> {
+        let mount = self.mount_of(&file)?;
+        mount.fs.close(file.inner).map_err(MountError::Fs)?;
+        mount.open -= 1;
+        Ok(())
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.read(&mut file.inner, buf).map_err(MountError::Fs)
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.write(&mut file.inner, buf).map_err(MountError::Fs)
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.read_at(&file.inner, buf, offset).map_err(MountError::Fs)
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.write_at(&file.inner, buf, offset).map_err(MountError::Fs)
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.readv(&mut file.inner, bufs).map_err(MountError::Fs)
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.writev(&mut file.inner, bufs).map_err(MountError::Fs)
+    }
+
+    fn fsync(&mut self, file: &Self::File) -> Result<(), F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.fsync(&file.inner).map_err(MountError::Fs)
+    }
+
+    /// 写回所有挂载的文件系统
+    fn sync(&mut self) -> Result<(), F> {
+        for mount in self.mounts.iter_mut() {
+            mount.fs.sync().map_err(MountError::Fs)?;
+        }
+        Ok(())
+    }
+
+    fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, F> {
+        let (fs, rel) = self.find_mut(path);
+        fs.list(&rel).map_err(MountError::Fs)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<(), F> {
+        let (fs, rel) = self.find_mut(path);
+        fs.mkdir(&rel).map_err(MountError::Fs)
+    }
+
+    fn rmdir(&mut self, path: &Path) -> Result<(), F> {
+        if self.mount_at(path).is_some() {
+            return Err(MountError::Busy(path.clone()));
+        }
+        let (fs, rel) = self.find_mut(path);
+        fs.rmdir(&rel).map_err(MountError::Fs)
+    }
+
+    /// 只能在同一个文件系统中移动，挂载点和包含挂载点的目录不能移动
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), F> {
+        if self.has_mount_below(from) {
+            return Err(MountError::Busy(from.clone()));
+        }
+        if self.mount_at(to).is_some() {
+            return Err(MountError::Busy(to.clone()));
+        }
+        let (index, from_rel, to_rel) = self.same_mount(from, to)?;
+        self.mounts[index].fs.rename(&from_rel, &to_rel).map_err(MountError::Fs)
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, F> {
+        let (fs, rel) = self.find_mut(path);
+        fs.exists(&rel).map_err(MountError::Fs)
+    }
+
+    fn user(&self) -> u32 {
+        self.uid
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.uid = uid;
+        for mount in self.mounts.iter_mut() {
+            mount.fs.set_user(uid);
+        }
+    }
+
+    /// 每个挂载各自关闭会话的文件，关闭之后挂载可能就可以卸载了
+    fn end_session(&mut self, session: usize) -> Result<usize, F> {
+        let mut closed = 0;
+        for mount in self.mounts.iter_mut() {
+            let count = mount.fs.end_session(session).map_err(MountError::Fs)?;
+            mount.open -= count;
+            closed += count;
+        }
+        Ok(closed)
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, F> {
+        let (fs, rel) = self.find_mut(path);
+        fs.is_open(session, &rel, mode).map_err(MountError::Fs)
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.lock(&file.inner, kind, range, wait).map_err(MountError::Fs)
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), F> {
+        let mount = self.mount_of(file)?;
+        mount.fs.unlock(&file.inner, range).map_err(MountError::Fs)
+    }
+
+    /// 锁的路径换回挂载表中的路径
+    fn locks(&mut self) -> Result<Vec<LockInfo>, F> {
+        let mut locks = Vec::new();
+        for mount in self.mounts.iter_mut() {
+            for mut info in mount.fs.locks().map_err(MountError::Fs)? {
+                info.path = mount.point.join(&info.path);
+                locks.push(info);
+            }
+        }
+        Ok(locks)
+    }
+
+    fn quotas(&mut self) -> Result<Vec<QuotaReport>, F> {
+        self.mounts[0].fs.quotas().map_err(MountError::Fs)
+    }
+
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> Result<(), F> {
+        match target {
+            QuotaTarget::User(_) => self.mounts[0].fs.set_quota(target, blocks, inodes),
+            QuotaTarget::Dir(path) => {
+                let (fs, rel) = self.find_mut(path);
+                fs.set_quota(&QuotaTarget::Dir(rel), blocks, inodes)
+            }
+        }.map_err(MountError::Fs)
+    }
+
+    fn set_grace_period(&mut self, grace_period: u32) -> Result<(), F> {
+        self.mounts[0].fs.set_grace_period(grace_period).map_err(MountError::Fs)
+    }
+
+    fn fragmentation(&mut self, path: &Path) -> Result<Vec<FragmentReport>, F> {
+        let (index, rel) = self.find(path);
+        let reports = self.mounts[index].fs.fragmentation(&rel).map_err(MountError::Fs)?;
+        Ok(self.with_point(index, reports))
+    }
+
+    fn defrag(&mut self, path: &Path) -> Result<Vec<FragmentReport>, F> {
+        let (index, rel) = self.find(path);
+        let reports = self.mounts[index].fs.defrag(&rel).map_err(MountError::Fs)?;
+        Ok(self.with_point(index, reports))
+    }
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> Result<(), F> {
+        let (fs, rel) = self.find_mut(path);
+        fs.set_compression(&rel, enabled).map_err(MountError::Fs)
+    }
+
+    fn dedup(&mut self) -> Result<DedupReport, F> {
+        self.mounts[0].fs.dedup().map_err(MountError::Fs)
+    }
+
+    fn snapshot_create(&mut self, name: &str) -> Result<(), F> {
+        self.mounts[0].fs.snapshot_create(name).map_err(MountError::Fs)
+    }
+
+    fn snapshots(&mut self) -> Result<Vec<SnapshotInfo>, F> {
+        self.mounts[0].fs.snapshots().map_err(MountError::Fs)
+    }
+
+    fn snapshot_delete(&mut self, name: &str) -> Result<(), F> {
+        self.mounts[0].fs.snapshot_delete(name).map_err(MountError::Fs)
+    }
+
+    fn snapshot_rollback(&mut self, name: &str) -> Result<(), F> {
+        self.mounts[0].fs.snapshot_rollback(name).map_err(MountError::Fs)
+    }
+
+    fn snapshot_diff(&mut self, name: &str) -> Result<SnapshotDiff, F> {
+        self.mounts[0].fs.snapshot_diff(name).map_err(MountError::Fs)
+    }
+
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> Result<Vec<Self::FileDescription>, F> {
+        self.mounts[0].fs.snapshot_list(name, path).map_err(MountError::Fs)
+    }
+
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> Result<Vec<u8>, F> {
+        self.mounts[0].fs.snapshot_read(name, path).map_err(MountError::Fs)
+    }
+
+    /// 挂载点必须是已经存在的目录
+    fn mount(&mut self, source: &str, point: &Path) -> Result<(), F> {
+        if self.mount_at(point).is_some() {
+            return Err(MountError::AlreadyMounted(point.clone()));
+        }
+        let (fs, rel) = self.find_mut(point);
+        if !fs.exists(&rel).map_err(MountError::Fs)? || fs.list(&rel).is_err() {
+            return Err(MountError::NotADirectory(point.clone()));
+        }
+
+        let mut fs = F::open_source(source).map_err(MountError::Io)?;
+        fs.set_user(self.uid);
+        self.mounts.push(Mount {
+            point: point.clone(),
+            source: source.to_string(),
+            fs,
+            open: 0,
+        });
+        Ok(())
+    }
+
+    /// 还有打开的文件或者下面还有挂载点时不能卸载
+    fn umount(&mut self, point: &Path) -> Result<(), F> {
+        let index = self.mount_at(point)
+            .ok_or(MountError::NotMounted(point.clone()))?;
+        if index == 0 || self.mounts[index].open > 0 {
+            return Err(MountError::Busy(point.clone()));
+        }
+        if self.mounts.iter().any(|mount| mount.point != *point && mount.point.starts_with(point)) {
+            return Err(MountError::Busy(point.clone()));
+        }
+
+        let mount = self.mounts.remove(index);
+        mount.fs.unmount().map_err(MountError::Io)
+    }
+
+    fn mounts(&mut self) -> Result<Vec<MountInfo>, F> {
+        Ok(self.mounts.iter()
+            .map(|mount| MountInfo {
+                point: mount.point.clone(),
+                source: mount.source.clone(),
+            })
+            .collect())
+    }
+}
+
+
+/// 挂载表中可以使用的文件系统
+///
+/// 来源是 `mem` 时挂载一个新的内存文件系统，`host:` 开头时挂载宿主机上的目录，其他都当作镜像文件，
+/// sync 和卸载时保存回原来的文件；加密的镜像使用环境变量 VSFS_PASSPHRASE 中的口令
+pub enum Backend {
+    Image {
+        fs: Box<VerySimpleFileSystem<'static>>,     // 比其他文件系统大得多，放在堆上
+    },
+    Host(HostFileSystem),
+    Memory(MemoryFileSystem),
+}
+
+#[derive(Debug)]
+pub enum BackendFile {
+    Image(VerySimpleFile),
+    Host(HostFile),
+    Memory(MemoryFile),
+}
+
+impl BackendFile {
+    fn inner(&self) -> &dyn VirtualFile {
+        match self {
+            BackendFile::Image(file) => file,
+            BackendFile::Host(file) => file,
+            BackendFile::Memory(file) => file,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn VirtualFile {
+        match self {
+            BackendFile::Image(file) => file,
+            BackendFile::Host(file) => file,
+            BackendFile::Memory(file) => file,
+        }
+    }
+}
+
+impl VirtualFile for BackendFile {
+    fn path(&self) -> &Path {
+        self.inner().path()
+    }
+
+    fn mode(&self) -> AccessMode {
+        self.inner().mode()
+    }
+
+    fn position(&self) -> usize {
+        self.inner().position()
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        self.inner_mut().set_position(pos)
+    }
+}
+
+#[derive(Debug)]
+pub enum BackendDescription {
+    Image(VerySimpleFileDescription),
+    Host(HostFileDescription),
+    Memory(MemoryFileDescription),
+}
+
+impl BackendDescription {
+    fn inner(&self) -> &dyn VirtualFileDescription {
+        match self {
+            BackendDescription::Image(fd) => fd,
+            BackendDescription::Host(fd) => fd,
+            BackendDescription::Memory(fd) => fd,
+        }
+    }
+}
+
+impl VirtualFileDescription for BackendDescription {
+    fn is_dir(&self) -> bool {
+        self.inner().is_dir()
+    }
+
+    fn name(&self) -> &str {
+        self.inner().name()
+    }
+
+    fn ctime(&self) -> u64 {
+        self.inner().ctime()
+    }
+
+    fn mtime(&self) -> u64 {
+        self.inner().mtime()
+    }
+
+    fn size(&self) -> usize {
+        self.inner().size()
+    }
+
+    fn owner(&self) -> u32 {
+        self.inner().owner()
+    }
+}
+
+/// 对每种后端执行同样的操作，错误都转换成 io::Error
+///
+/// 带上 wrap 时用同名的变体把结果包起来，带上 file 时文件必须来自同一种后端
+macro_rules! dispatch {
+    ($self:expr, $fs:ident => $body:expr) => {
+        match $self {
+            Backend::Image { fs: $fs, .. } => $body.map_err(io::Error::from),
+            Backend::Host($fs) => $body.map_err(io::Error::from),
+            Backend::Memory($fs) => $body.map_err(io::Error::from),
+        }
+    };
+    ($self:expr, $fs:ident => $body:expr, $wrap:ident) => {
+        match $self {
+            Backend::Image { fs: $fs, .. } => $body.map($wrap::Image).map_err(io::Error::from),
+            Backend::Host($fs) => $body.map($wrap::Host).map_err(io::Error::from),
+            Backend::Memory($fs) => $body.map($wrap::Memory).map_err(io::Error::from),
+        }
+    };
+    ($self:expr, $file:expr, $fs:ident, $f:ident => $body:expr) => {
+        match ($self, $file) {
+            (Backend::Image { fs: $fs, .. }, BackendFile::Image($f)) => $body.map_err(io::Error::from),
+            (Backend::Host($fs), BackendFile::Host($f)) => $body.map_err(io::Error::from),
+            (Backend::Memory($fs), BackendFile::Memory($f)) => $body.map_err(io::Error::from),
+            _ => Err(io::Error::new(ErrorKind::InvalidInput, "file is opened by another backend")),
+        }
+    };
+}
+
+/// 把一组描述用同名的变体包起来
+macro_rules! wrap_all {
+    ($wrap:path) => {
+        |fds| fds.into_iter().map($wrap).collect()
+    };
+}
+
+impl VirtualFileSystem for Backend {
+    type File = BackendFile;
+    type Error = io::Error;
+    type FileDescription = BackendDescription;
+
+    fn init(&mut self) -> io::Result<()> {
+        dispatch!(self, fs => fs.init())
+    }
+
+    fn create_file(&mut self, path: &Path) -> io::Result<Self::FileDescription> {
+        dispatch!(self, fs => fs.create_file(path), BackendDescription)
+    }
+
+    fn delete_file(&mut self, path: &Path) -> io::Result<()> {
+        dispatch!(self, fs => fs.delete_file(path))
+    }
+
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> io::Result<Self::FileDescription> {
+        dispatch!(self, fs => fs.clone_file(src, dst), BackendDescription)
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> io::Result<Self::File> {
+        dispatch!(self, fs => fs.open(path, mode), BackendFile)
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> io::Result<Self::File> {
+        dispatch!(self, fs => fs.open_with(path, options), BackendFile)
+    }
+
+    fn description(&mut self, file: &Self::File) -> io::Result<Self::FileDescription> {
+        match (self, file) {
+            (Backend::Image { fs, .. }, BackendFile::Image(f)) => fs.description(f).map(BackendDescription::Image).map_err(io::Error::from),
+            (Backend::Host(fs), BackendFile::Host(f)) => fs.description(f).map(BackendDescription::Host).map_err(io::Error::from),
+            (Backend::Memory(fs), BackendFile::Memory(f)) => fs.description(f).map(BackendDescription::Memory).map_err(io::Error::from),
+            _ => Err(io::Error::new(ErrorKind::InvalidInput, "file is opened by another backend")),
+        }
+    }
+
+    fn close(&mut self, file: Self::File) -> io::Result<()> {
+        dispatch!(self, file, fs, f => fs.close(f))
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(self, file, fs, f => fs.read(f, buf))
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> io::Result<usize> {
+        dispatch!(self, file, fs, f => fs.write(f, buf))
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> io::Result<usize> {
+        dispatch!(self, file, fs, f => fs.read_at(f, buf, offset))
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> io::Result<usize> {
+        dispatch!(self, file, fs, f => fs.write_at(f, buf, offset))
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        dispatch!(self, file, fs, f => fs.readv(f, bufs))
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        dispatch!(self, file, fs, f => fs.writev(f, bufs))
+    }
+
+    fn fsync(&mut self, file: &Self::File) -> io::Result<()> {
+        dispatch!(self, file, fs, f => fs.fsync(f))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        dispatch!(self, fs => fs.sync())
+    }
+
+    fn list(&mut self, path: &Path) -> io::Result<Vec<Self::FileDescription>> {
+        match self {
+            Backend::Image { fs, .. } => fs.list(path).map(wrap_all!(BackendDescription::Image)).map_err(io::Error::from),
+            Backend::Host(fs) => fs.list(path).map(wrap_all!(BackendDescription::Host)).map_err(io::Error::from),
+            Backend::Memory(fs) => fs.list(path).map(wrap_all!(BackendDescription::Memory)).map_err(io::Error::from),
+        }
+    }
+
+    fn mkdir(&mut self, path: &Path) -> io::Result<()> {
+        dispatch!(self, fs => fs.mkdir(path))
+    }
+
+    fn rmdir(&mut self, path: &Path) -> io::Result<()> {
+        dispatch!(self, fs => fs.rmdir(path))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        dispatch!(self, fs => fs.rename(from, to))
+    }
+
+    fn exists(&mut self, path: &Path) -> io::Result<bool> {
+        dispatch!(self, fs => fs.exists(path))
+    }
+
+    fn user(&self) -> u32 {
+        match self {
+            Backend::Image { fs, .. } => fs.user(),
+            Backend::Host(fs) => fs.user(),
+            Backend::Memory(fs) => fs.user(),
+        }
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        match self {
+            Backend::Image { fs, .. } => fs.set_user(uid),
+            Backend::Host(fs) => fs.set_user(uid),
+            Backend::Memory(fs) => fs.set_user(uid),
+        }
+    }
+
+    fn end_session(&mut self, session: usize) -> io::Result<usize> {
+        dispatch!(self, fs => fs.end_session(session))
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> io::Result<bool> {
+        dispatch!(self, fs => fs.is_open(session, path, mode))
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> io::Result<LockStatus> {
+        dispatch!(self, file, fs, f => fs.lock(f, kind, range, wait))
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> io::Result<()> {
+        dispatch!(self, file, fs, f => fs.unlock(f, range))
+    }
+
+    fn locks(&mut self) -> io::Result<Vec<LockInfo>> {
+        dispatch!(self, fs => fs.locks())
+    }
+
+    fn quotas(&mut self) -> io::Result<Vec<QuotaReport>> {
+        dispatch!(self, fs => fs.quotas())
+    }
+
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> io::Result<()> {
+        dispatch!(self, fs => fs.set_quota(target, blocks, inodes))
+    }
+
+    fn set_grace_period(&mut self, grace_period: u32) -> io::Result<()> {
+        dispatch!(self, fs => fs.set_grace_period(grace_period))
+    }
+
+    fn fragmentation(&mut self, path: &Path) -> io::Result<Vec<FragmentReport>> {
+        dispatch!(self, fs => fs.fragmentation(path))
+    }
+
+    fn defrag(&mut self, path: &Path) -> io::Result<Vec<FragmentReport>> {
+        dispatch!(self, fs => fs.defrag(path))
+    }
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> io::Result<()> {
+        dispatch!(self, fs => fs.set_compression(path, enabled))
+    }
+
+    fn dedup(&mut self) -> io::Result<DedupReport> {
+        dispatch!(self, fs => fs.dedup())
+    }
+
+    fn snapshot_create(&mut self, name: &str) -> io::Result<()> {
+        dispatch!(self, fs => fs.snapshot_create(name))
+    }
+
+    fn snapshots(&mut self) -> io::Result<Vec<SnapshotInfo>> {
+        dispatch!(self, fs => fs.snapshots())
+    }
+
+    fn snapshot_delete(&mut self, name: &str) -> io::Result<()> {
+        dispatch!(self, fs => fs.snapshot_delete(name))
+    }
+
+    fn snapshot_rollback(&mut self, name: &str) -> io::Result<()> {
+        dispatch!(self, fs => fs.snapshot_rollback(name))
+    }
+
+    fn snapshot_diff(&mut self, name: &str) -> io::Result<SnapshotDiff> {
+        dispatch!(self, fs => fs.snapshot_diff(name))
+    }
+
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> io::Result<Vec<Self::FileDescription>> {
+        match self {
+            Backend::Image { fs, .. } => fs.snapshot_list(name, path).map(wrap_all!(BackendDescription::Image)).map_err(io::Error::from),
+            Backend::Host(fs) => fs.snapshot_list(name, path).map(wrap_all!(BackendDescription::Host)).map_err(io::Error::from),
+            Backend::Memory(fs) => fs.snapshot_list(name, path).map(wrap_all!(BackendDescription::Memory)).map_err(io::Error::from),
+        }
+    }
+
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> io::Result<Vec<u8>> {
+        dispatch!(self, fs => fs.snapshot_read(name, path))
+    }
+
+    fn mount(&mut self, source: &str, point: &Path) -> io::Result<()> {
+        dispatch!(self, fs => fs.mount(source, point))
+    }
+
+    fn umount(&mut self, point: &Path) -> io::Result<()> {
+        dispatch!(self, fs => fs.umount(point))
+    }
+
+    fn mounts(&mut self) -> io::Result<Vec<MountInfo>> {
+        dispatch!(self, fs => fs.mounts())
+    }
+}
+
+impl Mountable for Backend {
+    fn open_source(source: &str) -> io::Result<Self> {
+        if source == MEMORY_SOURCE {
+            return Ok(Backend::Memory(MemoryFileSystem::new()));
+        }
+        if let Some(dir) = source.strip_prefix(HOST_PREFIX) {
+            return HostFileSystem::new(std::path::Path::new(dir)).map(Backend::Host);
+        }
+
+        let passphrase = std::env::var("VSFS_PASSPHRASE").ok();
+        let disk = Disk::load_with_passphrase(source, passphrase.as_deref())?;
+        let mut fs = VerySimpleFileSystem::with_disk(disk);
+        fs.set_image(source);
+        Ok(Backend::Image { fs: Box::new(fs) })
+    }
+
+    fn unmount(self) -> io::Result<()> {
+        match self {
+            Backend::Image { mut fs } => fs.sync().map_err(io::Error::from),
+            Backend::Host(_) | Backend::Memory(_) => Ok(()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::Savable;
+    use crate::vfs::conformance;
+    use crate::vsfs;
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    fn write<F: VirtualFileSystem>(fs: &mut F, path: &Path, data: &[u8]) {
+        let mut file = fs.open(path, AccessMode::Write).unwrap();
+        fs.write(&mut file, data).unwrap();
+        fs.close(file).unwrap();
+    }
+
+    fn read<F: VirtualFileSystem>(fs: &mut F, path: &Path) -> Vec<u8> {
+        let mut file = fs.open(path, AccessMode::Read).unwrap();
+        let mut buf = vec![0u8; fs.description(&file).unwrap().size()];
+        fs.read(&mut file, &mut buf).unwrap();
+        fs.close(file).unwrap();
+        buf
+    }
+
+    /// 根目录是内存文件系统，/img 挂载一个镜像，/host 挂载一个宿主机目录，/img/mem 再挂载一个内存文件系统
+    fn table(dir: &std::path::Path) -> MountTable<Backend> {
+        let image = dir.join("a.img");
+        let mut disk = Disk::new();
+        vsfs::init(&mut disk);
+        disk.save(&image).unwrap();
+        std::fs::create_dir(dir.join("host")).unwrap();
+
+        let mut fs = MountTable::new(MEMORY_SOURCE, Backend::open_source(MEMORY_SOURCE).unwrap());
+        fs.mkdir(&path("/img")).unwrap();
+        fs.mkdir(&path("/host")).unwrap();
+        fs.mount(image.to_str().unwrap(), &path("/img")).unwrap();
+        fs.mount(&format!("{}{}", HOST_PREFIX, dir.join("host").display()), &path("/host")).unwrap();
+        fs.mkdir(&path("/img/mem")).unwrap();
+        fs.mount(MEMORY_SOURCE, &path("/img/mem")).unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run_all(|| MountTable::new(MEMORY_SOURCE, Backend::open_source(MEMORY_SOURCE).unwrap()));
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = table(dir.path());
+        assert_eq!(fs.mounts().unwrap().len(), 4);
+
+        // 每个路径交给最长前缀的挂载
+        for name in ["/a", "/img/a", "/host/a", "/img/mem/a"] {
+            fs.create_file(&path(name)).unwrap();
+            write(&mut fs, &path(name), name.as_bytes());
+        }
+        for name in ["/a", "/img/a", "/host/a", "/img/mem/a"] {
+            assert_eq!(read(&mut fs, &path(name)), name.as_bytes());
+        }
+        assert_eq!(std::fs::read(dir.path().join("host/a")).unwrap(), b"/host/a");
+        assert!(!fs.exists(&path("/img/host")).unwrap());
+
+        let file = fs.open(&path("/img/mem/a"), AccessMode::Read).unwrap();
+        assert_eq!(file.path(), &path("/img/mem/a"));
+
+        // 有打开的文件或者下面有挂载点时不能卸载
+        assert!(matches!(fs.umount(&path("/img/mem")), Err(MountError::Busy(_))));
+        fs.close(file).unwrap();
+        assert!(matches!(fs.umount(&path("/img")), Err(MountError::Busy(_))));
+        assert!(matches!(fs.umount(&Path::root()), Err(MountError::Busy(_))));
+        assert!(matches!(fs.rmdir(&path("/img/mem")), Err(MountError::Busy(_))));
+        fs.umount(&path("/img/mem")).unwrap();
+        assert!(!fs.exists(&path("/img/mem/a")).unwrap());
+        assert!(matches!(fs.umount(&path("/img/mem")), Err(MountError::NotMounted(_))));
+
+        // 卸载之后镜像保存回原来的文件
+        fs.umount(&path("/img")).unwrap();
+        assert!(!fs.exists(&path("/img/a")).unwrap());
+        fs.mount(dir.path().join("a.img").to_str().unwrap(), &path("/img")).unwrap();
+        assert_eq!(read(&mut fs, &path("/img/a")), b"/img/a");
+
+        // 挂载点必须是目录
+        assert!(matches!(fs.mount(MEMORY_SOURCE, &path("/a")), Err(MountError::NotADirectory(_))));
+        assert!(matches!(fs.mount(MEMORY_SOURCE, &path("/missing")), Err(MountError::NotADirectory(_))));
+        assert!(matches!(fs.mount(MEMORY_SOURCE, &path("/img")), Err(MountError::AlreadyMounted(_))));
+        assert!(fs.mount("/missing.img", &path("/host")).is_err());
+        fs.unmount_all().unwrap();
+    }
+
+    #[test]
+    fn test_cross_mount() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut fs = table(dir.path());
+        fs.create_file(&path("/a")).unwrap();
+        fs.mkdir(&path("/img/d")).unwrap();
+        fs.create_file(&path("/img/d/b")).unwrap();
+
+        // 同一个挂载中可以移动
+        fs.rename(&path("/img/d/b"), &path("/img/c")).unwrap();
+        assert!(fs.exists(&path("/img/c")).unwrap());
+
+        let err = fs.rename(&path("/a"), &path("/img/a")).unwrap_err();
+        assert!(matches!(err, MountError::CrossMount(_, _)));
+        assert_eq!(io::Error::from(err).kind(), ErrorKind::CrossesDevices);
+        assert!(matches!(fs.rename(&path("/img/c"), &path("/img/mem/c")), Err(MountError::CrossMount(_, _))));
+        assert!(matches!(fs.clone_file(&path("/a"), &path("/host/a")), Err(MountError::CrossMount(_, _))));
+
+        // 挂载点和包含挂载点的目录不能移动
+        assert!(matches!(fs.rename(&path("/img/mem"), &path("/img/x")), Err(MountError::Busy(_))));
+        assert!(matches!(fs.rename(&path("/img"), &path("/x")), Err(MountError::Busy(_))));
+        assert!(matches!(fs.rename(&path("/a"), &path("/host")), Err(MountError::Busy(_))));
+        assert!(fs.exists(&path("/a")).unwrap());
+    }
+}
@@ -0,0 +1,824 @@
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::quota::{Limit, QuotaReport, QuotaTarget};
+use crate::rw::AccessMode;
+use crate::vfs::{DedupReport, FragmentReport, OpenOptions, SnapshotDiff, SnapshotInfo, Unsupported, VirtualFile, VirtualFileDescription, VirtualFileSystem};
+
+/// 上层中表示“下层的这个文件被删除了”的文件名前缀
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// 上层目录中有这个文件时，下层同名目录的内容全部被遮住
+const OPAQUE_NAME: &str = ".wh..wh..opq";
+
+/// 打开的文件，来自下层或上层
+#[derive(Debug)]
+pub enum OverlayFile<L, U> {
+    Lower(L),
+    Upper(U),
+}
+
+impl<L: VirtualFile, U: VirtualFile> VirtualFile for OverlayFile<L, U> {
+    fn path(&self) -> &Path {
+        match self {
+            OverlayFile::Lower(file) => file.path(),
+            OverlayFile::Upper(file) => file.path(),
+        }
+    }
+
+    fn mode(&self) -> AccessMode {
+        match self {
+            OverlayFile::Lower(file) => file.mode(),
+            OverlayFile::Upper(file) => file.mode(),
+        }
+    }
+
+    fn position(&self) -> usize {
+        match self {
+            OverlayFile::Lower(file) => file.position(),
+            OverlayFile::Upper(file) => file.position(),
+        }
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        match self {
+            OverlayFile::Lower(file) => file.set_position(pos),
+            OverlayFile::Upper(file) => file.set_position(pos),
+        }
+    }
+}
+
+/// 文件描述，来自下层或上层
+#[derive(Debug)]
+pub enum OverlayDescription<L, U> {
+    Lower(L),
+    Upper(U),
+}
+
+impl<L: VirtualFileDescription, U: VirtualFileDescription> OverlayDescription<L, U> {
+    fn inner(&self) -> &dyn VirtualFileDescription {
+        match self {
+            OverlayDescription::Lower(fd) => fd,
+            OverlayDescription::Upper(fd) => fd,
+        }
+    }
+}
+
+impl<L: VirtualFileDescription, U: VirtualFileDescription> VirtualFileDescription for OverlayDescription<L, U> {
+    fn is_dir(&self) -> bool {
+        self.inner().is_dir()
+    }
+
+    fn name(&self) -> &str {
+        self.inner().name()
+    }
+
+    fn ctime(&self) -> u64 {
+        self.inner().ctime()
+    }
+
+    fn mtime(&self) -> u64 {
+        self.inner().mtime()
+    }
+
+    fn size(&self) -> usize {
+        self.inner().size()
+    }
+
+    fn owner(&self) -> u32 {
+        self.inner().owner()
+    }
+}
+
+
+#[derive(Debug)]
+pub enum OverlayError<L, U> {
+    Lower(L),
+    Upper(U),
+    NotFound(Path),
+    FileExist(Path),
+    NotADirectory(Path),
+    IsADirectory(Path),
+    DirIsNotEmpty(Path),
+    CrossDevice(Path),
+    InvalidPath,
+    Unsupported,
+}
+
+impl<L: Display, U: Display> Display for OverlayError<L, U> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverlayError::Lower(err) => write!(f, "lower: {}", err),
+            OverlayError::Upper(err) => write!(f, "upper: {}", err),
+            OverlayError::NotFound(path) => write!(f, "path {} not found", path.to_str()),
+            OverlayError::FileExist(path) => write!(f, "file {} is already exist", path.to_str()),
+            OverlayError::NotADirectory(path) => write!(f, "{} is not a directory", path.to_str()),
+            OverlayError::IsADirectory(path) => write!(f, "{} is a directory", path.to_str()),
+            OverlayError::DirIsNotEmpty(path) => write!(f, "dir {} is not empty", path.to_str()),
+            OverlayError::CrossDevice(path) => write!(f, "dir {} is in the lower layer and cannot be moved", path.to_str()),
+            OverlayError::InvalidPath => write!(f, "invalid path"),
+            OverlayError::Unsupported => write!(f, "not supported by the overlay file system"),
+        }
+    }
+}
+
+impl<L: Error, U: Error> Error for OverlayError<L, U> {}
+
+impl<L, U> From<Unsupported> for OverlayError<L, U> {
+    fn from(_: Unsupported) -> Self {
+        OverlayError::Unsupported
+    }
+}
+
+impl<L, U> From<OverlayError<L, U>> for io::Error
+where L: Error + Into<io::Error> + Send + Sync + 'static, U: Error + Into<io::Error> + Send + Sync + 'static {
+    fn from(err: OverlayError<L, U>) -> Self {
+        let kind = match &err {
+            OverlayError::Lower(_) | OverlayError::Upper(_) => ErrorKind::Other,
+            OverlayError::NotFound(_) => ErrorKind::NotFound,
+            OverlayError::FileExist(_) => ErrorKind::AlreadyExists,
+            OverlayError::NotADirectory(_) => ErrorKind::NotADirectory,
+            OverlayError::IsADirectory(_) => ErrorKind::IsADirectory,
+            OverlayError::DirIsNotEmpty(_) => ErrorKind::DirectoryNotEmpty,
+            OverlayError::CrossDevice(_) => ErrorKind::CrossesDevices,
+            OverlayError::InvalidPath => ErrorKind::InvalidInput,
+            OverlayError::Unsupported => ErrorKind::Unsupported,
+        };
+        match err {
+            OverlayError::Lower(err) => err.into(),
+            OverlayError::Upper(err) => err.into(),
+            err => io::Error::new(kind, err),
+        }
+    }
+}
+
+
+/// 把只读的下层和可写的上层叠在一起
+///
+/// 读取时上层优先，打开写入时先把文件从下层复制到上层；删除下层的文件时在上层留下 `.wh.` 开头的标记，
+/// 目录被删除后重新创建时留下 `.wh..wh..opq` 标记，遮住下层目录原来的内容。
+/// 下层永远不会被修改，配额、快照等功能都作用在上层。
+pub struct OverlayFileSystem<L: VirtualFileSystem, U: VirtualFileSystem> {
+    lower: L,
+    upper: U,
+}
+
+type Description<L, U> = OverlayDescription<<L as VirtualFileSystem>::FileDescription, <U as VirtualFileSystem>::FileDescription>;
+type Result<T, L, U> = std::result::Result<T, OverlayError<<L as VirtualFileSystem>::Error, <U as VirtualFileSystem>::Error>>;
+
+/// path 对应的删除标记
+fn whiteout(path: &Path) -> Option<Path> {
+    let name = path.current()?;
+    let whiteout = format!("{}{}", WHITEOUT_PREFIX, name);
+    Some(path.clone().parent()?.move_push(whiteout))
+}
+
+/// 用户不能直接使用标记文件名
+fn check_name(path: &Path) -> std::result::Result<(), ()> {
+    if path.iter().any(|seg| seg.starts_with(WHITEOUT_PREFIX)) {
+        Err(())
+    } else {
+        Ok(())
+    }
+}
+
+impl<L: VirtualFileSystem, U: VirtualFileSystem> OverlayFileSystem<L, U> {
+    pub fn new(lower: L, upper: U) -> Self {
+        OverlayFileSystem { lower, upper }
+    }
+
+    fn check_name(path: &Path) -> Result<(), L, U> {
+        check_name(path).map_err(|_| OverlayError::InvalidPath)
+    }
+
+    fn in_upper(&mut self, path: &Path) -> Result<bool, L, U> {
+        self.upper.exists(path).map_err(OverlayError::Upper)
+    }
+
+    /// 下层的 path 是否被上层遮住了：路径上有删除标记、不透明目录，或者上级目录在上层是文件
+    fn hidden(&mut self, path: &Path) -> Result<bool, L, U> {
+        let mut current = Path::root();
+        for seg in path.iter() {
+            if !current.is_root() && self.in_upper(&current)? && self.upper.list(&current).is_err() {
+                return Ok(true);
+            }
+            if self.in_upper(&current.clone().move_push(OPAQUE_NAME.to_string()))? {
+                return Ok(true);
+            }
+            current.push(seg.clone());
+            if self.in_upper(&whiteout(&current).unwrap())? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// 下层的 path 是否可见
+    fn in_lower(&mut self, path: &Path) -> Result<bool, L, U> {
+        Ok(!self.hidden(path)? && self.lower.exists(path).map_err(OverlayError::Lower)?)
+    }
+
+    /// 合并之后 path 的描述，不存在时返回 None
+    fn describe(&mut self, path: &Path) -> Result<Option<Description<L, U>>, L, U> {
+        let (Some(name), Some(parent)) = (path.current().cloned(), path.clone().parent()) else {
+            return Ok(None);
+        };
+
+        if self.in_upper(path)? {
+            let fd = self.upper.list(&parent).map_err(OverlayError::Upper)?
+                .into_iter()
+                .find(|fd| fd.name() == name);
+            Ok(fd.map(OverlayDescription::Upper))
+        } else if self.in_lower(path)? {
+            let fd = self.lower.list(&parent).map_err(OverlayError::Lower)?
+                .into_iter()
+                .find(|fd| fd.name() == name);
+            Ok(fd.map(OverlayDescription::Lower))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// path 是否是目录，不存在时返回 None
+    fn is_dir(&mut self, path: &Path) -> Result<Option<bool>, L, U> {
+        if path.is_root() {
+            return Ok(Some(true));
+        }
+        Ok(self.describe(path)?.map(|fd| fd.is_dir()))
+    }
+
+    /// 在上层创建 path 和它的所有上级目录
+    fn copy_up_dir(&mut self, path: &Path) -> Result<(), L, U> {
+        let mut current = Path::root();
+        for seg in path.iter() {
+            current.push(seg.clone());
+            if !self.in_upper(&current)? {
+                self.upper.mkdir(&current).map_err(OverlayError::Upper)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 把下层的文件复制到上层，之后对它的修改都在上层进行
+    fn copy_up(&mut self, path: &Path) -> Result<(), L, U> {
+        if self.in_upper(path)? {
+            return Ok(());
+        }
+        match self.is_dir(path)? {
+            None => return Err(OverlayError::NotFound(path.clone())),
+            Some(true) => return self.copy_up_dir(path),
+            Some(false) => {}
+        }
+
+        let mut file = self.lower.open(path, AccessMode::Read).map_err(OverlayError::Lower)?;
+        let size = self.lower.description(&file).map_err(OverlayError::Lower)?.size();
+        let mut data = vec![0u8; size];
+        let res = self.lower.read(&mut file, &mut data);
+        self.lower.close(file).map_err(OverlayError::Lower)?;
+        res.map_err(OverlayError::Lower)?;
+
+        self.copy_up_dir(&path.clone().parent().unwrap())?;
+        self.upper.create_file(path).map_err(OverlayError::Upper)?;
+        let mut file = self.upper.open(path, AccessMode::Write).map_err(OverlayError::Upper)?;
+        let res = self.upper.write(&mut file, &data);
+        self.upper.close(file).map_err(OverlayError::Upper)?;
+        res.map(|_| ()).map_err(OverlayError::Upper)
+    }
+
+    /// 准备在上层新建 path：检查父目录，复制父目录，去掉删除标记，返回是否去掉了标记
+    fn prepare_new(&mut self, path: &Path) -> Result<bool, L, U> {
+        Self::check_name(path)?;
+        let parent = path.clone().parent()
+            .ok_or(OverlayError::InvalidPath)?;
+        match self.is_dir(&parent)? {
+            Some(true) => {}
+            Some(false) => return Err(OverlayError::NotADirectory(parent)),
+            None => return Err(OverlayError::NotFound(parent)),
+        }
+        if self.exists(path)? {
+            return Err(OverlayError::FileExist(path.clone()));
+        }
+
+        self.copy_up_dir(&parent)?;
+        let whiteout = whiteout(path).unwrap();
+        if self.in_upper(&whiteout)? {
+            self.upper.delete_file(&whiteout).map_err(OverlayError::Upper)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// 下层的 path 还能看到时，在上层留下删除标记
+    fn add_whiteout(&mut self, path: &Path) -> Result<(), L, U> {
+        if !self.in_lower(path)? {
+            return Ok(());
+        }
+        self.copy_up_dir(&path.clone().parent().unwrap())?;
+        self.upper.create_file(&whiteout(path).unwrap())
+            .map(|_| ())
+            .map_err(OverlayError::Upper)
+    }
+}
+
+
+impl<L: VirtualFileSystem, U: VirtualFileSystem> VirtualFileSystem for OverlayFileSystem<L, U> {
+    type File = OverlayFile<L::File, U::File>;
+    type Error = OverlayError<L::Error, U::Error>;
+    type FileDescription = OverlayDescription<L::FileDescription, U::FileDescription>;
+
+    /// 清空上层，回到下层原来的样子
+    fn init(&mut self) -> Result<(), L, U> {
+        self.upper.init().map_err(OverlayError::Upper)
+    }
+
+    fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, L, U> {
+        self.prepare_new(path)?;
+        self.upper.create_file(path)
+            .map(OverlayDescription::Upper)
+            .map_err(OverlayError::Upper)
+    }
+
+    fn delete_file(&mut self, path: &Path) -> Result<(), L, U> {
+        match self.is_dir(path)? {
+            None => return Err(OverlayError::NotFound(path.clone())),
+            Some(true) => return Err(OverlayError::IsADirectory(path.clone())),
+            Some(false) => {}
+        }
+
+        if self.in_upper(path)? {
+            self.upper.delete_file(path).map_err(OverlayError::Upper)?;
+        }
+        self.add_whiteout(path)
+    }
+
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> Result<Self::FileDescription, L, U> {
+        match self.is_dir(src)? {
+            None => return Err(OverlayError::NotFound(src.clone())),
+            Some(true) => return Err(OverlayError::IsADirectory(src.clone())),
+            Some(false) => {}
+        }
+
+        // 源文件先复制到上层，然后在上层克隆
+        self.copy_up(src)?;
+        self.prepare_new(dst)?;
+        self.upper.clone_file(src, dst)
+            .map(OverlayDescription::Upper)
+            .map_err(OverlayError::Upper)
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, L, U> {
+        self.open_with(path, &OpenOptions::from(mode))
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> Result<Self::File, L, U> {
+        Self::check_name(path)?;
+        // 选项不合法时交给上层报错
+        let Some(mode) = options.access_mode() else {
+            return self.upper.open_with(path, options)
+                .map(OverlayFile::Upper)
+                .map_err(OverlayError::Upper);
+        };
+
+        let exists = self.exists(path)?;
+        if options.create_new && exists {
+            return Err(OverlayError::FileExist(path.clone()));
+        }
+        if !exists {
+            if !options.creates() {
+                return Err(OverlayError::NotFound(path.clone()));
+            }
+            self.create_file(path)?;
+        }
+
+        if mode != AccessMode::Read {
+            self.copy_up(path)?;
+        }
+
+        // 文件已经在要打开的那一层中了
+        let options = options.existing();
+        if self.in_upper(path)? {
+            self.upper.open_with(path, &options)
+                .map(OverlayFile::Upper)
+                .map_err(OverlayError::Upper)
+        } else {
+            self.lower.open_with(path, &options)
+                .map(OverlayFile::Lower)
+                .map_err(OverlayError::Lower)
+        }
+    }
+
+    fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.description(file)
+                .map(OverlayDescription::Lower)
+                .map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.description(file)
+                .map(OverlayDescription::Upper)
+                .map_err(OverlayError::Upper),
+        }
+    }
+
+    fn close(&mut self, file: Self::File) -> Result<(), L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.close(file).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.close(file).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.read(file, buf).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.read(file, buf).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, L, U> {
+        match file {
+            // 下层只能以只读方式打开
+            OverlayFile::Lower(file) => self.lower.write(file, buf).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.write(file, buf).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.read_at(file, buf, offset).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.read_at(file, buf, offset).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.write_at(file, buf, offset).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.write_at(file, buf, offset).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.readv(file, bufs).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.readv(file, bufs).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.writev(file, bufs).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.writev(file, bufs).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn fsync(&mut self, file: &Self::File) -> Result<(), L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.fsync(file).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.fsync(file).map_err(OverlayError::Upper),
+        }
+    }
+
+    /// 下层不会被修改，只需要写回上层
+    fn sync(&mut self) -> Result<(), L, U> {
+        self.upper.sync().map_err(OverlayError::Upper)
+    }
+
+    fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, L, U> {
+        match self.is_dir(path)? {
+            None => return Err(OverlayError::NotFound(path.clone())),
+            Some(false) => return Err(OverlayError::NotADirectory(path.clone())),
+            Some(true) => {}
+        }
+
+        let mut fds = Vec::new();
+        if self.in_upper(path)? {
+            for fd in self.upper.list(path).map_err(OverlayError::Upper)? {
+                if !fd.name().starts_with(WHITEOUT_PREFIX) {
+                    fds.push(OverlayDescription::Upper(fd));
+                }
+            }
+        }
+
+        let opaque = path.clone().move_push(OPAQUE_NAME.to_string());
+        if !self.in_upper(&opaque)? && self.in_lower(path)? {
+            for fd in self.lower.list(path).map_err(OverlayError::Lower)? {
+                let child = path.clone().move_push(fd.name().to_string());
+                if fds.iter().any(|other| other.name() == fd.name()) || self.in_upper(&whiteout(&child).unwrap())? {
+                    continue;
+                }
+                fds.push(OverlayDescription::Lower(fd));
+            }
+        }
+
+        fds.sort_by(|a, b| a.name().cmp(b.name()));
+        Ok(fds)
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<(), L, U> {
+        let replaced = self.prepare_new(path)?;
+        self.upper.mkdir(path).map_err(OverlayError::Upper)?;
+
+        // 下层同名的目录被删除过，它原来的内容不能再出现
+        if replaced {
+            let opaque = path.clone().move_push(OPAQUE_NAME.to_string());
+            self.upper.create_file(&opaque).map_err(OverlayError::Upper)?;
+        }
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &Path) -> Result<(), L, U> {
+        if path.is_root() {
+            return Err(OverlayError::InvalidPath);
+        }
+        match self.is_dir(path)? {
+            None => return Err(OverlayError::NotFound(path.clone())),
+            Some(false) => return Err(OverlayError::NotADirectory(path.clone())),
+            Some(true) => {}
+        }
+        if !self.list(path)?.is_empty() {
+            return Err(OverlayError::DirIsNotEmpty(path.clone()));
+        }
+
+        if self.in_upper(path)? {
+            // 先删掉目录中的标记
+            for fd in self.upper.list(path).map_err(OverlayError::Upper)? {
+                let marker = path.clone().move_push(fd.name().to_string());
+                self.upper.delete_file(&marker).map_err(OverlayError::Upper)?;
+            }
+            self.upper.rmdir(path).map_err(OverlayError::Upper)?;
+        }
+        self.add_whiteout(path)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), L, U> {
+        Self::check_name(from)?;
+        if from.is_root() {
+            return Err(OverlayError::InvalidPath);
+        }
+        let is_dir = self.is_dir(from)?
+            .ok_or(OverlayError::NotFound(from.clone()))?;
+        if to == from {
+            return Ok(());
+        }
+        if to.starts_with(from) {
+            return Err(OverlayError::InvalidPath);
+        }
+
+        // 和 Linux 的 overlayfs 一样，下层能看到的目录不能移动，否则要把整棵树复制上来
+        if is_dir && self.in_lower(from)? {
+            return Err(OverlayError::CrossDevice(from.clone()));
+        }
+
+        let replaced = self.prepare_new(to)?;
+        self.copy_up(from)?;
+        self.upper.rename(from, to).map_err(OverlayError::Upper)?;
+        self.add_whiteout(from)?;
+
+        // 和 mkdir 一样，遮住下层同名目录原来的内容
+        let opaque = to.clone().move_push(OPAQUE_NAME.to_string());
+        if is_dir && replaced && !self.in_upper(&opaque)? {
+            self.upper.create_file(&opaque).map_err(OverlayError::Upper)?;
+        }
+        Ok(())
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, L, U> {
+        if check_name(path).is_err() {
+            return Ok(false);
+        }
+        Ok(self.in_upper(path)? || self.in_lower(path)?)
+    }
+
+    fn user(&self) -> u32 {
+        self.upper.user()
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.lower.set_user(uid);
+        self.upper.set_user(uid);
+    }
+
+    fn end_session(&mut self, session: usize) -> Result<usize, L, U> {
+        let lower = self.lower.end_session(session).map_err(OverlayError::Lower)?;
+        let upper = self.upper.end_session(session).map_err(OverlayError::Upper)?;
+        Ok(lower + upper)
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, L, U> {
+        Ok(self.upper.is_open(session, path, mode).map_err(OverlayError::Upper)?
+            || self.lower.is_open(session, path, mode).map_err(OverlayError::Lower)?)
+    }
+
+    /// 锁由文件所在的层管理，复制到上层之前和之后打开的文件互相看不到对方的锁
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.lock(file, kind, range, wait).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.lock(file, kind, range, wait).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), L, U> {
+        match file {
+            OverlayFile::Lower(file) => self.lower.unlock(file, range).map_err(OverlayError::Lower),
+            OverlayFile::Upper(file) => self.upper.unlock(file, range).map_err(OverlayError::Upper),
+        }
+    }
+
+    fn locks(&mut self) -> Result<Vec<LockInfo>, L, U> {
+        let mut locks = self.lower.locks().map_err(OverlayError::Lower)?;
+        locks.extend(self.upper.locks().map_err(OverlayError::Upper)?);
+        Ok(locks)
+    }
+
+    fn quotas(&mut self) -> Result<Vec<QuotaReport>, L, U> {
+        self.upper.quotas().map_err(OverlayError::Upper)
+    }
+
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> Result<(), L, U> {
+        self.upper.set_quota(target, blocks, inodes).map_err(OverlayError::Upper)
+    }
+
+    fn set_grace_period(&mut self, grace_period: u32) -> Result<(), L, U> {
+        self.upper.set_grace_period(grace_period).map_err(OverlayError::Upper)
+    }
+
+    fn fragmentation(&mut self, path: &Path) -> Result<Vec<FragmentReport>, L, U> {
+        self.upper.fragmentation(path).map_err(OverlayError::Upper)
+    }
+
+    fn defrag(&mut self, path: &Path) -> Result<Vec<FragmentReport>, L, U> {
+        self.upper.defrag(path).map_err(OverlayError::Upper)
+    }
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> Result<(), L, U> {
+        self.copy_up(path)?;
+        self.upper.set_compression(path, enabled).map_err(OverlayError::Upper)
+    }
+
+    fn dedup(&mut self) -> Result<DedupReport, L, U> {
+        self.upper.dedup().map_err(OverlayError::Upper)
+    }
+
+    fn snapshot_create(&mut self, name: &str) -> Result<(), L, U> {
+        self.upper.snapshot_create(name).map_err(OverlayError::Upper)
+    }
+
+    fn snapshots(&mut self) -> Result<Vec<SnapshotInfo>, L, U> {
+        self.upper.snapshots().map_err(OverlayError::Upper)
+    }
+
+    fn snapshot_delete(&mut self, name: &str) -> Result<(), L, U> {
+        self.upper.snapshot_delete(name).map_err(OverlayError::Upper)
+    }
+
+    fn snapshot_rollback(&mut self, name: &str) -> Result<(), L, U> {
+        self.upper.snapshot_rollback(name).map_err(OverlayError::Upper)
+    }
+
+    fn snapshot_diff(&mut self, name: &str) -> Result<SnapshotDiff, L, U> {
+        self.upper.snapshot_diff(name).map_err(OverlayError::Upper)
+    }
+
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> Result<Vec<Self::FileDescription>, L, U> {
+        self.upper.snapshot_list(name, path)
+            .map(|fds| fds.into_iter().map(OverlayDescription::Upper).collect())
+            .map_err(OverlayError::Upper)
+    }
+
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> Result<Vec<u8>, L, U> {
+        self.upper.snapshot_read(name, path).map_err(OverlayError::Upper)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memfs::MemoryFileSystem;
+    use crate::vfs::conformance;
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    fn write<F: VirtualFileSystem>(fs: &mut F, path: &Path, data: &[u8]) {
+        let mut file = fs.open(path, AccessMode::Write).unwrap();
+        fs.write(&mut file, data).unwrap();
+        fs.close(file).unwrap();
+    }
+
+    fn read<F: VirtualFileSystem>(fs: &mut F, path: &Path) -> Vec<u8> {
+        let mut file = fs.open(path, AccessMode::Read).unwrap();
+        let mut buf = vec![0u8; fs.description(&file).unwrap().size()];
+        fs.read(&mut file, &mut buf).unwrap();
+        fs.close(file).unwrap();
+        buf
+    }
+
+    fn names<F: VirtualFileSystem>(fs: &mut F, path: &Path) -> Vec<String> {
+        fs.list(path).unwrap().iter()
+            .map(|fd| fd.name().to_string())
+            .collect()
+    }
+
+    /// 下层有 /base、/etc/conf 和 /etc/old
+    fn base() -> MemoryFileSystem {
+        let mut lower = MemoryFileSystem::new();
+        lower.create_file(&path("/base")).unwrap();
+        write(&mut lower, &path("/base"), b"base");
+        lower.mkdir(&path("/etc")).unwrap();
+        lower.create_file(&path("/etc/conf")).unwrap();
+        write(&mut lower, &path("/etc/conf"), b"lower conf");
+        lower.create_file(&path("/etc/old")).unwrap();
+        lower
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run_all(|| OverlayFileSystem::new(MemoryFileSystem::new(), MemoryFileSystem::new()));
+    }
+
+    #[test]
+    fn test_copy_up() {
+        let mut fs = OverlayFileSystem::new(base(), MemoryFileSystem::new());
+        fs.create_file(&path("/etc/new")).unwrap();
+        assert_eq!(names(&mut fs, &Path::root()), vec!["base", "etc"]);
+        assert_eq!(names(&mut fs, &path("/etc")), vec!["conf", "new", "old"]);
+
+        // 只读打开不复制
+        assert_eq!(read(&mut fs, &path("/etc/conf")), b"lower conf");
+        assert!(!fs.upper.exists(&path("/etc/conf")).unwrap());
+
+        // 打开写入时复制到上层，下层不变
+        let mut file = fs.open(&path("/etc/conf"), AccessMode::ReadWrite).unwrap();
+        file.set_position(6);
+        fs.write(&mut file, b"CONF").unwrap();
+        fs.close(file).unwrap();
+        assert_eq!(read(&mut fs, &path("/etc/conf")), b"lower CONF");
+        assert_eq!(read(&mut fs.lower, &path("/etc/conf")), b"lower conf");
+    }
+
+    #[test]
+    fn test_whiteout() {
+        let mut fs = OverlayFileSystem::new(base(), MemoryFileSystem::new());
+
+        // 删除下层的文件留下标记，标记不出现在列表中
+        fs.delete_file(&path("/etc/old")).unwrap();
+        assert!(!fs.exists(&path("/etc/old")).unwrap());
+        assert!(fs.open(&path("/etc/old"), AccessMode::Read).is_err());
+        assert_eq!(names(&mut fs, &path("/etc")), vec!["conf"]);
+        assert!(fs.lower.exists(&path("/etc/old")).unwrap());
+        assert!(fs.create_file(&path("/etc/.wh.conf")).is_err());
+
+        // 重新创建之后是新的空文件
+        fs.create_file(&path("/etc/old")).unwrap();
+        assert!(read(&mut fs, &path("/etc/old")).is_empty());
+
+        // 上层的文件遮住下层同名目录中的内容
+        let mut other = OverlayFileSystem::new(base(), MemoryFileSystem::new());
+        other.delete_file(&path("/etc/conf")).unwrap();
+        other.delete_file(&path("/etc/old")).unwrap();
+        other.rmdir(&path("/etc")).unwrap();
+        other.create_file(&path("/etc")).unwrap();
+        assert!(!other.exists(&path("/etc/conf")).unwrap());
+
+        // 删除整个目录之后再创建，下层的内容不会出现
+        fs.delete_file(&path("/etc/old")).unwrap();
+        assert!(fs.rmdir(&path("/etc")).is_err());
+        fs.delete_file(&path("/etc/conf")).unwrap();
+        fs.rmdir(&path("/etc")).unwrap();
+        assert_eq!(names(&mut fs, &Path::root()), vec!["base"]);
+        fs.mkdir(&path("/etc")).unwrap();
+        assert!(names(&mut fs, &path("/etc")).is_empty());
+        assert!(!fs.exists(&path("/etc/conf")).unwrap());
+
+        // 清空上层之后回到下层原来的样子
+        fs.init().unwrap();
+        assert_eq!(names(&mut fs, &path("/etc")), vec!["conf", "old"]);
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut fs = OverlayFileSystem::new(base(), MemoryFileSystem::new());
+
+        // 下层的文件先复制上来再移动，原来的位置留下删除标记
+        fs.rename(&path("/etc/conf"), &path("/conf")).unwrap();
+        assert_eq!(read(&mut fs, &path("/conf")), b"lower conf");
+        assert!(!fs.exists(&path("/etc/conf")).unwrap());
+        assert!(fs.lower.exists(&path("/etc/conf")).unwrap());
+
+        // 下层能看到的目录不能移动，只在上层的目录可以
+        let err = fs.rename(&path("/etc"), &path("/etc2")).unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), ErrorKind::CrossesDevices);
+        fs.mkdir(&path("/new")).unwrap();
+        fs.create_file(&path("/new/a")).unwrap();
+        fs.rename(&path("/new"), &path("/moved")).unwrap();
+        assert_eq!(names(&mut fs, &path("/moved")), vec!["a"]);
+
+        // 移动到下层被删除过的目录的位置，下层原来的内容不会出现
+        fs.delete_file(&path("/etc/old")).unwrap();
+        fs.rmdir(&path("/etc")).unwrap();
+        fs.rename(&path("/moved"), &path("/etc")).unwrap();
+        assert_eq!(names(&mut fs, &path("/etc")), vec!["a"]);
+    }
+}
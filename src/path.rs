@@ -14,7 +14,7 @@ impl Path {
         self.segs.is_empty()
     }
 
-    fn check_seg_valid(seg: &str) -> bool {
+    pub fn check_seg_valid(seg: &str) -> bool {
         if seg.is_empty() {
             return false;
         }
@@ -68,7 +68,7 @@ impl Path {
         &self.segs
     }
 
-    pub fn iter(&self) -> std::slice::Iter<String> {
+    pub fn iter(&self) -> std::slice::Iter<'_, String> {
         self.segs.iter()
     }
 
@@ -80,7 +80,7 @@ impl Path {
         if self.segs.is_empty() {
             return None;
         }
-        if let Some(_) = self.segs.pop() {
+        if self.segs.pop().is_some() {
             Some(self)
         } else {
             None
@@ -103,6 +103,24 @@ impl Path {
     pub fn current(&self) -> Option<&String> {
         self.segs.last()
     }
+
+    /// prefix 是不是这个路径本身或者它的祖先
+    pub fn starts_with(&self, prefix: &Path) -> bool {
+        self.segs.starts_with(&prefix.segs)
+    }
+
+    /// 去掉前缀 prefix，得到相对于 prefix 的路径
+    pub fn strip_prefix(&self, prefix: &Path) -> Option<Path> {
+        self.segs.strip_prefix(prefix.segs.as_slice())
+            .map(|segs| Path { segs: segs.to_vec() })
+    }
+
+    /// 把 other 接在这个路径后面
+    pub fn join(&self, other: &Path) -> Path {
+        let mut segs = self.segs.clone();
+        segs.extend(other.segs.iter().cloned());
+        Path { segs }
+    }
 }
 
 
@@ -141,4 +159,20 @@ mod tests {
         let path = Path::from_str("/").unwrap();
         assert_eq!(path.to_str(), "/");
     }
+
+    #[test]
+    fn test_prefix() {
+        let path = Path::from_str("/a/b/c").unwrap();
+        let prefix = Path::from_str("/a/b").unwrap();
+        assert!(path.starts_with(&prefix));
+        assert!(path.starts_with(&Path::root()));
+        assert!(!prefix.starts_with(&path));
+        assert!(!Path::from_str("/a/bc").unwrap().starts_with(&prefix));
+
+        let rest = path.strip_prefix(&prefix).unwrap();
+        assert_eq!(rest.to_str(), "/c");
+        assert_eq!(prefix.join(&rest), path);
+        assert_eq!(path.strip_prefix(&path).unwrap(), Path::root());
+        assert!(prefix.strip_prefix(&path).is_none());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,206 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::path::Path;
+
+/// 默认宽限期：7 天
+pub const DEFAULT_GRACE_PERIOD: u32 = 7 * 24 * 60 * 60;
+
+/// 软限制和硬限制，0 表示不限制
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Limit {
+    pub soft: u32,
+    pub hard: u32,
+}
+
+impl Limit {
+    pub fn new(soft: u32, hard: u32) -> Self {
+        Limit { soft, hard }
+    }
+}
+
+/// 一项配额：块和 inode 的限制以及使用量
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct QuotaEntry {
+    pub blocks: Limit,                  // 数据块限制
+    pub inodes: Limit,                  // inode 限制
+    pub blocks_used: u32,               // 已经使用的数据块
+    pub inodes_used: u32,               // 已经使用的 inode
+    pub block_grace_start: u32,         // 超过块软限制的时间，0 表示没有超过
+    pub inode_grace_start: u32,         // 超过 inode 软限制的时间，0 表示没有超过
+}
+
+/// 检查一个用量在限制下是否允许
+fn check_limit(limit: &Limit, used: u32, delta: i64, grace_start: u32, grace_period: u32, now: u32) -> bool {
+    if delta <= 0 {
+        return true;
+    }
+
+    let new_used = used as i64 + delta;
+    if limit.hard != 0 && new_used > limit.hard as i64 {
+        return false;
+    }
+    if limit.soft != 0 && new_used > limit.soft as i64 && grace_start != 0 {
+        return now.saturating_sub(grace_start) <= grace_period;
+    }
+    true
+}
+
+/// 更新用量，同时维护宽限期的开始时间
+fn apply_delta(limit: &Limit, used: &mut u32, delta: i64, grace_start: &mut u32, now: u32) {
+    *used = (*used as i64 + delta).max(0) as u32;
+    if limit.soft != 0 && *used > limit.soft {
+        if *grace_start == 0 {
+            *grace_start = now;
+        }
+    } else {
+        *grace_start = 0;
+    }
+}
+
+impl QuotaEntry {
+    /// 是否允许增加 blocks 个块和 inodes 个 inode
+    pub fn allows(&self, blocks: i64, inodes: i64, grace_period: u32, now: u32) -> bool {
+        check_limit(&self.blocks, self.blocks_used, blocks, self.block_grace_start, grace_period, now)
+            && check_limit(&self.inodes, self.inodes_used, inodes, self.inode_grace_start, grace_period, now)
+    }
+
+    /// 记录用量的变化
+    pub fn charge(&mut self, blocks: i64, inodes: i64, now: u32) {
+        apply_delta(&self.blocks, &mut self.blocks_used, blocks, &mut self.block_grace_start, now);
+        apply_delta(&self.inodes, &mut self.inodes_used, inodes, &mut self.inode_grace_start, now);
+    }
+
+    /// 设置限制，用量不变
+    pub fn set_limits(&mut self, blocks: Limit, inodes: Limit, now: u32) {
+        self.blocks = blocks;
+        self.inodes = inodes;
+        self.charge(0, 0, now);
+    }
+}
+
+/// 目录配额，记录目录路径以便显示
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct DirQuota {
+    pub path: String,
+    pub quota: QuotaEntry,
+}
+
+/// 配额表，存放在磁盘的配额文件中
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct QuotaTable {
+    pub grace_period: u32,                      // 宽限期，单位秒
+    pub users: BTreeMap<u32, QuotaEntry>,       // 用户配额，key 为 uid
+    pub dirs: BTreeMap<u32, DirQuota>,          // 目录配额，key 为目录的 inum
+}
+
+impl Default for QuotaTable {
+    fn default() -> Self {
+        QuotaTable {
+            grace_period: DEFAULT_GRACE_PERIOD,
+            users: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+        }
+    }
+}
+
+impl QuotaTable {
+    /// 是否允许 uid 在 dirs 这些目录下增加用量
+    pub fn allows(&self, uid: u32, dirs: &[usize], blocks: i64, inodes: i64, now: u32) -> bool {
+        if let Some(entry) = self.users.get(&uid) {
+            if !entry.allows(blocks, inodes, self.grace_period, now) {
+                return false;
+            }
+        }
+        dirs.iter()
+            .filter_map(|inum| self.dirs.get(&(*inum as u32)))
+            .all(|dir| dir.quota.allows(blocks, inodes, self.grace_period, now))
+    }
+
+    /// 记录 uid 在 dirs 这些目录下用量的变化
+    pub fn charge(&mut self, uid: u32, dirs: &[usize], blocks: i64, inodes: i64, now: u32) {
+        if let Some(entry) = self.users.get_mut(&uid) {
+            entry.charge(blocks, inodes, now);
+        }
+        for inum in dirs {
+            if let Some(dir) = self.dirs.get_mut(&(*inum as u32)) {
+                dir.quota.charge(blocks, inodes, now);
+            }
+        }
+    }
+}
+
+/// 配额的对象
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum QuotaTarget {
+    User(u32),
+    Dir(Path),
+}
+
+/// 配额查询结果
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct QuotaReport {
+    pub target: QuotaTarget,
+    pub entry: QuotaEntry,
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hard_limit() {
+        let mut entry = QuotaEntry::default();
+        entry.set_limits(Limit::new(0, 10), Limit::new(0, 2), 100);
+
+        assert!(entry.allows(10, 2, DEFAULT_GRACE_PERIOD, 100));
+        assert!(!entry.allows(11, 0, DEFAULT_GRACE_PERIOD, 100));
+        assert!(!entry.allows(0, 3, DEFAULT_GRACE_PERIOD, 100));
+
+        entry.charge(10, 2, 100);
+        assert!(!entry.allows(1, 0, DEFAULT_GRACE_PERIOD, 100));
+        assert!(entry.allows(-1, -1, DEFAULT_GRACE_PERIOD, 100));
+    }
+
+    #[test]
+    fn test_soft_limit_grace() {
+        let mut entry = QuotaEntry::default();
+        entry.set_limits(Limit::new(5, 10), Limit::default(), 100);
+
+        entry.charge(6, 0, 100);
+        assert_eq!(entry.block_grace_start, 100);
+
+        // 宽限期内可以继续增加
+        assert!(entry.allows(1, 0, 50, 150));
+        // 宽限期过后不能继续增加
+        assert!(!entry.allows(1, 0, 50, 151));
+
+        // 回到软限制以下之后重置宽限期
+        entry.charge(-2, 0, 200);
+        assert_eq!(entry.block_grace_start, 0);
+        assert!(entry.allows(1, 0, 50, 1000));
+    }
+
+    #[test]
+    fn test_table() {
+        let mut table = QuotaTable::default();
+        let mut entry = QuotaEntry::default();
+        entry.set_limits(Limit::default(), Limit::new(0, 1), 0);
+        table.dirs.insert(3, DirQuota { path: "/a".to_string(), quota: entry });
+
+        assert!(table.allows(7, &[0, 3], 0, 1, 0));
+        table.charge(7, &[0, 3], 0, 1, 0);
+        assert!(!table.allows(7, &[0, 3], 0, 1, 0));
+        assert!(table.allows(7, &[0], 0, 1, 0));
+
+        let buf = serde_json::to_vec(&table).unwrap();
+        let read: QuotaTable = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(read, table);
+    }
+}
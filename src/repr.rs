@@ -1,38 +1,57 @@
+use std::alloc::Layout;
+use std::collections::{BTreeSet, HashMap};
 use std::error::Error;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::mem::ManuallyDrop;
 use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
 
+use crate::cache::DirCacheCell;
+use crate::crypto;
+use crate::crypto::{KdfParams, Key, IMAGE_ID_LEN, KEY_CHECK_LEN, SALT_LEN, SEALED_BLOCK_SIZE};
 use crate::io::{Loadable, Savable};
+use crate::logic::DirectoryData;
 
-/// 128 MB
+/// 默认磁盘块数，256 MB
 pub const SIZE: usize = 4096 * 16;
-/// 8 K 个 IBlock，256 K 个 INode
+/// 默认 4 K 个 IBlock，128 K 个 INode
 pub const INDEX_BLOCK_COUNT: usize = 1024 * 4;
-/// 4 个 IBlock 位图块
-pub const INDEX_BITMAP_BLOCK_COUNT: usize = INDEX_BLOCK_COUNT / 1024;
-/// DataBlock 位图块个数 (DATA_BLOCK_COUNT / 1024 / 32 向上取整)
-pub const DATA_BITMAP_BLOCK_COUNT: usize = 2;
-/// 数据块个数
-pub const DATA_BLOCK_COUNT: usize = SIZE - 1 - INDEX_BLOCK_COUNT - INDEX_BITMAP_BLOCK_COUNT - DATA_BITMAP_BLOCK_COUNT;
-/// 按照 4096 对齐
-pub const ALIGN_SIZE: usize = 4096;
+/// 一个位图块可以表示的项数
+pub const BITMAP_BLOCK_BITS: usize = 1024 * 32;
+/// 一个引用计数块可以表示的数据块数
+pub const REF_BLOCK_ITEMS: usize = 1024;
 /// 一个 inode 可以存放 12 个直接块，一个间接块
 pub const DIRECT_BLOCK_COUNT: usize = 12;
+/// inode 标志：文件数据压缩存储，目录带有这个标志时新建的文件和子目录继承它
+pub const INODE_COMPRESSED: u32 = 1;
+/// inode 标志：数据直接存放在直接块的位置上，没有占用数据块
+pub const INODE_INLINE: u32 = 2;
+/// 内联数据的最大字节数，也就是直接块编号占用的空间
+pub const INLINE_DATA_SIZE: usize = DIRECT_BLOCK_COUNT * 4;
+/// 最多可以保存的快照个数
+pub const MAX_SNAPSHOT_COUNT: usize = 64;
+/// 快照名称的最大字节数
+pub const SNAPSHOT_NAME_LEN: usize = 28;
 
-/// 磁盘结构
-#[repr(align(4096))]
+/// 磁盘结构，各个区域的大小记录在超级块中
 #[derive(PartialEq)]
 pub struct Disk {
     pub sb: SuperBlock,                                         // 超级块
 
-    pub i_bitmaps: [BitmapBlock; INDEX_BITMAP_BLOCK_COUNT],     // inode 位图
-    pub d_bitmaps: [BitmapBlock; DATA_BITMAP_BLOCK_COUNT],      // 数据块位图
+    pub i_bitmaps: Vec<BitmapBlock>,                            // inode 位图
+    pub d_bitmaps: Vec<BitmapBlock>,                            // 数据块位图
+    pub d_refs: Vec<RefBlock>,                                  // 数据块引用计数
+
+    pub i_blocks: Vec<IBlock>,                                  // inode 块
+    pub d_blocks: Vec<DataBlock>,                               // 数据块
+
+    pub snapshots: Vec<Snapshot>,                               // 快照，和超级块中的快照表一一对应
+
+    pub key: Option<Key>,                                       // 加密镜像的密钥，不保存到镜像中
 
-    pub i_blocks: [IBlock; INDEX_BLOCK_COUNT],                  // inode 块
-    pub d_blocks: [DataBlock; DATA_BLOCK_COUNT],                // 数据块
+    pub dir_cache: DirCacheCell,                                // 目录项缓存，不保存到镜像中
 }
 
 impl Debug for Disk {
@@ -50,14 +69,86 @@ impl Debug for Disk {
 pub struct INode {
     pub size: u32,                                      // 文件大小
     pub is_dir: bool,                                   // 是否是目录
+    pub uid: u32,                                       // 文件所有者
 
     pub atime: u32,                                     // 文件最近一次被访问的时间
     pub ctime: u32,                                     // 文件的创建时间
     pub mtime: u32,                                     // 文件最近一次被修改的时间
 
     pub block_count: u32,                               // 这个 inode 占用的块数（包括直接块和间接块）
-    pub block_direct: [u32; DIRECT_BLOCK_COUNT],        // 直接块，存放数据块编号
+    pub block_direct: [u32; DIRECT_BLOCK_COUNT],        // 直接块，存放数据块编号；内联时存放数据
     pub block_indirect: u32,                            // 一级间接块，属于索引块
+
+    pub flags: u32,                                     // 文件标志，见 INODE_COMPRESSED 和 INODE_INLINE
+    pub logical_size: u32,                              // 压缩文件解压之后的大小，这时 size 是压缩后的大小
+}
+
+/// 旧版本镜像中的 inode 结构，只在加载旧镜像时用来转换
+#[repr(align(128))]
+#[derive(Clone, Copy)]
+struct LegacyINode {
+    size: u32,
+    is_dir: bool,
+
+    atime: u32,
+    ctime: u32,
+    mtime: u32,
+
+    block_count: u32,
+    block_direct: [u32; DIRECT_BLOCK_COUNT],
+    block_indirect: u32,
+}
+
+impl LegacyINode {
+    /// 转换成现在的结构，文件属于用户 0，不压缩也不内联
+    ///
+    /// 旧版本的一级间接块记录的是块号乘 32，现在直接记录块号
+    fn upgrade(&self) -> INode {
+        INode {
+            size: self.size,
+            is_dir: self.is_dir,
+            uid: 0,
+            atime: self.atime,
+            ctime: self.ctime,
+            mtime: self.mtime,
+            block_count: self.block_count,
+            block_direct: self.block_direct,
+            block_indirect: self.block_indirect / 32,
+            flags: 0,
+            logical_size: 0,
+        }
+    }
+}
+
+impl INode {
+    /// 是否压缩存储
+    pub fn is_compressed(&self) -> bool {
+        self.flags & INODE_COMPRESSED != 0
+    }
+
+    /// 数据是否内联存放
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_INLINE != 0
+    }
+
+    /// 内联数据所在的空间
+    pub fn inline_data(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.block_direct.as_ptr() as *const u8, INLINE_DATA_SIZE) }
+    }
+
+    /// 内联数据所在的可变空间
+    pub fn inline_data_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.block_direct.as_mut_ptr() as *mut u8, INLINE_DATA_SIZE) }
+    }
+
+    /// 用户看到的文件大小
+    pub fn file_size(&self) -> usize {
+        if self.is_compressed() {
+            self.logical_size as usize
+        } else {
+            self.size as usize
+        }
+    }
 }
 
 /// inode 块，一个块可以存放 32 个 inode
@@ -84,12 +175,121 @@ pub struct BitmapBlock {
 }
 
 
+/// 引用计数块
+#[repr(align(4096))]
+#[derive(PartialEq)]
+pub struct RefBlock {
+    pub refs: [u32; REF_BLOCK_ITEMS],   // 每个数据块除了第一个引用之外的额外引用数，0 表示没有共享
+}
+
+
+/// 快照表中的一项
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct SnapshotEntry {
+    pub name: [u8; SNAPSHOT_NAME_LEN],  // 快照名称，不足的部分用 0 填充
+    pub ctime: u32,                     // 快照的创建时间
+    pub quota_inum: u32,                // 快照中配额文件的 inode 编号
+}
+
+impl SnapshotEntry {
+    /// 创建一项，名称太长时返回 None
+    pub fn new(name: &str, ctime: u32, quota_inum: u32) -> Option<SnapshotEntry> {
+        let bytes = name.as_bytes();
+        if bytes.is_empty() || bytes.len() > SNAPSHOT_NAME_LEN {
+            return None;
+        }
+
+        let mut entry = SnapshotEntry {
+            name: [0; SNAPSHOT_NAME_LEN],
+            ctime,
+            quota_inum,
+        };
+        entry.name[..bytes.len()].copy_from_slice(bytes);
+        Some(entry)
+    }
+
+    /// 快照名称
+    pub fn name(&self) -> &str {
+        let len = self.name.iter()
+            .position(|c| *c == 0)
+            .unwrap_or(SNAPSHOT_NAME_LEN);
+        std::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+
+/// 快照，保存拍摄快照时的 inode 位图和 inode 块，数据块和当前文件系统共享
+#[derive(PartialEq)]
+pub struct Snapshot {
+    pub i_bitmaps: Vec<BitmapBlock>,    // inode 位图
+    pub i_blocks: Vec<IBlock>,          // inode 块
+}
+
+
 /// 超级块
 #[repr(align(4096))]
 #[derive(PartialEq, Debug)]
 pub struct SuperBlock {
     pub version: u32,                   // 文件系统版本
     pub root_inum: u32,                 // 根目录的 inode 编号
+    pub quota_inum: u32,                // 配额文件的 inode 编号，0 表示没有配额文件
+
+    pub size: u32,                      // 磁盘总块数，不包括快照
+    pub index_bitmap_block_count: u32,  // inode 位图块个数
+    pub data_bitmap_block_count: u32,   // 数据块位图块个数
+    pub ref_block_count: u32,           // 引用计数块个数
+    pub index_block_count: u32,         // inode 块个数
+    pub data_block_count: u32,          // 数据块个数
+
+    pub snapshot_count: u32,                                // 快照个数
+    pub snapshots: [SnapshotEntry; MAX_SNAPSHOT_COUNT],     // 快照表
+
+    pub cipher: u32,                                        // 加密算法，见 crypto::CIPHER_*
+    pub kdf_m_cost: u32,                                    // 密钥派生参数
+    pub kdf_t_cost: u32,
+    pub kdf_p_cost: u32,
+    pub kdf_salt: [u8; SALT_LEN],                           // 密钥派生用的盐
+    pub key_check: [u8; KEY_CHECK_LEN],                     // 口令校验值，同时认证超级块的其他内容
+    pub image_id: [u8; IMAGE_ID_LEN],                       // 加密镜像的标识，设置口令时随机生成
+    pub generation: Generation,                             // 加密镜像保存的次数，和镜像标识一起认证每个块
+}
+
+
+/// 加密镜像保存的次数，保存镜像只需要 &Disk，所以用原子变量，保存之前加一
+#[repr(transparent)]
+#[derive(Default)]
+pub struct Generation(AtomicU32);
+
+impl Generation {
+    /// 当前的值
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// 加一，返回新的值
+    fn advance(&self) -> u32 {
+        self.0.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+}
+
+impl PartialEq for Generation {
+    fn eq(&self, other: &Self) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl Debug for Generation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.get())
+    }
+}
+
+
+/// 旧版本镜像中的超级块结构，只在加载旧镜像时用来转换
+#[repr(align(4096))]
+struct LegacySuperBlock {
+    version: u32,
+    root_inum: u32,
 }
 
 
@@ -102,52 +302,576 @@ pub struct DataBlock {
 
 
 
+impl SuperBlock {
+    /// 根据磁盘块数计算各个区域的大小，磁盘太小时返回 None
+    pub fn with_size(size: usize) -> Option<SuperBlock> {
+        // inode 块按照默认磁盘的比例分配
+        let index_block_count = (size * INDEX_BLOCK_COUNT / SIZE).max(1);
+        SuperBlock::with_index_block_count(size, index_block_count)
+    }
+
+    /// 保持 inode 块个数不变，根据磁盘块数计算各个区域的大小，磁盘太小时返回 None
+    pub fn with_index_block_count(size: usize, index_block_count: usize) -> Option<SuperBlock> {
+        let index_bitmap_block_count = index_block_count.div_ceil(1024);
+
+        // 剩下的块分给数据块、数据块位图和引用计数块
+        let rest = size.checked_sub(1 + index_block_count + index_bitmap_block_count)?;
+        let cost = |count: usize| count + count.div_ceil(BITMAP_BLOCK_BITS) + count.div_ceil(REF_BLOCK_ITEMS);
+
+        let mut data_block_count = rest * BITMAP_BLOCK_BITS / (BITMAP_BLOCK_BITS + 1 + BITMAP_BLOCK_BITS / REF_BLOCK_ITEMS);
+        while data_block_count > 0 && cost(data_block_count) > rest {
+            data_block_count -= 1;
+        }
+        while cost(data_block_count + 1) <= rest {
+            data_block_count += 1;
+        }
+        if data_block_count == 0 {
+            return None;
+        }
+
+        Some(SuperBlock {
+            version: 0,
+            root_inum: 0,
+            quota_inum: 0,
+            size: size as u32,
+            index_bitmap_block_count: index_bitmap_block_count as u32,
+            data_bitmap_block_count: data_block_count.div_ceil(BITMAP_BLOCK_BITS) as u32,
+            ref_block_count: data_block_count.div_ceil(REF_BLOCK_ITEMS) as u32,
+            index_block_count: index_block_count as u32,
+            data_block_count: data_block_count as u32,
+            snapshot_count: 0,
+            snapshots: [SnapshotEntry::default(); MAX_SNAPSHOT_COUNT],
+            cipher: crypto::CIPHER_NONE,
+            kdf_m_cost: 0,
+            kdf_t_cost: 0,
+            kdf_p_cost: 0,
+            kdf_salt: [0; SALT_LEN],
+            key_check: [0; KEY_CHECK_LEN],
+            image_id: [0; IMAGE_ID_LEN],
+            generation: Generation::default(),
+        })
+    }
+
+    /// 是否加密
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher != crypto::CIPHER_NONE
+    }
+
+    /// 密钥派生参数
+    pub fn kdf_params(&self) -> KdfParams {
+        KdfParams {
+            m_cost: self.kdf_m_cost,
+            t_cost: self.kdf_t_cost,
+            p_cost: self.kdf_p_cost,
+        }
+    }
+
+    /// 使用 other 的加密设置
+    fn set_encryption(&mut self, other: &SuperBlock) {
+        self.cipher = other.cipher;
+        self.kdf_m_cost = other.kdf_m_cost;
+        self.kdf_t_cost = other.kdf_t_cost;
+        self.kdf_p_cost = other.kdf_p_cost;
+        self.kdf_salt = other.kdf_salt;
+        self.image_id = other.image_id;
+        self.generation.0.store(other.generation.get(), Ordering::Relaxed);
+    }
+
+    /// 除了口令校验值以外的所有字节，口令校验值会认证这些内容
+    fn header_bytes(&self) -> Vec<u8> {
+        let mut bytes = as_bytes(std::slice::from_ref(self)).to_vec();
+        let offset = std::mem::offset_of!(SuperBlock, key_check);
+        bytes[offset..offset + KEY_CHECK_LEN].fill(0);
+        bytes
+    }
+
+    /// 使用 layout 中的磁盘布局，其他内容保持不变
+    pub fn set_geometry(&mut self, layout: &SuperBlock) {
+        self.size = layout.size;
+        self.index_bitmap_block_count = layout.index_bitmap_block_count;
+        self.data_bitmap_block_count = layout.data_bitmap_block_count;
+        self.ref_block_count = layout.ref_block_count;
+        self.index_block_count = layout.index_block_count;
+        self.data_block_count = layout.data_block_count;
+    }
+
+    /// 是否是旧版本的镜像，旧版本的超级块中没有记录磁盘布局
+    pub fn is_legacy(&self) -> bool {
+        self.size == 0
+    }
+
+    /// 把旧版本的超级块转换成现在的结构
+    ///
+    /// 旧版本的布局是默认大小，没有引用计数块，数据块占满剩下的空间；
+    /// 引用计数块只在内存中补上，全为 0 表示每个数据块只有一处引用，保存时按现在的格式写出
+    fn upgrade(&self) -> SuperBlock {
+        let legacy = unsafe { std::ptr::read(self as *const SuperBlock as *const LegacySuperBlock) };
+        let index_bitmap_block_count = INDEX_BLOCK_COUNT.div_ceil(1024);
+        let data_bitmap_block_count = 2;
+        let data_block_count = SIZE - 1 - INDEX_BLOCK_COUNT - index_bitmap_block_count - data_bitmap_block_count;
+        let ref_block_count = data_block_count.div_ceil(REF_BLOCK_ITEMS);
+
+        let mut sb = SuperBlock::with_size(SIZE).unwrap();
+        sb.version = legacy.version;
+        sb.root_inum = legacy.root_inum;
+        sb.size = (SIZE + ref_block_count) as u32;
+        sb.index_bitmap_block_count = index_bitmap_block_count as u32;
+        sb.data_bitmap_block_count = data_bitmap_block_count as u32;
+        sb.ref_block_count = ref_block_count as u32;
+        sb.index_block_count = INDEX_BLOCK_COUNT as u32;
+        sb.data_block_count = data_block_count as u32;
+        sb
+    }
+
+    /// 所有快照
+    pub fn snapshots(&self) -> &[SnapshotEntry] {
+        &self.snapshots[..self.snapshot_count as usize]
+    }
+}
+
+
+/// 分配一段全为 0 的内存
+///
+/// T 必须可以用全 0 表示
+unsafe fn zeroed_vec<T>(len: usize) -> Vec<T> {
+    if len == 0 {
+        return Vec::new();
+    }
+    let layout = Layout::array::<T>(len).unwrap();
+    let ptr = std::alloc::alloc_zeroed(layout) as *mut T;
+    if ptr.is_null() {
+        std::alloc::handle_alloc_error(layout);
+    }
+    Vec::from_raw_parts(ptr, len, len)
+}
+
+/// 把一段内存看作字节
+fn as_bytes<T>(items: &[T]) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(items.as_ptr() as *const u8, std::mem::size_of_val(items))
+    }
+}
+
+/// 把一段内存看作可变字节
+///
+/// T 必须可以用任意字节表示
+unsafe fn as_bytes_mut<T>(items: &mut [T]) -> &mut [u8] {
+    std::slice::from_raw_parts_mut(items.as_mut_ptr() as *mut u8, std::mem::size_of_val(items))
+}
+
+/// 把位图中 [start, end) 的项设置为 state
+pub fn set_bitmap_range(bitmap_blocks: &mut [BitmapBlock], start: usize, end: usize, state: bool) {
+    for index in start..end {
+        let u32 = &mut bitmap_blocks[index / BITMAP_BLOCK_BITS].bitmaps[index % BITMAP_BLOCK_BITS / 32];
+        if state {
+            *u32 |= 1 << (index % 32);
+        } else {
+            *u32 &= !(1 << (index % 32));
+        }
+    }
+}
+
+
 impl Disk {
+    /// 创建一个默认大小的磁盘
     pub fn new() -> Box<Disk> {
+        Disk::with_super_block(SuperBlock::with_size(SIZE).unwrap())
+    }
+
+    /// 创建一个 size 个块的磁盘，磁盘太小时返回 None
+    pub fn with_size(size: usize) -> Option<Box<Disk>> {
+        SuperBlock::with_size(size).map(Disk::with_super_block)
+    }
+
+    /// 按照超级块中记录的布局创建磁盘
+    fn with_super_block(sb: SuperBlock) -> Box<Disk> {
+        let mut disk = unsafe {
+            Box::new(Disk {
+                i_bitmaps: zeroed_vec(sb.index_bitmap_block_count as usize),
+                d_bitmaps: zeroed_vec(sb.data_bitmap_block_count as usize),
+                d_refs: zeroed_vec(sb.ref_block_count as usize),
+                i_blocks: zeroed_vec(sb.index_block_count as usize),
+                d_blocks: zeroed_vec(sb.data_block_count as usize),
+                snapshots: Vec::new(),
+                key: None,
+                dir_cache: DirCacheCell::default(),
+                sb,
+            })
+        };
+        disk.reserve_bitmap_tail();
+        disk
+    }
+
+    /// 把位图中超出磁盘范围的项标记为已占用，这样分配时不会用到它们
+    pub fn reserve_bitmap_tail(&mut self) {
+        let inode_count = self.sb.index_block_count as usize * 32;
+        let inode_capacity = self.i_bitmaps.len() * BITMAP_BLOCK_BITS;
+        set_bitmap_range(&mut self.i_bitmaps, inode_count, inode_capacity, true);
+
+        let data_count = self.sb.data_block_count as usize;
+        let data_capacity = self.d_bitmaps.len() * BITMAP_BLOCK_BITS;
+        set_bitmap_range(&mut self.d_bitmaps, data_count, data_capacity, true);
+    }
+
+    /// 清空磁盘，保留磁盘布局
+    pub fn reset_zero(&mut self) {
         unsafe {
-            // 直接从堆栈分配内存
-            let size = std::mem::size_of::<Disk>();
-            let disk = std::alloc::alloc(std::alloc::Layout::from_size_align(size, 4096).unwrap()) as *mut Disk;
-            Box::from_raw(disk)
+            as_bytes_mut(&mut self.i_bitmaps).fill(0);
+            as_bytes_mut(&mut self.d_bitmaps).fill(0);
+            as_bytes_mut(&mut self.d_refs).fill(0);
+            as_bytes_mut(&mut self.i_blocks).fill(0);
+            as_bytes_mut(&mut self.d_blocks).fill(0);
         }
+        self.snapshots.clear();
+        self.dir_cache.lock().clear();
+
+        let mut sb = SuperBlock::with_size(SIZE).unwrap();
+        sb.set_geometry(&self.sb);
+        sb.set_encryption(&self.sb);
+        self.sb = sb;
+        self.reserve_bitmap_tail();
     }
 
-    pub fn to_bytes(&self) -> &[u8] {
-        unsafe {
-            std::slice::from_raw_parts(self as *const Disk as *const u8, std::mem::size_of::<Disk>())
+    /// 设置口令，None 表示不再加密，保存时生效
+    pub fn set_passphrase(&mut self, passphrase: Option<&str>, params: KdfParams) -> Result<(), crypto::Error> {
+        match passphrase {
+            Some(passphrase) => {
+                let salt = crypto::random_salt();
+                self.key = Some(crypto::derive_key(passphrase, &salt, &params)?);
+                self.sb.cipher = crypto::CIPHER_CHACHA20_POLY1305;
+                self.sb.kdf_m_cost = params.m_cost;
+                self.sb.kdf_t_cost = params.t_cost;
+                self.sb.kdf_p_cost = params.p_cost;
+                self.sb.kdf_salt = salt;
+                self.sb.image_id = crypto::random_image_id();
+            }
+            None => {
+                self.key = None;
+                self.sb.set_encryption(&SuperBlock::with_size(SIZE).unwrap());
+                self.sb.key_check = [0; KEY_CHECK_LEN];
+            }
         }
+        Ok(())
     }
 
-    pub fn reset_zero(&mut self) {
-        unsafe {
-            std::ptr::write_bytes(self as *mut Disk as *mut u8, 0, std::mem::size_of::<Disk>());
+    /// 除了超级块以外的所有区域，按照在镜像中的顺序排列
+    fn regions(&self) -> Vec<&[u8]> {
+        let mut regions = vec![
+            as_bytes(&self.i_bitmaps),
+            as_bytes(&self.d_bitmaps),
+            as_bytes(&self.d_refs),
+            as_bytes(&self.i_blocks),
+            as_bytes(&self.d_blocks),
+        ];
+        // 快照放在最后
+        for snapshot in &self.snapshots {
+            regions.push(as_bytes(&snapshot.i_bitmaps));
+            regions.push(as_bytes(&snapshot.i_blocks));
         }
+        regions
     }
-}
 
+    /// 同 regions
+    unsafe fn regions_mut(&mut self) -> Vec<&mut [u8]> {
+        let mut regions = vec![
+            as_bytes_mut(&mut self.i_bitmaps),
+            as_bytes_mut(&mut self.d_bitmaps),
+            as_bytes_mut(&mut self.d_refs),
+            as_bytes_mut(&mut self.i_blocks),
+            as_bytes_mut(&mut self.d_blocks),
+        ];
+        for snapshot in &mut self.snapshots {
+            regions.push(as_bytes_mut(&mut snapshot.i_bitmaps));
+            regions.push(as_bytes_mut(&mut snapshot.i_blocks));
+        }
+        regions
+    }
 
-impl Loadable for Disk {
-    fn load<P: AsRef<Path>>(path: P) -> Result<Box<Self>, impl Error> {
+    /// 只读取超级块，用来判断镜像是否加密
+    pub fn read_super_block<P: AsRef<Path>>(path: P) -> std::io::Result<Box<SuperBlock>> {
+        let mut file = File::open(path)?;
+        let mut sb = unsafe { Box::<SuperBlock>::new_zeroed().assume_init() };
+        file.read_exact(unsafe { as_bytes_mut(std::slice::from_mut(sb.as_mut())) })?;
+        Ok(sb)
+    }
+
+    /// 加载镜像，加密的镜像需要提供口令
+    pub fn load_with_passphrase<P: AsRef<Path>>(path: P, passphrase: Option<&str>) -> std::io::Result<Box<Disk>> {
         let mut file = File::open(path)?;
-        let mut bytes = vec![0u8; std::mem::size_of::<Disk>()];
-        file.read(&mut bytes)?;
 
-        let disk = Disk::new();
+        // 先读超级块，得到磁盘布局
+        let mut sb = unsafe { std::mem::zeroed::<SuperBlock>() };
+        file.read_exact(unsafe { as_bytes_mut(std::slice::from_mut(&mut sb)) })?;
+        if sb.snapshot_count as usize > MAX_SNAPSHOT_COUNT {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unsupported disk image"));
+        }
+        let legacy = sb.is_legacy();
+        if legacy {
+            sb = sb.upgrade();
+        }
+
+        // 加密的镜像先检查口令，再分配空间
+        let key = if sb.is_encrypted() {
+            let passphrase = passphrase.ok_or(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied, "passphrase required"
+            ))?;
+            let key = crypto::derive_key(passphrase, &sb.kdf_salt, &sb.kdf_params())
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+            crypto::verify_key_check(&key, &sb.header_bytes(), &sb.key_check)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::PermissionDenied, err))?;
+
+            // 校验值只在镜像中有意义，每次保存时重新生成
+            sb.key_check = [0; KEY_CHECK_LEN];
+            Some(key)
+        } else {
+            None
+        };
+
+        let snapshot_count = sb.snapshot_count;
+        let mut disk = Disk::with_super_block(sb);
+        for _ in 0..snapshot_count {
+            disk.snapshots.push(unsafe {
+                Snapshot {
+                    i_bitmaps: zeroed_vec(disk.i_bitmaps.len()),
+                    i_blocks: zeroed_vec(disk.i_blocks.len()),
+                }
+            });
+        }
+
+        let mut index = 0;
+        let mut sealed = vec![0u8; SEALED_BLOCK_SIZE];
+        let (image_id, generation) = (disk.sb.image_id, disk.sb.generation.get());
+        for (i, region) in unsafe { disk.regions_mut() }.into_iter().enumerate() {
+            // 旧版本的镜像中没有引用计数块
+            if legacy && i == 2 {
+                continue;
+            }
+            match &key {
+                None => file.read_exact(region)?,
+                Some(key) => {
+                    for block in region.chunks_mut(4096) {
+                        file.read_exact(&mut sealed)?;
+                        let plain = crypto::open_block(key, &image_id, generation, index, &sealed)
+                            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+                        block.copy_from_slice(&plain);
+                        index += 1;
+                    }
+                }
+            }
+        }
+
+        if legacy {
+            disk.reserve_bitmap_tail();
+            disk.upgrade_legacy_inodes();
+        }
+
+        disk.key = key;
+        Ok(disk)
+    }
+
+    /// 把旧版本镜像中的 inode 转换成现在的结构
+    ///
+    /// 旧版本的一级间接块和装满的 inode 块在位图中无法区分，所以从根目录开始遍历目录树，只转换能访问到的 inode
+    ///
+    /// 旧版本在位图中保留第 n 个块作为间接块，索引却写到了第 n * 32 个块中，没有被保留，
+    /// 转换时把索引搬回保留的块。被覆盖的块先记下原来的内容，别的文件的索引可能正好在那里
+    fn upgrade_legacy_inodes(&mut self) {
+        let mut pending = vec![self.sb.root_inum as usize];
+        let mut visited = BTreeSet::new();
+        let mut overwritten = HashMap::new();
+        while let Some(inum) = pending.pop() {
+            if !visited.insert(inum) {
+                continue;
+            }
+
+            let inode = unsafe { crate::logic::get_inode_mut(&mut self.i_blocks, inum) };
+            let legacy = unsafe { std::ptr::read(inode as *const INode as *const LegacyINode) };
+            *inode = legacy.upgrade();
+
+            if legacy.block_indirect != 0 {
+                let old = legacy.block_indirect as usize;
+                let block = inode.block_indirect as usize;
+                let table = match overwritten.get(&old) {
+                    Some(table) => *table,
+                    None => unsafe { *crate::logic::get_indirect_block(&self.i_blocks, old) },
+                };
+                let target = unsafe { crate::logic::get_indirect_block_mut(&mut self.i_blocks, block) };
+                overwritten.entry(block).or_insert(*target);
+                *target = table;
+                unsafe { crate::logic::set_block_state(&mut self.i_bitmaps, block, true); }
+            }
+
+            if legacy.is_dir {
+                let dir = crate::logic::read_data_struct::<DirectoryData>(&self.d_blocks, &self.i_blocks, inum, 0);
+                pending.extend(dir.entries.iter().map(|entry| entry.inum as usize));
+            }
+        }
+    }
+
+    /// 根据镜像开头的超级块创建磁盘，各个区域都是 0，之后按照 metadata_mut 和 data_block_offset 读取
+    pub fn from_super_block_bytes(bytes: &[u8]) -> std::io::Result<Box<Disk>> {
+        let mut sb = unsafe { std::mem::zeroed::<SuperBlock>() };
+        let sb_bytes = unsafe { as_bytes_mut(std::slice::from_mut(&mut sb)) };
+        if bytes.len() < sb_bytes.len() {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "super block too short"));
+        }
+        sb_bytes.copy_from_slice(&bytes[..sb_bytes.len()]);
+        if sb.snapshot_count as usize > MAX_SNAPSHOT_COUNT {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unsupported disk image"));
+        }
+        // 旧版本的镜像各个区域的位置不同，inode 也要转换，需要先整个加载一次再按现在的格式保存
+        if sb.is_legacy() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "legacy disk image, load and save it once to upgrade"));
+        }
+
+        let snapshot_count = sb.snapshot_count;
+        let mut disk = Disk::with_super_block(sb);
+        for _ in 0..snapshot_count {
+            disk.snapshots.push(unsafe {
+                Snapshot {
+                    i_bitmaps: zeroed_vec(disk.i_bitmaps.len()),
+                    i_blocks: zeroed_vec(disk.i_blocks.len()),
+                }
+            });
+        }
+        Ok(disk)
+    }
+
+    /// 未加密的镜像中第 dnum 个数据块的位置
+    pub fn data_block_offset(&self, dnum: usize) -> u64 {
+        let before = std::mem::size_of::<SuperBlock>() + self.regions()[..4].iter()
+            .map(|region| region.len())
+            .sum::<usize>();
+        (before + dnum * 4096) as u64
+    }
+
+    /// 未加密的镜像中除了数据块以外的部分，和它们在镜像中的位置
+    pub fn metadata(&self) -> Vec<(u64, &[u8])> {
+        let mut offset = 0;
+        let mut metadata = Vec::new();
+        let regions = std::iter::once(as_bytes(std::slice::from_ref(&self.sb)))
+            .chain(self.regions());
+        for (index, region) in regions.enumerate() {
+            // 第 5 个是数据块区域
+            if index != 5 {
+                metadata.push((offset as u64, region));
+            }
+            offset += region.len();
+        }
+        metadata
+    }
+
+    /// 同 metadata，不包括超级块
+    pub fn metadata_mut(&mut self) -> Vec<(u64, &mut [u8])> {
+        let mut offset = std::mem::size_of::<SuperBlock>();
+        let mut metadata = Vec::new();
+        for (index, region) in unsafe { self.regions_mut() }.into_iter().enumerate() {
+            let len = region.len();
+            if index != 4 {
+                metadata.push((offset as u64, region));
+            }
+            offset += len;
+        }
+        metadata
+    }
+
+    /// 复制当前的 inode 位图和 inode 块，作为一个快照
+    pub fn capture_snapshot(&self) -> Snapshot {
+        unsafe {
+            let mut i_bitmaps = zeroed_vec::<BitmapBlock>(self.i_bitmaps.len());
+            let mut i_blocks = zeroed_vec::<IBlock>(self.i_blocks.len());
+            as_bytes_mut(&mut i_bitmaps).copy_from_slice(as_bytes(&self.i_bitmaps));
+            as_bytes_mut(&mut i_blocks).copy_from_slice(as_bytes(&self.i_blocks));
+            Snapshot { i_bitmaps, i_blocks }
+        }
+    }
+
+    /// 交换当前文件系统和第 index 个快照的 inode 位图、inode 块和配额文件
+    ///
+    /// 再调用一次就可以换回来；换过之后目录的内容都变了，目录缓存要清空
+    pub fn swap_snapshot(&mut self, index: usize) {
+        self.dir_cache.lock().clear();
+        let snapshot = &mut self.snapshots[index];
+        std::mem::swap(&mut self.i_bitmaps, &mut snapshot.i_bitmaps);
+        std::mem::swap(&mut self.i_blocks, &mut snapshot.i_blocks);
+        std::mem::swap(&mut self.sb.quota_inum, &mut self.sb.snapshots[index].quota_inum);
+    }
+
+    /// 按照 sb 中的布局调整数据区域的大小，inode 区域保持不变
+    ///
+    /// 调用者需要保证被截掉的数据块都是空闲的
+    pub fn resize_data_area(&mut self, sb: SuperBlock) {
+        let old_count = self.sb.data_block_count as usize;
+        let new_count = sb.data_block_count as usize;
+
+        // 先清掉旧的保留项，再按照新的大小重新保留
+        let old_capacity = self.d_bitmaps.len() * BITMAP_BLOCK_BITS;
+        set_bitmap_range(&mut self.d_bitmaps, old_count, old_capacity, false);
+
         unsafe {
-            std::ptr::copy_nonoverlapping(bytes.as_ptr(), disk.as_ref() as *const Disk as *mut u8, bytes.len());
+            resize_zeroed(&mut self.d_bitmaps, sb.data_bitmap_block_count as usize);
+            resize_zeroed(&mut self.d_refs, sb.ref_block_count as usize);
+            resize_zeroed(&mut self.d_blocks, new_count);
         }
 
+        self.sb.set_geometry(&sb);
+        self.reserve_bitmap_tail();
+    }
+}
+
+/// 调整 Vec 的长度，新增的部分全为 0
+///
+/// T 必须可以用全 0 表示
+unsafe fn resize_zeroed<T>(items: &mut Vec<T>, len: usize) {
+    let old_len = items.len();
+    if len <= old_len {
+        items.truncate(len);
+        items.shrink_to_fit();
+        return;
+    }
 
-        Ok::<Box<Self>, std::io::Error>(disk)
+    items.reserve_exact(len - old_len);
+    std::ptr::write_bytes(items.as_mut_ptr().add(old_len), 0, len - old_len);
+    items.set_len(len);
+}
+
+
+impl Loadable for Disk {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Box<Self>, impl Error> {
+        Disk::load_with_passphrase(path, None)
     }
 }
 
 impl Savable for Disk {
     fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), impl Error> {
         let mut file = File::create(path)?;
-        let bytes = self.to_bytes();
 
-        file.write_all(&bytes)?;
+        let key = match &self.key {
+            None => {
+                file.write_all(as_bytes(std::slice::from_ref(&self.sb)))?;
+                for region in self.regions() {
+                    file.write_all(region)?;
+                }
+                return Ok(());
+            }
+            Some(key) => key,
+        };
+
+        // 每次保存使用新的保存次数，旧镜像中的块不能混进新镜像
+        let generation = self.sb.generation.advance();
+
+        // 超级块不加密，但是被口令校验值认证
+        let mut sb = unsafe { Box::<SuperBlock>::new_zeroed().assume_init() };
+        unsafe { as_bytes_mut(std::slice::from_mut(sb.as_mut())) }
+            .copy_from_slice(as_bytes(std::slice::from_ref(&self.sb)));
+        sb.key_check = crypto::make_key_check(key, &sb.header_bytes());
+        file.write_all(as_bytes(std::slice::from_ref(sb.as_ref())))?;
+
+        let blocks = self.regions().into_iter()
+            .flat_map(|region| region.chunks(4096));
+        for (index, block) in blocks.enumerate() {
+            file.write_all(&crypto::seal_block(key, &sb.image_id, generation, index, block))?;
+        }
         Ok::<(), std::io::Error>(())
     }
 }
@@ -165,11 +889,33 @@ mod test {
         assert_eq!(std::mem::size_of::<BitmapBlock>(), 4096);
         assert_eq!(std::mem::size_of::<SuperBlock>(), 4096);
 
-        let size = std::mem::size_of::<Disk>();
-        println!("磁盘大小：{}", size);
-        println!("最大存储空间：{} 字节", DATA_BLOCK_COUNT * 4096);
+        let sb = SuperBlock::with_size(SIZE).unwrap();
+        assert_eq!(sb.index_block_count as usize, INDEX_BLOCK_COUNT);
+        assert_eq!(sb.index_bitmap_block_count, 4);
+        assert_eq!(sb.data_bitmap_block_count, 2);
+        assert_eq!(sb.ref_block_count, 60);
+        assert_eq!(sb.data_block_count, 61373);
+        println!("最大存储空间：{} 字节", sb.data_block_count as usize * 4096);
+    }
+
+    #[test]
+    fn test_with_size() {
+        assert!(Disk::with_size(3).is_none());
+
+        let disk = Disk::with_size(1024).unwrap();
+        let sb = &disk.sb;
+        assert_eq!(sb.index_block_count, 64);
+        assert_eq!(sb.index_bitmap_block_count, 1);
+        assert_eq!(sb.data_bitmap_block_count, 1);
+        assert_eq!(sb.ref_block_count, 1);
+        assert_eq!(sb.data_block_count, 1024 - 1 - 64 - 1 - 1 - 1);
+        assert_eq!(disk.d_blocks.len(), sb.data_block_count as usize);
 
-        println!("{:?}", DATA_BITMAP_BLOCK_COUNT);
+        // 超出磁盘范围的项被保留
+        assert_eq!(disk.d_bitmaps[0].bitmaps[0], 0);
+        assert_eq!(disk.d_bitmaps[0].bitmaps[1023], u32::MAX);
+        assert_eq!(disk.i_bitmaps[0].bitmaps[63], 0);
+        assert_eq!(disk.i_bitmaps[0].bitmaps[64], u32::MAX);
     }
 
     #[test]
@@ -187,10 +933,253 @@ mod test {
         disk.save("disk").unwrap();
 
 
+        let new_disk = Disk::load("disk").unwrap();
+        assert_eq!(new_disk, disk);
+
+        // 删除文件
+        std::fs::remove_file("disk").unwrap();
+
+        let mut disk = Disk::with_size(1024).unwrap();
+        disk.d_blocks[100].data[7] = 7;
+        disk.d_refs[0].refs[100] = 1;
+        disk.snapshots.push(disk.capture_snapshot());
+        disk.sb.snapshots[0] = SnapshotEntry::new("s1", 0, 0).unwrap();
+        disk.sb.snapshot_count = 1;
+        disk.save("disk").unwrap();
+
         let new_disk = Disk::load("disk").unwrap();
         assert_eq!(new_disk, disk);
 
         // 删除文件
         std::fs::remove_file("disk").unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_image_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk");
+        let mut disk = Disk::with_size(1024).unwrap();
+        disk.sb.version = 24;
+        disk.d_blocks[100].data[7] = 7;
+        unsafe { crate::logic::get_inode_mut(&mut disk.i_blocks, 100) }.size = 9;
+        disk.snapshots.push(disk.capture_snapshot());
+        disk.sb.snapshot_count = 1;
+        disk.save(&image).unwrap();
+        let bytes = std::fs::read(&image).unwrap();
+
+        // 按照各个区域的位置分别读取，得到的磁盘和整个加载的一样
+        let mut new_disk = Disk::from_super_block_bytes(&bytes).unwrap();
+        for (offset, region) in new_disk.metadata_mut() {
+            let offset = offset as usize;
+            region.copy_from_slice(&bytes[offset..offset + region.len()]);
+        }
+        for dnum in 0..new_disk.d_blocks.len() {
+            let offset = new_disk.data_block_offset(dnum) as usize;
+            new_disk.d_blocks[dnum].data.copy_from_slice(&bytes[offset..offset + 4096]);
+        }
+        assert!(new_disk == disk);
+
+        for (offset, region) in disk.metadata() {
+            let offset = offset as usize;
+            assert_eq!(&bytes[offset..offset + region.len()], region);
+        }
+        assert!(Disk::from_super_block_bytes(&bytes[..100]).is_err());
+    }
+
+    #[test]
+    fn test_load_legacy() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk");
+
+        // 旧版本的镜像：超级块中只有 version 和 root_inum，没有引用计数块，inode 是旧的结构
+        let sb_len = std::mem::size_of::<SuperBlock>();
+        let bitmaps_len = (4 + 2) * 4096;
+        let data_offset = sb_len + bitmaps_len + INDEX_BLOCK_COUNT * 4096;
+        let data_block_count = SIZE - 1 - INDEX_BLOCK_COUNT - 4 - 2;
+
+        let mut bytes = vec![0u8; data_offset + 2 * 4096];
+        bytes[..4].copy_from_slice(&1u32.to_le_bytes());
+        bytes[sb_len] = 0b11;                   // inode 0 和 1
+        bytes[sb_len + 4 * 4096] = 0b11;        // 数据块 0 和 1
+
+        let dir_data = br#"{"entries":[{"name":"a","inum":1}]}"#;
+        let root = LegacyINode {
+            size: 4 + dir_data.len() as u32,
+            is_dir: true,
+            atime: 1,
+            ctime: 2,
+            mtime: 3,
+            block_count: 1,
+            block_direct: [0; DIRECT_BLOCK_COUNT],
+            block_indirect: 0,
+        };
+        let mut file = LegacyINode { size: 5, is_dir: false, ..root };
+        file.block_direct[0] = 1;
+        for (inum, inode) in [root, file].iter().enumerate() {
+            let offset = sb_len + bitmaps_len + inum * 128;
+            bytes[offset..offset + 128].copy_from_slice(as_bytes(std::slice::from_ref(inode)));
+        }
+        bytes[data_offset..data_offset + 4].copy_from_slice(&(dir_data.len() as u32).to_le_bytes());
+        bytes[data_offset + 4..data_offset + 4 + dir_data.len()].copy_from_slice(dir_data);
+        bytes[data_offset + 4096..data_offset + 4096 + 5].copy_from_slice(b"hello");
+        std::fs::write(&image, &bytes).unwrap();
+        File::options().write(true).open(&image).unwrap()
+            .set_len((data_offset + data_block_count * 4096) as u64).unwrap();
+        assert!(Disk::from_super_block_bytes(&bytes).is_err());
+
+        let disk = Disk::load(&image).unwrap();
+        assert!(!disk.sb.is_legacy());
+        assert_eq!(disk.sb.version, 1);
+        assert_eq!(disk.d_blocks.len(), data_block_count);
+
+        let path = crate::path::Path::from_str("/a").unwrap();
+        let inode = crate::vsfs::get_inode_by_path(&disk, &path).unwrap();
+        assert_eq!((inode.size, inode.uid, inode.flags, inode.mtime), (5, 0, 0, 3));
+        let mut buf = [0u8; 5];
+        crate::vsfs::read_file(&disk, &path, 0, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // 保存之后是现在的格式
+        disk.save(&image).unwrap();
+        let new_disk = Disk::load(&image).unwrap();
+        assert!(new_disk == disk);
+    }
+
+    #[test]
+    fn test_load_legacy_indirect() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk");
+
+        let sb_len = std::mem::size_of::<SuperBlock>();
+        let bitmaps_len = (4 + 2) * 4096;
+        let index_offset = sb_len + bitmaps_len;
+        let data_offset = index_offset + INDEX_BLOCK_COUNT * 4096;
+        let data_block_count = SIZE - 1 - INDEX_BLOCK_COUNT - 4 - 2;
+
+        // 文件有 14 个数据块：旧版本在位图中保留第 1 个块，索引写在第 32 个块中
+        let mut bytes = vec![0u8; data_offset + 15 * 4096];
+        bytes[..4].copy_from_slice(&1u32.to_le_bytes());
+        bytes[sb_len] = 0b11;
+        bytes[sb_len + 4..sb_len + 8].fill(0xff);
+        bytes[sb_len + 4 * 4096] = 0xff;
+        bytes[sb_len + 4 * 4096 + 1] = 0x7f;
+
+        let dir_data = br#"{"entries":[{"name":"a","inum":1}]}"#;
+        let root = LegacyINode {
+            size: 4 + dir_data.len() as u32,
+            is_dir: true,
+            atime: 1,
+            ctime: 2,
+            mtime: 3,
+            block_count: 1,
+            block_direct: [0; DIRECT_BLOCK_COUNT],
+            block_indirect: 0,
+        };
+        let mut file = LegacyINode { size: 14 * 4096 - 100, block_count: 14, block_indirect: 32, is_dir: false, ..root };
+        for (i, dnum) in file.block_direct.iter_mut().enumerate() {
+            *dnum = i as u32 + 1;
+        }
+        for (inum, inode) in [root, file].iter().enumerate() {
+            let offset = index_offset + inum * 128;
+            bytes[offset..offset + 128].copy_from_slice(as_bytes(std::slice::from_ref(inode)));
+        }
+        let table = index_offset + 32 * 4096;
+        bytes[table..table + 4].copy_from_slice(&13u32.to_le_bytes());
+        bytes[table + 4..table + 8].copy_from_slice(&14u32.to_le_bytes());
+
+        bytes[data_offset..data_offset + 4].copy_from_slice(&(dir_data.len() as u32).to_le_bytes());
+        bytes[data_offset + 4..data_offset + 4 + dir_data.len()].copy_from_slice(dir_data);
+        let data = (0..file.size as usize).map(|i| (i / 4096) as u8 + 1).collect::<Vec<_>>();
+        bytes[data_offset + 4096..data_offset + 4096 + data.len()].copy_from_slice(&data);
+        std::fs::write(&image, &bytes).unwrap();
+        File::options().write(true).open(&image).unwrap()
+            .set_len((data_offset + data_block_count * 4096) as u64).unwrap();
+
+        // 索引搬到了保留的块中
+        let mut disk = Disk::load(&image).unwrap();
+        let a = crate::path::Path::from_str("/a").unwrap();
+        let inode = *crate::vsfs::get_inode_by_path(&disk, &a).unwrap();
+        assert_eq!(inode.block_indirect, 1);
+        assert_eq!(crate::logic::get_block_state(&disk.i_bitmaps, 1), u32::MAX);
+        let mut buf = vec![0u8; data.len()];
+        crate::vsfs::read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // 之后分配的间接块和数据块不会覆盖原来的文件
+        let root_path = crate::path::Path::root();
+        let b = crate::path::Path::from_str("/b").unwrap();
+        crate::vsfs::create_file(&mut disk, &root_path, "b", 0).unwrap();
+        let other = vec![0xeeu8; 20 * 4096];
+        let inum = crate::vsfs::get_inum_by_path(&disk, &b).unwrap();
+        let dirs = crate::vsfs::parent_inums(&disk, &b);
+        crate::vsfs::write_inode(&mut disk, inum, &dirs, 0, &[&other]).unwrap();
+        assert_ne!(crate::vsfs::get_inode_by_path(&disk, &b).unwrap().block_indirect, 1);
+        crate::vsfs::read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        let mut other_buf = vec![0u8; other.len()];
+        crate::vsfs::read_file(&disk, &b, 0, &mut other_buf).unwrap();
+        assert_eq!(other_buf, other);
+    }
+
+    #[test]
+    fn test_encrypted_save_load() {
+        let params = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+        let mut disk = Disk::with_size(1024).unwrap();
+        disk.d_blocks[100].data[..6].copy_from_slice(b"secret");
+        disk.set_passphrase(Some("passphrase"), params).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("disk_encrypted");
+        disk.save(&image).unwrap();
+
+        // 镜像中看不到明文
+        let bytes = std::fs::read(&image).unwrap();
+        assert!(!bytes.windows(6).any(|window| window == b"secret"));
+
+        let new_disk = Disk::load_with_passphrase(&image, Some("passphrase")).unwrap();
+        assert!(new_disk == disk);
+
+        let err = Disk::load_with_passphrase(&image, Some("wrong")).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+        let err = Disk::load_with_passphrase(&image, None).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // 被改动过的块无法通过认证
+        let mut tampered = bytes.clone();
+        tampered[4096 + 100] ^= 1;
+        std::fs::write(&image, &tampered).unwrap();
+        let err = Disk::load_with_passphrase(&image, Some("passphrase")).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_encrypted_save_generation() {
+        let params = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+        let mut disk = Disk::with_size(1024).unwrap();
+        disk.set_passphrase(Some("passphrase"), params).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let old_image = dir.path().join("disk_old");
+        let new_image = dir.path().join("disk_new");
+        disk.save(&old_image).unwrap();
+        assert_eq!(disk.sb.generation.get(), 1);
+        disk.save(&new_image).unwrap();
+        assert_eq!(disk.sb.generation.get(), 2);
+
+        let new_disk = Disk::load_with_passphrase(&new_image, Some("passphrase")).unwrap();
+        assert_eq!(new_disk.sb.generation.get(), 2);
+        assert_eq!(new_disk.sb.image_id, disk.sb.image_id);
+
+        // 把上一次保存的块放进新的镜像，即使位置相同也无法通过认证
+        let old_bytes = std::fs::read(&old_image).unwrap();
+        let mut spliced = std::fs::read(&new_image).unwrap();
+        let range = 4096..4096 + SEALED_BLOCK_SIZE;
+        spliced[range.clone()].copy_from_slice(&old_bytes[range]);
+        std::fs::write(&new_image, &spliced).unwrap();
+        let err = Disk::load_with_passphrase(&new_image, Some("passphrase")).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // 重新设置口令会换一个镜像标识
+        let image_id = disk.sb.image_id;
+        disk.set_passphrase(Some("passphrase"), params).unwrap();
+        assert_ne!(disk.sb.image_id, image_id);
+    }
+}
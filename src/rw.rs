@@ -10,42 +10,99 @@ pub enum AccessMode {
 #[derive(Hash, Eq, PartialEq)]
 struct RWTableEntry {
     pid: usize,                         // 进程 ID
-    path: String,                       // 文件路径
+    inum: usize,                        // 打开的 inode，改名和删除都不影响已经打开的文件
     mode: AccessMode,                   // 文件打开模式
 }
 
+impl RWTableEntry {
+    fn reads(&self) -> bool {
+        self.mode == AccessMode::Read || self.mode == AccessMode::ReadWrite
+    }
+
+    fn writes(&self) -> bool {
+        self.mode == AccessMode::Write || self.mode == AccessMode::ReadWrite
+    }
+}
+
+/// 打开文件表中的一项的编号，槽位被重新使用之后代数会变，旧的编号就失效了
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OpenId {
+    index: usize,
+    generation: u32,
+}
+
+/// 打开文件的句柄：会话中的文件描述符，加上打开文件表中的编号，用来发现已经关闭的旧句柄
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handle {
+    pub fd: usize,
+    id: OpenId,
+}
+
+struct Slot {
+    generation: u32,
+    entry: Option<RWTableEntry>,
+}
+
+/// 打开文件表，关闭之后槽位放进空闲链表，下次打开时重新使用
 struct OpenTable {
-    entries: Vec<(RWTableEntry, bool)>,         // 打开文件表，第二个元素表示是否被删除
+    slots: Vec<Slot>,
+    free: Vec<usize>,                   // 空闲的槽位
 }
 
 
 impl OpenTable {
     fn new() -> Self {
         OpenTable {
-            entries: Vec::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
         }
     }
 
     /// 打开文件
-    fn open_file(&mut self, pid: usize, path: &str, mode: AccessMode) -> usize {
+    fn open_file(&mut self, pid: usize, inum: usize, mode: AccessMode) -> OpenId {
         let entry = RWTableEntry{
             pid,
-            path: path.to_string(),
+            inum,
             mode,
         };
-        self.entries.push((entry, false));
-        self.entries.len() - 1
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.entry = Some(entry);
+                OpenId { index, generation: slot.generation }
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, entry: Some(entry) });
+                OpenId { index: self.slots.len() - 1, generation: 0 }
+            }
+        }
+    }
+
+    fn get(&self, id: OpenId) -> Option<&RWTableEntry> {
+        self.slots.get(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.entry.as_ref())
     }
 
-    /// 关闭
-    fn close(&mut self, id: usize) {
-        self.entries[id].1 = true;
+    /// 关闭，返回关闭的表项；旧的编号返回 None
+    fn close(&mut self, id: OpenId) -> Option<RWTableEntry> {
+        let slot = self.slots.get_mut(id.index)
+            .filter(|slot| slot.generation == id.generation)?;
+        let entry = slot.entry.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        Some(entry)
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &RWTableEntry> {
+        self.slots.iter().filter_map(|slot| slot.entry.as_ref())
     }
 }
 
 
+/// 每个 inode 的读者和写者个数
 struct FileRWTable {
-    map: HashMap<String, u8>,           // 文件读写状态表
+    map: HashMap<usize, (usize, usize)>,        // 文件读写状态表
 }
 
 
@@ -57,25 +114,73 @@ impl FileRWTable {
     }
 
     /// 是否可以写文件
-    fn can_write(&self, path: &str) -> bool {
-        match self.map.get(path) {
-            Some(state) => state & 0b00000010u8 == 0,
+    fn can_write(&self, inum: usize) -> bool {
+        match self.map.get(&inum) {
+            Some((_, writers)) => *writers == 0,
             None => true,
         }
     }
 
-    /// 设置文件读状态
-    fn set_read(&mut self, path: &str, read: bool) {
-        let state = self.map.entry(path.to_string())
-            .or_insert(0b00000000);
-        *state |= read as u8;
+    /// 记录打开的文件
+    fn add(&mut self, entry: &RWTableEntry) {
+        let (readers, writers) = self.map.entry(entry.inum)
+            .or_insert((0, 0));
+        *readers += entry.reads() as usize;
+        *writers += entry.writes() as usize;
     }
 
-    /// 设置文件写状态
-    fn set_write(&mut self, path: &str, write: bool) {
-        let state = self.map.entry(path.to_string())
-            .or_insert(0b00000000);
-        *state |= (write as u8) << 1;
+    /// 去掉关闭的文件，没有人打开时删除这一项
+    fn remove(&mut self, entry: &RWTableEntry) {
+        let Some(state) = self.map.get_mut(&entry.inum) else {
+            return;
+        };
+        state.0 -= entry.reads() as usize;
+        state.1 -= entry.writes() as usize;
+        if *state == (0, 0) {
+            self.map.remove(&entry.inum);
+        }
+    }
+}
+
+
+/// 一个会话（模拟的进程）的文件描述符表，下标是描述符，值是打开文件表中的编号
+struct FdTable {
+    fds: Vec<Option<OpenId>>,
+}
+
+
+impl FdTable {
+    fn new() -> Self {
+        FdTable {
+            fds: Vec::new(),
+        }
+    }
+
+    /// 分配最小的空闲描述符
+    fn alloc(&mut self, id: OpenId) -> usize {
+        match self.fds.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.fds[fd] = Some(id);
+                fd
+            }
+            None => {
+                self.fds.push(Some(id));
+                self.fds.len() - 1
+            }
+        }
+    }
+
+    /// 句柄是否还指向这个描述符上打开的文件
+    fn contains(&self, handle: Handle) -> bool {
+        self.fds.get(handle.fd) == Some(&Some(handle.id))
+    }
+
+    fn free(&mut self, handle: Handle) -> bool {
+        if !self.contains(handle) {
+            return false;
+        }
+        self.fds[handle.fd] = None;
+        true
     }
 }
 
@@ -83,6 +188,7 @@ impl FileRWTable {
 pub struct RWManager {
     open_table: OpenTable,              // 打开文件表
     file_rw_table: FileRWTable,         // 文件读写状态表
+    sessions: HashMap<usize, FdTable>,  // 每个会话的文件描述符表
 }
 
 
@@ -91,81 +197,76 @@ impl RWManager {
         RWManager {
             open_table: OpenTable::new(),
             file_rw_table: FileRWTable::new(),
+            sessions: HashMap::new(),
         }
     }
 
-    /// 打开文件
-    pub fn open(&mut self, pid: usize, path: &str, mode: AccessMode) -> usize {
-        let entry_id = self.open_table.open_file(pid, path, mode);
-        match mode {
-            AccessMode::Read => {
-                self.file_rw_table.set_read(path, true);
-            }
-            AccessMode::Write => {
-                self.file_rw_table.set_write(path, true);
-            }
-            AccessMode::ReadWrite => {
-                self.file_rw_table.set_read(path, true);
-                self.file_rw_table.set_write(path, true);
-            }
-        }
-        entry_id
+    /// 打开文件，返回 pid 会话中的句柄
+    pub fn open(&mut self, pid: usize, inum: usize, mode: AccessMode) -> Handle {
+        let id = self.open_table.open_file(pid, inum, mode);
+        self.file_rw_table.add(self.open_table.get(id).unwrap());
+        let fd = self.sessions.entry(pid)
+            .or_insert_with(FdTable::new)
+            .alloc(id);
+        Handle { fd, id }
     }
 
-    /// 更新状态
-    fn update_state(&mut self) {
-        self.file_rw_table.map.clear();
-        self.open_table.entries
-            .iter()
-            .for_each(|(entry, deleted)| {
-                if *deleted {
-                    return;
-                }
-                self.file_rw_table.set_read(&entry.path, entry.mode == AccessMode::Read || entry.mode == AccessMode::ReadWrite);
-                self.file_rw_table.set_write(&entry.path, entry.mode == AccessMode::Write || entry.mode == AccessMode::ReadWrite);
-
-            });
+    /// 关闭 pid 会话中的句柄，返回是否真的关闭了
+    pub fn close(&mut self, pid: usize, handle: Handle) -> bool {
+        if !self.sessions.get_mut(&pid).is_some_and(|table| table.free(handle)) {
+            return false;
+        }
+        let entry = self.open_table.close(handle.id).unwrap();
+        self.file_rw_table.remove(&entry);
+        true
     }
 
-    /// 关闭文件
-    pub fn close(&mut self, id: usize) {
-        self.open_table.close(id);
-        self.update_state();
+    /// 结束会话，关闭它打开的所有文件，返回关闭的个数
+    pub fn close_session(&mut self, pid: usize) -> usize {
+        let Some(table) = self.sessions.remove(&pid) else {
+            return 0;
+        };
+        let mut closed = 0;
+        for id in table.fds.into_iter().flatten() {
+            let entry = self.open_table.close(id).unwrap();
+            self.file_rw_table.remove(&entry);
+            closed += 1;
+        }
+        closed
     }
 
     /// 是否可以写文件
-    pub fn can_write(&self, path: &str) -> bool {
-        self.file_rw_table.can_write(path)
+    pub fn can_write(&self, inum: usize) -> bool {
+        self.file_rw_table.can_write(inum)
     }
 
     /// 是否文件已经打开
-    pub fn is_open(&self, pid: usize, path: &str, mode: AccessMode) -> bool {
-        self.open_table.entries.iter()
-            .any(|(entry, deleted)| {
-                if *deleted {
-                    return false;
-                }
-                entry.pid == pid && entry.path == path && entry.mode == mode
-            })
+    pub fn is_open(&self, pid: usize, inum: usize, mode: AccessMode) -> bool {
+        self.open_table.entries()
+            .any(|entry| entry.pid == pid && entry.inum == inum && entry.mode == mode)
+    }
+
+    /// inode 是否还被任何会话打开着
+    pub fn in_use(&self, inum: usize) -> bool {
+        self.file_rw_table.map.contains_key(&inum)
     }
 
-    /// 是否文件已经删除
-    fn is_deleted(&self, id: usize) -> bool {
-        self.open_table.entries[id].1
+    /// 是否有任何打开的文件
+    pub fn has_open_files(&self) -> bool {
+        !self.file_rw_table.map.is_empty()
     }
 
-    /// 是否仍然打开
-    pub fn already_open(&self, id: usize) -> bool {
-        self.is_deleted(id)
+    /// 句柄是否仍然打开着
+    pub fn already_open(&self, pid: usize, handle: Handle) -> bool {
+        self.sessions.get(&pid).is_some_and(|table| table.contains(handle))
     }
 
-    /// 根据 id 获得读写模式
-    pub fn access_mode(&self, id: usize) -> Option<AccessMode> {
-        if self.is_deleted(id) {
-            None
-        } else {
-            Some(self.open_table.entries[id].0.mode)
+    /// 根据 pid 会话中的句柄获得读写模式
+    pub fn access_mode(&self, pid: usize, handle: Handle) -> Option<AccessMode> {
+        if !self.already_open(pid, handle) {
+            return None;
         }
+        self.open_table.get(handle.id).map(|entry| entry.mode)
     }
 }
 
@@ -177,26 +278,91 @@ mod test {
     #[test]
     fn test_rw_manager() {
         let mut rw_manager = RWManager::new();
-        let x = rw_manager.open(1, "test.txt", AccessMode::Read);
-        assert_eq!(rw_manager.can_write("test.txt"), true);
+        let x = rw_manager.open(1, 1, AccessMode::Read);
+        assert!(rw_manager.can_write(1));
 
-        rw_manager.close(x);
+        rw_manager.close(1, x);
 
-        let x = rw_manager.open(1, "test.txt", AccessMode::Write);
-        assert_eq!(rw_manager.can_write("test.txt"), false);
+        let x = rw_manager.open(1, 1, AccessMode::Write);
+        assert!(!rw_manager.can_write(1));
 
-        rw_manager.close(x);
+        rw_manager.close(1, x);
 
-        let x = rw_manager.open(1, "test.txt", AccessMode::ReadWrite);
-        assert_eq!(rw_manager.can_write("test.txt"), false);
-        assert_eq!(rw_manager.is_open(1, "test.txt", AccessMode::Read), false);
-        assert_eq!(rw_manager.is_open(1, "test.txt", AccessMode::Write), false);
-        assert_eq!(rw_manager.is_open(1, "test.txt", AccessMode::ReadWrite), true);
+        let x = rw_manager.open(1, 1, AccessMode::ReadWrite);
+        assert!(!rw_manager.can_write(1));
+        assert!(!rw_manager.is_open(1, 1, AccessMode::Read));
+        assert!(!rw_manager.is_open(1, 1, AccessMode::Write));
+        assert!(rw_manager.is_open(1, 1, AccessMode::ReadWrite));
 
-        assert_eq!(rw_manager.can_write("test.txt"), false);
-        rw_manager.close(x);
+        assert!(!rw_manager.can_write(1));
+        rw_manager.close(1, x);
 
-        assert_eq!(rw_manager.can_write("test.txt"), true);
-        assert_eq!(rw_manager.is_open(1, "test.txt", AccessMode::Read), false);
+        assert!(rw_manager.can_write(1));
+        assert!(!rw_manager.is_open(1, 1, AccessMode::Read));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut rw_manager = RWManager::new();
+        let a = rw_manager.open(1, 1, AccessMode::Read);
+        let b = rw_manager.open(1, 2, AccessMode::Write);
+        let c = rw_manager.open(2, 1, AccessMode::Read);
+
+        // 每个会话的描述符从 0 开始分配
+        assert_eq!((a.fd, b.fd, c.fd), (0, 1, 0));
+        assert!(rw_manager.is_open(2, 1, AccessMode::Read));
+        assert!(!rw_manager.is_open(2, 2, AccessMode::Write));
+
+        // 描述符只在自己的会话中有效
+        assert!(!rw_manager.close(2, b));
+        assert_eq!(rw_manager.access_mode(2, b), None);
+        assert_eq!(rw_manager.access_mode(1, b), Some(AccessMode::Write));
+
+        // 关闭之后最小的描述符被重新使用
+        assert!(rw_manager.close(1, a));
+        assert_eq!(rw_manager.access_mode(1, a), None);
+        assert_eq!(rw_manager.open(1, 3, AccessMode::Read).fd, 0);
+
+        assert_eq!(rw_manager.close_session(1), 2);
+        assert!(rw_manager.can_write(2));
+        assert!(!rw_manager.in_use(2));
+        assert!(rw_manager.is_open(2, 1, AccessMode::Read));
+        assert_eq!(rw_manager.close_session(1), 0);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_slot_reuse() {
+        let mut rw_manager = RWManager::new();
+        let old = rw_manager.open(1, 1, AccessMode::Write);
+        assert!(rw_manager.already_open(1, old));
+        assert!(rw_manager.close(1, old));
+        assert!(!rw_manager.already_open(1, old));
+
+        // 槽位和描述符都被重新使用，但是代数变了，旧句柄不能再用
+        let new = rw_manager.open(1, 2, AccessMode::Read);
+        assert_eq!(new.fd, old.fd);
+        assert_eq!(new.id.index, old.id.index);
+        assert_ne!(new, old);
+        assert!(!rw_manager.already_open(1, old));
+        assert_eq!(rw_manager.access_mode(1, old), None);
+        assert!(!rw_manager.close(1, old));
+        assert_eq!(rw_manager.access_mode(1, new), Some(AccessMode::Read));
+
+        // 旧句柄的关闭没有影响新文件的状态
+        assert!(rw_manager.can_write(1));
+        assert!(rw_manager.in_use(2));
+
+        // 反复打开关闭，表不会变大
+        for _ in 0..100 {
+            let handle = rw_manager.open(2, 3, AccessMode::ReadWrite);
+            assert!(!rw_manager.can_write(3));
+            assert!(rw_manager.close(2, handle));
+        }
+        assert_eq!(rw_manager.open_table.slots.len(), 2);
+        assert!(rw_manager.can_write(3));
+        assert!(!rw_manager.file_rw_table.map.contains_key(&3));
+
+        assert!(rw_manager.close(1, new));
+        assert!(rw_manager.file_rw_table.map.is_empty());
+    }
+}
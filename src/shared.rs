@@ -0,0 +1,656 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::{IoSlice, IoSliceMut};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::cache::PAGE_CACHE_SIZE;
+use crate::io::Savable;
+use crate::logic;
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
+use crate::path::Path;
+use crate::quota::{Limit, QuotaReport, QuotaTarget};
+use crate::repr::Disk;
+use crate::rw::AccessMode;
+use crate::vfs::{DedupReport, FragmentReport, OpenOptions, SnapshotDiff, SnapshotInfo, VirtualFile, VirtualFileSystem};
+use crate::vsfs;
+use crate::vsfs_vfs::{VerySimpleError, VerySimpleFile, VerySimpleFileDescription, VerySimpleFileSystem};
+
+/// 共享文件系统打开的文件，读写数据时用打开时记下的 inum
+#[derive(Debug)]
+pub struct SharedFile {
+    file: VerySimpleFile,
+}
+
+impl SharedFile {
+    fn inum(&self) -> usize {
+        self.file.inum()
+    }
+}
+
+impl VirtualFile for SharedFile {
+    fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    fn mode(&self) -> AccessMode {
+        self.file.mode()
+    }
+
+    fn position(&self) -> usize {
+        self.file.position()
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        self.file.set_position(pos)
+    }
+}
+
+
+/// 一个文件在两次独占操作之间写入的页，属于这个文件，不在磁盘上
+#[derive(Default)]
+struct FileBuffer {
+    pages: BTreeMap<usize, Box<[u8; 4096]>>,    // 块序号到整页的内容
+    size: usize,                                // 写入之后的文件大小，比磁盘上的小时以磁盘上的为准
+    modified: bool,
+    accessed: AtomicBool,                       // 读只拿读锁，写回时更新访问时间
+}
+
+impl FileBuffer {
+    /// 把缓存的页盖到从 pos 开始读出的 buf 上
+    fn overlay(&self, pos: usize, buf: &mut [u8]) {
+        split_pages(pos, buf.len(), |index, offset, range| {
+            if let Some(page) = self.pages.get(&index) {
+                buf[range.clone()].copy_from_slice(&page[offset..offset + range.len()]);
+            }
+        });
+    }
+}
+
+/// 多个线程共享的部分
+///
+/// 锁的顺序：fs -> buffers -> 某个文件的 FileBuffer，只能按这个顺序获取
+struct Inner {
+    fs: RwLock<VerySimpleFileSystem<'static>>,              // 写锁独占整个文件系统，文件数据的读写只拿读锁
+    buffers: Mutex<HashMap<usize, Arc<RwLock<FileBuffer>>>>, // 每个 inum 写入的页，同一个文件的读写互斥
+    pages: AtomicUsize,                                     // 所有文件缓存的页数
+}
+
+impl Inner {
+    /// 某个文件的缓冲区，没有时新建一个，需要拿着 fs 的读锁
+    fn buffer(&self, inum: usize) -> Arc<RwLock<FileBuffer>> {
+        self.buffers.lock().unwrap()
+            .entry(inum)
+            .or_default()
+            .clone()
+    }
+
+    /// 把所有文件缓存的页和时间写回磁盘，需要拿着 fs 的写锁
+    ///
+    /// 缓存的写入都满足 vsfs::can_write_in_place，独占的操作在写回之后才会改变文件的数据块，所以写回不会失败
+    fn flush(&self, fs: &mut VerySimpleFileSystem<'static>) {
+        let buffers = std::mem::take(&mut *self.buffers.lock().unwrap());
+        self.pages.store(0, Ordering::Relaxed);
+        let disk = fs.disk_mut();
+        for (inum, buffer) in buffers {
+            let buffer = buffer.read().unwrap();
+            for (index, data) in &buffer.pages {
+                vsfs::write_page(disk, inum, *index, data);
+            }
+            if buffer.modified {
+                vsfs::grow_file_size(disk, inum, buffer.size);
+            }
+            if buffer.modified || buffer.accessed.load(Ordering::Relaxed) {
+                vsfs::update_inode_time(disk, inum, buffer.modified);
+            }
+        }
+    }
+}
+
+
+/// 可以在多个线程之间共享的 vsfs，每个线程 clone 一份使用
+///
+/// 不同文件的数据可以同时读写：读直接读磁盘，只改写已有数据块的写入先放在这个文件自己的页中，
+/// 下一次独占操作之前写回磁盘，页数超过 PAGE_CACHE_SIZE 时也会写回。
+/// 需要分配或复制数据块的写入和目录、快照、配额等其他操作一样独占整个文件系统，
+/// 经过 VerySimpleFileSystem 的写入路径；追加写入和向量读写也走独占的路径
+#[derive(Clone)]
+pub struct SharedFileSystem {
+    inner: Arc<Inner>,
+    uid: u32,
+}
+
+impl SharedFileSystem {
+    pub fn new(disk: Box<Disk>) -> Self {
+        // 共享的读直接访问数据块，页都缓存在 FileBuffer 中
+        let mut fs = VerySimpleFileSystem::with_disk(disk);
+        fs.set_page_cache_capacity(0);
+        SharedFileSystem {
+            inner: Arc::new(Inner {
+                fs: RwLock::new(fs),
+                buffers: Mutex::new(HashMap::new()),
+                pages: AtomicUsize::new(0),
+            }),
+            uid: 0,
+        }
+    }
+
+    /// sync 时把磁盘保存到 image，保存期间其他线程的操作都会等待
+    pub fn set_image(&self, image: impl Into<PathBuf>) {
+        let image = image.into();
+        self.exclusive(|fs| fs.set_image(image))
+    }
+
+    /// 保存磁盘，保存期间其他线程的操作都会等待
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> io::Result<()> {
+        self.exclusive(|fs| fs.disk().save(path).map_err(|err| io::Error::other(err.to_string())))
+    }
+
+    /// 写回缓存的页之后独占整个文件系统执行 f
+    fn exclusive<T>(&self, f: impl FnOnce(&mut VerySimpleFileSystem<'static>) -> T) -> T {
+        let mut fs = self.inner.fs.write().unwrap();
+        self.inner.flush(&mut fs);
+        fs.set_user(self.uid);
+        f(&mut fs)
+    }
+
+    /// 不独占文件系统从 pos 处读文件，目录和没有打开的文件交给独占的路径报错
+    fn read_shared(&self, file: &SharedFile, buf: &mut [u8], pos: usize) -> Option<usize> {
+        let fs = self.inner.fs.read().unwrap();
+        if !fs.still_open(&file.file) {
+            return None;
+        }
+        let disk = fs.disk();
+        let inode = unsafe { logic::get_inode(&disk.i_blocks, file.inum()) };
+        if inode.is_dir {
+            return None;
+        }
+
+        let lock = self.inner.buffer(file.inum());
+        let buffer = lock.read().unwrap();
+        // 位置可能在文件末尾之后，这时读不到数据；磁盘上的大小之后的数据都在缓存的页中
+        let len = min(buf.len(), inode.file_size().max(buffer.size).saturating_sub(pos));
+        let stored = min(len, inode.file_size().saturating_sub(pos));
+        if stored > 0 {
+            vsfs::read_inode(disk, file.inum(), pos, &mut buf[..stored]).ok()?;
+        }
+        buffer.overlay(pos, &mut buf[..len]);
+        buffer.accessed.store(true, Ordering::Relaxed);
+        Some(len)
+    }
+
+    /// 不独占文件系统写到 pos 处，只改写已有的数据块，需要独占的情况返回 None
+    fn write_shared(&self, file: &SharedFile, buf: &[u8], pos: usize) -> Option<usize> {
+        {
+            let fs = self.inner.fs.read().unwrap();
+            if !fs.still_open(&file.file) {
+                return None;
+            }
+            let disk = fs.disk();
+            let end = pos + buf.len();
+            if !vsfs::can_write_in_place(disk, file.inum(), pos, end) {
+                return None;
+            }
+
+            let lock = self.inner.buffer(file.inum());
+            let mut buffer = lock.write().unwrap();
+            split_pages(pos, buf.len(), |index, offset, range| {
+                let page = buffer.pages.entry(index).or_insert_with(|| {
+                    let mut data = Box::new([0u8; 4096]);
+                    vsfs::read_page(disk, file.inum(), index, &mut data);
+                    self.inner.pages.fetch_add(1, Ordering::Relaxed);
+                    data
+                });
+                page[offset..offset + range.len()].copy_from_slice(&buf[range]);
+            });
+            buffer.size = buffer.size.max(end);
+            buffer.modified = true;
+        }
+
+        // 缓存的页太多时写回
+        if self.inner.pages.load(Ordering::Relaxed) > PAGE_CACHE_SIZE {
+            self.exclusive(|_| ());
+        }
+        Some(buf.len())
+    }
+}
+
+/// 把从 pos 开始长度为 len 的数据按页切开
+///
+/// f 的参数是块序号、块内偏移和这一段在缓冲区中的范围
+fn split_pages(pos: usize, len: usize, mut f: impl FnMut(usize, usize, Range<usize>)) {
+    let mut done = 0;
+    while done < len {
+        let offset = (pos + done) % 4096;
+        let size = min(len - done, 4096 - offset);
+        f((pos + done) / 4096, offset, done..done + size);
+        done += size;
+    }
+}
+
+
+impl VirtualFileSystem for SharedFileSystem {
+    type File = SharedFile;
+    type Error = VerySimpleError;
+    type FileDescription = VerySimpleFileDescription;
+
+    fn init(&mut self) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.init())
+    }
+
+    fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, Self::Error> {
+        self.exclusive(|fs| fs.create_file(path))
+    }
+
+    fn delete_file(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.delete_file(path))
+    }
+
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> Result<Self::FileDescription, Self::Error> {
+        self.exclusive(|fs| fs.clone_file(src, dst))
+    }
+
+    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error> {
+        self.exclusive(|fs| Ok(SharedFile { file: fs.open(path, mode)? }))
+    }
+
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> Result<Self::File, Self::Error> {
+        self.exclusive(|fs| Ok(SharedFile { file: fs.open_with(path, options)? }))
+    }
+
+    fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, Self::Error> {
+        self.exclusive(|fs| fs.description(&file.file))
+    }
+
+    fn close(&mut self, file: Self::File) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.close(file.file))
+    }
+
+    fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self.read_shared(file, buf, file.position()) {
+            Some(len) => {
+                file.set_position(file.position() + len);
+                Ok(len)
+            }
+            None => self.exclusive(|fs| fs.read(&mut file.file, buf)),
+        }
+    }
+
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error> {
+        if file.mode() == AccessMode::Read {
+            return Err(VerySimpleError::AccessError);
+        }
+        // 追加写入要先取得文件大小，走独占的路径
+        if file.file.append() {
+            return self.exclusive(|fs| fs.write(&mut file.file, buf));
+        }
+        match self.write_shared(file, buf, file.position()) {
+            Some(len) => {
+                file.set_position(file.position() + len);
+                Ok(len)
+            }
+            None => self.exclusive(|fs| fs.write(&mut file.file, buf)),
+        }
+    }
+
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        match self.read_shared(file, buf, offset) {
+            Some(len) => Ok(len),
+            None => self.exclusive(|fs| fs.read_at(&file.file, buf, offset)),
+        }
+    }
+
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, Self::Error> {
+        if file.mode() == AccessMode::Read {
+            return Err(VerySimpleError::AccessError);
+        }
+        match self.write_shared(file, buf, offset) {
+            Some(len) => Ok(len),
+            None => self.exclusive(|fs| fs.write_at(&file.file, buf, offset)),
+        }
+    }
+
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, Self::Error> {
+        self.exclusive(|fs| fs.readv(&mut file.file, bufs))
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, Self::Error> {
+        self.exclusive(|fs| fs.writev(&mut file.file, bufs))
+    }
+
+    fn fsync(&mut self, file: &Self::File) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.fsync(&file.file))
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.sync())
+    }
+
+    fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        self.exclusive(|fs| fs.list(path))
+    }
+
+    fn mkdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.mkdir(path))
+    }
+
+    fn rmdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.rmdir(path))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.rename(from, to))
+    }
+
+    fn exists(&mut self, path: &Path) -> Result<bool, Self::Error> {
+        self.exclusive(|fs| fs.exists(path))
+    }
+
+    fn user(&self) -> u32 {
+        self.uid
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.uid = uid;
+    }
+
+    fn end_session(&mut self, session: usize) -> Result<usize, Self::Error> {
+        self.exclusive(|fs| fs.end_session(session))
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, Self::Error> {
+        self.exclusive(|fs| fs.is_open(session, path, mode))
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, Self::Error> {
+        self.exclusive(|fs| fs.lock(&file.file, kind, range, wait))
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.unlock(&file.file, range))
+    }
+
+    fn locks(&mut self) -> Result<Vec<LockInfo>, Self::Error> {
+        self.exclusive(|fs| fs.locks())
+    }
+
+    fn quotas(&mut self) -> Result<Vec<QuotaReport>, Self::Error> {
+        self.exclusive(|fs| fs.quotas())
+    }
+
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.set_quota(target, blocks, inodes))
+    }
+
+    fn set_grace_period(&mut self, grace_period: u32) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.set_grace_period(grace_period))
+    }
+
+    fn fragmentation(&mut self, path: &Path) -> Result<Vec<FragmentReport>, Self::Error> {
+        self.exclusive(|fs| fs.fragmentation(path))
+    }
+
+    fn defrag(&mut self, path: &Path) -> Result<Vec<FragmentReport>, Self::Error> {
+        self.exclusive(|fs| fs.defrag(path))
+    }
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.set_compression(path, enabled))
+    }
+
+    fn dedup(&mut self) -> Result<DedupReport, Self::Error> {
+        self.exclusive(|fs| fs.dedup())
+    }
+
+    fn snapshot_create(&mut self, name: &str) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.snapshot_create(name))
+    }
+
+    fn snapshots(&mut self) -> Result<Vec<SnapshotInfo>, Self::Error> {
+        self.exclusive(|fs| fs.snapshots())
+    }
+
+    fn snapshot_delete(&mut self, name: &str) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.snapshot_delete(name))
+    }
+
+    fn snapshot_rollback(&mut self, name: &str) -> Result<(), Self::Error> {
+        self.exclusive(|fs| fs.snapshot_rollback(name))
+    }
+
+    fn snapshot_diff(&mut self, name: &str) -> Result<SnapshotDiff, Self::Error> {
+        self.exclusive(|fs| fs.snapshot_diff(name))
+    }
+
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        self.exclusive(|fs| fs.snapshot_list(name, path))
+    }
+
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> Result<Vec<u8>, Self::Error> {
+        self.exclusive(|fs| fs.snapshot_read(name, path))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::thread;
+
+    use super::*;
+    use crate::vfs::conformance;
+
+    fn new_fs() -> SharedFileSystem {
+        let mut fs = SharedFileSystem::new(Disk::with_size(4096).unwrap());
+        fs.init().unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_conformance() {
+        conformance::run_all(|| SharedFileSystem::new(Disk::with_size(4096).unwrap()));
+    }
+
+    #[test]
+    fn test_stress() {
+        const THREADS: usize = 8;
+        const FILES: usize = 4;
+        const ROUNDS: usize = 20;
+
+        let mut fs = new_fs();
+        let handles = (0..THREADS).map(|t| {
+            let mut fs = fs.clone();
+            thread::spawn(move || {
+                for f in 0..FILES {
+                    fs.create_file(&Path::from_str(&format!("/t{}_{}", t, f)).unwrap()).unwrap();
+                }
+                // 反复清空重写，大小在直接块和间接块之间来回变化
+                for round in 0..ROUNDS {
+                    for f in 0..FILES {
+                        let path = Path::from_str(&format!("/t{}_{}", t, f)).unwrap();
+                        let len = 4096 * (1 + (round * 7 + f) % 16) + t;
+                        let data = vec![(t * FILES + f + round) as u8; len];
+                        let options = OpenOptions::new().read(true).write(true).truncate(true).clone();
+                        let mut file = fs.open_with(&path, &options).unwrap();
+                        fs.write(&mut file, &data).unwrap();
+                        file.set_position(0);
+                        let mut buf = vec![0u8; len + 1];
+                        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), len);
+                        assert_eq!(&buf[..len], &data[..]);
+                        fs.close(file).unwrap();
+                    }
+                }
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+
+        // 每个文件都是最后一轮写入的内容
+        for t in 0..THREADS {
+            for f in 0..FILES {
+                let path = Path::from_str(&format!("/t{}_{}", t, f)).unwrap();
+                let len = 4096 * (1 + ((ROUNDS - 1) * 7 + f) % 16) + t;
+                let mut file = fs.open(&path, AccessMode::Read).unwrap();
+                let mut buf = vec![0u8; len];
+                assert_eq!(fs.read(&mut file, &mut buf).unwrap(), len);
+                assert!(buf.iter().all(|byte| *byte == (t * FILES + f + ROUNDS - 1) as u8));
+                fs.close(file).unwrap();
+            }
+        }
+
+        // 每个数据块只属于一个 inode，位图中占用的块正好是这些块
+        fs.exclusive(|fs| {
+            let disk = fs.disk();
+            let mut owned = HashSet::new();
+            let files = (0..THREADS * FILES)
+                .map(|i| Path::from_str(&format!("/t{}_{}", i / FILES, i % FILES)).unwrap());
+            for path in files.chain([Path::root()]) {
+                let inum = vsfs::get_inum_by_path(disk, &path).unwrap();
+                for dnum in logic::get_dnums(&disk.i_blocks, inum) {
+                    assert!(owned.insert(dnum), "block {} owned twice", dnum);
+                }
+            }
+            let count = disk.sb.data_block_count as usize;
+            let used = count - logic::count_free_items(&disk.d_bitmaps, 0..count);
+            assert_eq!(used, owned.len());
+        });
+    }
+
+    #[test]
+    fn test_buffered_writes() {
+        let mut fs = new_fs();
+        let paths = (0..4).map(|i| Path::from_str(&format!("/f{}", i)).unwrap()).collect::<Vec<_>>();
+        for path in &paths {
+            fs.create_file(path).unwrap();
+            let mut file = fs.open(path, AccessMode::Write).unwrap();
+            fs.write(&mut file, &vec![0u8; 4096 * 2 + 100]).unwrap();
+            fs.close(file).unwrap();
+        }
+        let read_disk = |fs: &SharedFileSystem, path: &Path| fs.inner.fs.read().map(|fs| {
+            let disk = fs.disk();
+            let inum = vsfs::get_inum_by_path(disk, path).unwrap();
+            let mut buf = vec![0u8; 4096 * 2 + 200];
+            let size = unsafe { logic::get_inode(&disk.i_blocks, inum) }.file_size();
+            vsfs::read_inode(disk, inum, 0, &mut buf[..size]).unwrap();
+            (size, buf)
+        }).unwrap();
+
+        // 打开是独占的操作，先全部打开；每个线程改写自己的文件，跨页并且写过末尾，块数不变
+        let files = paths.iter().map(|path| fs.open(path, AccessMode::ReadWrite).unwrap()).collect::<Vec<_>>();
+        let files = files.into_iter().enumerate().map(|(i, file)| {
+            let mut fs = fs.clone();
+            thread::spawn(move || {
+                assert_eq!(fs.write_at(&file, &[i as u8 + 1; 4096], 4096 + 150).unwrap(), 4096);
+                file
+            })
+        }).collect::<Vec<_>>().into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<_>>();
+
+        // 写回之前磁盘上还是原来的数据，读到的是缓存的页
+        for (i, (path, file)) in paths.iter().zip(&files).enumerate() {
+            assert_eq!(read_disk(&fs, path).0, 4096 * 2 + 100);
+            let mut buf = vec![0u8; 4096 * 3];
+            assert_eq!(fs.read_at(file, &mut buf, 0).unwrap(), 4096 * 2 + 150);
+            assert!(buf[..4096 + 150].iter().all(|x| *x == 0));
+            assert!(buf[4096 + 150..4096 * 2 + 150].iter().all(|x| *x == i as u8 + 1));
+        }
+
+        // 独占的操作之前写回
+        for file in files {
+            fs.close(file).unwrap();
+        }
+        for (i, path) in paths.iter().enumerate() {
+            let (size, buf) = read_disk(&fs, path);
+            assert_eq!(size, 4096 * 2 + 150);
+            assert!(buf[4096 + 150..size].iter().all(|x| *x == i as u8 + 1));
+        }
+    }
+
+    #[test]
+    fn test_buffer_limit() {
+        let mut fs = new_fs();
+        let a = Path::from_str("/a").unwrap();
+        fs.create_file(&a).unwrap();
+        let mut file = fs.open(&a, AccessMode::ReadWrite).unwrap();
+        fs.write(&mut file, &vec![0u8; 4096 * (PAGE_CACHE_SIZE + 10)]).unwrap();
+        let pages = |fs: &SharedFileSystem| fs.inner.pages.load(Ordering::Relaxed);
+
+        // 页数没有超过 PAGE_CACHE_SIZE 时留在缓存中，sync 时写回
+        fs.write_at(&file, &vec![1u8; 4096 * 10], 0).unwrap();
+        assert_eq!(pages(&fs), 10);
+        fs.sync().unwrap();
+        assert_eq!(pages(&fs), 0);
+
+        // 超过之后马上写回
+        fs.write_at(&file, &vec![2u8; 4096 * (PAGE_CACHE_SIZE + 1)], 0).unwrap();
+        assert_eq!(pages(&fs), 0);
+        let mut buf = vec![0u8; 4096 * (PAGE_CACHE_SIZE + 10)];
+        assert_eq!(fs.read_at(&file, &mut buf, 0).unwrap(), buf.len());
+        assert!(buf[..4096 * (PAGE_CACHE_SIZE + 1)].iter().all(|x| *x == 2));
+        assert!(buf[4096 * (PAGE_CACHE_SIZE + 1)..].iter().all(|x| *x == 0));
+        fs.close(file).unwrap();
+    }
+
+    #[test]
+    fn test_shared_blocks() {
+        let mut fs = new_fs();
+        let a = Path::from_str("/a").unwrap();
+        let b = Path::from_str("/b").unwrap();
+        fs.create_file(&a).unwrap();
+        let mut file = fs.open(&a, AccessMode::Write).unwrap();
+        fs.write(&mut file, &vec![1u8; 4096 * 4]).unwrap();
+        fs.close(file).unwrap();
+        fs.clone_file(&a, &b).unwrap();
+
+        // 两个线程同时改写共享数据块的两个文件，各自复制一份
+        let handles = [(a.clone(), 2u8), (b.clone(), 3u8)].into_iter().map(|(path, byte)| {
+            let mut fs = fs.clone();
+            thread::spawn(move || {
+                let mut file = fs.open(&path, AccessMode::Write).unwrap();
+                file.set_position(4096);
+                fs.write(&mut file, &vec![byte; 4096]).unwrap();
+                fs.close(file).unwrap();
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter().for_each(|handle| handle.join().unwrap());
+
+        for (path, byte) in [(a, 2u8), (b, 3u8)] {
+            let mut file = fs.open(&path, AccessMode::Read).unwrap();
+            let mut buf = vec![0u8; 4096 * 2];
+            assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 4096 * 2);
+            assert!(buf[..4096].iter().all(|x| *x == 1));
+            assert!(buf[4096..].iter().all(|x| *x == byte));
+            fs.close(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_busy() {
+        let mut fs = new_fs();
+        let free = |fs: &SharedFileSystem| fs.exclusive(|fs| {
+            let d_bitmaps = &fs.disk().d_bitmaps;
+            logic::count_free_items(d_bitmaps, logic::all_data_block_range(d_bitmaps))
+        });
+        let before = free(&fs);
+
+        // 删除打开着的文件之后，其他线程还可以继续按 inum 读写
+        let a = Path::from_str("/a").unwrap();
+        fs.create_file(&a).unwrap();
+        let mut file = fs.open(&a, AccessMode::ReadWrite).unwrap();
+        fs.write(&mut file, &[1u8; 4096 * 2]).unwrap();
+        fs.delete_file(&a).unwrap();
+        assert!(!fs.exists(&a).unwrap());
+        fs.write(&mut file, &[2u8; 4096]).unwrap();
+
+        let mut buf = vec![0u8; 4096 * 3];
+        file.set_position(0);
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 4096 * 3);
+        assert_eq!(buf[4096 * 2], 2);
+        assert_eq!(free(&fs), before - 3);
+
+        // 最后一次关闭时才释放数据块
+        fs.close(file).unwrap();
+        assert_eq!(free(&fs), before);
+    }
+}
@@ -1,7 +1,10 @@
 use std::error::Error;
 use std::fmt::Debug;
+use std::io;
+use std::io::{IoSlice, IoSliceMut, SeekFrom};
+use crate::lock::{LockInfo, LockKind, LockRange, LockStatus, LockWait};
 use crate::path::Path;
-
+use crate::quota::{Limit, QuotaReport, QuotaTarget};
 use crate::rw::AccessMode;
 
 pub trait VirtualFile: Debug {
@@ -11,17 +14,171 @@ pub trait VirtualFile: Debug {
     fn set_position(&mut self, pos: usize);
 }
 
+/// 文件碎片情况
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentReport {
+    pub path: Path,             // 文件路径
+    pub blocks: usize,          // 数据块个数
+    pub extents_before: usize,  // 整理之前的连续区间个数
+    pub extents_after: usize,   // 整理之后的连续区间个数
+    pub skipped: Option<DefragSkip>,    // 没有被整理时的原因
+}
+
+/// 文件没有被整理的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefragSkip {
+    Shared,     // 和快照或者克隆的文件共享数据块
+    NoSpace,    // 没有足够长的连续空闲区域
+}
+
+/// 离线去重的结果
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DedupReport {
+    pub scanned: usize,         // 检查过的数据块个数
+    pub merged: usize,          // 被合并掉的数据块个数
+    pub saved: usize,           // 节省的字节数
+}
+
+/// 快照信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    pub name: String,           // 快照名称
+    pub ctime: u32,             // 创建时间
+}
+
+/// 快照和当前文件系统的差异
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SnapshotDiff {
+    pub added: Vec<Path>,       // 快照之后新增的文件
+    pub removed: Vec<Path>,     // 快照之后删除的文件
+    pub modified: Vec<Path>,    // 快照之后修改过的文件
+}
+
+/// 挂载信息
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountInfo {
+    pub point: Path,            // 挂载点
+    pub source: String,         // 挂载来源
+}
+
+/// 打开文件的选项，和 std::fs::OpenOptions 一样链式设置
+///
+/// ```ignore
+/// fs.open_with(&path, OpenOptions::new().write(true).create(true).truncate(true))
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,       // 每次写入都写到文件末尾，和 position 无关，隐含 write
+    pub truncate: bool,     // 打开时清空文件
+    pub create: bool,       // 不存在时新建
+    pub create_new: bool,   // 新建，已经存在时报错
+    pub session: usize,     // 打开文件的会话（模拟的进程），打开的文件属于这个会话
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    pub fn session(&mut self, session: usize) -> &mut Self {
+        self.session = session;
+        self
+    }
+
+    /// 打开文件用的读写模式，选项的组合不合法时返回 None
+    ///
+    /// 和 std 一样：新建和清空都要可写，追加时不能清空
+    pub fn access_mode(&self) -> Option<AccessMode> {
+        let write = self.write || self.append;
+        if (self.create || self.create_new || self.truncate) && !write {
+            return None;
+        }
+        if self.truncate && self.append {
+            return None;
+        }
+        match (self.read, write) {
+            (true, false) => Some(AccessMode::Read),
+            (false, true) => Some(AccessMode::Write),
+            (true, true) => Some(AccessMode::ReadWrite),
+            (false, false) => None,
+        }
+    }
+
+    /// 需要新建时是否新建
+    pub fn creates(&self) -> bool {
+        self.create || self.create_new
+    }
+
+    /// 去掉新建的选项，文件已经准备好之后用来打开
+    pub fn existing(&self) -> Self {
+        OpenOptions { create: false, create_new: false, ..self.clone() }
+    }
+}
+
+impl From<AccessMode> for OpenOptions {
+    fn from(mode: AccessMode) -> Self {
+        OpenOptions {
+            read: mode != AccessMode::Write,
+            write: mode != AccessMode::Read,
+            ..Default::default()
+        }
+    }
+}
+
+/// 文件系统不支持的操作，可选功能的方法默认返回它，各个实现的错误类型都要能从它转换过来
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unsupported;
+
+impl From<Unsupported> for io::Error {
+    fn from(_: Unsupported) -> Self {
+        io::Error::from(io::ErrorKind::Unsupported)
+    }
+}
+
 pub trait VirtualFileDescription: Debug {
     fn is_dir(&self) -> bool;
     fn name(&self) -> &str;
     fn ctime(&self) -> u64;
     fn mtime(&self) -> u64;
     fn size(&self) -> usize;
+    fn owner(&self) -> u32;
 }
 
 pub trait VirtualFileSystem {
     type File: VirtualFile;
-    type Error: Error;
+    type Error: Error + From<Unsupported>;
     type FileDescription: VirtualFileDescription;
 
     fn init(&mut self) -> Result<(), Self::Error>;
@@ -29,15 +186,657 @@ pub trait VirtualFileSystem {
     fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, Self::Error>;
     fn delete_file(&mut self, path: &Path) -> Result<(), Self::Error>;
 
+    /// 在会话 0 中打开文件
     fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error>;
+    /// 按选项打开文件，可以同时新建、清空，或者以追加方式打开，也可以指定打开文件的会话
+    ///
+    /// 打开的文件属于打开它的会话，之后的读写、加锁和关闭都以这个会话的身份进行，
+    /// 会话结束之后文件被关闭
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> Result<Self::File, Self::Error>;
     fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, Self::Error>;
     fn close(&mut self, file: Self::File) -> Result<(), Self::Error>;
     fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, Self::Error>;
     fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error>;
+    /// 从 offset 处读，不使用也不改变文件的 position
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error>;
+    /// 写到 offset 处，不使用也不改变文件的 position，追加方式打开的文件也写到 offset
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, Self::Error>;
+    /// 从 position 开始依次填满 bufs，相当于把它们连起来做一次 read
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, Self::Error>;
+    /// 把 bufs 依次写到 position 处，相当于把它们连起来做一次 write
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, Self::Error>;
+    /// 把打开的文件还没有写回的修改写到存储上
+    fn fsync(&mut self, file: &Self::File) -> Result<(), Self::Error>;
+    /// 把所有还没有写回的修改写到存储上，比如缓存中的脏页和镜像文件
+    fn sync(&mut self) -> Result<(), Self::Error>;
 
     fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error>;
     fn mkdir(&mut self, path: &Path) -> Result<(), Self::Error>;
     fn rmdir(&mut self, path: &Path) -> Result<(), Self::Error>;
+    /// 移动文件或目录，to 不能已经存在，目录不能移动到自己下面
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error>;
 
     fn exists(&mut self, path: &Path) -> Result<bool, Self::Error>;
-}
\ No newline at end of file
+
+    fn user(&self) -> u32;
+    fn set_user(&mut self, uid: u32);
+
+    /// 结束会话，关闭它打开的所有文件，返回关闭的个数
+    fn end_session(&mut self, session: usize) -> Result<usize, Self::Error>;
+    /// 会话 session 是否以 mode 打开了 path
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, Self::Error>;
+
+    // 下面是可选的功能，没有实现时返回 Unsupported
+
+    /// 复制文件，可以和源文件共享数据块
+    fn clone_file(&mut self, _src: &Path, _dst: &Path) -> Result<Self::FileDescription, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// 给打开的文件加锁，关闭文件或者结束会话时自动释放
+    fn lock(&mut self, _file: &Self::File, _kind: LockKind, _range: LockRange, _wait: LockWait) -> Result<LockStatus, Self::Error> {
+        Err(Unsupported.into())
+    }
+    /// 解锁，同时取消在这个范围上还在等待的请求
+    fn unlock(&mut self, _file: &Self::File, _range: LockRange) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    /// 所有持有的锁和等待中的请求
+    fn locks(&mut self) -> Result<Vec<LockInfo>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    fn quotas(&mut self) -> Result<Vec<QuotaReport>, Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn set_quota(&mut self, _target: &QuotaTarget, _blocks: Limit, _inodes: Limit) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn set_grace_period(&mut self, _grace_period: u32) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    fn fragmentation(&mut self, _path: &Path) -> Result<Vec<FragmentReport>, Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn defrag(&mut self, _path: &Path) -> Result<Vec<FragmentReport>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    fn set_compression(&mut self, _path: &Path, _enabled: bool) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    /// 离线去重：扫描整个镜像，合并已有的内容相同的数据块
+    ///
+    /// 写入时不查重，之后写入的重复数据要等下一次 dedup 才会合并
+    fn dedup(&mut self) -> Result<DedupReport, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    fn snapshot_create(&mut self, _name: &str) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn snapshots(&mut self) -> Result<Vec<SnapshotInfo>, Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn snapshot_delete(&mut self, _name: &str) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn snapshot_rollback(&mut self, _name: &str) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn snapshot_diff(&mut self, _name: &str) -> Result<SnapshotDiff, Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn snapshot_list(&mut self, _name: &str, _path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn snapshot_read(&mut self, _name: &str, _path: &Path) -> Result<Vec<u8>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    fn mount(&mut self, _source: &str, _point: &Path) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn umount(&mut self, _point: &Path) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+    fn mounts(&mut self) -> Result<Vec<MountInfo>, Self::Error> {
+        Err(Unsupported.into())
+    }
+}
+
+/// 把文件系统和一个打开的文件绑在一起，实现 std::io 的 Read、Write 和 Seek
+///
+/// 这样打开的文件可以直接交给 io::copy、BufReader 等使用
+pub struct FileHandle<'a, F: VirtualFileSystem> {
+    fs: &'a mut F,
+    file: &'a mut F::File,
+}
+
+impl<'a, F: VirtualFileSystem> FileHandle<'a, F> {
+    pub fn new(fs: &'a mut F, file: &'a mut F::File) -> Self {
+        FileHandle { fs, file }
+    }
+}
+
+impl<F: VirtualFileSystem> io::Read for FileHandle<'_, F>
+where F::Error: Into<io::Error> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fs.read(self.file, buf).map_err(Into::into)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        self.fs.readv(self.file, bufs).map_err(Into::into)
+    }
+}
+
+impl<F: VirtualFileSystem> io::Write for FileHandle<'_, F>
+where F::Error: Into<io::Error> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.fs.write(self.file, buf).map_err(Into::into)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.fs.writev(self.file, bufs).map_err(Into::into)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fs.fsync(self.file).map_err(Into::into)
+    }
+}
+
+impl<F: VirtualFileSystem> io::Seek for FileHandle<'_, F>
+where F::Error: Into<io::Error> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i64),
+            SeekFrom::Current(offset) => (self.file.position() as i64, offset),
+            SeekFrom::End(offset) => {
+                let size = self.fs.description(self.file).map_err(Into::into)?.size();
+                (size as i64, offset)
+            }
+        };
+
+        let pos = base.checked_add(offset)
+            .filter(|pos| *pos >= 0)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+        self.file.set_position(pos as usize);
+        Ok(pos as u64)
+    }
+}
+
+
+/// 所有 VirtualFileSystem 实现都要通过的测试场景
+///
+/// 每个实现在自己的测试中调用 run_all，传入新建文件系统的闭包
+#[cfg(test)]
+pub mod conformance {
+    use std::panic;
+    use std::panic::AssertUnwindSafe;
+
+    use super::*;
+    use crate::rw::AccessMode;
+
+    pub const SCENARIOS: &[&str] = &[
+        "create_read_write",
+        "create_errors",
+        "overwrite",
+        "list",
+        "mkdir_rmdir",
+        "delete_file",
+        "open_modes",
+        "open_options",
+        "positional_io",
+        "write_at_keeps_tail",
+        "sync",
+        "rename",
+        "sessions",
+        "locks",
+    ];
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    fn write_all<F: VirtualFileSystem>(fs: &mut F, path: &Path, data: &[u8]) {
+        let mut file = fs.open(path, AccessMode::Write).unwrap();
+        assert_eq!(fs.write(&mut file, data).unwrap(), data.len());
+        fs.close(file).unwrap();
+    }
+
+    fn read_all<F: VirtualFileSystem>(fs: &mut F, path: &Path) -> Vec<u8> {
+        let mut file = fs.open(path, AccessMode::Read).unwrap();
+        let mut buf = vec![0u8; fs.description(&file).unwrap().size()];
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), buf.len());
+        fs.close(file).unwrap();
+        buf
+    }
+
+    fn names<F: VirtualFileSystem>(fs: &mut F, path: &Path) -> Vec<String> {
+        let mut names = fs.list(path).unwrap().iter()
+            .map(|fd| fd.name().to_string())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// 依次执行所有场景，每个场景都在 new_fs 新建并初始化的文件系统上执行，失败时报告场景的名字
+    pub fn run_all<F: VirtualFileSystem>(mut new_fs: impl FnMut() -> F) {
+        for name in SCENARIOS {
+            let mut fs = new_fs();
+            fs.init().unwrap();
+            let res = panic::catch_unwind(AssertUnwindSafe(|| run(name, &mut fs)));
+            if let Err(err) = res {
+                let message = err.downcast_ref::<String>().map(String::as_str)
+                    .or_else(|| err.downcast_ref::<&str>().copied())
+                    .unwrap_or_default();
+                panic!("scenario {} failed: {}", name, message);
+            }
+        }
+    }
+
+    /// 在初始化好的 fs 上执行名为 name 的场景
+    fn run<F: VirtualFileSystem>(name: &str, fs: &mut F) {
+        match name {
+            "create_read_write" => create_read_write(fs),
+            "create_errors" => create_errors(fs),
+            "overwrite" => overwrite(fs),
+            "list" => list(fs),
+            "mkdir_rmdir" => mkdir_rmdir(fs),
+            "delete_file" => delete_file(fs),
+            "open_modes" => open_modes(fs),
+            "open_options" => open_options(fs),
+            "positional_io" => positional_io(fs),
+            "write_at_keeps_tail" => write_at_keeps_tail(fs),
+            "sync" => sync(fs),
+            "rename" => rename(fs),
+            "sessions" => sessions(fs),
+            "locks" => locks(fs),
+            _ => panic!("unknown scenario {}", name),
+        }
+    }
+
+    fn create_read_write<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        let fd = fs.create_file(&a).unwrap();
+        assert_eq!(fd.name(), "a");
+        assert!(!fd.is_dir());
+        assert_eq!(fd.size(), 0);
+        assert!(fs.exists(&a).unwrap());
+
+        let data = (0..10000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        write_all(fs, &a, &data);
+        assert_eq!(read_all(fs, &a), data);
+
+        // 分几次读，读到末尾之后返回 0
+        let mut file = fs.open(&a, AccessMode::Read).unwrap();
+        assert_eq!(fs.description(&file).unwrap().size(), data.len());
+        let mut buf = [0u8; 4096];
+        let mut read = Vec::new();
+        loop {
+            let len = fs.read(&mut file, &mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(read, data);
+        fs.close(file).unwrap();
+    }
+
+    fn create_errors<F: VirtualFileSystem>(fs: &mut F) {
+        fs.create_file(&path("/a")).unwrap();
+        assert!(fs.create_file(&path("/a")).is_err());
+        assert!(fs.create_file(&path("/missing/a")).is_err());
+        assert!(fs.create_file(&path("/a/b")).is_err());
+        assert!(!fs.exists(&path("/missing")).unwrap());
+    }
+
+    fn overwrite<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+
+        let mut file = fs.open(&a, AccessMode::Write).unwrap();
+        fs.write(&mut file, b"hello world").unwrap();
+        file.set_position(6);
+        fs.write(&mut file, b"there").unwrap();
+        assert_eq!(file.position(), 11);
+        fs.close(file).unwrap();
+        assert_eq!(read_all(fs, &a), b"hello there");
+
+        // 从中间开始读
+        let mut file = fs.open(&a, AccessMode::Read).unwrap();
+        file.set_position(6);
+        let mut buf = [0u8; 10];
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"there");
+        fs.close(file).unwrap();
+    }
+
+    fn list<F: VirtualFileSystem>(fs: &mut F) {
+        assert!(names(fs, &Path::root()).is_empty());
+
+        fs.mkdir(&path("/d")).unwrap();
+        fs.create_file(&path("/d/y")).unwrap();
+        fs.create_file(&path("/d/x")).unwrap();
+        fs.create_file(&path("/e")).unwrap();
+        write_all(fs, &path("/d/x"), b"12345");
+
+        assert_eq!(names(fs, &Path::root()), vec!["d", "e"]);
+        assert_eq!(names(fs, &path("/d")), vec!["x", "y"]);
+
+        let fds = fs.list(&Path::root()).unwrap();
+        let d = fds.iter().find(|fd| fd.name() == "d").unwrap();
+        assert!(d.is_dir());
+        let fds = fs.list(&path("/d")).unwrap();
+        let x = fds.iter().find(|fd| fd.name() == "x").unwrap();
+        assert!(!x.is_dir());
+        assert_eq!(x.size(), 5);
+
+        assert!(fs.list(&path("/missing")).is_err());
+    }
+
+    fn mkdir_rmdir<F: VirtualFileSystem>(fs: &mut F) {
+        let d = path("/d");
+        fs.mkdir(&d).unwrap();
+        assert!(fs.exists(&d).unwrap());
+        assert!(fs.mkdir(&d).is_err());
+        assert!(fs.mkdir(&path("/missing/d")).is_err());
+
+        fs.mkdir(&path("/d/sub")).unwrap();
+        fs.create_file(&path("/d/sub/a")).unwrap();
+        assert!(fs.rmdir(&path("/d/sub")).is_err());
+        assert!(fs.rmdir(&path("/d/sub/a")).is_err());
+
+        fs.delete_file(&path("/d/sub/a")).unwrap();
+        fs.rmdir(&path("/d/sub")).unwrap();
+        fs.rmdir(&d).unwrap();
+        assert!(!fs.exists(&d).unwrap());
+        assert!(fs.rmdir(&d).is_err());
+    }
+
+    fn delete_file<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+        write_all(fs, &a, b"data");
+        fs.delete_file(&a).unwrap();
+        assert!(!fs.exists(&a).unwrap());
+        assert!(fs.delete_file(&a).is_err());
+        assert!(fs.open(&a, AccessMode::Read).is_err());
+
+        // 删除之后可以重新创建，内容是空的
+        fs.create_file(&a).unwrap();
+        assert!(read_all(fs, &a).is_empty());
+
+        fs.mkdir(&path("/d")).unwrap();
+        assert!(fs.delete_file(&path("/d")).is_err());
+    }
+
+    fn open_modes<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        assert!(fs.open(&a, AccessMode::Read).is_err());
+        fs.create_file(&a).unwrap();
+
+        // 只读打开不能写
+        let mut reader = fs.open(&a, AccessMode::Read).unwrap();
+        assert_eq!(reader.mode(), AccessMode::Read);
+        assert!(fs.write(&mut reader, b"x").is_err());
+
+        // 同时只能有一个写者，读者不受影响
+        let writer = fs.open(&a, AccessMode::Write).unwrap();
+        assert!(fs.open(&a, AccessMode::Write).is_err());
+        assert!(fs.open(&a, AccessMode::ReadWrite).is_err());
+        let other_reader = fs.open(&a, AccessMode::Read).unwrap();
+        fs.close(writer).unwrap();
+
+        let mut writer = fs.open(&a, AccessMode::ReadWrite).unwrap();
+        fs.write(&mut writer, b"abc").unwrap();
+        writer.set_position(0);
+        let mut buf = [0u8; 3];
+        assert_eq!(fs.read(&mut writer, &mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"abc");
+
+        fs.close(writer).unwrap();
+        fs.close(reader).unwrap();
+        fs.close(other_reader).unwrap();
+    }
+
+    /// 在会话 session 中以 mode 打开
+    fn in_session(session: usize, mode: AccessMode) -> OpenOptions {
+        OpenOptions::from(mode).session(session).clone()
+    }
+
+    fn sessions<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+
+        // 会话 1 打开的文件属于会话 1，别的会话查不到
+        let mut writer = fs.open_with(&a, &in_session(1, AccessMode::Write)).unwrap();
+        assert!(fs.is_open(1, &a, AccessMode::Write).unwrap());
+        let reader = fs.open_with(&a, &in_session(1, AccessMode::Read)).unwrap();
+        assert!(!fs.is_open(2, &a, AccessMode::Write).unwrap());
+        assert!(!fs.is_open(0, &a, AccessMode::Read).unwrap());
+
+        // 写者的限制对所有会话都有效，读者不受影响
+        assert!(fs.open_with(&a, &in_session(2, AccessMode::Write)).is_err());
+        let other_reader = fs.open_with(&a, &in_session(2, AccessMode::Read)).unwrap();
+        assert!(fs.is_open(2, &a, AccessMode::Read).unwrap());
+
+        // 结束会话 1 之后它打开的文件都被关闭
+        fs.write(&mut writer, b"x").unwrap();
+        assert_eq!(fs.end_session(1).unwrap(), 2);
+        assert!(fs.write(&mut writer, b"x").is_err());
+        let writer = fs.open_with(&a, &in_session(2, AccessMode::Write)).unwrap();
+        fs.close(writer).unwrap();
+        fs.close(other_reader).unwrap();
+        assert!(!fs.is_open(2, &a, AccessMode::Read).unwrap());
+        assert_eq!(fs.end_session(1).unwrap(), 0);
+        assert!(fs.close(reader).is_err());
+    }
+
+    fn locks<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+        let bytes = |start, len| LockRange::Bytes { start, len };
+
+        let first = fs.open_with(&a, &in_session(1, AccessMode::Read)).unwrap();
+        let second = fs.open_with(&a, &in_session(2, AccessMode::Read)).unwrap();
+
+        // 共享锁可以同时持有，独占锁不行
+        assert_eq!(fs.lock(&first, LockKind::Shared, LockRange::File, LockWait::NonBlocking).unwrap(), LockStatus::Acquired);
+        assert_eq!(fs.lock(&second, LockKind::Shared, LockRange::File, LockWait::NonBlocking).unwrap(), LockStatus::Acquired);
+        assert!(fs.lock(&second, LockKind::Exclusive, LockRange::File, LockWait::NonBlocking).is_err());
+        fs.unlock(&first, LockRange::File).unwrap();
+        assert_eq!(fs.lock(&second, LockKind::Exclusive, LockRange::File, LockWait::NonBlocking).unwrap(), LockStatus::Acquired);
+
+        // 字节范围锁只在重叠时冲突
+        fs.lock(&first, LockKind::Exclusive, bytes(0, 10), LockWait::NonBlocking).unwrap();
+        fs.lock(&second, LockKind::Exclusive, bytes(10, 10), LockWait::NonBlocking).unwrap();
+        assert!(fs.lock(&second, LockKind::Exclusive, bytes(5, 10), LockWait::NonBlocking).is_err());
+
+        // 2 等 1，1 再等 2 会死锁
+        assert_eq!(fs.lock(&second, LockKind::Shared, bytes(0, 5), LockWait::Blocking).unwrap(), LockStatus::Waiting);
+        assert!(fs.lock(&first, LockKind::Exclusive, bytes(10, 1), LockWait::DetectDeadlock).is_err());
+
+        // 1 关闭文件之后它的锁被释放，2 等待的锁自动获得
+        fs.close(first).unwrap();
+        let locks = fs.locks().unwrap();
+        assert!(locks.iter().all(|lock| lock.session == 2 && !lock.waiting));
+        assert!(locks.iter().any(|lock| lock.range == bytes(0, 5)));
+
+        assert_eq!(fs.end_session(2).unwrap(), 1);
+        assert!(fs.locks().unwrap().is_empty());
+        assert!(fs.close(second).is_err());
+    }
+
+    fn open_options<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+
+        // 新建和清空都要可写
+        assert!(fs.open_with(&a, OpenOptions::new().read(true).create(true)).is_err());
+        assert!(fs.open_with(&a, OpenOptions::new().append(true).truncate(true)).is_err());
+        assert!(fs.open_with(&a, &OpenOptions::new()).is_err());
+        assert!(fs.open_with(&a, OpenOptions::new().write(true)).is_err());
+        assert!(!fs.exists(&a).unwrap());
+
+        let file = fs.open_with(&a, OpenOptions::new().write(true).create_new(true)).unwrap();
+        fs.close(file).unwrap();
+        assert!(fs.open_with(&a, OpenOptions::new().write(true).create_new(true)).is_err());
+        write_all(fs, &a, b"hello");
+
+        // 追加时不管 position，每次都写到末尾
+        let mut file = fs.open_with(&a, OpenOptions::new().append(true).create(true)).unwrap();
+        assert_eq!(file.mode(), AccessMode::Write);
+        file.set_position(0);
+        fs.write(&mut file, b" world").unwrap();
+        fs.write(&mut file, b"!").unwrap();
+        assert_eq!(file.position(), 12);
+        fs.close(file).unwrap();
+        assert_eq!(read_all(fs, &a), b"hello world!");
+
+        // 清空之后从头写
+        let mut file = fs.open_with(&a, OpenOptions::new().read(true).write(true).truncate(true)).unwrap();
+        assert_eq!(fs.description(&file).unwrap().size(), 0);
+        let mut buf = [0u8; 4];
+        assert_eq!(fs.read(&mut file, &mut buf).unwrap(), 0);
+        fs.write(&mut file, b"new").unwrap();
+        fs.close(file).unwrap();
+        assert_eq!(read_all(fs, &a), b"new");
+
+        fs.mkdir(&path("/d")).unwrap();
+        assert!(fs.open_with(&path("/d"), OpenOptions::new().write(true).truncate(true)).is_err());
+        assert!(fs.open_with(&path("/missing/a"), OpenOptions::new().write(true).create(true)).is_err());
+    }
+
+    fn positional_io<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+        write_all(fs, &a, b"hello world");
+
+        // read_at 和 write_at 不动 position
+        let mut file = fs.open(&a, AccessMode::ReadWrite).unwrap();
+        file.set_position(6);
+        let mut buf = [0u8; 5];
+        assert_eq!(fs.read_at(&file, &mut buf, 0).unwrap(), 5);
+        assert_eq!(&buf, b"hello");
+        assert_eq!(fs.read_at(&file, &mut buf, 20).unwrap(), 0);
+        assert_eq!(file.position(), 6);
+
+        // readv 从 position 开始依次填满，读到末尾为止
+        let (mut first, mut second) = ([0u8; 3], [0u8; 10]);
+        let len = fs.readv(&mut file, &mut [IoSliceMut::new(&mut first), IoSliceMut::new(&mut []), IoSliceMut::new(&mut second)]).unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(&first, b"wor");
+        assert_eq!(&second[..2], b"ld");
+        assert_eq!(file.position(), 11);
+
+        // writev 相当于把缓冲区连起来写一次
+        file.set_position(0);
+        let len = fs.writev(&mut file, &[IoSlice::new(b"ab"), IoSlice::new(b""), IoSlice::new(b"cde")]).unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(file.position(), 5);
+        assert_eq!(fs.write_at(&file, b"xy", 5).unwrap(), 2);
+        assert_eq!(file.position(), 5);
+        fs.close(file).unwrap();
+        assert_eq!(read_all(fs, &a), b"abcdexyorld");
+
+        // 追加方式打开时 writev 也写到末尾
+        let mut file = fs.open_with(&a, OpenOptions::new().append(true)).unwrap();
+        file.set_position(0);
+        fs.writev(&mut file, &[IoSlice::new(b"1"), IoSlice::new(b"2")]).unwrap();
+        assert_eq!(file.position(), 13);
+        fs.close(file).unwrap();
+        assert_eq!(read_all(fs, &a), b"abcdexyorld12");
+
+        let mut reader = fs.open(&a, AccessMode::Read).unwrap();
+        assert!(fs.write_at(&reader, b"x", 0).is_err());
+        assert!(fs.writev(&mut reader, &[IoSlice::new(b"x")]).is_err());
+        fs.close(reader).unwrap();
+        assert_eq!(read_all(fs, &a), b"abcdexyorld12");
+    }
+
+    fn write_at_keeps_tail<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+        let data = (0..9100).map(|i| i as u8).collect::<Vec<_>>();
+        write_all(fs, &a, &data);
+
+        // 写在文件中间时不截断后面的数据
+        let mut expected = data.clone();
+        let file = fs.open(&a, AccessMode::Write).unwrap();
+        assert_eq!(fs.write_at(&file, b"xy", 10).unwrap(), 2);
+        expected[10..12].copy_from_slice(b"xy");
+        assert_eq!(fs.description(&file).unwrap().size(), 9100);
+
+        // 写过末尾时文件变大，中间空出来的部分补 0
+        fs.write_at(&file, b"z", 9200).unwrap();
+        expected.resize(9200, 0);
+        expected.push(b'z');
+        fs.close(file).unwrap();
+        assert_eq!(read_all(fs, &a), expected);
+
+        // 顺序写入也一样
+        let mut file = fs.open(&a, AccessMode::Write).unwrap();
+        fs.write(&mut file, b"head").unwrap();
+        expected[..4].copy_from_slice(b"head");
+        fs.close(file).unwrap();
+        assert_eq!(read_all(fs, &a), expected);
+    }
+
+    fn sync<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+
+        // 写回不改变读到的内容
+        let mut file = fs.open(&a, AccessMode::ReadWrite).unwrap();
+        fs.write(&mut file, &[1u8; 5000]).unwrap();
+        fs.write(&mut file, &[2u8; 100]).unwrap();
+        fs.fsync(&file).unwrap();
+        let mut buf = [0u8; 100];
+        assert_eq!(fs.read_at(&file, &mut buf, 5000).unwrap(), 100);
+        assert_eq!(buf, [2u8; 100]);
+
+        fs.write_at(&file, &[3u8; 100], 5000).unwrap();
+        fs.sync().unwrap();
+        fs.close(file).unwrap();
+        let data = read_all(fs, &a);
+        assert_eq!(data.len(), 5100);
+        assert_eq!(&data[4999..5001], &[1, 3]);
+
+        // 会话结束之后文件已经关闭，不能再 fsync
+        let file = fs.open(&a, AccessMode::Read).unwrap();
+        fs.fsync(&file).unwrap();
+        assert_eq!(fs.end_session(0).unwrap(), 1);
+        assert!(fs.fsync(&file).is_err());
+    }
+
+    fn rename<F: VirtualFileSystem>(fs: &mut F) {
+        let a = path("/a");
+        fs.create_file(&a).unwrap();
+        write_all(fs, &a, b"data");
+        fs.mkdir(&path("/d")).unwrap();
+
+        // 移动到别的目录并改名
+        fs.rename(&a, &path("/d/b")).unwrap();
+        assert!(!fs.exists(&a).unwrap());
+        assert_eq!(read_all(fs, &path("/d/b")), b"data");
+
+        // 目录连同里面的文件一起移动
+        fs.rename(&path("/d"), &path("/e")).unwrap();
+        assert!(!fs.exists(&path("/d")).unwrap());
+        assert_eq!(names(fs, &path("/e")), vec!["b"]);
+        assert_eq!(read_all(fs, &path("/e/b")), b"data");
+
+        fs.create_file(&path("/c")).unwrap();
+        assert!(fs.rename(&path("/missing"), &path("/x")).is_err());
+        assert!(fs.rename(&path("/c"), &path("/e/b")).is_err());
+        assert!(fs.rename(&path("/c"), &path("/missing/c")).is_err());
+        assert!(fs.rename(&path("/e"), &path("/e/sub")).is_err());
+        assert!(fs.rename(&Path::root(), &path("/x")).is_err());
+        assert_eq!(read_all(fs, &path("/e/b")), b"data");
+
+        fs.rename(&path("/e"), &path("/f")).unwrap();
+        assert_eq!(names(fs, &Path::root()), vec!["c", "f"]);
+    }
+}
@@ -1,9 +1,13 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::fmt::{Debug, Display, Formatter};
 
 use crate::{logic, utils};
-use crate::logic::{ALL_INODE_RANGE, DirectoryData, DirectoryEntry, get_state};
+use crate::logic::{all_inode_range, DirectoryData, DirectoryEntry, get_state};
 use crate::path::Path;
-use crate::repr::{DIRECT_BLOCK_COUNT, Disk, INode, SuperBlock};
+use crate::quota::{DirQuota, Limit, QuotaEntry, QuotaReport, QuotaTable, QuotaTarget};
+use crate::repr::{DIRECT_BLOCK_COUNT, Disk, INode, INODE_COMPRESSED, MAX_SNAPSHOT_COUNT, SnapshotEntry, SuperBlock};
+use crate::vfs::{DedupReport, DefragSkip, FragmentReport, SnapshotDiff, SnapshotInfo};
 
 const VERSION: u32 = 1;
 
@@ -23,6 +27,30 @@ pub enum Error {
 
     /// 文件夹不为空
     DirIsNotEmpty,
+
+    /// 超出配额
+    QuotaExceeded,
+
+    /// 不合法的磁盘大小
+    InvalidSize,
+
+    /// 找不到快照
+    SnapshotNotFound(String),
+
+    /// 快照已经存在
+    SnapshotExist(String),
+
+    /// 不合法的快照名称
+    InvalidSnapshotName,
+
+    /// 快照个数已经达到上限
+    TooManySnapshots,
+
+    /// 不能把目录移动到它自己下面
+    MoveIntoItself(Path),
+
+    /// 磁盘上的数据已经损坏
+    CorruptData,
 }
 
 impl Display for Error {
@@ -33,6 +61,14 @@ impl Display for Error {
             Error::NoSpace => write!(f, "no space"),
             Error::InvalidFileType => write!(f, "invalid file type. file, dir, or root dir"),
             Error::DirIsNotEmpty => write!(f, "dir is not empty"),
+            Error::QuotaExceeded => write!(f, "quota exceeded"),
+            Error::InvalidSize => write!(f, "invalid disk size"),
+            Error::SnapshotNotFound(name) => write!(f, "snapshot {} not found", name),
+            Error::SnapshotExist(name) => write!(f, "snapshot {} is already exist", name),
+            Error::InvalidSnapshotName => write!(f, "invalid snapshot name"),
+            Error::TooManySnapshots => write!(f, "too many snapshots"),
+            Error::MoveIntoItself(path) => write!(f, "cannot move {} into itself", path.to_str()),
+            Error::CorruptData => write!(f, "corrupt data on disk"),
         }
     }
 }
@@ -45,19 +81,28 @@ impl Debug for Error {
 
 impl std::error::Error for Error {}
 
+impl From<logic::CorruptData> for Error {
+    fn from(_: logic::CorruptData) -> Self {
+        Error::CorruptData
+    }
+}
+
 
 /// 初始化文件夹
-fn init_dir(disk: &mut Disk, inum: usize) {
+fn init_dir(disk: &mut Disk, inum: usize, uid: u32) {
     let dir_inode = unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) };
     *dir_inode = INode {
         size: 0,
         is_dir: true,
+        uid,
         atime: utils::time(),
         ctime: utils::time(),
         mtime: utils::time(),
         block_count: 0,
         block_direct: [0; DIRECT_BLOCK_COUNT],
         block_indirect: 0,
+        flags: 0,
+        logical_size: 0,
     };
 
     let dir_data = DirectoryData {
@@ -67,38 +112,48 @@ fn init_dir(disk: &mut Disk, inum: usize) {
     logic::write_data_struct_auto_resize(
         &mut disk.i_bitmaps,
         &mut disk.d_bitmaps,
+        &mut disk.d_refs,
         &mut disk.i_blocks,
         &mut disk.d_blocks,
         inum, 0, &dir_data,
     );
+    disk.dir_cache.lock().invalidate(inum);
 }
 
 /// 初始化文件
-fn init_file(disk: &mut Disk, inum: usize) {
+fn init_file(disk: &mut Disk, inum: usize, uid: u32) {
     let file_inode = unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) };
     *file_inode = INode {
         size: 0,
         is_dir: false,
+        uid,
         atime: utils::time(),
         ctime: utils::time(),
         mtime: utils::time(),
         block_count: 0,
         block_direct: [0; DIRECT_BLOCK_COUNT],
         block_indirect: 0,
+        flags: 0,
+        logical_size: 0,
     };
 }
 
+/// 新建的 inode 继承父目录的标志
+fn inherit_flags(disk: &mut Disk, parent: usize, inum: usize) {
+    let flags = unsafe { logic::get_inode(&disk.i_blocks, parent) }.flags & INODE_COMPRESSED;
+    unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) }.flags |= flags;
+}
+
 /// 通过 path 获得 inode
 fn get_inode_mut_by_path<'a>(disk: &'a mut Disk, path: &Path) -> Option<&'a mut INode> {
     let inum = get_inum_by_path(disk, path)?;
     Some(unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) })
 }
 
-/// 更新修改时间
-pub fn update_modify_time(disk: &mut Disk, path: &Path) -> Result<(), Error> {
+/// 更新访问时间
+pub fn update_access_time(disk: &mut Disk, path: &Path) -> Result<(), Error> {
     let inode = get_inode_mut_by_path(disk, path);
     if let Some(inode) = inode {
-        inode.mtime = utils::time();
         inode.atime = utils::time();
         Ok(())
     } else {
@@ -106,15 +161,13 @@ pub fn update_modify_time(disk: &mut Disk, path: &Path) -> Result<(), Error> {
     }
 }
 
-/// 更新访问时间
-pub fn update_access_time(disk: &mut Disk, path: &Path) -> Result<(), Error> {
-    let inode = get_inode_mut_by_path(disk, path);
-    if let Some(inode) = inode {
-        inode.atime = utils::time();
-        Ok(())
-    } else {
-        Err(Error::PathNotFound(path.clone()))
+/// 通过 inum 更新访问时间，modified 时同时更新修改时间
+pub fn update_inode_time(disk: &mut Disk, inum: usize, modified: bool) {
+    let inode = unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) };
+    if modified {
+        inode.mtime = utils::time();
     }
+    inode.atime = utils::time();
 }
 
 /// 初始化磁盘
@@ -122,54 +175,78 @@ pub fn init(disk: &mut Disk) {
     // 先全部置为 0
     disk.reset_zero();
 
-    // 初始化超级块
-    disk.sb = SuperBlock {
-        version: VERSION,
-        root_inum: 0,
-    };
+    // 初始化超级块，磁盘布局在 reset_zero 中已经保留
+    disk.sb.version = VERSION;
 
     // 添加根目录
     logic::set_state(&mut disk.i_bitmaps, 0, true);
-    init_dir(disk, 0);
+    init_dir(disk, 0, 0);
+}
+
+/// 读取目录 inum 的内容，先查目录缓存
+fn read_dir(disk: &Disk, inum: usize) -> DirectoryData {
+    if let Some(dir) = disk.dir_cache.lock().dir(inum) {
+        return dir;
+    }
+
+    let dir = logic::read_data_struct::<DirectoryData>(
+        &disk.d_blocks,
+        &disk.i_blocks,
+        inum, 0,
+    );
+    disk.dir_cache.lock().insert_dir(inum, dir.clone());
+    dir
+}
+
+/// 在目录 parent 中查找 name，先查目录项缓存
+fn lookup(disk: &Disk, parent: usize, name: &str) -> Option<usize> {
+    if let Some(inum) = disk.dir_cache.lock().lookup(parent, name) {
+        return Some(inum);
+    }
+
+    let inum = read_dir(disk, parent).entries.iter()
+        .find(|&entry| entry.name.eq(name))?
+        .inum as usize;
+    disk.dir_cache.lock().insert_dentry(parent, name, inum);
+    Some(inum)
 }
 
 /// 通过 path 获得 inum
-fn get_inum_by_path(disk: &Disk, path: &Path) -> Option<usize> {
+pub fn get_inum_by_path(disk: &Disk, path: &Path) -> Option<usize> {
     let mut inum = 0;
     for seg in path.iter() {
-        let dir_data = logic::read_data_struct::<DirectoryData>(
-            &disk.d_blocks,
-            &disk.i_blocks,
-            inum,
-            0,
-        );
-        let target_entry = dir_data.entries.iter()
-            .find(|&entry| entry.name.eq(seg));
-
-        if let Some(target_entry) = target_entry {
-            inum = target_entry.inum as usize;
-        } else {
+        // 路径中间的文件不能当作目录读取
+        if !unsafe { logic::get_inode(&disk.i_blocks, inum) }.is_dir {
             return None;
         }
+        inum = lookup(disk, inum, seg)?;
     }
     Some(inum)
 }
 
+/// 通过 path 获得从根目录到目标的所有 inum
+fn get_inums_by_path(disk: &Disk, path: &Path) -> Option<Vec<usize>> {
+    let mut inums = vec![0];
+    for seg in path.iter() {
+        let inum = *inums.last().unwrap();
+        if !unsafe { logic::get_inode(&disk.i_blocks, inum) }.is_dir {
+            return None;
+        }
+        inums.push(lookup(disk, inum, seg)?);
+    }
+    Some(inums)
+}
+
 /// 通过 path 获得 dir 和 inum
 fn get_dir_by_path(disk: &Disk, path: &Path) -> Option<(DirectoryData, usize)> {
     // 找到文件夹的 inum
-    let inum = get_inum_by_path(disk, path);
-    if inum.is_none() {
+    let inum = get_inum_by_path(disk, path)?;
+    if !unsafe { logic::get_inode(&disk.i_blocks, inum) }.is_dir {
         return None;
     }
-    let inum = inum.unwrap();
 
     // 读取目录信息
-    let dir = logic::read_data_struct::<DirectoryData>(
-        &disk.d_blocks,
-        &disk.i_blocks,
-        inum, 0,
-    );
+    let dir = read_dir(disk, inum);
 
     Some((dir, inum))
 }
@@ -189,10 +266,49 @@ pub fn is_dir(disk: &Disk, path: &Path) -> Result<bool, Error> {
     Ok(inode.is_dir)
 }
 
+/// 写入目录数据，并把目录大小的变化记到目录所有者的配额上
+///
+/// inums 是从根目录到这个目录的所有 inum
+fn write_dir_data(disk: &mut Disk, inums: &[usize], dir: &DirectoryData) -> Result<(), Error> {
+    let inum = *inums.last().unwrap();
+    let old_inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
+
+    // 目录变大时先检查空间，放不下时什么都不改
+    let size = 4 + serde_json::to_vec(dir).unwrap().len();
+    let blocks = logic::block_count_for_size(size) as i64 - old_inode.block_count as i64;
+    let free = logic::count_free_items(&disk.d_bitmaps, logic::all_data_block_range(&disk.d_bitmaps));
+    if blocks > free as i64 {
+        return Err(Error::NoSpace);
+    }
+
+    logic::write_data_struct_auto_resize(
+        &mut disk.i_bitmaps,
+        &mut disk.d_bitmaps,
+        &mut disk.d_refs,
+        &mut disk.i_blocks,
+        &mut disk.d_blocks,
+        inum, 0, dir,
+    );
+    disk.dir_cache.lock().invalidate(inum);
+
+    let new_inode = unsafe { logic::get_inode(&disk.i_blocks, inum) };
+    let blocks = new_inode.block_count as i64 - old_inode.block_count as i64;
+    charge_quota(disk, old_inode.uid, &inums[..inums.len() - 1], blocks, 0)
+}
+
+/// 新建的 inode 写不进目录项时撤销：释放 inode 和它的数据块，退回记到配额上的用量
+///
+/// inums 是从根目录到父目录的所有 inum，返回原来的错误
+fn undo_create(disk: &mut Disk, inums: &[usize], inum: usize, err: Error) -> Result<(), Error> {
+    free_inode_with_quota(disk, &[inums, &[inum]].concat())?;
+    Err(err)
+}
 
 /// 创建一个目录
-pub fn create_dir(disk: &mut Disk, path: &Path, name: &str) -> Result<(), Error> {
-    let (mut dir, par_inum) = get_dir_by_path(disk, path)
+pub fn create_dir(disk: &mut Disk, path: &Path, name: &str, uid: u32) -> Result<(), Error> {
+    let inums = get_inums_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+    let (mut dir, _) = get_dir_by_path(disk, path)
         .ok_or(Error::PathNotFound(path.clone()))?;
 
     // 检测是否存在同名文件
@@ -203,12 +319,19 @@ pub fn create_dir(disk: &mut Disk, path: &Path, name: &str) -> Result<(), Error>
     }
 
     // 创建一个 inode
-    let inum = logic::get_free_item(&mut disk.i_bitmaps, ALL_INODE_RANGE)
+    let inum = logic::get_free_item(&disk.i_bitmaps, all_inode_range(&disk.i_bitmaps))
         .ok_or(Error::NoSpace)?;
 
-    // 初始化 inode
+    // 检查配额，新目录占用一个 inode 和一个数据块
+    check_quota(disk, uid, &inums, 1, 1)?;
+
+    // 初始化 inode，继承父目录的压缩标志
     logic::set_state(&mut disk.i_bitmaps, inum, true);
-    init_dir(disk, inum);
+    init_dir(disk, inum, uid);
+    inherit_flags(disk, *inums.last().unwrap(), inum);
+
+    let block_count = unsafe { logic::get_inode(&disk.i_blocks, inum) }.block_count;
+    charge_quota(disk, uid, &inums, block_count as i64, 1)?;
 
     // 添加目录项
     let entry = DirectoryEntry {
@@ -216,15 +339,8 @@ pub fn create_dir(disk: &mut Disk, path: &Path, name: &str) -> Result<(), Error>
         name: name.to_string(),
     };
     dir.entries.push(entry);
-    logic::write_data_struct_auto_resize(
-        &mut disk.i_bitmaps,
-        &mut disk.d_bitmaps,
-        &mut disk.i_blocks,
-        &mut disk.d_blocks,
-        par_inum, 0, &dir,
-    );
-
-    Ok(())
+    write_dir_data(disk, &inums, &dir)
+        .or_else(|err| undo_create(disk, &inums, inum, err))
 }
 
 /// 通过 path 获得目录
@@ -238,15 +354,11 @@ pub fn get_dir(disk: &Disk, path: &Path) -> Result<DirectoryData, Error> {
         .ok_or(Error::PathNotFound(path.clone()))
 }
 
-/// 文件夹是否为空
-pub fn dir_is_empty(disk: &Disk, path: &Path) -> Result<bool, Error> {
-    let dir = get_dir(disk, path)?;
-    Ok(dir.is_empty())
-}
-
 /// 创建一个文件
-pub fn create_file(disk: &mut Disk, path: &Path, name: &str) -> Result<(), Error> {
-    let (mut dir, par_inum) = get_dir_by_path(disk, path)
+pub fn create_file(disk: &mut Disk, path: &Path, name: &str, uid: u32) -> Result<(), Error> {
+    let inums = get_inums_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+    let (mut dir, _) = get_dir_by_path(disk, path)
         .ok_or(Error::PathNotFound(path.clone()))?;
 
     // 检测是否存在同名文件
@@ -257,12 +369,17 @@ pub fn create_file(disk: &mut Disk, path: &Path, name: &str) -> Result<(), Error
     }
 
     // 创建一个 inode
-    let inum = logic::get_free_item(&mut disk.i_bitmaps, ALL_INODE_RANGE)
+    let inum = logic::get_free_item(&disk.i_bitmaps, all_inode_range(&disk.i_bitmaps))
         .ok_or(Error::NoSpace)?;
 
-    // 初始化 inode
+    // 检查配额，新文件占用一个 inode
+    check_quota(disk, uid, &inums, 0, 1)?;
+
+    // 初始化 inode，继承父目录的压缩标志
     logic::set_state(&mut disk.i_bitmaps, inum, true);
-    init_file(disk, inum);
+    init_file(disk, inum, uid);
+    inherit_flags(disk, *inums.last().unwrap(), inum);
+    charge_quota(disk, uid, &inums, 0, 1)?;
 
     // 添加目录项
     let entry = DirectoryEntry {
@@ -270,15 +387,141 @@ pub fn create_file(disk: &mut Disk, path: &Path, name: &str) -> Result<(), Error
         name: name.to_string(),
     };
     dir.entries.push(entry);
-    logic::write_data_struct_auto_resize(
-        &mut disk.i_bitmaps,
-        &mut disk.d_bitmaps,
-        &mut disk.i_blocks,
-        &mut disk.d_blocks,
-        par_inum, 0, &dir,
-    );
+    write_dir_data(disk, &inums, &dir)
+        .or_else(|err| undo_create(disk, &inums, inum, err))
+}
 
-    Ok(())
+/// 把 src 移动到 parent 目录下，改名为 name
+///
+/// 只改目录项，inode 和数据都不动；整棵子树的用量从原来的目录配额转到新的目录配额
+pub fn rename(disk: &mut Disk, src: &Path, parent: &Path, name: &str) -> Result<(), Error> {
+    let src_inums = get_inums_by_path(disk, src)
+        .ok_or(Error::PathNotFound(src.clone()))?;
+    let inum = *src_inums.last().unwrap();
+
+    // 根目录不能移动
+    if inum == 0 {
+        return Err(Error::InvalidFileType);
+    }
+
+    let dst = parent.clone().move_push(name.to_string());
+    if dst == *src {
+        return Ok(());
+    }
+    if dst.starts_with(src) {
+        return Err(Error::MoveIntoItself(src.clone()));
+    }
+
+    let dst_inums = get_inums_by_path(disk, parent)
+        .ok_or(Error::PathNotFound(parent.clone()))?;
+    let (dst_dir, _) = get_dir_by_path(disk, parent)
+        .ok_or(Error::PathNotFound(parent.clone()))?;
+    if dst_dir.exists(name) {
+        return Err(Error::FileExist(dst));
+    }
+
+    // 子树的用量，目录要加上下面所有的文件
+    let inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
+    let (mut blocks, mut inodes) = if inode.is_dir {
+        usage_below(disk, inum, None)
+    } else {
+        (0, 0)
+    };
+    blocks += inode.block_count;
+    inodes += 1;
+
+    let src_parents = &src_inums[..src_inums.len() - 1];
+    move_quota(disk, src, &dst, src_parents, &dst_inums, blocks as i64, inodes as i64)?;
+
+    // 从原来的目录中删掉目录项
+    let src_parent = src.clone().parent()
+        .ok_or(Error::InvalidFileType)?;
+    let (mut src_dir, _) = get_dir_by_path(disk, &src_parent)
+        .ok_or(Error::PathNotFound(src_parent.clone()))?;
+    src_dir.entries.retain(|entry| Some(&entry.name) != src.current());
+    write_dir_data(disk, src_parents, &src_dir)?;
+
+    // 新目录可能就是原来的目录，要重新读一遍
+    let (mut dst_dir, _) = get_dir_by_path(disk, parent)
+        .ok_or(Error::PathNotFound(parent.clone()))?;
+    dst_dir.entries.push(DirectoryEntry {
+        inum: inum as u32,
+        name: name.to_string(),
+    });
+    write_dir_data(disk, &dst_inums, &dst_dir)
+}
+
+/// 克隆文件，新文件和 src 共享所有数据块，之后谁先修改谁复制
+pub fn clone_file(disk: &mut Disk, src: &Path, path: &Path, name: &str, uid: u32) -> Result<(), Error> {
+    let src_inum = get_inum_by_path(disk, src)
+        .ok_or(Error::PathNotFound(src.clone()))?;
+    let src_inode = *unsafe { logic::get_inode(&disk.i_blocks, src_inum) };
+    if src_inode.is_dir {
+        return Err(Error::InvalidFileType);
+    }
+
+    let inums = get_inums_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+    let (mut dir, _) = get_dir_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+
+    // 检测是否存在同名文件
+    if dir.exists(name) {
+        let current_path = path.clone()
+            .move_push(name.to_string());
+        return Err(Error::FileExist(current_path));
+    }
+
+    // 共享的块也算在新文件所有者的配额上
+    check_quota(disk, uid, &inums, src_inode.block_count as i64, 1)?;
+
+    // 创建一个 inode
+    let inum = logic::get_free_item(&disk.i_bitmaps, all_inode_range(&disk.i_bitmaps))
+        .ok_or(Error::NoSpace)?;
+    logic::set_state(&mut disk.i_bitmaps, inum, true);
+
+    // 数据块多于直接块时还需要一个间接块
+    let block_indirect = if src_inode.block_count as usize > DIRECT_BLOCK_COUNT {
+        let block_id = logic::get_free_block(&disk.i_bitmaps, logic::all_index_block_range(&disk.i_bitmaps));
+        if block_id.is_none() {
+            logic::set_state(&mut disk.i_bitmaps, inum, false);
+            return Err(Error::NoSpace);
+        }
+        block_id
+    } else {
+        None
+    };
+
+    init_file(disk, inum, uid);
+    let inode = unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) };
+    inode.size = src_inode.size;
+    inode.block_count = src_inode.block_count;
+    inode.block_direct = src_inode.block_direct;
+    inode.flags = src_inode.flags;
+    inode.logical_size = src_inode.logical_size;
+
+    if let Some(block_id) = block_indirect {
+        unsafe {
+            logic::set_block_state(&mut disk.i_bitmaps, block_id, true);
+            let indirect = *logic::get_indirect_block(&disk.i_blocks, src_inode.block_indirect as usize);
+            *logic::get_indirect_block_mut(&mut disk.i_blocks, block_id) = indirect;
+        }
+        unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) }.block_indirect = block_id as u32;
+    }
+
+    for dnum in logic::get_dnums(&disk.i_blocks, inum) {
+        logic::share_block(&mut disk.d_refs, dnum);
+    }
+    charge_quota(disk, uid, &inums, src_inode.block_count as i64, 1)?;
+
+    // 添加目录项
+    let entry = DirectoryEntry {
+        inum: inum as u32,
+        name: name.to_string(),
+    };
+    dir.entries.push(entry);
+    write_dir_data(disk, &inums, &dir)
+        .or_else(|err| undo_create(disk, &inums, inum, err))
 }
 
 /// 某个文件或目录是否存在
@@ -291,34 +534,236 @@ pub fn read_file(disk: &Disk, path: &Path, start_pos: usize, buf: &mut [u8]) ->
     let inum = get_inum_by_path(disk, path)
         .ok_or(Error::PathNotFound(path.clone()))?;
 
+    if unsafe { logic::get_inode(&disk.i_blocks, inum) }.is_dir {
+        return Err(Error::PathNotFound(path.clone()));
+    }
+
+    read_inode(disk, inum, start_pos, buf)
+}
+
+/// 通过 inum 读文件，不经过路径查找
+pub fn read_inode(disk: &Disk, inum: usize, start_pos: usize, buf: &mut [u8]) -> Result<(), Error> {
     let inode = unsafe { logic::get_inode(&disk.i_blocks, inum) };
     if inode.is_dir {
-        return Err(Error::PathNotFound(path.clone()));
+        return Err(Error::InvalidFileType);
     }
 
-    logic::read_data(&disk.d_blocks, &disk.i_blocks, inum, start_pos, buf);
+    if inode.is_compressed() {
+        logic::read_compressed_data(&disk.d_blocks, &disk.i_blocks, inum, start_pos, buf)?;
+    } else {
+        logic::read_data(&disk.d_blocks, &disk.i_blocks, inum, start_pos, buf);
+    }
 
     Ok(())
 }
 
-/// 写文件
-pub fn write_file(disk: &mut Disk, path: &Path, start_pos: usize, buf: &[u8]) -> Result<(), Error> {
-    let inum = get_inum_by_path(disk, path)
-        .ok_or(Error::PathNotFound(path.clone()))?;
+/// 通过 inum 把 bufs 依次写到 start_pos 处，不经过路径查找
+///
+/// dirs 是从根目录到父目录的所有 inum，用来记目录配额，由打开文件的一方记下并在移动之后更新；
+/// 已经被删除的文件为空，只记用户配额
+pub fn write_inode(disk: &mut Disk, inum: usize, dirs: &[usize], start_pos: usize, bufs: &[&[u8]]) -> Result<(), Error> {
+    if unsafe { logic::get_inode(&disk.i_blocks, inum) }.is_dir {
+        return Err(Error::InvalidFileType);
+    }
 
+    write_data(disk, dirs, inum, start_pos, bufs)
+}
+
+/// 写入 inum 的 [start, end) 时能否直接改写已有的数据块，写过末尾时文件变大
+///
+/// 不能有块数的变化、内联和压缩的转换，也不能碰到和快照或克隆共享的块，这样写入只改数据块的内容和文件大小，
+/// 可以先放在页缓存中，之后再用 write_page 写回
+pub fn can_write_in_place(disk: &Disk, inum: usize, start: usize, end: usize) -> bool {
     let inode = unsafe { logic::get_inode(&disk.i_blocks, inum) };
+    if inode.is_dir || inode.is_compressed() || inode.is_inline() || start > inode.file_size() {
+        return false;
+    }
+    let size = inode.file_size().max(end);
+    if inode.block_count == 0 || logic::block_count_for_size(size) != inode.block_count as usize {
+        return false;
+    }
+
+    (start / 4096..end.div_ceil(4096)).all(|index| {
+        let dnum = *logic::get_dnum(&disk.i_blocks, inum, index) as usize;
+        !logic::is_shared(&disk.d_refs, dnum)
+    })
+}
+
+/// inum 的所有数据块的块号，页缓存把它缓存起来，之后按块号读写
+pub fn block_map(disk: &Disk, inum: usize) -> Vec<usize> {
+    logic::get_dnums(&disk.i_blocks, inum)
+}
+
+/// 读出块号为 dnum 的数据块
+pub fn read_block(disk: &Disk, dnum: usize, buf: &mut [u8; 4096]) {
+    buf.copy_from_slice(&disk.d_blocks[dnum].data);
+}
+
+/// 把页缓存中的一页写回块号为 dnum 的数据块
+pub fn write_block(disk: &mut Disk, dnum: usize, data: &[u8; 4096]) {
+    disk.d_blocks[dnum].data.copy_from_slice(data);
+}
+
+/// 读出 inum 的第 index 个数据块
+pub fn read_page(disk: &Disk, inum: usize, index: usize, buf: &mut [u8; 4096]) {
+    let dnum = *logic::get_dnum(&disk.i_blocks, inum, index) as usize;
+    buf.copy_from_slice(&disk.d_blocks[dnum].data);
+}
+
+/// 把页缓存中的一页写回 inum 的第 index 个数据块，块已经不在文件中时丢掉
+pub fn write_page(disk: &mut Disk, inum: usize, index: usize, data: &[u8; 4096]) {
+    if index >= unsafe { logic::get_inode(&disk.i_blocks, inum) }.block_count as usize {
+        return;
+    }
+    let dnum = *logic::get_dnum(&disk.i_blocks, inum, index) as usize;
+    disk.d_blocks[dnum].data.copy_from_slice(data);
+}
+
+/// 写到 end 之后更新文件大小，只会变大，只用于 can_write_in_place 的写入，块数不变
+pub fn grow_file_size(disk: &mut Disk, inum: usize, end: usize) {
+    let inode = unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) };
+    inode.size = inode.size.max(end as u32);
+}
+
+/// 通过 inum 把文件截断为空，释放的用量从配额中减去，dirs 和 write_inode 中的一样
+pub fn truncate_inode(disk: &mut Disk, inum: usize, dirs: &[usize]) -> Result<(), Error> {
+    let inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
     if inode.is_dir {
-        return Err(Error::PathNotFound(path.clone()));
+        return Err(Error::InvalidFileType);
     }
 
-    logic::write_data_auto_resize(
+    logic::resize_with_inline(
         &mut disk.i_bitmaps,
         &mut disk.d_bitmaps,
+        &mut disk.d_refs,
         &mut disk.i_blocks,
         &mut disk.d_blocks,
-        inum, start_pos, buf,
+        inum, 0,
+    );
+    unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) }.logical_size = 0;
+
+    charge_quota(disk, inode.uid, dirs, -(inode.block_count as i64), 0)
+}
+
+/// 从根目录到 path 的父目录的所有 inum，path 不存在时为空
+pub fn parent_inums(disk: &Disk, path: &Path) -> Vec<usize> {
+    let mut inums = get_inums_by_path(disk, path).unwrap_or_default();
+    inums.pop();
+    inums
+}
+
+/// 把 bufs 依次写到 inode 的 start_pos 处，写过末尾时文件变大，不会截断后面的数据
+///
+/// 压缩文件只重新压缩写到的簇，再改写簇表；dirs 是从根目录到这个 inode 的父目录的所有 inum
+fn write_data(disk: &mut Disk, dirs: &[usize], inum: usize, start_pos: usize, bufs: &[&[u8]]) -> Result<(), Error> {
+    let inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
+    let end_pos = start_pos + bufs.iter().map(|buf| buf.len()).sum::<usize>();
+    if inode.is_compressed() {
+        let buf = bufs.concat();
+        let update = logic::update_compressed_data(&disk.d_blocks, &disk.i_blocks, inum, start_pos, &buf)?;
+        write_stored_patches(disk, dirs, inum, &update.patches, update.stored_size)?;
+        unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) }.logical_size = inode.file_size().max(end_pos) as u32;
+        Ok(())
+    } else {
+        write_stored_data(disk, dirs, inum, start_pos, bufs, inode.file_size().max(end_pos))
+    }
+}
+
+/// 把 bufs 依次写到 inode 的数据块上，写完之后存储的大小为 new_size，检查并记录配额
+///
+/// dirs 是从根目录到这个 inode 的父目录的所有 inum
+fn write_stored_data(disk: &mut Disk, dirs: &[usize], inum: usize, start_pos: usize, bufs: &[&[u8]], new_size: usize) -> Result<(), Error> {
+    let end_pos = start_pos + bufs.iter().map(|buf| buf.len()).sum::<usize>();
+    let blocks = reserve_stored_blocks(disk, dirs, inum, &[(start_pos, end_pos)], new_size)?;
+
+    logic::write_vectored_with_size(
+        &mut disk.i_bitmaps,
+        &mut disk.d_bitmaps,
+        &mut disk.d_refs,
+        &mut disk.i_blocks,
+        &mut disk.d_blocks,
+        inum, start_pos, bufs, new_size,
     );
 
+    let uid = unsafe { logic::get_inode(&disk.i_blocks, inum) }.uid;
+    charge_quota(disk, uid, dirs, blocks, 0)
+}
+
+/// 把几段数据分别写到 inode 的数据块上，写完之后存储的大小为 new_size，检查并记录配额
+///
+/// 所有的段一起检查，不会写到一半因为空间不够失败
+fn write_stored_patches(disk: &mut Disk, dirs: &[usize], inum: usize, patches: &[(usize, Vec<u8>)], new_size: usize) -> Result<(), Error> {
+    let ranges = patches.iter()
+        .map(|(pos, data)| (*pos, *pos + data.len()))
+        .collect::<Vec<_>>();
+    let blocks = reserve_stored_blocks(disk, dirs, inum, &ranges, new_size)?;
+
+    for (pos, data) in patches {
+        logic::write_vectored_with_size(
+            &mut disk.i_bitmaps,
+            &mut disk.d_bitmaps,
+            &mut disk.d_refs,
+            &mut disk.i_blocks,
+            &mut disk.d_blocks,
+            inum, *pos, &[data], new_size,
+        );
+    }
+
+    let uid = unsafe { logic::get_inode(&disk.i_blocks, inum) }.uid;
+    charge_quota(disk, uid, dirs, blocks, 0)
+}
+
+/// 检查把 ranges 写到 inode 上并且存储的大小变为 new_size 时的配额和空间，返回要记账的块数
+fn reserve_stored_blocks(disk: &Disk, dirs: &[usize], inum: usize, ranges: &[(usize, usize)], new_size: usize) -> Result<i64, Error> {
+    let inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
+
+    // 检查配额
+    let new_block_count = logic::block_count_for_size(new_size);
+    let blocks = new_block_count as i64 - inode.block_count as i64;
+    check_quota(disk, inode.uid, dirs, blocks, 0)?;
+
+    // 检查空间，和快照共享的块在写入前要复制一份
+    let shared = ranges.iter()
+        .map(|(start, end)| logic::count_shared_blocks(&disk.d_refs, &disk.i_blocks, inum, *start, *end))
+        .sum::<usize>();
+    let free = logic::count_free_items(&disk.d_bitmaps, logic::all_data_block_range(&disk.d_bitmaps));
+    if blocks.max(0) as usize + shared > free {
+        return Err(Error::NoSpace);
+    }
+    Ok(blocks)
+}
+
+/// 打开或关闭压缩
+///
+/// 目录只设置标志，之后在它下面新建的文件和子目录继承这个标志；文件会按新的方式重新写入
+pub fn set_compression(disk: &mut Disk, path: &Path, enabled: bool) -> Result<(), Error> {
+    let mut inums = get_inums_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+    let inum = inums.pop().unwrap();
+
+    let inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
+    if inode.is_dir || inode.is_compressed() == enabled {
+        let inode = unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) };
+        if enabled {
+            inode.flags |= INODE_COMPRESSED;
+        } else {
+            inode.flags &= !INODE_COMPRESSED;
+        }
+        return Ok(());
+    }
+
+    let mut buf = vec![0u8; inode.file_size()];
+    read_file(disk, path, 0, &mut buf)?;
+    let data = if enabled {
+        logic::compress_data(&buf)
+    } else {
+        buf.clone()
+    };
+
+    write_stored_data(disk, &inums, inum, 0, &[&data], data.len())?;
+    let inode = unsafe { logic::get_inode_mut(&mut disk.i_blocks, inum) };
+    inode.flags ^= INODE_COMPRESSED;
+    inode.logical_size = if enabled { buf.len() as u32 } else { 0 };
     Ok(())
 }
 
@@ -330,12 +775,14 @@ pub fn get_inode_by_path<'a>(disk: &'a Disk, path: &Path) -> Option<&'a INode> {
 
 /// 更新目录数据，删掉一些已经被 free 的文件
 fn update_dir_data(disk: &mut Disk, path: &Path) -> Result<(), Error> {
-    let (dir, inum) = get_dir_by_path(disk, path)
+    let inums = get_inums_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+    let (dir, _) = get_dir_by_path(disk, path)
         .ok_or(Error::PathNotFound(path.clone()))?;
     let filtered_entries = dir.entries.into_iter()
         .filter(|entry| {
             get_state(
-                &mut disk.i_bitmaps,
+                &disk.i_bitmaps,
                 entry.inum as usize,
             )
         })
@@ -344,15 +791,25 @@ fn update_dir_data(disk: &mut Disk, path: &Path) -> Result<(), Error> {
         entries: filtered_entries
     };
 
-    logic::write_data_struct_auto_resize(
+    write_dir_data(disk, &inums, &dir)
+}
+
+/// 释放 inode，并把释放的用量从配额中减去
+///
+/// inums 是从根目录到这个 inode 的所有 inum
+fn free_inode_with_quota(disk: &mut Disk, inums: &[usize]) -> Result<(), Error> {
+    let inum = *inums.last().unwrap();
+    let inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
+
+    logic::free_inode(
         &mut disk.i_bitmaps,
         &mut disk.d_bitmaps,
+        &mut disk.d_refs,
         &mut disk.i_blocks,
-        &mut disk.d_blocks,
-        inum, 0, &dir,
+        inum,
     );
 
-    Ok(())
+    charge_quota(disk, inode.uid, &inums[..inums.len() - 1], -(inode.block_count as i64), -1)
 }
 
 /// 删除文件
@@ -361,15 +818,10 @@ pub fn delete_file(disk: &mut Disk, path: &Path) -> Result<(), Error> {
         return Err(Error::InvalidFileType);
     }
 
-    let inum = get_inum_by_path(disk, path)
+    let inums = get_inums_by_path(disk, path)
         .ok_or(Error::PathNotFound(path.clone()))?;
 
-    logic::free_inode(
-        &mut disk.i_bitmaps,
-        &mut disk.d_bitmaps,
-        &mut disk.i_blocks,
-        inum,
-    );
+    free_inode_with_quota(disk, &inums)?;
 
     let parent = path.clone().parent()
         .ok_or(Error::InvalidFileType)?;
@@ -377,12 +829,54 @@ pub fn delete_file(disk: &mut Disk, path: &Path) -> Result<(), Error> {
     update_dir_data(disk, &parent)
 }
 
+/// 把文件从目录中删除，但是保留 inode 和数据，返回 inum
+///
+/// 用于删除还打开着的文件：用量从目录配额中移出，用户配额等到 release_inode 时再减去
+pub fn unlink_file(disk: &mut Disk, path: &Path) -> Result<usize, Error> {
+    if is_dir(disk, path)? {
+        return Err(Error::InvalidFileType);
+    }
+
+    let mut inums = get_inums_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+    let inum = inums.pop().unwrap();
+    let block_count = unsafe { logic::get_inode(&disk.i_blocks, inum) }.block_count;
+    move_quota(disk, path, path, &inums, &[], block_count as i64, 1)?;
+
+    let parent = path.clone().parent()
+        .ok_or(Error::InvalidFileType)?;
+    let (mut dir, _) = get_dir_by_path(disk, &parent)
+        .ok_or(Error::PathNotFound(parent.clone()))?;
+    dir.entries.retain(|entry| entry.inum as usize != inum);
+    write_dir_data(disk, &inums, &dir)?;
+
+    Ok(inum)
+}
+
+/// 释放 unlink_file 留下的 inode
+pub fn release_inode(disk: &mut Disk, inum: usize) -> Result<(), Error> {
+    free_inode_with_quota(disk, &[inum])
+}
+
+/// path 以及它下面所有文件和目录的 inum
+pub fn tree_inums(disk: &Disk, path: &Path) -> Vec<usize> {
+    let Some(inum) = get_inum_by_path(disk, path) else {
+        return Vec::new();
+    };
+
+    let mut items = Vec::new();
+    collect_tree(disk, path, inum, &mut items);
+    items.into_iter().map(|(_, inum)| inum).collect()
+}
+
 /// 删除文件夹
 pub fn delete_dir(disk: &mut Disk, path: &Path) -> Result<(), Error> {
     if !is_dir(disk, path)? {
         return Err(Error::InvalidFileType);
     }
 
+    let inums = get_inums_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
     let (dir, inum) = get_dir_by_path(disk, path)
         .ok_or(Error::PathNotFound(path.clone()))?;
 
@@ -396,29 +890,577 @@ pub fn delete_dir(disk: &mut Disk, path: &Path) -> Result<(), Error> {
         return Err(Error::DirIsNotEmpty);
     }
 
-    logic::free_inode(
+    free_inode_with_quota(disk, &inums)?;
+
+    // 删除这个目录的配额
+    let mut table = get_quota_table(disk);
+    if table.dirs.remove(&(inum as u32)).is_some() {
+        save_quota_table(disk, &table)?;
+    }
+
+    let parent = path.clone().parent()
+        .ok_or(Error::InvalidFileType)?;
+
+    update_dir_data(disk, &parent)
+}
+
+/// 读取配额表，没有配额文件时返回空表
+pub fn get_quota_table(disk: &Disk) -> QuotaTable {
+    if disk.sb.quota_inum == 0 {
+        return QuotaTable::default();
+    }
+
+    logic::read_data_struct::<QuotaTable>(
+        &disk.d_blocks,
+        &disk.i_blocks,
+        disk.sb.quota_inum as usize, 0,
+    )
+}
+
+/// 写入配额表，没有配额文件时先创建一个
+///
+/// 配额文件不在任何目录中，它占用的空间不计入配额
+fn save_quota_table(disk: &mut Disk, table: &QuotaTable) -> Result<(), Error> {
+    if disk.sb.quota_inum == 0 {
+        let inum = logic::get_free_item(&disk.i_bitmaps, all_inode_range(&disk.i_bitmaps))
+            .ok_or(Error::NoSpace)?;
+        logic::set_state(&mut disk.i_bitmaps, inum, true);
+        init_file(disk, inum, 0);
+        disk.sb.quota_inum = inum as u32;
+    }
+
+    logic::write_data_struct_auto_resize(
         &mut disk.i_bitmaps,
         &mut disk.d_bitmaps,
+        &mut disk.d_refs,
         &mut disk.i_blocks,
-        inum,
+        &mut disk.d_blocks,
+        disk.sb.quota_inum as usize, 0, table,
     );
 
-    let parent = path.clone().parent()
-        .ok_or(Error::InvalidFileType)?;
+    Ok(())
+}
 
-    update_dir_data(disk, &parent)
+/// 检查 uid 在 dirs 这些目录下增加用量是否会超出配额
+fn check_quota(disk: &Disk, uid: u32, dirs: &[usize], blocks: i64, inodes: i64) -> Result<(), Error> {
+    if disk.sb.quota_inum == 0 {
+        return Ok(());
+    }
+
+    let table = get_quota_table(disk);
+    if table.allows(uid, dirs, blocks, inodes, utils::time()) {
+        Ok(())
+    } else {
+        Err(Error::QuotaExceeded)
+    }
+}
+
+/// 记录 uid 在 dirs 这些目录下用量的变化
+fn charge_quota(disk: &mut Disk, uid: u32, dirs: &[usize], blocks: i64, inodes: i64) -> Result<(), Error> {
+    if disk.sb.quota_inum == 0 || (blocks == 0 && inodes == 0) {
+        return Ok(());
+    }
+
+    let mut table = get_quota_table(disk);
+    table.charge(uid, dirs, blocks, inodes, utils::time());
+    save_quota_table(disk, &table)
+}
+
+/// 子树从 src 移动到 dst 时，把它的用量从只在 from 中的目录配额转到只在 to 中的目录配额
+///
+/// 用户配额不变；子树中设置了配额的目录，记录的路径也跟着改
+fn move_quota(disk: &mut Disk, src: &Path, dst: &Path, from: &[usize], to: &[usize], blocks: i64, inodes: i64) -> Result<(), Error> {
+    if disk.sb.quota_inum == 0 {
+        return Ok(());
+    }
+
+    let mut table = get_quota_table(disk);
+    let now = utils::time();
+    let leaving = from.iter().filter(|inum| !to.contains(inum)).collect::<Vec<_>>();
+    let entering = to.iter().filter(|inum| !from.contains(inum)).collect::<Vec<_>>();
+
+    let allowed = entering.iter()
+        .filter_map(|inum| table.dirs.get(&(**inum as u32)))
+        .all(|dir| dir.quota.allows(blocks, inodes, table.grace_period, now));
+    if !allowed {
+        return Err(Error::QuotaExceeded);
+    }
+
+    for (dirs, sign) in [(&leaving, -1), (&entering, 1)] {
+        for inum in dirs {
+            if let Some(dir) = table.dirs.get_mut(&(**inum as u32)) {
+                dir.quota.charge(sign * blocks, sign * inodes, now);
+            }
+        }
+    }
+
+    for dir in table.dirs.values_mut() {
+        let rest = Path::from_str(&dir.path)
+            .and_then(|path| path.strip_prefix(src));
+        if let Some(rest) = rest {
+            dir.path = dst.join(&rest).to_str();
+        }
+    }
+
+    save_quota_table(disk, &table)
+}
+
+/// 统计 inum 这个目录下面（不含自身）的用量，返回 (块数, inode 数)
+///
+/// uid 为 None 时统计所有用户
+fn usage_below(disk: &Disk, inum: usize, uid: Option<u32>) -> (u32, u32) {
+    let dir = read_dir(disk, inum);
+
+    let mut blocks = 0;
+    let mut inodes = 0;
+    for entry in dir.iter() {
+        let inode = unsafe { logic::get_inode(&disk.i_blocks, entry.inum as usize) };
+        if uid.is_none_or(|uid| uid == inode.uid) {
+            blocks += inode.block_count;
+            inodes += 1;
+        }
+        if inode.is_dir {
+            let (b, i) = usage_below(disk, entry.inum as usize, uid);
+            blocks += b;
+            inodes += i;
+        }
+    }
+    (blocks, inodes)
+}
+
+/// 设置配额，限制都为 0 时删除这项配额
+pub fn set_quota(disk: &mut Disk, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> Result<(), Error> {
+    let mut table = get_quota_table(disk);
+    let now = utils::time();
+    let remove = blocks == Limit::default() && inodes == Limit::default();
+
+    match target {
+        QuotaTarget::User(uid) => {
+            if remove {
+                table.users.remove(uid);
+            } else {
+                let entry = table.users.entry(*uid).or_insert_with(|| {
+                    let root = unsafe { logic::get_inode(&disk.i_blocks, 0) };
+                    let (mut blocks_used, mut inodes_used) = usage_below(disk, 0, Some(*uid));
+                    if root.uid == *uid {
+                        blocks_used += root.block_count;
+                        inodes_used += 1;
+                    }
+                    QuotaEntry { blocks_used, inodes_used, ..Default::default() }
+                });
+                entry.set_limits(blocks, inodes, now);
+            }
+        }
+        QuotaTarget::Dir(path) => {
+            if !is_dir(disk, path)? {
+                return Err(Error::InvalidFileType);
+            }
+            let inum = get_inum_by_path(disk, path)
+                .ok_or(Error::PathNotFound(path.clone()))?;
+
+            if remove {
+                table.dirs.remove(&(inum as u32));
+            } else {
+                let dir = table.dirs.entry(inum as u32).or_insert_with(|| {
+                    let (blocks_used, inodes_used) = usage_below(disk, inum, None);
+                    DirQuota {
+                        path: path.to_str(),
+                        quota: QuotaEntry { blocks_used, inodes_used, ..Default::default() },
+                    }
+                });
+                dir.quota.set_limits(blocks, inodes, now);
+            }
+        }
+    }
+
+    save_quota_table(disk, &table)
+}
+
+/// 设置宽限期
+pub fn set_grace_period(disk: &mut Disk, grace_period: u32) -> Result<(), Error> {
+    let mut table = get_quota_table(disk);
+    table.grace_period = grace_period;
+    save_quota_table(disk, &table)
+}
+
+/// 列出所有配额
+pub fn quotas(disk: &Disk) -> Vec<QuotaReport> {
+    let table = get_quota_table(disk);
+
+    let users = table.users.into_iter()
+        .map(|(uid, entry)| QuotaReport {
+            target: QuotaTarget::User(uid),
+            entry,
+        });
+    let dirs = table.dirs.into_values()
+        .filter_map(|dir| Some(QuotaReport {
+            target: QuotaTarget::Dir(Path::from_str(&dir.path)?),
+            entry: dir.quota,
+        }));
+
+    users.chain(dirs).collect()
+}
+
+/// 收集 path 以及它下面所有的文件和目录
+fn collect_tree(disk: &Disk, path: &Path, inum: usize, items: &mut Vec<(Path, usize)>) {
+    items.push((path.clone(), inum));
+
+    let inode = unsafe { logic::get_inode(&disk.i_blocks, inum) };
+    if !inode.is_dir {
+        return;
+    }
+
+    let dir = read_dir(disk, inum);
+    for entry in dir.iter() {
+        let child = path.clone().move_push(entry.name.clone());
+        collect_tree(disk, &child, entry.inum as usize, items);
+    }
+}
+
+/// 查看 path 以及它下面所有文件的碎片情况
+pub fn fragmentation(disk: &Disk, path: &Path) -> Result<Vec<FragmentReport>, Error> {
+    let inum = get_inum_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+
+    let mut items = Vec::new();
+    collect_tree(disk, path, inum, &mut items);
+
+    let reports = items.into_iter()
+        .map(|(path, inum)| {
+            let dnums = logic::get_dnums(&disk.i_blocks, inum);
+            let extents = logic::count_extents(&dnums);
+            FragmentReport {
+                path,
+                blocks: dnums.len(),
+                extents_before: extents,
+                extents_after: extents,
+                skipped: None,
+            }
+        })
+        .collect();
+    Ok(reports)
+}
+
+/// 整理 path 以及它下面所有文件的碎片，把每个文件的数据块搬到连续的区域
+///
+/// 没能整理的文件在报告中记下原因
+pub fn defrag(disk: &mut Disk, path: &Path) -> Result<Vec<FragmentReport>, Error> {
+    let inum = get_inum_by_path(disk, path)
+        .ok_or(Error::PathNotFound(path.clone()))?;
+
+    let mut items = Vec::new();
+    collect_tree(disk, path, inum, &mut items);
+
+    let mut reports = Vec::new();
+    for (path, inum) in items {
+        let extents_before = logic::count_extents(&logic::get_dnums(&disk.i_blocks, inum));
+        let skipped = match logic::defrag_inode(&mut disk.d_bitmaps, &disk.d_refs, &mut disk.i_blocks, &mut disk.d_blocks, inum) {
+            logic::Defrag::Moved | logic::Defrag::Contiguous => None,
+            logic::Defrag::Shared => Some(DefragSkip::Shared),
+            logic::Defrag::NoSpace => Some(DefragSkip::NoSpace),
+        };
+        let dnums = logic::get_dnums(&disk.i_blocks, inum);
+
+        reports.push(FragmentReport {
+            path,
+            blocks: dnums.len(),
+            extents_before,
+            extents_after: logic::count_extents(&dnums),
+            skipped,
+        });
+    }
+    Ok(reports)
+}
+
+/// 收集所有正在使用的 inode，包括目录树中的 inode 和配额文件
+fn all_inodes(disk: &Disk) -> Vec<usize> {
+    let mut items = Vec::new();
+    collect_tree(disk, &Path::root(), 0, &mut items);
+
+    let mut inums = items.into_iter()
+        .map(|(_, inum)| inum)
+        .collect::<Vec<_>>();
+    if disk.sb.quota_inum != 0 {
+        inums.push(disk.sb.quota_inum as usize);
+    }
+    inums
+}
+
+/// 按照 moved 修改所有 inode 中的数据块编号
+fn relocate_blocks(disk: &mut Disk, moved: &HashMap<u32, u32>) {
+    for inum in all_inodes(disk) {
+        let block_count = unsafe { logic::get_inode(&disk.i_blocks, inum) }.block_count;
+        for index in 0..block_count as usize {
+            let dnum = logic::get_dnum_mut(&mut disk.i_blocks, inum, index);
+            if let Some(new_dnum) = moved.get(dnum) {
+                *dnum = *new_dnum;
+            }
+        }
+    }
+}
+
+/// 调整磁盘大小，size 为磁盘块数，inode 区域保持不变
+///
+/// 缩小时先把数据块搬出被截掉的区域，剩下的空间放不下已有数据时拒绝
+pub fn resize(disk: &mut Disk, size: usize) -> Result<(), Error> {
+    let sb = SuperBlock::with_index_block_count(size, disk.sb.index_block_count as usize)
+        .ok_or(Error::InvalidSize)?;
+    let old_count = disk.sb.data_block_count as usize;
+    let new_count = sb.data_block_count as usize;
+
+    if new_count < old_count {
+        // 先确认放得下，再开始搬动
+        let used = (0..old_count)
+            .filter(|dnum| get_state(&disk.d_bitmaps, *dnum))
+            .count();
+        if used > new_count {
+            return Err(Error::NoSpace);
+        }
+
+        // 数据块可能被快照共享，所以先把块连同引用数一起搬走，再修改所有引用它的 inode
+        let mut moved = HashMap::new();
+        for dnum in new_count..old_count {
+            if !get_state(&disk.d_bitmaps, dnum) {
+                continue;
+            }
+            let new_dnum = logic::get_free_item(&disk.d_bitmaps, 0..new_count)
+                .ok_or(Error::NoSpace)?;
+
+            disk.d_blocks[new_dnum].data = disk.d_blocks[dnum].data;
+            let refs = logic::get_ref(&disk.d_refs, dnum);
+            logic::set_ref(&mut disk.d_refs, new_dnum, refs);
+            logic::set_ref(&mut disk.d_refs, dnum, 0);
+            logic::set_state(&mut disk.d_bitmaps, new_dnum, true);
+            moved.insert(dnum as u32, new_dnum as u32);
+        }
+
+        relocate_blocks(disk, &moved);
+        for index in 0..disk.snapshots.len() {
+            disk.swap_snapshot(index);
+            relocate_blocks(disk, &moved);
+            disk.swap_snapshot(index);
+        }
+    }
+
+    disk.resize_data_area(sb);
+    Ok(())
+}
+
+/// 按照 merged 把所有 inode 中的数据块换成内容相同的块，同时调整引用数
+fn merge_blocks(disk: &mut Disk, merged: &HashMap<u32, u32>) {
+    for inum in all_inodes(disk) {
+        let block_count = unsafe { logic::get_inode(&disk.i_blocks, inum) }.block_count;
+        for index in 0..block_count as usize {
+            let dnum = logic::get_dnum_mut(&mut disk.i_blocks, inum, index);
+            let old_dnum = *dnum;
+            if let Some(new_dnum) = merged.get(&old_dnum) {
+                *dnum = *new_dnum;
+                logic::share_block(&mut disk.d_refs, *new_dnum as usize);
+                logic::release_block(&mut disk.d_bitmaps, &mut disk.d_refs, old_dnum as usize);
+            }
+        }
+    }
+}
+
+/// 离线合并内容相同的数据块
+///
+/// 每次调用都扫描所有已分配的数据块，临时建立内容哈希到块号的索引，哈希相同时再比较内容；
+/// 索引不保存，写入时也不查重。被合并的块在最后一个引用释放时变为空闲
+pub fn dedup(disk: &mut Disk) -> DedupReport {
+    let mut report = DedupReport::default();
+    let mut index: HashMap<u64, Vec<u32>> = HashMap::new();
+    let mut merged = HashMap::new();
+
+    for dnum in 0..disk.sb.data_block_count as usize {
+        if !get_state(&disk.d_bitmaps, dnum) {
+            continue;
+        }
+        report.scanned += 1;
+
+        let data = &disk.d_blocks[dnum].data;
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+
+        let candidates = index.entry(hasher.finish()).or_default();
+        let same = candidates.iter()
+            .find(|other| disk.d_blocks[**other as usize].data == *data);
+        match same {
+            Some(other) => {
+                merged.insert(dnum as u32, *other);
+            }
+            None => candidates.push(dnum as u32),
+        }
+    }
+
+    if merged.is_empty() {
+        return report;
+    }
+
+    merge_blocks(disk, &merged);
+    for index in 0..disk.snapshots.len() {
+        with_snapshot(disk, index, |disk| merge_blocks(disk, &merged));
+    }
+
+    report.merged = merged.len();
+    report.saved = merged.len() * 4096;
+    report
+}
+
+/// 查找快照，返回它在快照表中的下标
+fn find_snapshot(disk: &Disk, name: &str) -> Result<usize, Error> {
+    disk.sb.snapshots().iter()
+        .position(|entry| entry.name() == name)
+        .ok_or(Error::SnapshotNotFound(name.to_string()))
+}
+
+/// 收集一组 inode 的所有数据块
+fn collect_dnums(disk: &Disk, inums: &[usize]) -> Vec<usize> {
+    inums.iter()
+        .flat_map(|inum| logic::get_dnums(&disk.i_blocks, *inum))
+        .collect()
+}
+
+/// 在第 index 个快照上执行 f，执行完之后换回当前文件系统
+pub fn with_snapshot<T>(disk: &mut Disk, index: usize, f: impl FnOnce(&mut Disk) -> T) -> T {
+    disk.swap_snapshot(index);
+    let res = f(disk);
+    disk.swap_snapshot(index);
+    res
+}
+
+/// 创建快照
+///
+/// 快照复制一份 inode 位图和 inode 块，数据块和当前文件系统共享，之后谁先修改谁复制
+pub fn snapshot_create(disk: &mut Disk, name: &str) -> Result<(), Error> {
+    if find_snapshot(disk, name).is_ok() {
+        return Err(Error::SnapshotExist(name.to_string()));
+    }
+    let count = disk.sb.snapshot_count as usize;
+    if count >= MAX_SNAPSHOT_COUNT {
+        return Err(Error::TooManySnapshots);
+    }
+    let entry = SnapshotEntry::new(name, utils::time(), disk.sb.quota_inum)
+        .ok_or(Error::InvalidSnapshotName)?;
+
+    for dnum in collect_dnums(disk, &all_inodes(disk)) {
+        logic::share_block(&mut disk.d_refs, dnum);
+    }
+
+    disk.snapshots.push(disk.capture_snapshot());
+    disk.sb.snapshots[count] = entry;
+    disk.sb.snapshot_count += 1;
+    Ok(())
+}
+
+/// 列出所有快照
+pub fn snapshots(disk: &Disk) -> Vec<SnapshotInfo> {
+    disk.sb.snapshots().iter()
+        .map(|entry| SnapshotInfo {
+            name: entry.name().to_string(),
+            ctime: entry.ctime,
+        })
+        .collect()
+}
+
+/// 删除快照，只被这个快照引用的数据块会被释放
+pub fn snapshot_delete(disk: &mut Disk, name: &str) -> Result<(), Error> {
+    let index = find_snapshot(disk, name)?;
+
+    let dnums = with_snapshot(disk, index, |disk| collect_dnums(disk, &all_inodes(disk)));
+    for dnum in dnums {
+        logic::release_block(&mut disk.d_bitmaps, &mut disk.d_refs, dnum);
+    }
+
+    let count = disk.sb.snapshot_count as usize;
+    disk.snapshots.remove(index);
+    disk.sb.snapshots.copy_within(index + 1..count, index);
+    disk.sb.snapshots[count - 1] = SnapshotEntry::default();
+    disk.sb.snapshot_count -= 1;
+    Ok(())
+}
+
+/// 回滚到快照，当前文件系统的修改全部丢弃，快照本身保留
+pub fn snapshot_rollback(disk: &mut Disk, name: &str) -> Result<(), Error> {
+    let index = find_snapshot(disk, name)?;
+
+    let old_dnums = collect_dnums(disk, &all_inodes(disk));
+
+    // 换入快照之后，当前文件系统就是快照的内容，再复制一份放回快照表
+    disk.swap_snapshot(index);
+    disk.sb.snapshots[index].quota_inum = disk.sb.quota_inum;
+    disk.snapshots[index] = disk.capture_snapshot();
+
+    // 先增加引用再释放，两边共有的块不会被误释放
+    for dnum in collect_dnums(disk, &all_inodes(disk)) {
+        logic::share_block(&mut disk.d_refs, dnum);
+    }
+    for dnum in old_dnums {
+        logic::release_block(&mut disk.d_bitmaps, &mut disk.d_refs, dnum);
+    }
+    Ok(())
+}
+
+/// 比较快照和当前文件系统，列出新增、删除和修改的文件
+pub fn snapshot_diff(disk: &mut Disk, name: &str) -> Result<SnapshotDiff, Error> {
+    let index = find_snapshot(disk, name)?;
+
+    let tree = |disk: &mut Disk| {
+        let mut items = Vec::new();
+        collect_tree(disk, &Path::root(), 0, &mut items);
+        items.into_iter()
+            .map(|(path, inum)| {
+                let inode = *unsafe { logic::get_inode(&disk.i_blocks, inum) };
+                (path.to_str(), (path, inode, logic::get_dnums(&disk.i_blocks, inum)))
+            })
+            .collect::<BTreeMap<_, _>>()
+    };
+    let old = with_snapshot(disk, index, tree);
+    let new = tree(disk);
+
+    let mut diff = SnapshotDiff::default();
+    for (key, (path, inode, dnums)) in &old {
+        match new.get(key) {
+            None => diff.removed.push(path.clone()),
+            Some((_, new_inode, _)) if new_inode.is_dir != inode.is_dir => {
+                diff.removed.push(path.clone());
+                diff.added.push(path.clone());
+            }
+            Some((_, new_inode, new_dnums)) => {
+                // 目录的变化体现在它下面的文件上，内联的文件比较 inode 中的数据
+                let inline_changed = (new_inode.is_inline() || inode.is_inline())
+                    && new_inode.inline_data() != inode.inline_data();
+                if !inode.is_dir && (new_inode.size != inode.size || new_dnums != dnums || inline_changed) {
+                    diff.modified.push(path.clone());
+                }
+            }
+        }
+    }
+    for (key, (path, _, _)) in &new {
+        if !old.contains_key(key) {
+            diff.added.push(path.clone());
+        }
+    }
+    Ok(diff)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    /// 按路径写文件，和 VFS 一样经过 write_inode
+    fn write_file(disk: &mut Disk, path: &Path, start_pos: usize, buf: &[u8]) -> Result<(), Error> {
+        let inum = get_inum_by_path(disk, path)
+            .ok_or(Error::PathNotFound(path.clone()))?;
+        let dirs = parent_inums(disk, path);
+        write_inode(disk, inum, &dirs, start_pos, &[buf])
+    }
+
     #[test]
     fn test_init_dir() {
         let mut disk = Disk::new();
         init(&mut disk);
         logic::set_state(&mut disk.i_bitmaps, 1, true);
-        init_dir(&mut disk, 1);
+        init_dir(&mut disk, 1, 0);
 
         let inode = unsafe { logic::get_inode(&disk.i_blocks, 0) };
 
@@ -430,17 +1472,17 @@ mod test {
         let mut disk = Disk::new();
         init(&mut disk);
         let mut path = Path::root();
-        create_dir(&mut disk, &path, "test").unwrap();
+        create_dir(&mut disk, &path, "test", 0).unwrap();
 
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 1);
         assert_eq!(dir.entries[0].name, "test");
-        assert_eq!(is_dir(&disk, &path).unwrap(), true);
+        assert!(is_dir(&disk, &path).unwrap());
 
         path.push("test".to_string());
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 0);
-        assert_eq!(is_dir(&disk, &path).unwrap(), true);
+        assert!(is_dir(&disk, &path).unwrap());
         println!("{:?}", dir);
     }
 
@@ -449,14 +1491,14 @@ mod test {
         let mut disk = Disk::new();
         init(&mut disk);
         let mut path = Path::root();
-        create_file(&mut disk, &path, "test.c").unwrap();
+        create_file(&mut disk, &path, "test.c", 0).unwrap();
 
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 1);
         assert_eq!(dir.entries[0].name, "test.c");
 
         path.push("test.c".to_string());
-        assert_eq!(is_dir(&disk, &path).unwrap(), false);
+        assert!(!is_dir(&disk, &path).unwrap());
     }
 
     #[test]
@@ -464,12 +1506,12 @@ mod test {
         let mut disk = Disk::new();
         init(&mut disk);
         let mut path = Path::root();
-        create_file(&mut disk, &path, "test.c").unwrap();
+        create_file(&mut disk, &path, "test.c", 0).unwrap();
 
         path.push("test.c".to_string());
         let mut buf = [0; 4096];
-        for i in 0..4096 {
-            buf[i] = i as u8;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = i as u8;
         }
         write_file(&mut disk, &path, 1000, &buf).unwrap();
 
@@ -483,7 +1525,7 @@ mod test {
         let mut disk = Disk::new();
         init(&mut disk);
         let mut path = Path::root();
-        create_file(&mut disk, &path, "test.c").unwrap();
+        create_file(&mut disk, &path, "test.c", 0).unwrap();
 
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 1);
@@ -504,18 +1546,18 @@ mod test {
         let mut disk = Disk::new();
         init(&mut disk);
 
-        let mut path = Path::root();
-        create_file(&mut disk, &path, "test.c").unwrap();
-        create_dir(&mut disk, &path, "test1").unwrap();
+        let path = Path::root();
+        create_file(&mut disk, &path, "test.c", 0).unwrap();
+        create_dir(&mut disk, &path, "test1", 0).unwrap();
 
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 2);
         assert_eq!(dir.entries[0].name, "test.c");
         assert_eq!(dir.entries[1].name, "test1");
 
-        let mut path = Path::from_str("/test1").unwrap();
-        create_file(&mut disk, &path, "test2.c").unwrap();
-        create_dir(&mut disk, &path, "test4").unwrap();
+        let path = Path::from_str("/test1").unwrap();
+        create_file(&mut disk, &path, "test2.c", 0).unwrap();
+        create_dir(&mut disk, &path, "test4", 0).unwrap();
 
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 2);
@@ -539,11 +1581,11 @@ mod test {
         init(&mut disk);
 
 
-        let mut path = Path::root();
-        create_file(&mut disk, &path, "test.c").unwrap();
-        create_dir(&mut disk, &path, "test1").unwrap();
-        create_dir(&mut disk, &path, "test2").unwrap();
-        create_dir(&mut disk, &path, "test3").unwrap();
+        let path = Path::root();
+        create_file(&mut disk, &path, "test.c", 0).unwrap();
+        create_dir(&mut disk, &path, "test1", 0).unwrap();
+        create_dir(&mut disk, &path, "test2", 0).unwrap();
+        create_dir(&mut disk, &path, "test3", 0).unwrap();
 
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 4);
@@ -557,9 +1599,9 @@ mod test {
         assert_eq!(get_dir(&disk, &Path::from_str("/test2").unwrap()).unwrap().len(), 0);
         assert_eq!(get_dir(&disk, &Path::from_str("/test3").unwrap()).unwrap().len(), 0);
 
-        let mut path = Path::from_str("/test1").unwrap();
-        create_file(&mut disk, &path, "test2.c").unwrap();
-        create_dir(&mut disk, &path, "test4").unwrap();
+        let path = Path::from_str("/test1").unwrap();
+        create_file(&mut disk, &path, "test2.c", 0).unwrap();
+        create_dir(&mut disk, &path, "test4", 0).unwrap();
 
         let dir = get_dir(&disk, &path).unwrap();
         assert_eq!(dir.entries.len(), 2);
@@ -593,4 +1635,447 @@ mod test {
         assert_eq!(dir.entries.len(), 1);
         assert_eq!(dir.entries[0].name, "test4");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_user_quota() {
+        let mut disk = Disk::new();
+        init(&mut disk);
+        let root = Path::root();
+
+        set_quota(&mut disk, &QuotaTarget::User(1), Limit::new(0, 2), Limit::new(0, 2)).unwrap();
+
+        create_file(&mut disk, &root, "a", 1).unwrap();
+        create_file(&mut disk, &root, "b", 1).unwrap();
+        assert!(matches!(create_file(&mut disk, &root, "c", 1), Err(Error::QuotaExceeded)));
+        // 其他用户不受影响
+        create_file(&mut disk, &root, "c", 2).unwrap();
+
+        let path = Path::from_str("/a").unwrap();
+        write_file(&mut disk, &path, 0, &[1u8; 4096 * 2]).unwrap();
+        assert!(matches!(write_file(&mut disk, &path, 0, &[1u8; 4096 * 2 + 1]), Err(Error::QuotaExceeded)));
+
+        let report = quotas(&disk);
+        assert_eq!(report.len(), 1);
+        assert_eq!(report[0].entry.blocks_used, 2);
+        assert_eq!(report[0].entry.inodes_used, 2);
+
+        delete_file(&mut disk, &path).unwrap();
+        let report = quotas(&disk);
+        assert_eq!(report[0].entry.blocks_used, 0);
+        assert_eq!(report[0].entry.inodes_used, 1);
+        create_file(&mut disk, &root, "d", 1).unwrap();
+    }
+
+    #[test]
+    fn test_create_without_dir_space() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        init(&mut disk);
+        let root = Path::root();
+        set_quota(&mut disk, &QuotaTarget::User(1), Limit::default(), Limit::new(0, 10000)).unwrap();
+        create_file(&mut disk, &root, "a", 1).unwrap();
+
+        // 占满所有的数据块，之后根目录变大时写不下新的目录项
+        while let Some(dnum) = logic::get_free_item(&disk.d_bitmaps, logic::all_data_block_range(&disk.d_bitmaps)) {
+            logic::set_state(&mut disk.d_bitmaps, dnum, true);
+        }
+        let free_inodes = |disk: &Disk| logic::count_free_items(&disk.i_bitmaps, all_inode_range(&disk.i_bitmaps));
+        let mut count = 1;
+        while create_file(&mut disk, &root, &format!("f{}", count), 1).is_ok() {
+            count += 1;
+        }
+
+        // 失败时新建的 inode 被释放，配额的用量不变
+        let free = free_inodes(&disk);
+        assert!(free > 0);
+        let a = Path::from_str("/a").unwrap();
+        assert!(matches!(create_file(&mut disk, &root, "x", 1), Err(Error::NoSpace)));
+        assert!(matches!(create_dir(&mut disk, &root, "x", 1), Err(Error::NoSpace)));
+        assert!(matches!(clone_file(&mut disk, &a, &root, "x", 1), Err(Error::NoSpace)));
+        assert_eq!(free_inodes(&disk), free);
+        assert_eq!(quotas(&disk)[0].entry.inodes_used, count);
+        assert!(!exists(&disk, &Path::from_str("/x").unwrap()));
+
+        // 删掉一个文件之后目录中又有空间了
+        delete_file(&mut disk, &a).unwrap();
+        create_file(&mut disk, &root, "x", 1).unwrap();
+        assert_eq!(quotas(&disk)[0].entry.inodes_used, count);
+    }
+
+    #[test]
+    fn test_dir_quota() {
+        let mut disk = Disk::new();
+        init(&mut disk);
+        let root = Path::root();
+        create_dir(&mut disk, &root, "home", 0).unwrap();
+        let home = Path::from_str("/home").unwrap();
+        create_file(&mut disk, &home, "a", 3).unwrap();
+
+        // 设置配额时统计已有的用量
+        set_quota(&mut disk, &QuotaTarget::Dir(home.clone()), Limit::default(), Limit::new(0, 3)).unwrap();
+        assert_eq!(quotas(&disk)[0].entry.inodes_used, 1);
+
+        create_dir(&mut disk, &home, "sub", 4).unwrap();
+        let sub = Path::from_str("/home/sub").unwrap();
+        create_file(&mut disk, &sub, "b", 5).unwrap();
+        assert!(matches!(create_file(&mut disk, &sub, "c", 5), Err(Error::QuotaExceeded)));
+        create_file(&mut disk, &root, "c", 5).unwrap();
+
+        delete_file(&mut disk, &Path::from_str("/home/sub/b").unwrap()).unwrap();
+        delete_dir(&mut disk, &sub).unwrap();
+        assert_eq!(quotas(&disk)[0].entry.inodes_used, 1);
+    }
+
+    #[test]
+    fn test_quota_grace() {
+        let mut disk = Disk::new();
+        init(&mut disk);
+        let root = Path::root();
+
+        set_quota(&mut disk, &QuotaTarget::User(1), Limit::default(), Limit::new(1, 3)).unwrap();
+        create_file(&mut disk, &root, "a", 1).unwrap();
+        create_file(&mut disk, &root, "b", 1).unwrap();
+
+        // 宽限期为 0 时超过软限制就不能再创建，但已经超过的时刻仍在宽限期内
+        set_grace_period(&mut disk, 0).unwrap();
+        let mut table = get_quota_table(&disk);
+        table.users.get_mut(&1).unwrap().inode_grace_start = 1;
+        save_quota_table(&mut disk, &table).unwrap();
+        assert!(matches!(create_file(&mut disk, &root, "c", 1), Err(Error::QuotaExceeded)));
+    }
+
+    #[test]
+    fn test_rename_quota() {
+        let mut disk = Disk::new();
+        init(&mut disk);
+        let root = Path::root();
+        create_dir(&mut disk, &root, "home", 0).unwrap();
+        create_dir(&mut disk, &root, "tmp", 0).unwrap();
+        let home = Path::from_str("/home").unwrap();
+        let tmp = Path::from_str("/tmp").unwrap();
+        set_quota(&mut disk, &QuotaTarget::Dir(home.clone()), Limit::default(), Limit::new(0, 2)).unwrap();
+
+        create_dir(&mut disk, &tmp, "sub", 1).unwrap();
+        let sub = Path::from_str("/tmp/sub").unwrap();
+        create_file(&mut disk, &sub, "a", 1).unwrap();
+        create_file(&mut disk, &sub, "b", 1).unwrap();
+
+        // 子树有三个 inode，超出 home 的配额
+        assert!(matches!(rename(&mut disk, &sub, &home, "sub"), Err(Error::QuotaExceeded)));
+        delete_file(&mut disk, &Path::from_str("/tmp/sub/b").unwrap()).unwrap();
+        rename(&mut disk, &sub, &home, "sub").unwrap();
+        assert_eq!(quotas(&disk)[0].entry.inodes_used, 2);
+        assert!(exists(&disk, &Path::from_str("/home/sub/a").unwrap()));
+        assert!(!exists(&disk, &sub));
+
+        // 设置了配额的目录移动之后，配额跟着走
+        rename(&mut disk, &home, &tmp, "home").unwrap();
+        assert_eq!(quotas(&disk)[0].target, QuotaTarget::Dir(Path::from_str("/tmp/home").unwrap()));
+        assert!(matches!(rename(&mut disk, &tmp, &Path::from_str("/tmp/home").unwrap(), "x"), Err(Error::MoveIntoItself(_))));
+
+        // 移出来之后用量减少
+        let moved = Path::from_str("/tmp/home/sub").unwrap();
+        rename(&mut disk, &moved, &root, "sub").unwrap();
+        assert_eq!(quotas(&disk)[0].entry.inodes_used, 0);
+    }
+
+    #[test]
+    fn test_defrag() {
+        let mut disk = Disk::new();
+        init(&mut disk);
+        let root = Path::root();
+        create_file(&mut disk, &root, "a", 0).unwrap();
+        create_file(&mut disk, &root, "b", 0).unwrap();
+
+        // 交替写入两个文件，让数据块交错
+        let a = Path::from_str("/a").unwrap();
+        let b = Path::from_str("/b").unwrap();
+        let data = (0..4096 * 4).map(|i| i as u8).collect::<Vec<_>>();
+        for i in 1..=4 {
+            write_file(&mut disk, &a, 0, &data[..4096 * i]).unwrap();
+            write_file(&mut disk, &b, 0, &data[..4096 * i]).unwrap();
+        }
+
+        let reports = fragmentation(&disk, &a).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].blocks, 4);
+        assert_eq!(reports[0].extents_before, 4);
+
+        let reports = defrag(&mut disk, &root).unwrap();
+        assert_eq!(reports.len(), 3);
+        assert!(reports.iter().all(|report| report.extents_after <= 1 && report.skipped.is_none()));
+
+        let mut buf = vec![0u8; 4096 * 4];
+        read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        read_file(&disk, &b, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // 和克隆共享数据块的文件不搬动，在报告中说明
+        for i in 1..=4 {
+            write_file(&mut disk, &a, 0, &data[..4096 * i]).unwrap();
+            write_file(&mut disk, &b, 0, &data[..4096 * i]).unwrap();
+        }
+        for i in 1..=4 {
+            write_file(&mut disk, &a, 4096 * 4, &data[..4096 * i]).unwrap();
+            write_file(&mut disk, &b, 4096 * 4, &data[..4096 * i]).unwrap();
+        }
+        clone_file(&mut disk, &a, &root, "c", 0).unwrap();
+        let reports = defrag(&mut disk, &root).unwrap();
+        let report = |name: &str| reports.iter().find(|report| report.path.to_str() == name).unwrap().clone();
+        assert_eq!(report("/a").skipped, Some(DefragSkip::Shared));
+        assert_eq!(report("/c").skipped, Some(DefragSkip::Shared));
+        assert!(report("/a").extents_after > 1);
+        assert_eq!(report("/b").skipped, None);
+        assert_eq!(report("/b").extents_after, 1);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        init(&mut disk);
+        let root = Path::root();
+
+        // 让数据块分布到磁盘的后半部分
+        create_file(&mut disk, &root, "filler", 0).unwrap();
+        create_file(&mut disk, &root, "a", 0).unwrap();
+        let filler = Path::from_str("/filler").unwrap();
+        let a = Path::from_str("/a").unwrap();
+        write_file(&mut disk, &filler, 0, &vec![0u8; 4096 * 500]).unwrap();
+        let data = (0..4096 * 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        write_file(&mut disk, &a, 0, &data).unwrap();
+        delete_file(&mut disk, &filler).unwrap();
+
+        // 放不下时拒绝，并且不改变磁盘
+        assert!(matches!(resize(&mut disk, 64 + 10), Err(Error::NoSpace)));
+        assert!(matches!(resize(&mut disk, 10), Err(Error::InvalidSize)));
+        assert_eq!(disk.sb.size, 1024);
+
+        resize(&mut disk, 200).unwrap();
+        assert_eq!(disk.sb.size, 200);
+        assert_eq!(disk.d_blocks.len(), disk.sb.data_block_count as usize);
+        let mut buf = vec![0u8; data.len()];
+        read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        resize(&mut disk, 4096).unwrap();
+        assert_eq!(disk.sb.data_block_count as usize, 4096 - 1 - 64 - 1 - 1 - 4);
+        write_file(&mut disk, &a, 0, &vec![1u8; 4096 * 1000]).unwrap();
+        create_dir(&mut disk, &root, "dir", 0).unwrap();
+        assert_eq!(get_dir(&disk, &root).unwrap().len(), 2);
+    }
+
+    /// 统计被占用的数据块
+    fn used_blocks(disk: &Disk) -> usize {
+        let count = disk.sb.data_block_count as usize;
+        count - logic::count_free_items(&disk.d_bitmaps, 0..count)
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        init(&mut disk);
+        let root = Path::root();
+        let a = Path::from_str("/a").unwrap();
+        let b = Path::from_str("/b").unwrap();
+
+        create_file(&mut disk, &root, "a", 0).unwrap();
+        write_file(&mut disk, &a, 0, &vec![1u8; 4096 * 10]).unwrap();
+        let used = used_blocks(&disk);
+
+        // 创建快照不占用数据块
+        snapshot_create(&mut disk, "s1").unwrap();
+        assert_eq!(used_blocks(&disk), used);
+        assert!(matches!(snapshot_create(&mut disk, "s1"), Err(Error::SnapshotExist(_))));
+        assert_eq!(snapshots(&disk)[0].name, "s1");
+
+        // 修改一个块只复制这一个块
+        write_file(&mut disk, &a, 4096, &vec![2u8; 4096 * 10 - 4096]).unwrap();
+        write_file(&mut disk, &a, 0, &[3u8; 100]).unwrap();
+        assert!(used_blocks(&disk) > used);
+        create_file(&mut disk, &root, "b", 0).unwrap();
+
+        let diff = snapshot_diff(&mut disk, "s1").unwrap();
+        assert_eq!(diff.added, vec![b.clone()]);
+        assert_eq!(diff.modified, vec![a.clone()]);
+        assert!(diff.removed.is_empty());
+
+        // 快照中的内容不变
+        let mut buf = vec![0u8; 4096 * 10];
+        with_snapshot(&mut disk, 0, |disk| read_file(disk, &a, 0, &mut buf)).unwrap();
+        assert!(buf.iter().all(|c| *c == 1));
+
+        // 缩小磁盘时快照引用的块也要搬动
+        resize(&mut disk, 200).unwrap();
+        with_snapshot(&mut disk, 0, |disk| read_file(disk, &a, 0, &mut buf)).unwrap();
+        assert!(buf.iter().all(|c| *c == 1));
+
+        snapshot_rollback(&mut disk, "s1").unwrap();
+        assert!(!exists(&disk, &b));
+        read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert!(buf.iter().all(|c| *c == 1));
+        assert_eq!(used_blocks(&disk), used);
+
+        // 删除快照之后所有块都只有一个引用
+        snapshot_delete(&mut disk, "s1").unwrap();
+        assert!(snapshots(&disk).is_empty());
+        assert_eq!(used_blocks(&disk), used);
+        delete_file(&mut disk, &a).unwrap();
+        assert_eq!(used_blocks(&disk), used - 10);
+        assert!(matches!(snapshot_delete(&mut disk, "s1"), Err(Error::SnapshotNotFound(_))));
+    }
+
+    #[test]
+    fn test_clone_file() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        init(&mut disk);
+        let root = Path::root();
+        let a = Path::from_str("/a").unwrap();
+        let b = Path::from_str("/b").unwrap();
+
+        create_file(&mut disk, &root, "a", 0).unwrap();
+        let data = (0..4096 * 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        write_file(&mut disk, &a, 0, &data).unwrap();
+        let used = used_blocks(&disk);
+
+        clone_file(&mut disk, &a, &root, "b", 0).unwrap();
+        assert!(matches!(clone_file(&mut disk, &a, &root, "b", 0), Err(Error::FileExist(_))));
+        assert!(matches!(clone_file(&mut disk, &root, &root, "c", 0), Err(Error::InvalidFileType)));
+        // 只有根目录放不下内联数据，多占用一个块
+        assert_eq!(used_blocks(&disk), used + 1);
+
+        // 修改克隆出来的文件只复制写到的块
+        let mut modified = data.clone();
+        modified[4096 * 15] = 0xff;
+        write_file(&mut disk, &b, 4096 * 15, &modified[4096 * 15..]).unwrap();
+        assert_eq!(used_blocks(&disk), used + 6);
+
+        let mut buf = vec![0u8; data.len()];
+        read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        read_file(&disk, &b, 0, &mut buf).unwrap();
+        assert_eq!(buf, modified);
+
+        // 删除原文件不影响克隆
+        delete_file(&mut disk, &a).unwrap();
+        read_file(&disk, &b, 0, &mut buf).unwrap();
+        assert_eq!(buf, modified);
+        delete_file(&mut disk, &b).unwrap();
+        assert_eq!(used_blocks(&disk), used - 20);
+    }
+
+    #[test]
+    fn test_dedup() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        init(&mut disk);
+        let root = Path::root();
+        let a = Path::from_str("/a").unwrap();
+        let b = Path::from_str("/b").unwrap();
+
+        // 两个文件内容相同，a 内部还有重复的块
+        let data = [[1u8; 4096], [2u8; 4096], [1u8; 4096]].concat();
+        create_file(&mut disk, &root, "a", 0).unwrap();
+        create_file(&mut disk, &root, "b", 0).unwrap();
+        write_file(&mut disk, &a, 0, &data).unwrap();
+        write_file(&mut disk, &b, 0, &data).unwrap();
+        snapshot_create(&mut disk, "s1").unwrap();
+        let used = used_blocks(&disk);
+
+        let report = dedup(&mut disk);
+        assert_eq!(report.merged, 4);
+        assert_eq!(report.saved, 4 * 4096);
+        assert_eq!(used_blocks(&disk), used - 4);
+        assert_eq!(dedup(&mut disk).merged, 0);
+
+        // 合并后的块写时复制，快照中的内容不变
+        write_file(&mut disk, &a, 0, &[3u8; 10]).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        read_file(&disk, &b, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        snapshot_rollback(&mut disk, "s1").unwrap();
+        read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // 写入时不查重，重复的数据要等下一次 dedup 才合并
+        let c = Path::from_str("/c").unwrap();
+        create_file(&mut disk, &root, "c", 0).unwrap();
+        let used = used_blocks(&disk);
+        write_file(&mut disk, &c, 0, &data).unwrap();
+        assert_eq!(used_blocks(&disk), used + 3);
+        assert_eq!(dedup(&mut disk).merged, 3);
+        assert_eq!(used_blocks(&disk), used);
+
+        // 引用全部释放后块才变为空闲
+        snapshot_delete(&mut disk, "s1").unwrap();
+        delete_file(&mut disk, &a).unwrap();
+        delete_file(&mut disk, &b).unwrap();
+        delete_file(&mut disk, &c).unwrap();
+        assert_eq!(used_blocks(&disk), 0);
+    }
+
+    #[test]
+    fn test_compression() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        init(&mut disk);
+        let root = Path::root();
+        let logs = Path::from_str("/logs").unwrap();
+        let a = Path::from_str("/logs/a").unwrap();
+
+        // 目录的压缩标志被新建的文件继承
+        create_dir(&mut disk, &root, "logs", 0).unwrap();
+        set_compression(&mut disk, &logs, true).unwrap();
+        create_file(&mut disk, &logs, "a", 0).unwrap();
+        assert!(get_inode_by_path(&disk, &a).unwrap().is_compressed());
+
+        let line = b"2024-01-01 00:00:00 INFO request handled\n";
+        let data = line.iter().cycle().take(4096 * 30).copied().collect::<Vec<_>>();
+        write_file(&mut disk, &a, 0, &data).unwrap();
+        let inode = get_inode_by_path(&disk, &a).unwrap();
+        assert_eq!(inode.file_size(), data.len());
+        assert!(inode.block_count < 5);
+
+        let mut buf = vec![0u8; 100];
+        read_file(&disk, &a, 4096 * 17 + 7, &mut buf).unwrap();
+        assert_eq!(buf, data[4096 * 17 + 7..4096 * 17 + 107]);
+
+        // 关闭压缩之后内容不变
+        set_compression(&mut disk, &a, false).unwrap();
+        let inode = get_inode_by_path(&disk, &a).unwrap();
+        assert_eq!(inode.block_count, 30);
+        let mut buf = vec![0u8; data.len()];
+        read_file(&disk, &a, 0, &mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_dir_cache() {
+        let mut disk = Disk::new();
+        init(&mut disk);
+        let root = Path::root();
+        let a = Path::from_str("/a").unwrap();
+        let b = Path::from_str("/b").unwrap();
+        create_dir(&mut disk, &root, "d", 0).unwrap();
+        create_file(&mut disk, &root, "a", 0).unwrap();
+        let inum = get_inum_by_path(&disk, &a).unwrap();
+        assert_eq!(disk.dir_cache.lock().lookup(0, "a"), Some(inum));
+
+        // 改名、删除和新建之后缓存的目录项不能再用
+        rename(&mut disk, &a, &root, "b").unwrap();
+        assert_eq!(disk.dir_cache.lock().lookup(0, "a"), None);
+        assert!(!exists(&disk, &a));
+        assert_eq!(get_inum_by_path(&disk, &b), Some(inum));
+
+        delete_file(&mut disk, &b).unwrap();
+        assert!(!exists(&disk, &b));
+
+        create_file(&mut disk, &Path::from_str("/d").unwrap(), "b", 0).unwrap();
+        assert!(exists(&disk, &Path::from_str("/d/b").unwrap()));
+        create_file(&mut disk, &root, "b", 0).unwrap();
+        assert!(exists(&disk, &b));
+
+        // 换到快照之后看到的是快照中的目录
+        snapshot_create(&mut disk, "s").unwrap();
+        delete_file(&mut disk, &b).unwrap();
+        with_snapshot(&mut disk, 0, |disk| assert!(exists(disk, &b)));
+        assert!(!exists(&disk, &b));
+    }
+}
@@ -1,24 +1,48 @@
 use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
-
+use std::io;
+use std::io::{ErrorKind, IoSlice, IoSliceMut};
+use std::ops::{Deref, DerefMut};
+use std::path::PathBuf;
+
+use crate::cache::{Page, PageCache, PAGE_CACHE_SIZE};
+use crate::io::Savable;
+use crate::logic;
+use crate::lock::{LockError, LockInfo, LockKind, LockManager, LockOwner, LockRange, LockStatus, LockWait};
 use crate::path::Path;
+use crate::quota::{Limit, QuotaReport, QuotaTarget};
 use crate::repr::{Disk, INode};
-use crate::rw::{AccessMode, RWManager};
+use crate::rw::{AccessMode, Handle, RWManager};
 use crate::rw::AccessMode::Read;
-use crate::vfs::{VirtualFile, VirtualFileDescription, VirtualFileSystem};
+use crate::vfs::{DedupReport, FragmentReport, OpenOptions, SnapshotDiff, SnapshotInfo, Unsupported, VirtualFile, VirtualFileDescription, VirtualFileSystem};
 use crate::vsfs;
-use crate::vsfs::{update_access_time, update_modify_time};
+use crate::vsfs::update_access_time;
 
 #[derive(Debug)]
 pub struct VerySimpleFile {
-    path: Path,
+    path: Path,         // 打开时的路径，文件被移动或删除之后不再更新
+    inum: usize,        // 读写都通过 inum，不再查找路径
     mode: AccessMode,
     position: usize,
-    id: usize,
+    append: bool,       // 每次写入之前先移到文件末尾
+    session: usize,     // 打开文件的会话
+    handle: Handle,     // 会话中的句柄
 }
 
 
+impl VerySimpleFile {
+    pub fn inum(&self) -> usize {
+        self.inum
+    }
+
+    /// 是否以追加方式打开
+    pub fn append(&self) -> bool {
+        self.append
+    }
+}
+
 impl VirtualFile for VerySimpleFile {
     fn path(&self) -> &Path {
         &self.path
@@ -61,7 +85,11 @@ impl VirtualFileDescription for VerySimpleFileDescription {
     }
 
     fn size(&self) -> usize {
-        self.inode.size as usize
+        self.inode.file_size()
+    }
+
+    fn owner(&self) -> u32 {
+        self.inode.uid
     }
 }
 
@@ -74,6 +102,10 @@ pub enum VerySimpleError {
     FileNotExist,
     InvalidPath,
     AccessError,
+    ReadOnly,
+    Unsupported,
+    Lock(LockError),
+    Io(io::Error),
     VSFSError(vsfs::Error)
 }
 
@@ -87,16 +119,93 @@ impl Display for VerySimpleError {
             VerySimpleError::VSFSError(error) => Display::fmt(error, f),
             VerySimpleError::InvalidPath => write!(f, "invalid path"),
             VerySimpleError::AccessError => write!(f, "access error. r, w, or rw"),
+            VerySimpleError::ReadOnly => write!(f, "read-only file system"),
+            VerySimpleError::Unsupported => write!(f, "not supported by the very simple file system"),
+            VerySimpleError::Lock(error) => Display::fmt(error, f),
+            VerySimpleError::Io(error) => Display::fmt(error, f),
         }
     }
 }
 
 impl Error for VerySimpleError {}
 
+impl From<Unsupported> for VerySimpleError {
+    fn from(_: Unsupported) -> Self {
+        VerySimpleError::Unsupported
+    }
+}
+
+impl From<VerySimpleError> for io::Error {
+    fn from(err: VerySimpleError) -> Self {
+        let kind = match &err {
+            VerySimpleError::UnknownError => ErrorKind::Other,
+            VerySimpleError::FileCannotWrite => ErrorKind::ResourceBusy,
+            VerySimpleError::FileNotOpen | VerySimpleError::InvalidPath => ErrorKind::InvalidInput,
+            VerySimpleError::FileNotExist => ErrorKind::NotFound,
+            VerySimpleError::AccessError => ErrorKind::PermissionDenied,
+            VerySimpleError::ReadOnly => ErrorKind::ReadOnlyFilesystem,
+            VerySimpleError::Unsupported => ErrorKind::Unsupported,
+            VerySimpleError::Lock(LockError::WouldBlock) => ErrorKind::WouldBlock,
+            VerySimpleError::Lock(LockError::Deadlock) => ErrorKind::Deadlock,
+            VerySimpleError::Io(err) => err.kind(),
+            VerySimpleError::VSFSError(err) => match err {
+                vsfs::Error::PathNotFound(_) | vsfs::Error::SnapshotNotFound(_) => ErrorKind::NotFound,
+                vsfs::Error::FileExist(_) | vsfs::Error::SnapshotExist(_) => ErrorKind::AlreadyExists,
+                vsfs::Error::NoSpace | vsfs::Error::TooManySnapshots => ErrorKind::StorageFull,
+                vsfs::Error::QuotaExceeded => ErrorKind::QuotaExceeded,
+                vsfs::Error::DirIsNotEmpty => ErrorKind::DirectoryNotEmpty,
+                vsfs::Error::CorruptData => ErrorKind::InvalidData,
+                vsfs::Error::InvalidFileType
+                | vsfs::Error::InvalidSize
+                | vsfs::Error::InvalidSnapshotName
+                | vsfs::Error::MoveIntoItself(_) => ErrorKind::InvalidInput,
+            },
+        };
+        match err {
+            VerySimpleError::Io(err) => err,
+            err => io::Error::new(kind, err),
+        }
+    }
+}
+
+
+/// 文件系统使用的磁盘，可以借用调用者的，也可以自己持有
+enum DiskRef<'disk> {
+    Borrowed(&'disk mut Disk),
+    Owned(Box<Disk>),
+}
+
+impl Deref for DiskRef<'_> {
+    type Target = Disk;
+
+    fn deref(&self) -> &Disk {
+        match self {
+            DiskRef::Borrowed(disk) => disk,
+            DiskRef::Owned(disk) => disk,
+        }
+    }
+}
+
+impl DerefMut for DiskRef<'_> {
+    fn deref_mut(&mut self) -> &mut Disk {
+        match self {
+            DiskRef::Borrowed(disk) => disk,
+            DiskRef::Owned(disk) => disk,
+        }
+    }
+}
+
 
 pub struct VerySimpleFileSystem<'disk> {
     rw: RWManager,
-    disk: &'disk mut Disk,
+    locks: LockManager<usize>,  // 按 inum 加锁，文件被移动之后锁仍然有效
+    orphans: HashSet<usize>,    // 删除时还打开着的文件，最后一个句柄关闭时释放
+    parents: HashMap<usize, Vec<usize>>,    // 打开着的文件从根目录到父目录的 inum，写入时记目录配额，移动之后更新
+    disk: DiskRef<'disk>,
+    uid: u32,
+    snapshot: Option<usize>,    // 打开的是哪个快照，快照是只读的
+    pages: PageCache,           // 文件数据和块号表的页缓存，sync 或者被淘汰时才写回磁盘
+    image: Option<PathBuf>,     // sync 时把磁盘保存到这个镜像文件，没有时只写回页缓存
 }
 
 
@@ -106,134 +215,212 @@ impl<'disk> VirtualFileSystem for VerySimpleFileSystem<'disk> {
     type FileDescription = VerySimpleFileDescription;
 
     fn init(&mut self) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        // 磁盘整个被清空，缓存的页都作废
+        self.pages.clear();
         vsfs::init(&mut self.disk);
         Ok(())
     }
 
     fn create_file(&mut self, path: &Path) -> Result<Self::FileDescription, Self::Error> {
+        self.check_writable()?;
         let name = path.current()
             .ok_or(VerySimpleError::InvalidPath)?;
         let parent = path.clone().parent()
             .ok_or(VerySimpleError::InvalidPath)?;
-        vsfs::create_file(&mut self.disk, &parent, name)
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
+        vsfs::create_file(&mut self.disk, &parent, name, self.uid)
+            .map_err(VerySimpleError::VSFSError)?;
 
-        let inode = vsfs::get_inode_by_path(&mut self.disk, path)
+        let inode = vsfs::get_inode_by_path(&self.disk, path)
             .ok_or(VerySimpleError::UnknownError)?;
 
         Ok(VerySimpleFileDescription {
-            inode: inode.clone(),
+            inode: *inode,
             name: name.clone(),
         })
     }
 
     fn delete_file(&mut self, path: &Path) -> Result<(), Self::Error> {
-        vsfs::delete_file(&mut self.disk, &path)
-            .map_err(|err| VerySimpleError::VSFSError(err))
+        self.check_writable()?;
+        let inum = vsfs::get_inum_by_path(&self.disk, path)
+            .ok_or(VerySimpleError::FileNotExist)?;
+        if !self.rw.in_use(inum) {
+            self.discard_pages(inum);
+            return vsfs::delete_file(&mut self.disk, path)
+                .map_err(VerySimpleError::VSFSError);
+        }
+
+        // 还打开着的文件只从目录中删除，数据保留到最后一次关闭，之后的写入只记用户配额
+        vsfs::unlink_file(&mut self.disk, path)
+            .map_err(VerySimpleError::VSFSError)?;
+        self.orphans.insert(inum);
+        self.parents.insert(inum, Vec::new());
+        Ok(())
     }
 
 
-    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error> {
-        // 检查是否可以打开
-        match mode {
-            AccessMode::Read => {}
-            AccessMode::Write | AccessMode::ReadWrite => {
-                if !self.rw.can_write(&path.to_str()) {
-                    return Err(VerySimpleError::FileCannotWrite);
-                }
-            }
-        }
+    fn clone_file(&mut self, src: &Path, dst: &Path) -> Result<Self::FileDescription, Self::Error> {
+        self.check_writable()?;
+        let name = dst.current()
+            .ok_or(VerySimpleError::InvalidPath)?;
+        let parent = dst.clone().parent()
+            .ok_or(VerySimpleError::InvalidPath)?;
+        // 克隆直接共享数据块，先把源文件的修改写回
+        self.write_back();
+        vsfs::clone_file(&mut self.disk, src, &parent, name, self.uid)
+            .map_err(VerySimpleError::VSFSError)?;
 
-        if !vsfs::exists(&mut self.disk, path) {
-            return Err(VerySimpleError::FileNotExist);
-        }
+        let inode = vsfs::get_inode_by_path(&self.disk, dst)
+            .ok_or(VerySimpleError::UnknownError)?;
 
-        // 打开文件
-        let id = self.rw.open(0, &path.to_str(), mode);
+        Ok(VerySimpleFileDescription {
+            inode: *inode,
+            name: name.clone(),
+        })
+    }
 
+    fn open(&mut self, path: &Path, mode: AccessMode) -> Result<Self::File, Self::Error> {
+        self.open_existing(0, path, mode)
+    }
 
-        update_access_time(&mut self.disk, path)
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
+    fn open_with(&mut self, path: &Path, options: &OpenOptions) -> Result<Self::File, Self::Error> {
+        let mode = options.access_mode()
+            .ok_or(VerySimpleError::AccessError)?;
 
-        // 返回文件
-        Ok(VerySimpleFile {
-            path: path.clone(),
-            mode,
-            position: 0,
-            id,
-        })
+        let exists = vsfs::exists(&self.disk, path);
+        if options.create_new && exists {
+            return Err(VerySimpleError::VSFSError(vsfs::Error::FileExist(path.clone())));
+        }
+        if options.creates() && !exists {
+            self.create_file(path)?;
+        }
+
+        let mut file = self.open_existing(options.session, path, mode)?;
+        file.append = options.append;
+        if options.truncate {
+            self.discard_pages(file.inum);
+            let dirs = self.parent_dirs(file.inum);
+            if let Err(err) = vsfs::truncate_inode(&mut self.disk, file.inum, &dirs) {
+                self.close(file)?;
+                return Err(VerySimpleError::VSFSError(err));
+            }
+            vsfs::update_inode_time(&mut self.disk, file.inum, true);
+        }
+        Ok(file)
     }
 
     fn description(&mut self, file: &Self::File) -> Result<Self::FileDescription, Self::Error> {
-        update_access_time(&mut self.disk, file.path())
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
-
-        let inode = vsfs::get_inode_by_path(&self.disk, &file.path)
-            .ok_or(VerySimpleError::UnknownError)?;
+        self.access_mode(file)?;
+        self.touch(file.inum);
 
         Ok(VerySimpleFileDescription {
-            inode: inode.clone(),
-            name: file.path.current().unwrap().clone(),
+            inode: *self.inode(file.inum),
+            name: file.path.current().cloned().unwrap_or_default(),
         })
     }
 
     fn close(&mut self, file: Self::File) -> Result<(), Self::Error> {
-        if self.rw.already_open(file.id) {
+        if !self.still_open(&file) {
             return Err(VerySimpleError::FileNotOpen);
         }
 
-        self.rw.close(file.id);
-        Ok(())
+        self.rw.close(file.session, file.handle);
+        self.locks.release_file(&file.inum, Self::lock_owner(&file));
+        if !self.rw.in_use(file.inum) {
+            self.parents.remove(&file.inum);
+        }
+        self.release_orphans()
     }
 
     fn read(&mut self, file: &mut Self::File, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.read_at(file, buf, file.position)?;
+        file.position += len;
+        Ok(len)
+    }
 
-        let mode = self.rw.access_mode(file.id)
-            .ok_or(VerySimpleError::FileNotOpen)?;
+    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.check_file_writable(file)?;
 
-        if mode != file.mode {
-            return Err(VerySimpleError::AccessError);
+        if file.append {
+            file.position = self.inode(file.inum).file_size();
         }
 
-        let inode = vsfs::get_inode_by_path(&self.disk, &file.path)
-            .ok_or(VerySimpleError::FileNotExist)?;
-
-        let len = min(buf.len(), inode.size as usize - file.position);
+        let len = self.write_bufs(file, file.position, &[buf])?;
+        file.position += len;
+        Ok(len)
+    }
 
-        vsfs::read_file(&self.disk, &file.path, file.position, &mut buf[..len])
-            .ok()
-            .ok_or(VerySimpleError::UnknownError)?;
+    fn read_at(&mut self, file: &Self::File, buf: &mut [u8], offset: usize) -> Result<usize, Self::Error> {
+        self.check_file_readable(file)?;
 
-        file.position += len;
+        // 位置可能在文件末尾之后，这时读不到数据
+        let size = self.inode(file.inum).file_size();
+        let len = min(buf.len(), size.saturating_sub(offset));
+        if len > 0 {
+            self.read_data(file.inum, offset, &mut buf[..len])?;
+        }
 
-        update_access_time(&mut self.disk, file.path())
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
+        self.touch(file.inum);
 
         Ok(len)
     }
 
-    fn write(&mut self, file: &mut Self::File, buf: &[u8]) -> Result<usize, Self::Error> {
-        let mode = self.rw.access_mode(file.id)
-            .ok_or(VerySimpleError::FileNotOpen)?;
+    fn write_at(&mut self, file: &Self::File, buf: &[u8], offset: usize) -> Result<usize, Self::Error> {
+        self.check_file_writable(file)?;
+        self.write_bufs(file, offset, &[buf])
+    }
 
-        if mode != file.mode || mode == Read {
-            return Err(VerySimpleError::AccessError);
+    fn readv(&mut self, file: &mut Self::File, bufs: &mut [IoSliceMut<'_>]) -> Result<usize, Self::Error> {
+        self.check_file_readable(file)?;
+
+        let size = self.inode(file.inum).file_size();
+        let start = file.position;
+        for buf in bufs.iter_mut() {
+            let len = min(buf.len(), size.saturating_sub(file.position));
+            if len > 0 {
+                self.read_data(file.inum, file.position, &mut buf[..len])?;
+            }
+            file.position += len;
+            if len < buf.len() {
+                break;
+            }
         }
 
-        vsfs::write_file(&mut self.disk, &file.path, file.position, &buf)
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
+        self.touch(file.inum);
+
+        Ok(file.position - start)
+    }
+
+    fn writev(&mut self, file: &mut Self::File, bufs: &[IoSlice<'_>]) -> Result<usize, Self::Error> {
+        self.check_file_writable(file)?;
+
+        if file.append {
+            file.position = self.inode(file.inum).file_size();
+        }
 
-        file.position += buf.len();
+        let bufs = bufs.iter().map(|buf| &**buf).collect::<Vec<_>>();
+        let len = self.write_bufs(file, file.position, &bufs)?;
+        file.position += len;
+        Ok(len)
+    }
 
-        update_modify_time(&mut self.disk, file.path())
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
+    /// inode 等元数据和其他文件在同一个镜像中，设置了镜像文件时只能整个保存
+    fn fsync(&mut self, file: &Self::File) -> Result<(), Self::Error> {
+        self.access_mode(file)?;
+        for ((inum, index), data) in self.pages.take_dirty(Some(file.inum)) {
+            self.write_page(inum, index, &data);
+        }
+        self.save_image()
+    }
 
-        Ok(buf.len())
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        self.write_back();
+        self.save_image()
     }
 
     fn list(&mut self, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
         let dir = vsfs::get_dir(&self.disk, path)
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
+            .map_err(VerySimpleError::VSFSError)?;
 
         let mut fds = Vec::new();
 
@@ -243,37 +430,201 @@ impl<'disk> VirtualFileSystem for VerySimpleFileSystem<'disk> {
                 .ok_or(VerySimpleError::UnknownError)?;
 
             fds.push(VerySimpleFileDescription {
-                inode: inode.clone(),
+                inode: *inode,
                 name: entry.name.clone(),
             })
         }
 
-        update_access_time(&mut self.disk, path)
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
+        self.touch_path(path)?;
 
         Ok(fds)
     }
 
     fn mkdir(&mut self, path: &Path) -> Result<(), Self::Error> {
+        self.check_writable()?;
         let name = path.current()
             .ok_or(VerySimpleError::InvalidPath)?;
         let path = path.clone().parent()
             .ok_or(VerySimpleError::InvalidPath)?;
 
-        vsfs::create_dir(&mut self.disk, &path, name)
-            .map_err(|err| VerySimpleError::VSFSError(err))
+        vsfs::create_dir(&mut self.disk, &path, name, self.uid)
+            .map_err(VerySimpleError::VSFSError)
     }
 
     fn rmdir(&mut self, path: &Path) -> Result<(), Self::Error> {
-        vsfs::delete_dir(&mut self.disk, &path)
-            .map_err(|err| VerySimpleError::VSFSError(err))
+        self.check_writable()?;
+        if self.in_use(path) {
+            return Err(VerySimpleError::FileCannotWrite);
+        }
+        for inum in vsfs::tree_inums(&self.disk, path) {
+            self.discard_pages(inum);
+        }
+        vsfs::delete_dir(&mut self.disk, path)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        let name = to.current()
+            .ok_or(VerySimpleError::InvalidPath)?;
+        let parent = to.clone().parent()
+            .ok_or(VerySimpleError::InvalidPath)?;
+
+        let inum = vsfs::get_inum_by_path(&self.disk, from);
+        vsfs::rename(&mut self.disk, from, &parent, name)
+            .map_err(VerySimpleError::VSFSError)?;
+
+        // 移动的子树中打开着的文件，父目录换成新位置上的
+        let moved = vsfs::parent_inums(&self.disk, to);
+        for (open, dirs) in self.parents.iter_mut() {
+            if Some(*open) == inum {
+                *dirs = moved.clone();
+            } else if let Some(pos) = dirs.iter().position(|dir| Some(*dir) == inum) {
+                *dirs = [&moved[..], &dirs[pos..]].concat();
+            }
+        }
+        Ok(())
     }
 
     fn exists(&mut self, path: &Path) -> Result<bool, Self::Error> {
-        let res = Ok(vsfs::exists(&self.disk, &path));
-        update_access_time(&mut self.disk, path)
-            .map_err(|err| VerySimpleError::VSFSError(err))?;
-        res
+        let exists = vsfs::exists(&self.disk, path);
+        if exists {
+            self.touch_path(path)?;
+        }
+        Ok(exists)
+    }
+
+    fn user(&self) -> u32 {
+        self.uid
+    }
+
+    fn set_user(&mut self, uid: u32) {
+        self.uid = uid;
+    }
+
+    fn end_session(&mut self, session: usize) -> Result<usize, Self::Error> {
+        self.locks.release_session(session);
+        let closed = self.rw.close_session(session);
+        self.release_orphans()?;
+        Ok(closed)
+    }
+
+    fn is_open(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<bool, Self::Error> {
+        Ok(vsfs::get_inum_by_path(&self.disk, path)
+            .is_some_and(|inum| self.rw.is_open(session, inum, mode)))
+    }
+
+    fn lock(&mut self, file: &Self::File, kind: LockKind, range: LockRange, wait: LockWait) -> Result<LockStatus, Self::Error> {
+        self.access_mode(file)?;
+        self.locks.lock(&file.inum, &file.path, Self::lock_owner(file), kind, range, wait)
+            .map_err(VerySimpleError::Lock)
+    }
+
+    fn unlock(&mut self, file: &Self::File, range: LockRange) -> Result<(), Self::Error> {
+        self.access_mode(file)?;
+        self.locks.unlock(&file.inum, Self::lock_owner(file), range);
+        Ok(())
+    }
+
+    fn locks(&mut self) -> Result<Vec<LockInfo>, Self::Error> {
+        Ok(self.locks.locks())
+    }
+
+    fn quotas(&mut self) -> Result<Vec<QuotaReport>, Self::Error> {
+        Ok(vsfs::quotas(&self.disk))
+    }
+
+    fn set_quota(&mut self, target: &QuotaTarget, blocks: Limit, inodes: Limit) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        vsfs::set_quota(&mut self.disk, target, blocks, inodes)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn set_grace_period(&mut self, grace_period: u32) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        vsfs::set_grace_period(&mut self.disk, grace_period)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn fragmentation(&mut self, path: &Path) -> Result<Vec<FragmentReport>, Self::Error> {
+        vsfs::fragmentation(&self.disk, path)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn set_compression(&mut self, path: &Path, enabled: bool) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.invalidate_pages();
+        vsfs::set_compression(&mut self.disk, path, enabled)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn dedup(&mut self) -> Result<DedupReport, Self::Error> {
+        self.check_writable()?;
+        self.check_no_orphans()?;
+        self.invalidate_pages();
+        Ok(vsfs::dedup(&mut self.disk))
+    }
+
+    fn defrag(&mut self, path: &Path) -> Result<Vec<FragmentReport>, Self::Error> {
+        self.check_writable()?;
+        self.invalidate_pages();
+        vsfs::defrag(&mut self.disk, path)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn snapshot_create(&mut self, name: &str) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.check_no_orphans()?;
+        self.invalidate_pages();
+        vsfs::snapshot_create(&mut self.disk, name)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn snapshots(&mut self) -> Result<Vec<SnapshotInfo>, Self::Error> {
+        Ok(vsfs::snapshots(&self.disk))
+    }
+
+    fn snapshot_delete(&mut self, name: &str) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.invalidate_pages();
+        vsfs::snapshot_delete(&mut self.disk, name)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn snapshot_rollback(&mut self, name: &str) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        // 回滚之后打开的文件记下的 inum 可能已经不是原来的文件
+        if self.rw.has_open_files() {
+            return Err(VerySimpleError::FileCannotWrite);
+        }
+        self.invalidate_pages();
+        vsfs::snapshot_rollback(&mut self.disk, name)
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    /// 缓存中的页只改写不和快照共享的数据块，不影响快照的内容和差异，不需要写回
+    fn snapshot_diff(&mut self, name: &str) -> Result<SnapshotDiff, Self::Error> {
+        self.with_live_disk(|disk| vsfs::snapshot_diff(disk, name))
+            .map_err(VerySimpleError::VSFSError)
+    }
+
+    fn snapshot_list(&mut self, name: &str, path: &Path) -> Result<Vec<Self::FileDescription>, Self::Error> {
+        self.with_live_disk(|disk| {
+            let mut fs = VerySimpleFileSystem::open_snapshot(disk, name)?;
+            fs.list(path)
+        })
+    }
+
+    fn snapshot_read(&mut self, name: &str, path: &Path) -> Result<Vec<u8>, Self::Error> {
+        self.with_live_disk(|disk| {
+            let mut fs = VerySimpleFileSystem::open_snapshot(disk, name)?;
+            let mut file = fs.open(path, AccessMode::Read)?;
+            let size = fs.description(&file)?.size();
+            let mut buf = vec![0u8; size];
+            fs.read(&mut file, &mut buf)?;
+            fs.close(file)?;
+            Ok(buf)
+        })
     }
 }
 
@@ -282,7 +633,341 @@ impl<'disk> VerySimpleFileSystem<'disk> {
     pub fn new(disk: &'disk mut Disk) -> Self {
         VerySimpleFileSystem {
             rw: RWManager::new(),
-            disk,
+            locks: LockManager::new(),
+            orphans: HashSet::new(),
+            parents: HashMap::new(),
+            disk: DiskRef::Borrowed(disk),
+            uid: 0,
+            snapshot: None,
+            pages: PageCache::new(PAGE_CACHE_SIZE),
+            image: None,
+        }
+    }
+
+    /// 持有磁盘的文件系统，不受调用者的生命周期限制，比如挂载到挂载表中
+    pub fn with_disk(disk: Box<Disk>) -> VerySimpleFileSystem<'static> {
+        VerySimpleFileSystem {
+            rw: RWManager::new(),
+            locks: LockManager::new(),
+            orphans: HashSet::new(),
+            parents: HashMap::new(),
+            disk: DiskRef::Owned(disk),
+            uid: 0,
+            snapshot: None,
+            pages: PageCache::new(PAGE_CACHE_SIZE),
+            image: None,
+        }
+    }
+
+    /// 文件系统所在的磁盘，用来保存
+    pub fn disk(&self) -> &Disk {
+        &self.disk
+    }
+
+    /// 直接修改磁盘，比如从块设备上读入数据块，缓存的块号表可能过期，先丢掉
+    pub fn disk_mut(&mut self) -> &mut Disk {
+        self.pages.clear_maps();
+        &mut self.disk
+    }
+
+    /// 以只读方式打开一个快照，在它被 drop 之前磁盘上看到的都是快照的内容
+    pub fn open_snapshot(disk: &'disk mut Disk, name: &str) -> Result<Self, VerySimpleError> {
+        let index = vsfs::snapshots(disk).iter()
+            .position(|info| info.name == name)
+            .ok_or(VerySimpleError::VSFSError(vsfs::Error::SnapshotNotFound(name.to_string())))?;
+
+        disk.swap_snapshot(index);
+        Ok(VerySimpleFileSystem {
+            rw: RWManager::new(),
+            locks: LockManager::new(),
+            orphans: HashSet::new(),
+            parents: HashMap::new(),
+            disk: DiskRef::Borrowed(disk),
+            uid: 0,
+            snapshot: Some(index),
+            pages: PageCache::new(PAGE_CACHE_SIZE),
+            image: None,
+        })
+    }
+
+    /// path 本身或者它下面是否有打开的文件
+    pub fn in_use(&self, path: &Path) -> bool {
+        vsfs::tree_inums(&self.disk, path).into_iter()
+            .any(|inum| self.rw.in_use(inum))
+    }
+
+    /// 在 session 中打开已经存在的文件
+    fn open_existing(&mut self, session: usize, path: &Path, mode: AccessMode) -> Result<VerySimpleFile, VerySimpleError> {
+        let inum = vsfs::get_inum_by_path(&self.disk, path)
+            .ok_or(VerySimpleError::FileNotExist)?;
+
+        // 检查是否可以打开
+        match mode {
+            AccessMode::Read => {}
+            AccessMode::Write | AccessMode::ReadWrite => {
+                self.check_writable()?;
+                if !self.rw.can_write(inum) {
+                    return Err(VerySimpleError::FileCannotWrite);
+                }
+            }
+        }
+
+        // 打开文件
+        let handle = self.rw.open(session, inum, mode);
+        self.parents.insert(inum, vsfs::parent_inums(&self.disk, path));
+
+        self.touch(inum);
+
+        // 返回文件
+        Ok(VerySimpleFile {
+            path: path.clone(),
+            inum,
+            mode,
+            position: 0,
+            append: false,
+            session,
+            handle,
+        })
+    }
+
+    /// 文件是否仍然被打开它的会话打开着
+    pub fn still_open(&self, file: &VerySimpleFile) -> bool {
+        self.rw.already_open(file.session, file.handle)
+    }
+
+    /// 锁的持有者是打开文件的会话和文件描述符
+    fn lock_owner(file: &VerySimpleFile) -> LockOwner {
+        LockOwner { session: file.session, file: file.handle.fd }
+    }
+
+    /// 文件的读写模式，只有打开它的会话可以使用
+    fn access_mode(&self, file: &VerySimpleFile) -> Result<AccessMode, VerySimpleError> {
+        if !self.still_open(file) {
+            return Err(VerySimpleError::FileNotOpen);
+        }
+        self.rw.access_mode(file.session, file.handle)
+            .ok_or(VerySimpleError::FileNotOpen)
+    }
+
+    fn check_file_readable(&self, file: &VerySimpleFile) -> Result<(), VerySimpleError> {
+        if self.access_mode(file)? != file.mode {
+            return Err(VerySimpleError::AccessError);
+        }
+        Ok(())
+    }
+
+    fn check_file_writable(&self, file: &VerySimpleFile) -> Result<(), VerySimpleError> {
+        self.check_writable()?;
+        let mode = self.access_mode(file)?;
+        if mode != file.mode || mode == Read {
+            return Err(VerySimpleError::AccessError);
+        }
+        Ok(())
+    }
+
+    /// 把 bufs 依次写到 offset 处，返回写入的总长度，调用前要先检查文件可写
+    ///
+    /// 只改写已有数据块的写入先放在页缓存中，其他的写入先写回这个文件的脏页再直接写到磁盘上
+    fn write_bufs(&mut self, file: &VerySimpleFile, offset: usize, bufs: &[&[u8]]) -> Result<usize, VerySimpleError> {
+        let len = bufs.iter().map(|buf| buf.len()).sum();
+        if self.pages.capacity() > 0 && vsfs::can_write_in_place(&self.disk, file.inum, offset, offset + len) {
+            let mut pos = offset;
+            for buf in bufs {
+                self.write_pages(file.inum, pos, buf);
+                pos += buf.len();
+            }
+            vsfs::grow_file_size(&mut self.disk, file.inum, offset + len);
+        } else {
+            self.evict_pages(file.inum);
+            let dirs = self.parent_dirs(file.inum);
+            vsfs::write_inode(&mut self.disk, file.inum, &dirs, offset, bufs)
+                .map_err(VerySimpleError::VSFSError)?;
+        }
+
+        vsfs::update_inode_time(&mut self.disk, file.inum, true);
+
+        Ok(len)
+    }
+
+    /// 从 offset 处读满 buf，调用前要保证没有超出文件末尾
+    fn read_data(&mut self, inum: usize, offset: usize, buf: &mut [u8]) -> Result<(), VerySimpleError> {
+        let inode = self.inode(inum);
+        if self.pages.capacity() == 0 || inode.is_dir || inode.is_compressed() || inode.is_inline() {
+            return vsfs::read_inode(&self.disk, inum, offset, buf)
+                .map_err(VerySimpleError::VSFSError);
+        }
+
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let start = pos % 4096;
+            let len = min(4096 - start, buf.len() - done);
+            let page = self.page(inum, pos / 4096);
+            buf[done..done + len].copy_from_slice(&page.data[start..start + len]);
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// 把 buf 写到缓存中 offset 处的页上
+    fn write_pages(&mut self, inum: usize, offset: usize, buf: &[u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let start = pos % 4096;
+            let len = min(4096 - start, buf.len() - done);
+            let page = self.page(inum, pos / 4096);
+            page.data[start..start + len].copy_from_slice(&buf[done..done + len]);
+            page.dirty = true;
+            done += len;
+        }
+    }
+
+    /// 缓存中的一页，没有时从磁盘读入，被淘汰的脏页写回磁盘
+    fn page(&mut self, inum: usize, index: usize) -> &mut Page {
+        if self.pages.get_mut(inum, index).is_none() {
+            let dnum = self.pages.block_map(inum, || vsfs::block_map(&self.disk, inum))[index];
+            let mut data = Box::new([0u8; 4096]);
+            vsfs::read_block(&self.disk, dnum, &mut data);
+            let evicted = self.pages.insert(inum, index, Page { data, dirty: false });
+            if let Some(((inum, index), page)) = evicted {
+                self.write_page(inum, index, &page.data);
+            }
+        }
+        self.pages.get_mut(inum, index).unwrap()
+    }
+
+    /// 把一页写回磁盘，缓存了块号表时直接按块号写，块已经不在文件中时丢掉
+    ///
+    /// 不在这里读入块号表：写回之后文件的数据块可能马上就要变化
+    fn write_page(&mut self, inum: usize, index: usize, data: &[u8; 4096]) {
+        match self.pages.cached_map(inum).map(|map| map.get(index).copied()) {
+            Some(Some(dnum)) => vsfs::write_block(&mut self.disk, dnum, data),
+            Some(None) => {}
+            None => vsfs::write_page(&mut self.disk, inum, index, data),
+        }
+    }
+
+    /// 写回这个文件的脏页并从缓存中去掉，之后这个文件的数据块可以直接读写
+    fn evict_pages(&mut self, inum: usize) {
+        for ((inum, index), page) in self.pages.remove_inode(inum) {
+            self.write_page(inum, index, &page.data);
+        }
+    }
+
+    /// 丢掉这个文件缓存的页，不写回，用于删除和截断
+    fn discard_pages(&mut self, inum: usize) {
+        self.pages.remove_inode(inum);
+    }
+
+    /// 写回所有的脏页并清空缓存，用于合并、整理数据块和快照等直接操作数据块的功能
+    fn invalidate_pages(&mut self) {
+        for ((inum, index), page) in self.pages.clear() {
+            self.write_page(inum, index, &page.data);
+        }
+    }
+
+    /// 把所有的脏页写回磁盘，不保存镜像
+    fn write_back(&mut self) {
+        for ((inum, index), data) in self.pages.take_dirty(None) {
+            self.write_page(inum, index, &data);
+        }
+    }
+
+    /// 设置了镜像文件时把磁盘保存过去，快照是只读的，不保存
+    fn save_image(&mut self) -> Result<(), VerySimpleError> {
+        match &self.image {
+            Some(image) if self.snapshot.is_none() => self.disk.save(image)
+                .map_err(|err| VerySimpleError::Io(io::Error::other(err.to_string()))),
+            _ => Ok(()),
+        }
+    }
+
+    /// sync 和 fsync 时把磁盘保存到 image
+    pub fn set_image(&mut self, image: impl Into<PathBuf>) {
+        self.image = Some(image.into());
+    }
+
+    /// 设置页缓存的页数，为 0 时不缓存，所有的读写直接访问数据块
+    pub fn set_page_cache_capacity(&mut self, pages: usize) {
+        for ((inum, index), page) in self.pages.set_capacity(pages) {
+            self.write_page(inum, index, &page.data);
+        }
+    }
+
+    /// 打开着的文件从根目录到父目录的 inum
+    fn parent_dirs(&self, inum: usize) -> Vec<usize> {
+        self.parents.get(&inum).cloned().unwrap_or_default()
+    }
+
+    fn inode(&self, inum: usize) -> &INode {
+        unsafe { logic::get_inode(&self.disk.i_blocks, inum) }
+    }
+
+    /// 释放已经没有句柄的被删除文件
+    fn release_orphans(&mut self) -> Result<(), VerySimpleError> {
+        let released = self.orphans.iter()
+            .copied()
+            .filter(|inum| !self.rw.in_use(*inum))
+            .collect::<Vec<_>>();
+        for inum in released {
+            self.orphans.remove(&inum);
+            self.discard_pages(inum);
+            vsfs::release_inode(&mut self.disk, inum)
+                .map_err(VerySimpleError::VSFSError)?;
+        }
+        Ok(())
+    }
+
+    /// 被删除但还打开着的文件不在目录树中，合并数据块和快照都找不到它们
+    fn check_no_orphans(&self) -> Result<(), VerySimpleError> {
+        if self.orphans.is_empty() {
+            Ok(())
+        } else {
+            Err(VerySimpleError::FileCannotWrite)
+        }
+    }
+
+    /// 快照是只读的
+    fn check_writable(&self) -> Result<(), VerySimpleError> {
+        match self.snapshot {
+            Some(_) => Err(VerySimpleError::ReadOnly),
+            None => Ok(()),
+        }
+    }
+
+    /// 在当前文件系统（而不是打开的快照）上执行 f，用于只读的快照查询
+    fn with_live_disk<T>(&mut self, f: impl FnOnce(&mut Disk) -> T) -> T {
+        match self.snapshot {
+            Some(index) => vsfs::with_snapshot(&mut self.disk, index, f),
+            None => f(&mut self.disk),
+        }
+    }
+
+    /// 更新打开的文件的访问时间，快照中不更新
+    fn touch(&mut self, inum: usize) {
+        if self.snapshot.is_none() {
+            vsfs::update_inode_time(&mut self.disk, inum, false);
+        }
+    }
+
+    /// 更新访问时间，快照中不更新
+    fn touch_path(&mut self, path: &Path) -> Result<(), VerySimpleError> {
+        if self.snapshot.is_some() {
+            return Ok(());
+        }
+        update_access_time(&mut self.disk, path)
+            .map_err(VerySimpleError::VSFSError)
+    }
+}
+
+impl<'disk> Drop for VerySimpleFileSystem<'disk> {
+    fn drop(&mut self) {
+        self.write_back();
+
+        // 换回当前文件系统
+        if let Some(index) = self.snapshot {
+            self.disk.swap_snapshot(index);
         }
     }
 }
@@ -298,7 +983,7 @@ mod test {
         let mut fs = VerySimpleFileSystem::new(&mut disk);
 
         let path = Path::from_str("/test.txt").unwrap();
-        let file = fs.open(&path, AccessMode::ReadWrite).unwrap();
+        let _file = fs.open(&path, AccessMode::ReadWrite).unwrap();
     }
 
     #[test]
@@ -331,10 +1016,7 @@ mod test {
 
         let mut file = fs.open(&path, AccessMode::ReadWrite).unwrap();
 
-        let mut buf = vec![0u8; 10020];
-        for i in 0..buf.len() {
-            buf[i] = i as u8;
-        }
+        let buf: Vec<u8> = (0..10020).map(|i| i as u8).collect();
 
         fs.write(&mut file, &buf).unwrap();
 
@@ -365,7 +1047,7 @@ mod test {
 
         let fds = fs.list(&Path::root()).unwrap();
         assert_eq!(fds.len(), 1);
-        assert_eq!(fds[0].is_dir(), false);
+        assert!(!fds[0].is_dir());
         assert_eq!(fds[0].name(), "test.txt");
     }
 
@@ -380,7 +1062,7 @@ mod test {
 
         let fds = fs.list(&Path::root()).unwrap();
         assert_eq!(fds.len(), 1);
-        assert_eq!(fds[0].is_dir(), true);
+        assert!(fds[0].is_dir());
         assert_eq!(fds[0].name(), "test");
 
         let path = Path::from_str("/test2").unwrap();
@@ -388,9 +1070,9 @@ mod test {
 
         let fds = fs.list(&Path::root()).unwrap();
         assert_eq!(fds.len(), 2);
-        assert_eq!(fds[0].is_dir(), true);
+        assert!(fds[0].is_dir());
         assert_eq!(fds[0].name(), "test");
-        assert_eq!(fds[1].is_dir(), true);
+        assert!(fds[1].is_dir());
         assert_eq!(fds[1].name(), "test2");
 
 
@@ -399,14 +1081,413 @@ mod test {
 
         let fds = fs.list(&Path::root()).unwrap();
         assert_eq!(fds.len(), 2);
-        assert_eq!(fds[0].is_dir(), true);
+        assert!(fds[0].is_dir());
         assert_eq!(fds[0].name(), "test");
-        assert_eq!(fds[1].is_dir(), true);
+        assert!(fds[1].is_dir());
         assert_eq!(fds[1].name(), "test2");
 
         let fds = fs.list(&Path::root().move_push("test".to_string())).unwrap();
         assert_eq!(fds.len(), 1);
-        assert_eq!(fds[0].is_dir(), true);
+        assert!(fds[0].is_dir());
         assert_eq!(fds[0].name(), "test3");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_quota() {
+        let mut disk = Disk::new();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+
+        fs.set_user(1);
+        fs.set_quota(&QuotaTarget::User(1), Limit::new(0, 1), Limit::default()).unwrap();
+
+        let path = Path::from_str("/test.txt").unwrap();
+        let fd = fs.create_file(&path).unwrap();
+        assert_eq!(fd.owner(), 1);
+
+        let mut file = fs.open(&path, AccessMode::Write).unwrap();
+        fs.write(&mut file, &[0u8; 4096]).unwrap();
+        file.set_position(0);
+        assert!(fs.write(&mut file, &[0u8; 4097]).is_err());
+
+        let quotas = fs.quotas().unwrap();
+        assert_eq!(quotas[0].target, QuotaTarget::User(1));
+        assert_eq!(quotas[0].entry.blocks_used, 1);
+
+        // 打开时清空，释放的块从配额中减去
+        fs.close(file).unwrap();
+        let file = fs.open_with(&path, OpenOptions::new().write(true).truncate(true)).unwrap();
+        assert_eq!(fs.quotas().unwrap()[0].entry.blocks_used, 0);
+        fs.close(file).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+
+        let path = Path::from_str("/test.txt").unwrap();
+        fs.create_file(&path).unwrap();
+        let mut file = fs.open(&path, AccessMode::Write).unwrap();
+        fs.write(&mut file, b"hello").unwrap();
+        fs.snapshot_create("s1").unwrap();
+
+        file.set_position(0);
+        fs.write(&mut file, b"world").unwrap();
+        fs.close(file).unwrap();
+
+        assert_eq!(fs.snapshot_read("s1", &path).unwrap(), b"hello");
+        assert_eq!(fs.snapshot_list("s1", &Path::root()).unwrap().len(), 1);
+        let diff = fs.snapshot_diff("s1").unwrap();
+        assert_eq!(diff.modified, vec![path.clone()]);
+        drop(fs);
+
+        // 快照是只读的
+        let mut snapshot = VerySimpleFileSystem::open_snapshot(&mut disk, "s1").unwrap();
+        assert!(matches!(snapshot.open(&path, AccessMode::Write), Err(VerySimpleError::ReadOnly)));
+        assert!(matches!(snapshot.mkdir(&Path::from_str("/dir").unwrap()), Err(VerySimpleError::ReadOnly)));
+        let mut file = snapshot.open(&path, AccessMode::Read).unwrap();
+        let mut buf = [0u8; 5];
+        snapshot.read(&mut file, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // 只读的快照中也可以查询快照，查询的是快照和当前文件系统
+        assert_eq!(snapshot.snapshot_read("s1", &path).unwrap(), b"hello");
+        assert_eq!(snapshot.snapshot_list("s1", &Path::root()).unwrap().len(), 1);
+        assert_eq!(snapshot.snapshot_diff("s1").unwrap(), diff);
+        drop(snapshot);
+
+        // 关闭快照之后看到的是当前文件系统
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        let mut file = fs.open(&path, AccessMode::Read).unwrap();
+        fs.read(&mut file, &mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+    }
+
+    #[test]
+    fn test_clone_file() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+
+        let src = Path::from_str("/a.txt").unwrap();
+        let dst = Path::from_str("/b.txt").unwrap();
+        fs.create_file(&src).unwrap();
+        let mut file = fs.open(&src, AccessMode::Write).unwrap();
+        fs.write(&mut file, b"hello").unwrap();
+        fs.close(file).unwrap();
+
+        let fd = fs.clone_file(&src, &dst).unwrap();
+        assert_eq!(fd.name(), "b.txt");
+        assert_eq!(fd.size(), 5);
+
+        let mut file = fs.open(&dst, AccessMode::Write).unwrap();
+        fs.write(&mut file, b"world").unwrap();
+        fs.close(file).unwrap();
+
+        let mut file = fs.open(&src, AccessMode::Read).unwrap();
+        let mut buf = [0u8; 5];
+        fs.read(&mut file, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[test]
+    fn test_file_handle() {
+        use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+        use crate::vfs::FileHandle;
+
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+
+        let path = Path::from_str("/a.txt").unwrap();
+        fs.create_file(&path).unwrap();
+        let mut file = fs.open(&path, AccessMode::Write).unwrap();
+        let mut handle = FileHandle::new(&mut fs, &mut file);
+        for i in 0..100 {
+            writeln!(handle, "line {}", i).unwrap();
+        }
+        fs.close(file).unwrap();
+
+        let missing = Path::from_str("/b.txt").unwrap();
+        let err = io::Error::from(fs.open(&missing, AccessMode::Read).unwrap_err());
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+
+        let mut file = fs.open(&path, AccessMode::Read).unwrap();
+        let mut handle = FileHandle::new(&mut fs, &mut file);
+        let lines = BufReader::new(&mut handle).lines()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(lines.len(), 100);
+        assert_eq!(lines[42], "line 42");
+
+        // 从文件末尾往前移动
+        assert_eq!(handle.seek(SeekFrom::End(-8)).unwrap(), 7 * 10 + 8 * 90 - 8);
+        let mut tail = String::new();
+        handle.read_to_string(&mut tail).unwrap();
+        assert_eq!(tail, "line 99\n");
+
+        // 移到文件末尾之后读不到数据，移到开头之前是错误
+        handle.seek(SeekFrom::Current(10)).unwrap();
+        assert_eq!(handle.read(&mut [0u8; 4]).unwrap(), 0);
+        let err = handle.seek(SeekFrom::Start(0)).and_then(|_| handle.seek(SeekFrom::Current(-1)));
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        let mut copied = Vec::new();
+        std::io::copy(&mut handle, &mut copied).unwrap();
+        assert!(copied.starts_with(b"line 0\nline 1\n"));
+        fs.close(file).unwrap();
+    }
+
+    #[test]
+    fn test_vectored() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+
+        // 跨数据块写入，压缩文件也只重新写一次
+        for (name, compressed) in [("/a", false), ("/b", true)] {
+            let path = Path::from_str(name).unwrap();
+            fs.create_file(&path).unwrap();
+            fs.set_compression(&path, compressed).unwrap();
+
+            let mut file = fs.open(&path, AccessMode::ReadWrite).unwrap();
+            let (first, second) = (vec![1u8; 5000], vec![2u8; 5000]);
+            let len = fs.writev(&mut file, &[IoSlice::new(&first), IoSlice::new(&second)]).unwrap();
+            assert_eq!(len, 10000);
+            assert_eq!(fs.description(&file).unwrap().size(), 10000);
+
+            let mut buf = [0u8; 4];
+            assert_eq!(fs.read_at(&file, &mut buf, 4998).unwrap(), 4);
+            assert_eq!(buf, [1, 1, 2, 2]);
+
+            file.set_position(4096);
+            let (mut head, mut tail) = ([0u8; 1000], [0u8; 8000]);
+            let len = fs.readv(&mut file, &mut [IoSliceMut::new(&mut head), IoSliceMut::new(&mut tail)]).unwrap();
+            assert_eq!(len, 10000 - 4096);
+            assert!(head[..904].iter().all(|byte| *byte == 1));
+            assert!(head[904..].iter().all(|byte| *byte == 2));
+            assert!(tail[..4904].iter().all(|byte| *byte == 2));
+            assert_eq!(file.position(), 10000);
+            fs.close(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_page_cache() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+        let path = Path::from_str("/a").unwrap();
+        fs.create_file(&path).unwrap();
+        let mut file = fs.open(&path, AccessMode::ReadWrite).unwrap();
+        let inum = file.inum;
+        let on_disk = |fs: &VerySimpleFileSystem, offset: usize, len: usize| {
+            let mut buf = vec![0u8; len];
+            vsfs::read_inode(fs.disk(), inum, offset, &mut buf).unwrap();
+            buf
+        };
+
+        // 分配数据块的写入直接到达磁盘
+        fs.write(&mut file, &[1u8; 5000]).unwrap();
+        assert_eq!(on_disk(&fs, 0, 5000), vec![1u8; 5000]);
+
+        // 只改写已有数据块的写入先留在缓存中，读的时候能看到
+        fs.write(&mut file, &[2u8; 100]).unwrap();
+        let mut buf = [0u8; 100];
+        fs.read_at(&file, &mut buf, 5000).unwrap();
+        assert_eq!(buf, [2u8; 100]);
+        assert_eq!(fs.description(&file).unwrap().size(), 5100);
+        assert_ne!(on_disk(&fs, 5000, 100), vec![2u8; 100]);
+
+        fs.fsync(&file).unwrap();
+        assert_eq!(on_disk(&fs, 5000, 100), vec![2u8; 100]);
+
+        // 通过 FileHandle 写入时 flush 相当于 fsync
+        {
+            use std::io::Write;
+            use crate::vfs::FileHandle;
+
+            fs.write_at(&file, &[7u8; 100], 5000).unwrap();
+            assert_ne!(on_disk(&fs, 5000, 100), vec![7u8; 100]);
+            FileHandle::new(&mut fs, &mut file).flush().unwrap();
+            assert_eq!(on_disk(&fs, 5000, 100), vec![7u8; 100]);
+        }
+
+        // 被淘汰的脏页写回磁盘
+        fs.write_at(&file, &[3u8; 5100], 0).unwrap();
+        assert_ne!(on_disk(&fs, 0, 5100), vec![3u8; 5100]);
+        fs.set_page_cache_capacity(0);
+        assert_eq!(on_disk(&fs, 0, 5100), vec![3u8; 5100]);
+        fs.set_page_cache_capacity(PAGE_CACHE_SIZE);
+
+        // 快照中是写入之后的内容，之后的写入不会改到快照共享的块
+        fs.write_at(&file, &[4u8; 5100], 0).unwrap();
+        fs.snapshot_create("s").unwrap();
+        fs.write_at(&file, &[5u8; 5100], 0).unwrap();
+        assert_eq!(fs.snapshot_read("s", &path).unwrap(), vec![4u8; 5100]);
+        fs.sync().unwrap();
+        assert_eq!(on_disk(&fs, 0, 5100), vec![5u8; 5100]);
+
+        // 删除的文件的页直接丢掉
+        fs.write_at(&file, &[6u8; 5100], 0).unwrap();
+        assert!(fs.pages.get_mut(inum, 0).is_some());
+        fs.close(file).unwrap();
+        fs.delete_file(&path).unwrap();
+        assert!(fs.pages.get_mut(inum, 0).is_none());
+        assert!(fs.pages.cached_map(inum).is_none());
+    }
+
+    #[test]
+    fn test_page_cache_block_map() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+        let path = Path::from_str("/a").unwrap();
+        fs.create_file(&path).unwrap();
+        let mut file = fs.open(&path, AccessMode::ReadWrite).unwrap();
+        let inum = file.inum;
+
+        // 超过直接块的文件，块号表包括间接块中的块号
+        fs.write(&mut file, &[1u8; 4096 * 14]).unwrap();
+        fs.write_at(&file, &[2u8; 10], 4096 * 13).unwrap();
+        let map = vsfs::block_map(fs.disk(), inum);
+        assert_eq!(map.len(), 14);
+        assert_eq!(fs.pages.cached_map(inum), Some(map.as_slice()));
+
+        // 快照之后写入要换掉共享的块，块号表随页一起丢掉，之后重新读入
+        fs.snapshot_create("s").unwrap();
+        fs.write_at(&file, &[3u8; 10], 4096 * 13).unwrap();
+        let mut buf = [0u8; 10];
+        fs.read_at(&file, &mut buf, 4096 * 13).unwrap();
+        assert_eq!(buf, [3u8; 10]);
+        fs.write_at(&file, &[4u8; 10], 4096 * 13).unwrap();
+        let new_map = vsfs::block_map(fs.disk(), inum);
+        assert_ne!(new_map[13], map[13]);
+        assert_eq!(fs.pages.cached_map(inum), Some(new_map.as_slice()));
+        fs.sync().unwrap();
+        assert_eq!(fs.snapshot_read("s", &path).unwrap()[4096 * 13..4096 * 13 + 10], [2u8; 10]);
+
+        let mut buf = vec![0u8; 10];
+        vsfs::read_inode(fs.disk(), inum, 4096 * 13, &mut buf).unwrap();
+        assert_eq!(buf, vec![4u8; 10]);
+        fs.close(file).unwrap();
+    }
+
+    #[test]
+    fn test_sync_image() {
+        use crate::io::Loadable;
+
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("fs.img");
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+        fs.set_image(&image);
+        let path = Path::from_str("/a").unwrap();
+        fs.create_file(&path).unwrap();
+        let mut file = fs.open(&path, AccessMode::ReadWrite).unwrap();
+        fs.write(&mut file, &[1u8; 5000]).unwrap();
+        fs.write_at(&file, &[2u8; 100], 0).unwrap();
+        assert!(!image.exists());
+
+        // fsync 把缓存中的页和磁盘一起写到镜像
+        fs.fsync(&file).unwrap();
+        let saved = Disk::load(&image).unwrap();
+        let mut buf = vec![0u8; 100];
+        vsfs::read_file(&saved, &path, 0, &mut buf).unwrap();
+        assert_eq!(buf, vec![2u8; 100]);
+
+        // sync 之后镜像中能看到新建的文件
+        fs.create_file(&Path::from_str("/b").unwrap()).unwrap();
+        fs.sync().unwrap();
+        let saved = Disk::load(&image).unwrap();
+        assert!(vsfs::get_inode_by_path(&saved, &Path::from_str("/b").unwrap()).is_some());
+    }
+
+    #[test]
+    fn test_open_inode() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+        fs.set_quota(&QuotaTarget::User(0), Limit::default(), Limit::new(0, 100)).unwrap();
+        let path = |s: &str| Path::from_str(s).unwrap();
+
+        fs.mkdir(&path("/d")).unwrap();
+        fs.create_file(&path("/d/a")).unwrap();
+        let mut writer = fs.open(&path("/d/a"), AccessMode::Write).unwrap();
+        fs.write(&mut writer, b"hello").unwrap();
+        fs.lock(&writer, LockKind::Exclusive, LockRange::File, LockWait::NonBlocking).unwrap();
+
+        // 移动之后句柄仍然指向同一个文件，锁也跟着文件走
+        fs.rename(&path("/d"), &path("/e")).unwrap();
+        fs.write(&mut writer, b" world").unwrap();
+        let reader = fs.open(&path("/e/a"), AccessMode::Read).unwrap();
+        assert!(matches!(
+            fs.lock(&reader, LockKind::Shared, LockRange::File, LockWait::NonBlocking),
+            Err(VerySimpleError::Lock(LockError::WouldBlock))
+        ));
+        fs.close(reader).unwrap();
+        assert!(matches!(fs.open(&path("/e/a"), AccessMode::Write), Err(VerySimpleError::FileCannotWrite)));
+
+        // 删除之后目录中没有了，数据保留到最后一次关闭
+        let mut reader = fs.open(&path("/e/a"), AccessMode::Read).unwrap();
+        let free = |fs: &VerySimpleFileSystem| logic::count_free_items(&fs.disk.i_bitmaps, logic::all_inode_range(&fs.disk.i_bitmaps));
+        let charged = |fs: &mut VerySimpleFileSystem| fs.quotas().unwrap()[0].entry.inodes_used;
+        let before = (free(&fs), charged(&mut fs));
+        fs.delete_file(&path("/e/a")).unwrap();
+        assert!(!fs.exists(&path("/e/a")).unwrap());
+        assert!(fs.list(&path("/e")).unwrap().is_empty());
+        fs.create_file(&path("/e/a")).unwrap();
+        assert!(matches!(fs.snapshot_create("s1"), Err(VerySimpleError::FileCannotWrite)));
+
+        fs.write(&mut writer, &[b'!'; 5000]).unwrap();
+        fs.close(writer).unwrap();
+        let mut buf = [0u8; 11];
+        fs.read(&mut reader, &mut buf).unwrap();
+        assert_eq!(&buf, b"hello world");
+        assert_eq!(fs.description(&reader).unwrap().size(), 5011);
+        assert_eq!((free(&fs), charged(&mut fs)), (before.0 - 1, before.1 + 1));
+
+        fs.close(reader).unwrap();
+        assert_eq!((free(&fs), charged(&mut fs)), before);
+        fs.snapshot_create("s1").unwrap();
+    }
+
+    #[test]
+    fn test_open_inode_dir_quota() {
+        let mut disk = Disk::with_size(1024).unwrap();
+        let mut fs = VerySimpleFileSystem::new(&mut disk);
+        fs.init().unwrap();
+        let path = |s: &str| Path::from_str(s).unwrap();
+
+        fs.mkdir(&path("/d")).unwrap();
+        fs.create_file(&path("/d/a")).unwrap();
+        fs.set_quota(&QuotaTarget::Dir(path("/d")), Limit::new(0, 4), Limit::default()).unwrap();
+        let used = |fs: &mut VerySimpleFileSystem| fs.quotas().unwrap()[0].entry.blocks_used;
+        let mut file = fs.open(&path("/d/a"), AccessMode::Write).unwrap();
+
+        // 移出目录之后的写入不再记到目录的配额上
+        fs.rename(&path("/d/a"), &path("/a")).unwrap();
+        fs.write(&mut file, &[1u8; 4096 * 3]).unwrap();
+        assert_eq!(used(&mut fs), 0);
+
+        // 移回来之后整个文件都算在目录上，继续写会超出配额
+        fs.mkdir(&path("/d/e")).unwrap();
+        fs.rename(&path("/a"), &path("/d/e/a")).unwrap();
+        fs.rename(&path("/d/e"), &path("/d/f")).unwrap();
+        assert_eq!(used(&mut fs), 3);
+        assert!(matches!(
+            fs.write(&mut file, &[1u8; 4096 * 2]),
+            Err(VerySimpleError::VSFSError(vsfs::Error::QuotaExceeded))
+        ));
+        fs.close(file).unwrap();
+    }
+
+    #[test]
+    fn test_conformance() {
+        crate::vfs::conformance::run_all(|| VerySimpleFileSystem::with_disk(Disk::with_size(1024).unwrap()));
+    }
+}